@@ -0,0 +1,170 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use fs_err as fs;
+use std::path::PathBuf;
+use tdx_measure::{FileChange, FirmwareDiff, FirmwareReport, FirmwareVolume};
+
+#[derive(Subcommand)]
+pub enum FirmwareCommand {
+    /// Show the TDVF sections, firmware volumes and files of a firmware image
+    Inspect {
+        /// Path to the firmware image, e.g. OVMF.fd
+        firmware: PathBuf,
+
+        /// Output JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Explain which TDVF sections, GPA ranges and FFS files differ between two firmware images
+    Diff {
+        /// Path to the old firmware image
+        old: PathBuf,
+
+        /// Path to the new firmware image
+        new: PathBuf,
+
+        /// Output JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+pub fn run(command: &FirmwareCommand) -> Result<()> {
+    match command {
+        FirmwareCommand::Inspect { firmware, json } => {
+            let fw = fs::read(firmware)?;
+            let report = tdx_measure::inspect_firmware(&fw).context("Failed to inspect firmware")?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_report(&report);
+            }
+        }
+        FirmwareCommand::Diff { old, new, json } => {
+            let old_fw = fs::read(old)?;
+            let new_fw = fs::read(new)?;
+            let diff = tdx_measure::diff_firmware(&old_fw, &new_fw).context("Failed to compare firmware")?;
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&diff)?);
+            } else {
+                print_diff(&diff);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_diff(diff: &FirmwareDiff) {
+    println!("MRTD old: {}", hex::encode(&diff.old_mrtd));
+    println!("MRTD new: {}", hex::encode(&diff.new_mrtd));
+    println!("CFV digest old: {}", hex::encode(&diff.old_cfv_digest));
+    println!("CFV digest new: {}", hex::encode(&diff.new_cfv_digest));
+    println!("TD HOB digest old: {}", hex::encode(&diff.old_td_hob_digest));
+    println!("TD HOB digest new: {}", hex::encode(&diff.new_td_hob_digest));
+    let affected = match (diff.affects_mrtd, diff.affects_rtmr0) {
+        (true, true) => "MRTD and RTMR[0]",
+        (true, false) => "MRTD",
+        (false, true) => "RTMR[0]",
+        (false, false) => "none",
+    };
+    println!("Affected measurements: {}", affected);
+
+    if !diff.sections.is_empty() {
+        println!();
+        println!("TDVF sections:");
+    }
+    for section in &diff.sections {
+        let address = |a: Option<u64>| a.map_or("absent".to_string(), |a| format!("{:#010x}", a));
+        let mut measured = Vec::new();
+        if section.affects_mrtd {
+            measured.push("MRTD");
+        }
+        if section.affects_rtmr0 {
+            measured.push("RTMR[0]");
+        }
+        println!(
+            "  {} at {} -> {}{}{}",
+            section.section_type,
+            address(section.old_address),
+            address(section.new_address),
+            if section.layout_changed { ", layout changed" } else { "" },
+            if measured.is_empty() { String::new() } else { format!(" (affects {})", measured.join(", ")) },
+        );
+        for range in &section.ranges {
+            println!("    GPA {:#010x}-{:#010x} differs", range.start, range.end);
+        }
+    }
+
+    if !diff.files.is_empty() {
+        println!();
+        println!("FFS files:");
+    }
+    for file in &diff.files {
+        let change = match file.change {
+            FileChange::Added => "added",
+            FileChange::Removed => "removed",
+            FileChange::Modified => "modified",
+        };
+        let line = format!(
+            "  {:<8} {} {:<4} {}",
+            change,
+            file.guid,
+            file.section.as_deref().unwrap_or("-"),
+            file.ui_name.as_deref().unwrap_or(""),
+        );
+        println!("{}", line.trim_end());
+    }
+}
+
+fn print_report(report: &FirmwareReport) {
+    println!("Size: {:#x}", report.size);
+    println!(
+        "Firmware version: {} ({})",
+        report.firmware_version.version, report.firmware_version.release_date
+    );
+    if let Some(ref build_path) = report.build_path {
+        println!("Build: {}", build_path);
+    }
+
+    println!();
+    println!("TDVF sections:");
+    for section in &report.sections {
+        let line = format!(
+            "  {:<12} offset {:#010x} size {:#010x}  GPA {:#010x}-{:#010x}  {}",
+            section.section_type,
+            section.data_offset,
+            section.raw_data_size,
+            section.memory_address,
+            section.memory_address + section.memory_data_size,
+            section.attributes_string(),
+        );
+        println!("{}", line.trim_end());
+    }
+
+    for volume in &report.volumes {
+        println!();
+        print_volume(volume, 0);
+    }
+}
+
+fn print_volume(volume: &FirmwareVolume, depth: usize) {
+    let indent = "  ".repeat(depth);
+    println!("{}Firmware volume {} at {:#x}, {:#x} bytes", indent, volume.file_system, volume.offset, volume.length);
+    for file in &volume.files {
+        let mut line = format!("{}  {:#010x} {} {:<22}", indent, file.offset, file.guid, file.file_type);
+        if let Some(ref name) = file.ui_name {
+            line.push_str(&format!(" {}", name));
+        }
+        if let Some(ref version) = file.version {
+            line.push_str(&format!(" ({})", version));
+        }
+        println!("{}", line.trim_end());
+        for nested in &file.volumes {
+            print_volume(nested, depth + 2);
+        }
+    }
+}
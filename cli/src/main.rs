@@ -6,13 +6,17 @@
  */
 
 use anyhow::{Context, Result, anyhow};
-use clap::Parser;
-use tdx_measure::{Machine, ImageConfig};
+use clap::{Parser, Subcommand};
+use tdx_measure::{BootOptions, GrubConfig, Machine, ImageConfig, Rtmr3Event, SystemdBootConfig, TdxGuestConfig, UpgradeConfig, Vmm};
 use fs_err as fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
+mod firmware;
+mod td_hob;
 mod transcript;
+use firmware::FirmwareCommand;
+use td_hob::{search_memory_size, show_td_hob};
 use transcript::generate_transcript;
 
 const CREATE_ACPI_TABLES_SCRIPT: &str = include_str!("../../create_acpi_tables.sh");
@@ -20,9 +24,14 @@ const DOCKERFILE_QEMU_ACPI_DUMP: &str = include_str!("../../Dockerfile.qemu-acpi
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// Path to metadata json file
-    metadata: PathBuf,
+    #[arg(required = true)]
+    metadata: Option<PathBuf>,
 
     /// Enable direct boot (overrides JSON configuration)
     #[arg(long)]
@@ -40,7 +49,7 @@ struct Cli {
     #[arg(long)]
     platform_only: bool,
 
-    /// Compute RTMR1 and RTMR2 only
+    /// Compute RTMR1 and RTMR2 only, and RTMR3 when the metadata lists its events
     #[arg(long)]
     runtime_only: bool,
 
@@ -48,9 +57,44 @@ struct Cli {
     #[arg(long)]
     transcript: Option<PathBuf>,
 
+    /// Show the TD HOB and memory map measured into RTMR0 instead of the measurements
+    #[arg(long)]
+    td_hob: bool,
+
+    /// Compare the TD HOB with a dump of the TD_HOB section taken from the guest
+    #[arg(long)]
+    compare_td_hob: Option<PathBuf>,
+
+    /// Search the memory sizes whose TD HOB reproduces an observed RTMR0 (hex)
+    #[arg(long, value_name = "RTMR0")]
+    search_memory: Option<String>,
+
+    /// Step between the memory sizes searched
+    #[arg(long, default_value = "64M", requires = "search_memory")]
+    memory_step: String,
+
+    /// Largest memory size searched
+    #[arg(long, default_value = "1T", requires = "search_memory")]
+    max_memory: String,
+
     /// Generate ACPI tables for direct boot and a specific distribution, e.g., ubuntu:25.04
     #[arg(long)]
     create_acpi_tables: Option<String>,
+
+    /// Write the initrd built from `initrd_tree` to its `output` path
+    #[arg(long)]
+    write_initrd: bool,
+
+    /// Write the firmware customized by `firmware_variables` to its `output` path
+    #[arg(long)]
+    write_firmware: bool,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Inspect firmware images
+    #[command(subcommand)]
+    Firmware(FirmwareCommand),
 }
 
 /// Helper struct to resolve and store file paths
@@ -59,7 +103,8 @@ struct PathResolver {
 }
 
 struct PathStorage {
-    cpu_count: u8,
+    vmm: Vmm,
+    cpu_count: u32,
     memory_size: u64,
     firmware: String,
     cmdline: String,
@@ -68,15 +113,38 @@ struct PathStorage {
     table_loader: Option<String>,
     boot_order: Option<String>,
     path_boot_xxxx: Option<String>,
+    boot_options: Option<BootOptions>,
+    mrconfigid: Option<Vec<u8>>,
+    mrowner: Option<Vec<u8>>,
+    mrownerconfig: Option<Vec<u8>>,
+    tdx_guest: Option<TdxGuestConfig>,
+    cpu: Option<String>,
     // Direct boot specific
     kernel: Option<String>,
-    initrd: Option<String>,
-    // Indirect boot specific
-    qcow2: Option<String>,
+    initrds: Vec<String>,
+    shim: Option<String>,
+    // Shim specific, for indirect boot or direct boot through shim
     mok_list: Option<String>,
     mok_list_trusted: Option<String>,
     mok_list_x: Option<String>,
     sbat_level: Option<String>,
+    // Indirect boot specific
+    qcow2: Option<String>,
+    grub: Option<GrubConfig>,
+    systemd_boot: Option<SystemdBootConfig>,
+    upgrade: Option<UpgradeConfig>,
+    // Workload specific
+    rtmr3_events: Option<Vec<Rtmr3Event>>,
+}
+
+/// Makes the host paths of an upgrade relative to the metadata directory.
+fn resolve_upgrade(upgrade: &UpgradeConfig, parent_dir: &Path) -> UpgradeConfig {
+    let resolve = |p: &String| parent_dir.join(p).display().to_string();
+    UpgradeConfig {
+        packages: upgrade.packages.iter().map(resolve).collect(),
+        install: upgrade.install.clone(),
+        files: upgrade.files.iter().map(|(guest, host)| (guest.clone(), resolve(host))).collect(),
+    }
 }
 
 impl PathResolver {
@@ -86,6 +154,7 @@ impl PathResolver {
         // Handle optional boot_config
         let paths = if let Some(boot_config) = &image_config.boot_config {
             PathStorage {
+                vmm: boot_config.vmm,
                 cpu_count: boot_config.cpus,
                 memory_size: image_config.memory_size()?,
                 firmware: parent_dir.join(&boot_config.bios).display().to_string(),
@@ -95,13 +164,24 @@ impl PathResolver {
                 table_loader: boot_config.table_loader.as_ref().map(|p| parent_dir.join(p).display().to_string()),
                 boot_order: boot_config.boot_order.as_ref().map(|p| parent_dir.join(p).display().to_string()),
                 path_boot_xxxx: boot_config.path_boot_xxxx.as_ref().map(|p| parent_dir.join(p).display().to_string()),
+                boot_options: boot_config.boot_options.clone(),
+                mrconfigid: boot_config.mrconfigid.as_ref().map(|v| v.resolve(parent_dir)).transpose().context("Invalid mrconfigid")?,
+                mrowner: boot_config.mrowner.as_ref().map(|v| v.resolve(parent_dir)).transpose().context("Invalid mrowner")?,
+                mrownerconfig: boot_config.mrownerconfig.as_ref().map(|v| v.resolve(parent_dir)).transpose().context("Invalid mrownerconfig")?,
+                tdx_guest: boot_config.tdx_guest.clone(),
+                cpu: boot_config.cpu.clone(),
                 kernel: image_config.direct_boot().map(|d| parent_dir.join(&d.kernel).display().to_string()),
-                initrd: image_config.direct_boot().map(|d| parent_dir.join(&d.initrd).display().to_string()),
+                initrds: image_config.direct_boot().map(|d| d.initrd.iter().map(|p| parent_dir.join(p).display().to_string()).collect()).unwrap_or_default(),
                 qcow2: image_config.indirect_boot().map(|i| parent_dir.join(&i.qcow2).display().to_string()),
-                mok_list: image_config.indirect_boot().map(|i| parent_dir.join(&i.mok_list).display().to_string()),
-                mok_list_trusted: image_config.indirect_boot().map(|i| parent_dir.join(&i.mok_list_trusted).display().to_string()),
-                mok_list_x: image_config.indirect_boot().map(|i| parent_dir.join(&i.mok_list_x).display().to_string()),
-                sbat_level: image_config.indirect_boot().map(|i| parent_dir.join(&i.sbat_level).display().to_string()),
+                mok_list: image_config.indirect_boot().map(|i| i.mok_list.as_str()).or_else(|| image_config.direct_boot().and_then(|d| d.mok_list.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                mok_list_trusted: image_config.indirect_boot().map(|i| i.mok_list_trusted.as_str()).or_else(|| image_config.direct_boot().and_then(|d| d.mok_list_trusted.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                mok_list_x: image_config.indirect_boot().map(|i| i.mok_list_x.as_str()).or_else(|| image_config.direct_boot().and_then(|d| d.mok_list_x.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                sbat_level: image_config.indirect_boot().map(|i| i.sbat_level.as_str()).filter(|p| !p.is_empty()).or_else(|| image_config.direct_boot().and_then(|d| d.sbat_level.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                shim: image_config.direct_boot().and_then(|d| d.shim.as_ref()).map(|p| parent_dir.join(p).display().to_string()),
+                grub: image_config.indirect_boot().and_then(|i| i.grub.clone()),
+                systemd_boot: image_config.indirect_boot().and_then(|i| i.systemd_boot.clone()),
+                upgrade: image_config.indirect_boot().and_then(|i| i.upgrade.as_ref()).map(|u| resolve_upgrade(u, parent_dir)),
+                rtmr3_events: image_config.rtmr3.clone(),
             }
         } else {
             // When boot_config is None (runtime-only mode), provide empty strings for platform fields
@@ -109,6 +189,7 @@ impl PathResolver {
                 return Err(anyhow!("Boot info is required but not provided in the configuration"));
             }
             PathStorage {
+                vmm: Vmm::default(),
                 cpu_count: 0,
                 memory_size: 0,
                 firmware: String::new(),
@@ -118,13 +199,24 @@ impl PathResolver {
                 table_loader: None,
                 boot_order: None,
                 path_boot_xxxx: None,
+                boot_options: None,
+                mrconfigid: None,
+                mrowner: None,
+                mrownerconfig: None,
+                tdx_guest: None,
+                cpu: None,
                 kernel: image_config.direct_boot().map(|d| parent_dir.join(&d.kernel).display().to_string()),
-                initrd: image_config.direct_boot().map(|d| parent_dir.join(&d.initrd).display().to_string()),
+                initrds: image_config.direct_boot().map(|d| d.initrd.iter().map(|p| parent_dir.join(p).display().to_string()).collect()).unwrap_or_default(),
                 qcow2: image_config.indirect_boot().map(|i| parent_dir.join(&i.qcow2).display().to_string()),
-                mok_list: image_config.indirect_boot().map(|i| parent_dir.join(&i.mok_list).display().to_string()),
-                mok_list_trusted: image_config.indirect_boot().map(|i| parent_dir.join(&i.mok_list_trusted).display().to_string()),
-                mok_list_x: image_config.indirect_boot().map(|i| parent_dir.join(&i.mok_list_x).display().to_string()),
-                sbat_level: image_config.indirect_boot().map(|i| parent_dir.join(&i.sbat_level).display().to_string()),
+                mok_list: image_config.indirect_boot().map(|i| i.mok_list.as_str()).or_else(|| image_config.direct_boot().and_then(|d| d.mok_list.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                mok_list_trusted: image_config.indirect_boot().map(|i| i.mok_list_trusted.as_str()).or_else(|| image_config.direct_boot().and_then(|d| d.mok_list_trusted.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                mok_list_x: image_config.indirect_boot().map(|i| i.mok_list_x.as_str()).or_else(|| image_config.direct_boot().and_then(|d| d.mok_list_x.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                sbat_level: image_config.indirect_boot().map(|i| i.sbat_level.as_str()).filter(|p| !p.is_empty()).or_else(|| image_config.direct_boot().and_then(|d| d.sbat_level.as_deref())).map(|p| parent_dir.join(p).display().to_string()),
+                shim: image_config.direct_boot().and_then(|d| d.shim.as_ref()).map(|p| parent_dir.join(p).display().to_string()),
+                grub: image_config.indirect_boot().and_then(|i| i.grub.clone()),
+                systemd_boot: image_config.indirect_boot().and_then(|i| i.systemd_boot.clone()),
+                upgrade: image_config.indirect_boot().and_then(|i| i.upgrade.as_ref()).map(|u| resolve_upgrade(u, parent_dir)),
+                rtmr3_events: image_config.rtmr3.clone(),
             }
         };
 
@@ -142,21 +234,56 @@ impl PathResolver {
             .table_loader(self.paths.table_loader.as_deref().unwrap_or(""))
             .boot_order(self.paths.boot_order.as_deref().unwrap_or(""))
             .path_boot_xxxx(self.paths.path_boot_xxxx.as_deref().unwrap_or(""))
+            .maybe_boot_options(self.paths.boot_options.as_ref())
             .kernel(self.paths.kernel.as_deref().unwrap_or(""))
-            .initrd(self.paths.initrd.as_deref().unwrap_or(""))
+            .initrds(&self.paths.initrds)
             .qcow2(self.paths.qcow2.as_deref().unwrap_or(""))
-            .mok_list(self.paths.mok_list.as_deref().unwrap_or(""))
-            .mok_list_trusted(self.paths.mok_list_trusted.as_deref().unwrap_or(""))
-            .mok_list_x(self.paths.mok_list_x.as_deref().unwrap_or(""))
-            .sbat_level(self.paths.sbat_level.as_deref().unwrap_or(""))
+            .maybe_mok_list(self.paths.mok_list.as_deref())
+            .maybe_mok_list_trusted(self.paths.mok_list_trusted.as_deref())
+            .maybe_mok_list_x(self.paths.mok_list_x.as_deref())
+            .maybe_sbat_level(self.paths.sbat_level.as_deref())
+            .maybe_shim(self.paths.shim.as_deref())
+            .maybe_grub(self.paths.grub.as_ref())
+            .maybe_systemd_boot(self.paths.systemd_boot.as_ref())
             .direct_boot(direct_boot)
+            .vmm(self.paths.vmm)
+            .maybe_mrconfigid(self.paths.mrconfigid.as_deref())
+            .maybe_mrowner(self.paths.mrowner.as_deref())
+            .maybe_mrownerconfig(self.paths.mrownerconfig.as_deref())
+            .maybe_tdx_guest(self.paths.tdx_guest.as_ref())
+            .maybe_cpu(self.paths.cpu.as_deref())
+            .maybe_rtmr3_events(self.paths.rtmr3_events.as_deref())
+            .maybe_upgrade(self.paths.upgrade.as_ref())
             .build()
     }
 }
 
-fn generate_acpi_tables(metadata_path: &Path, distribution: &str) -> Result<()> {
+fn generate_acpi_tables(metadata_path: &Path, image_config: &ImageConfig, distribution: &str, platform_args: &[String]) -> Result<()> {
     let tmp_dir = std::env::temp_dir();
 
+    // The script reads the settings of the effective configuration, e.g. of a
+    // Kata profile, with paths made absolute as they are relative to the metadata
+    let parent_dir = metadata_path.parent().unwrap_or(".".as_ref());
+    let resolve = |path: &str| -> Result<String> {
+        Ok(std::path::absolute(parent_dir.join(path))?.display().to_string())
+    };
+    let boot_config = image_config.boot_config.as_ref().context("Boot config is required")?;
+    let direct = image_config.direct_boot().context("Direct boot configuration is required")?;
+    let effective_metadata = serde_json::json!({
+        "boot_config": {
+            "bios": resolve(&boot_config.bios)?,
+            "acpi_tables": resolve(&boot_config.acpi_tables)?,
+        },
+        "direct": {
+            "kernel": resolve(&direct.kernel)?,
+            "initrd": direct.initrd.first().map(|p| resolve(p)).transpose()?,
+            "cmdline": direct.cmdline,
+        },
+    });
+    let effective_metadata_path = tmp_dir.join("acpi_tables_metadata.json");
+    std::fs::write(&effective_metadata_path, effective_metadata.to_string())
+        .context("Failed to write the metadata of the ACPI table generation")?;
+
     // Write the embedded script to a temporary file
     let script_path = tmp_dir.join("create_acpi_tables.sh");
     std::fs::write(&script_path, CREATE_ACPI_TABLES_SCRIPT)
@@ -172,9 +299,11 @@ fn generate_acpi_tables(metadata_path: &Path, distribution: &str) -> Result<()>
     let output = Command::new("bash")
         .arg(&script_path)
         .arg("-j")
-        .arg(metadata_path)
+        .arg(&effective_metadata_path)
         .arg("-d")
         .arg(distribution)
+        .arg("--")
+        .args(platform_args)
         .output()
         .context("Failed to execute create_acpi_tables.sh script")?;
 
@@ -185,7 +314,7 @@ fn generate_acpi_tables(metadata_path: &Path, distribution: &str) -> Result<()>
     Ok(())
 }
 
-fn process_measurements(config: &Cli, image_config: &ImageConfig) -> Result<()> {
+fn process_measurements(config: &Cli, metadata_path: &Path, image_config: &ImageConfig) -> Result<()> {
     // Validate the configuration
     image_config.validate()
         .map_err(|e| anyhow!("Invalid image configuration: {}", e))?;
@@ -209,11 +338,13 @@ fn process_measurements(config: &Cli, image_config: &ImageConfig) -> Result<()>
             return Err(anyhow!("--create-acpi-tables flag is only valid with direct boot mode"));
         }
 
-        generate_acpi_tables(&config.metadata, distribution)?;
+        let boot_config = image_config.boot_config.as_ref().context("Boot config is required")?;
+        let platform_args = boot_config.qemu_platform_args().context("Invalid platform configuration")?;
+        generate_acpi_tables(metadata_path, image_config, distribution, &platform_args)?;
     }
 
     // Build machine
-    let path_resolver = PathResolver::new(&config.metadata, image_config, !config.runtime_only)?;
+    let path_resolver = PathResolver::new(metadata_path, image_config, !config.runtime_only)?;
     let machine = path_resolver.build_machine(direct_boot);
 
     // Generate transcript
@@ -221,6 +352,16 @@ fn process_measurements(config: &Cli, image_config: &ImageConfig) -> Result<()>
         return generate_transcript(transcript_file, &path_resolver, direct_boot, config.platform_only, config.runtime_only);
     }
 
+    // Show TD HOB
+    if config.td_hob || config.compare_td_hob.is_some() {
+        return show_td_hob(&machine, config.compare_td_hob.as_deref(), config.json);
+    }
+
+    // Search memory size
+    if let Some(ref rtmr0) = config.search_memory {
+        return search_memory_size(&machine, rtmr0, &config.memory_step, &config.max_memory, config.json);
+    }
+
     // Measure
     let measurements = if config.platform_only {
         machine.measure_platform().context("Failed to measure platform")?
@@ -247,6 +388,18 @@ fn output_measurements(config: &Cli, measurements: &tdx_measure::TdxMeasurements
         println!("RTMR0: {}", hex::encode(&measurements.rtmr0));
         println!("RTMR1: {}", hex::encode(&measurements.rtmr1));
         println!("RTMR2: {}", hex::encode(&measurements.rtmr2));
+        if !measurements.rtmr3.is_empty() {
+            println!("RTMR3: {}", hex::encode(&measurements.rtmr3));
+        }
+        if !measurements.mrconfigid.is_empty() {
+            println!("MRCONFIGID: {}", hex::encode(&measurements.mrconfigid));
+            println!("MROWNER: {}", hex::encode(&measurements.mrowner));
+            println!("MROWNERCONFIG: {}", hex::encode(&measurements.mrownerconfig));
+        }
+        if !measurements.td_attributes.is_empty() {
+            println!("TDATTRIBUTES: {}", hex::encode(&measurements.td_attributes));
+            println!("XFAM: {}", hex::encode(&measurements.xfam));
+        }
     }
 
     if let Some(ref json_file) = config.json_file {
@@ -261,12 +414,29 @@ fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let cli = Cli::parse();
-    let metadata = fs::read_to_string(&cli.metadata)
+    if let Some(Commands::Firmware(command)) = &cli.command {
+        return firmware::run(command);
+    }
+
+    let metadata_path = cli.metadata.as_deref().context("Metadata file required")?;
+    let base_dir = metadata_path.parent().unwrap_or(".".as_ref());
+    let metadata = fs::read_to_string(metadata_path)
         .context("Failed to read image metadata")?;
-    let image_config: ImageConfig = serde_json::from_str(&metadata)
+    let mut image_config: ImageConfig = serde_json::from_str(&metadata)
         .context("Failed to parse image metadata")?;
-
-    process_measurements(&cli, &image_config)?;
+    image_config.validate()
+        .map_err(|e| anyhow!("Invalid image configuration: {}", e))?;
+    image_config.apply_kata_profile(base_dir)
+        .context("Failed to apply Kata profile")?;
+    // Temporary files of the built firmware and initrd, kept until measured
+    let _firmware = image_config.customize_firmware(base_dir, cli.write_firmware)
+        .context("Failed to customize firmware variables")?;
+    let _initrd = image_config.build_initrd(base_dir, cli.write_initrd)
+        .context("Failed to build initrd")?;
+    image_config.apply_verity(base_dir)
+        .context("Failed to compute dm-verity root hash")?;
+
+    process_measurements(&cli, metadata_path, &image_config)?;
 
     Ok(())
 }
@@ -0,0 +1,94 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+use anyhow::{Context, Result};
+use fs_err as fs;
+use std::path::Path;
+use tdx_measure::{Hob, Machine};
+
+/// Prints the TD HOB of the machine and, with a dump taken from the guest,
+/// the HOBs that differ from it.
+pub fn show_td_hob(machine: &Machine, dump: Option<&Path>, json: bool) -> Result<()> {
+    let td_hob = machine.td_hob()?;
+    let hobs = td_hob.hobs().context("Failed to parse TD HOB")?;
+    let differences = match dump {
+        Some(dump) => {
+            let data = fs::read(dump)?;
+            let actual = tdx_measure::parse_hob_list(&data).context("Failed to parse TD HOB dump")?;
+            Some(tdx_measure::compare_hob_lists(&hobs, &actual))
+        }
+        None => None,
+    };
+
+    if json {
+        let output = serde_json::json!({
+            "td_hob": td_hob,
+            "hobs": hobs,
+            "differences": differences,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("TD HOB at {:#x}, {} bytes", td_hob.address, td_hob.data.len());
+    println!("Digest: {}", hex::encode(td_hob.digest()));
+    println!();
+    println!("Accepted by the memory acceptor:");
+    for range in &td_hob.accepted {
+        println!("  {:#012x}-{:#012x} {}", range.start, range.end, range.section);
+    }
+    println!();
+    println!("HOBs:");
+    for (index, hob) in hobs.iter().enumerate() {
+        println!("  [{}] {}", index, hob);
+    }
+
+    if let Some(differences) = differences {
+        println!();
+        if differences.is_empty() {
+            println!("The dump matches the TD HOB");
+        } else {
+            println!("Differences from the dump:");
+        }
+        for difference in &differences {
+            let hob = |h: &Option<Hob>| h.as_ref().map_or("absent".to_string(), |h| h.to_string());
+            println!("  [{}] expected: {}", difference.index, hob(&difference.expected));
+            println!("  [{}] actual:   {}", difference.index, hob(&difference.actual));
+        }
+    }
+    Ok(())
+}
+
+/// Prints the memory sizes whose TD HOB reproduces an observed RTMR0.
+pub fn search_memory_size(machine: &Machine, rtmr0: &str, step: &str, max: &str, json: bool) -> Result<()> {
+    let rtmr0 = hex::decode(rtmr0.trim()).context("Invalid RTMR0")?;
+    let step = tdx_measure::parse_memory_size(step).context("Invalid memory step")?;
+    let max = tdx_measure::parse_memory_size(max).context("Invalid maximum memory size")?;
+    let search = machine.search_memory_size(&rtmr0, step, max)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&search)?);
+        return Ok(());
+    }
+
+    println!("Searched {} memory sizes", search.candidates);
+    if search.distinct_td_hobs {
+        println!("Each memory size gives a different TD HOB");
+    } else {
+        println!("Some memory sizes give the same TD HOB");
+    }
+    if search.matches.is_empty() {
+        println!("No memory size reproduces RTMR0");
+    } else {
+        println!("Memory sizes reproducing RTMR0:");
+    }
+    for m in &search.matches {
+        println!("  -m {} ({:#x} bytes)", m.qemu_option, m.memory_size);
+    }
+    println!(
+        "Note: the ACPI tables are those of -m {}, a match at another size only holds if they do not depend on the memory size",
+        tdx_measure::qemu_memory_option(search.acpi_memory_size)
+    );
+    Ok(())
+}
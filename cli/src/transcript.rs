@@ -9,6 +9,7 @@ use anyhow::{Context, Result, anyhow};
 use fs_err as fs;
 use std::path::Path;
 use crate::{PathResolver, PathStorage};
+use tdx_measure::{BootOptions, EfiLoadOption};
 
 /// Generate a human-readable transcript of metadata files
 pub fn generate_transcript(output_file: &Path, path_resolver: &PathResolver, direct_boot: bool, platform_only: bool, runtime_only: bool) -> Result<()> {
@@ -25,6 +26,12 @@ pub fn generate_transcript(output_file: &Path, path_resolver: &PathResolver, dir
 
         // Display boot order and boot variables
         write_boot_variables(&mut output, &path_resolver.paths, direct_boot)?;
+
+        // Display TD registers set by the VMM at launch
+        write_td_registers(&mut output, &path_resolver.paths);
+
+        // Display the variables baked into the firmware CFV
+        write_firmware_variables(&mut output, &path_resolver.paths)?;
     }
 
     if !platform_only {
@@ -33,8 +40,13 @@ pub fn generate_transcript(output_file: &Path, path_resolver: &PathResolver, dir
         writeln!(output, "{}", path_resolver.paths.cmdline).unwrap();
         writeln!(output).unwrap();
 
-        // Display MOK variables and SBAT level for indirect boot
-        if !direct_boot {
+        // Display MOK variables and SBAT level when booting through shim
+        let through_shim = if direct_boot {
+            path_resolver.paths.shim.is_some()
+        } else {
+            path_resolver.paths.systemd_boot.is_none()
+        };
+        if through_shim {
             write_mok_variables(&mut output, &path_resolver.paths)?;
             write_sbat_level(&mut output, &path_resolver.paths)?;
         }
@@ -49,6 +61,47 @@ pub fn generate_transcript(output_file: &Path, path_resolver: &PathResolver, dir
     Ok(())
 }
 
+/// Write the MRCONFIGID, MROWNER and MROWNERCONFIG values, all zeros when not configured
+fn write_td_registers(output: &mut Vec<u8>, paths: &PathStorage) {
+    writeln!(output, "=== TD Registers ===").unwrap();
+    for (name, value) in [
+        ("MRCONFIGID", &paths.mrconfigid),
+        ("MROWNER", &paths.mrowner),
+        ("MROWNERCONFIG", &paths.mrownerconfig),
+    ] {
+        match value {
+            Some(value) => writeln!(output, "{}: {}", name, hex::encode(value)).unwrap(),
+            None => writeln!(output, "{}: (not set, all zeros)", name).unwrap(),
+        }
+    }
+    writeln!(output).unwrap();
+}
+
+/// Write the variables of the firmware CFV, flagging those that change the Secure Boot events
+fn write_firmware_variables(output: &mut Vec<u8>, paths: &PathStorage) -> Result<()> {
+    writeln!(output, "=== Firmware Variables (CFV) ===").unwrap();
+    let fw_data = fs::read(&paths.firmware)?;
+    match tdx_measure::read_firmware_variables(&fw_data) {
+        Ok(store) => {
+            writeln!(output, "Variable store: {} bytes, {}", store.size,
+                if store.authenticated { "authenticated" } else { "not authenticated" }).unwrap();
+            for variable in &store.variables {
+                writeln!(output, "{}-{} ({}), {} bytes", variable.vendor_guid, variable.name,
+                    variable.attributes_string(), variable.data.len()).unwrap();
+                if variable.is_secure_boot_variable() {
+                    writeln!(output, "  Secure Boot configuration, measured in RTMR0").unwrap();
+                }
+                if !variable.data.is_empty() {
+                    writeln!(output, "  {}", hex::encode(&variable.data)).unwrap();
+                }
+            }
+        }
+        Err(e) => writeln!(output, "(no variable store: {:#})", e).unwrap(),
+    }
+    writeln!(output).unwrap();
+    Ok(())
+}
+
 /// ACPI Table info extracted from header
 struct AcpiTableInfo {
     signature: String,
@@ -299,215 +352,6 @@ fn write_raw_table_hex_dump(output: &mut Vec<u8>, data: &[u8], table_name: &str)
 // Specification can be found at https://uefi.org/specs/UEFI/2.10/03_Boot_Manager.html
 
 
-/// EFI_LOAD_OPTION structure representing a UEFI boot option
-#[derive(Debug)]
-struct EfiLoadOption {
-    attributes: u32,
-    file_path_list_length: u16,
-    description: String,
-    file_path_list: Vec<u8>,
-    optional_data: Vec<u8>,
-}
-
-/// UEFI Load Option Attributes constants
-const LOAD_OPTION_ACTIVE: u32 = 0x00000001;
-const LOAD_OPTION_FORCE_RECONNECT: u32 = 0x00000002;
-const LOAD_OPTION_HIDDEN: u32 = 0x00000008;
-const LOAD_OPTION_CATEGORY: u32 = 0x00001F00;
-
-/// UEFI Device Path structure
-#[derive(Debug, Clone)]
-struct DevicePath {
-    path_type: u8,
-    sub_type: u8,
-    data: Vec<u8>,
-}
-
-/// Device Path Types
-const DEVICE_PATH_TYPE_HARDWARE: u8 = 0x01;
-const DEVICE_PATH_TYPE_ACPI: u8 = 0x02;
-const DEVICE_PATH_TYPE_MESSAGING: u8 = 0x03;
-const DEVICE_PATH_TYPE_MEDIA: u8 = 0x04;
-const DEVICE_PATH_TYPE_BIOS_BOOT: u8 = 0x05;
-const DEVICE_PATH_TYPE_END: u8 = 0x7F;
-
-/// Device Path SubTypes
-const DEVICE_PATH_SUBTYPE_PCI: u8 = 0x01;
-const DEVICE_PATH_SUBTYPE_ACPI: u8 = 0x01;
-const DEVICE_PATH_SUBTYPE_HARD_DRIVE: u8 = 0x01;
-const DEVICE_PATH_SUBTYPE_FILE_PATH: u8 = 0x04;
-
-/// Parse device path list into individual device paths
-fn parse_device_paths(data: &[u8]) -> Result<Vec<DevicePath>> {
-    let mut paths = Vec::new();
-    let mut offset = 0;
-
-    while offset < data.len() {
-        if offset + 4 > data.len() {
-            break; // Not enough data for header
-        }
-
-        let path_type = data[offset];
-        let sub_type = data[offset + 1];
-        let length = u16::from_le_bytes([data[offset + 2], data[offset + 3]]);
-
-        if length < 4 {
-            break; // Invalid length
-        }
-
-        if offset + length as usize > data.len() {
-            break; // Not enough data for this path
-        }
-
-        let path_data = if length > 4 {
-            data[offset + 4..offset + length as usize].to_vec()
-        } else {
-            Vec::new()
-        };
-
-        paths.push(DevicePath {
-            path_type,
-            sub_type,
-            data: path_data,
-        });
-
-        // Check for end of device path
-        if path_type == DEVICE_PATH_TYPE_END {
-            break;
-        }
-
-        offset += length as usize;
-    }
-
-    Ok(paths)
-}
-
-/// Format device path as human-readable string
-fn format_device_path(path: &DevicePath) -> String {
-    match path.path_type {
-        DEVICE_PATH_TYPE_HARDWARE => format_hardware_device_path(path),
-        DEVICE_PATH_TYPE_ACPI => format_acpi_device_path(path),
-        DEVICE_PATH_TYPE_MESSAGING => format_messaging_device_path(path),
-        DEVICE_PATH_TYPE_MEDIA => format_media_device_path(path),
-        DEVICE_PATH_TYPE_BIOS_BOOT => format_bios_boot_device_path(path),
-        DEVICE_PATH_TYPE_END => "End".to_string(),
-        _ => format!("Unknown(Type={}, SubType={}, {} bytes)",
-                    path.path_type, path.sub_type, path.data.len()),
-    }
-}
-
-/// Format hardware device path
-fn format_hardware_device_path(path: &DevicePath) -> String {
-    match path.sub_type {
-        DEVICE_PATH_SUBTYPE_PCI => {
-            if path.data.len() >= 2 {
-                let function = path.data[0];
-                let device = path.data[1];
-                format!("PCI(0x{:02X},0x{:02X})", device, function)
-            } else {
-                "PCI(Invalid)".to_string()
-            }
-        }
-        _ => format!("Hardware(SubType={}, {} bytes)", path.sub_type, path.data.len()),
-    }
-}
-
-/// Format ACPI device path
-fn format_acpi_device_path(path: &DevicePath) -> String {
-    match path.sub_type {
-        DEVICE_PATH_SUBTYPE_ACPI => {
-            if path.data.len() >= 8 {
-                let hid = u32::from_le_bytes([path.data[0], path.data[1], path.data[2], path.data[3]]);
-                let uid = u32::from_le_bytes([path.data[4], path.data[5], path.data[6], path.data[7]]);
-                format!("ACPI(HID=0x{:08X},UID=0x{:08X})", hid, uid)
-            } else {
-                "ACPI(Invalid)".to_string()
-            }
-        }
-        _ => format!("ACPI(SubType={}, {} bytes)", path.sub_type, path.data.len()),
-    }
-}
-
-/// Format messaging device path
-fn format_messaging_device_path(path: &DevicePath) -> String {
-    format!("Messaging(SubType={}, {} bytes)", path.sub_type, path.data.len())
-}
-
-/// Format media device path
-fn format_media_device_path(path: &DevicePath) -> String {
-    match path.sub_type {
-        DEVICE_PATH_SUBTYPE_HARD_DRIVE => {
-            if path.data.len() >= 42 {
-                let partition_number = u32::from_le_bytes([path.data[0], path.data[1], path.data[2], path.data[3]]);
-                let partition_start = u64::from_le_bytes([
-                    path.data[4], path.data[5], path.data[6], path.data[7],
-                    path.data[8], path.data[9], path.data[10], path.data[11]
-                ]);
-                let partition_size = u64::from_le_bytes([
-                    path.data[12], path.data[13], path.data[14], path.data[15],
-                    path.data[16], path.data[17], path.data[18], path.data[19]
-                ]);
-                let signature_type = path.data[41];
-                let signature_type_str = match signature_type {
-                    0x00 => "None",
-                    0x01 => "MBR",
-                    0x02 => "GPT",
-                    _ => "Unknown",
-                };
-                format!("HD(Part={},Sig={},Start=0x{:X},Size=0x{:X})",
-                       partition_number, signature_type_str, partition_start, partition_size)
-            } else {
-                "HD(Invalid)".to_string()
-            }
-        }
-        DEVICE_PATH_SUBTYPE_FILE_PATH => {
-            if !path.data.is_empty() {
-                // File path is UTF-16 encoded
-                let utf16_chars: Vec<u16> = path.data.chunks(2)
-                    .filter_map(|chunk| {
-                        if chunk.len() == 2 {
-                            Some(u16::from_le_bytes([chunk[0], chunk[1]]))
-                        } else {
-                            None
-                        }
-                    })
-                    .take_while(|&c| c != 0) // Stop at null terminator
-                    .collect();
-
-                match String::from_utf16(&utf16_chars) {
-                    Ok(file_path) => format!("File({})", file_path),
-                    Err(_) => format!("File([Invalid UTF-16, {} bytes])", path.data.len()),
-                }
-            } else {
-                "File(Empty)".to_string()
-            }
-        }
-        _ => format!("Media(SubType={}, {} bytes)", path.sub_type, path.data.len()),
-    }
-}
-
-/// Format BIOS boot device path
-fn format_bios_boot_device_path(path: &DevicePath) -> String {
-    format!("BIOS(SubType={}, {} bytes)", path.sub_type, path.data.len())
-}
-
-/// Format device path list as human-readable string
-fn format_device_path_list(data: &[u8]) -> String {
-    match parse_device_paths(data) {
-        Ok(paths) => {
-            if paths.is_empty() {
-                "[Empty]".to_string()
-            } else {
-                paths.iter()
-                    .map(format_device_path)
-                    .collect::<Vec<_>>()
-                    .join("/")
-            }
-        }
-        Err(_) => "[Parse Error]".to_string(),
-    }
-}
-
 /// Parse BootOrder variable (array of UINT16 values in little-endian)
 fn parse_boot_order(data: &[u8]) -> Result<Vec<u16>> {
     if data.len() % 2 != 0 {
@@ -523,92 +367,6 @@ fn parse_boot_order(data: &[u8]) -> Result<Vec<u16>> {
     Ok(boot_order)
 }
 
-/// Parse Boot#### variable (EFI_LOAD_OPTION structure)
-fn parse_boot_option(data: &[u8]) -> Result<EfiLoadOption> {
-    if data.len() < 6 {
-        return Err(anyhow!("Boot option data too short (need at least 6 bytes, got {})", data.len()));
-    }
-
-    // Parse fixed header (6 bytes)
-    let attributes = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
-    let file_path_list_length = u16::from_le_bytes([data[4], data[5]]);
-
-    let mut offset = 6;
-
-    // Parse description (UTF-16 null-terminated string)
-    let mut description_bytes = Vec::new();
-    while offset + 1 < data.len() {
-        let char_bytes = [data[offset], data[offset + 1]];
-        let char_value = u16::from_le_bytes(char_bytes);
-
-        if char_value == 0 {
-            offset += 2; // Skip null terminator
-            break;
-        }
-
-        description_bytes.push(char_value);
-        offset += 2;
-    }
-
-    // Convert UTF-16 to UTF-8
-    let description = String::from_utf16(&description_bytes)
-        .unwrap_or_else(|_| format!("[Invalid UTF-16: {} chars]", description_bytes.len()));
-
-    // Parse file path list
-    let file_path_end = offset + file_path_list_length as usize;
-    let file_path_list = if file_path_end <= data.len() {
-        data[offset..file_path_end].to_vec()
-    } else {
-        return Err(anyhow!("File path list extends beyond data (need {} bytes, have {})",
-                          file_path_end, data.len()));
-    };
-
-    offset = file_path_end;
-
-    // Parse optional data (remaining bytes)
-    let optional_data = if offset < data.len() {
-        data[offset..].to_vec()
-    } else {
-        Vec::new()
-    };
-
-    Ok(EfiLoadOption {
-        attributes,
-        file_path_list_length,
-        description,
-        file_path_list,
-        optional_data,
-    })
-}
-
-/// Format load option attributes as human-readable string
-fn format_load_option_attributes(attributes: u32) -> String {
-    let mut parts = Vec::new();
-
-    if attributes & LOAD_OPTION_ACTIVE != 0 {
-        parts.push("ACTIVE".to_string());
-    }
-    if attributes & LOAD_OPTION_FORCE_RECONNECT != 0 {
-        parts.push("FORCE_RECONNECT".to_string());
-    }
-    if attributes & LOAD_OPTION_HIDDEN != 0 {
-        parts.push("HIDDEN".to_string());
-    }
-
-    let category = (attributes & LOAD_OPTION_CATEGORY) >> 8;
-    if category != 0 {
-        parts.push(format!("CATEGORY_{}", category));
-    }
-
-    let result = if parts.is_empty() {
-        "NONE".to_string()
-    } else {
-        parts.join(" | ")
-    };
-
-    format!("{} (0x{:08x})", result, attributes)
-}
-
 /// Write boot variables with pretty printing and hex dumps
 fn write_boot_variables(output: &mut Vec<u8>, paths: &PathStorage, direct_boot: bool) -> Result<()> {
     writeln!(output, "=== Boot Variables ===").unwrap();
@@ -616,17 +374,31 @@ fn write_boot_variables(output: &mut Vec<u8>, paths: &PathStorage, direct_boot:
     writeln!(output, "Reference: UEFI Specification 2.10+ Chapter 3: Boot Manager").unwrap();
     writeln!(output).unwrap();
 
-    // Parse and display BootOrder
-    let boot_entries = write_boot_order_analysis(output, &paths.boot_order.as_deref().unwrap_or(""), direct_boot)?;
-
-    // Parse and display Boot#### variables
+    // Boot variables are synthesized from the boot options, and for direct boot
+    let boot_options = match &paths.boot_options {
+        Some(options) => Some(options.clone()),
+        None if direct_boot => Some(BootOptions::direct_boot()),
+        None => None,
+    };
 
-    if direct_boot {
-        // Boot0000 data for direct boot mode
-        let boot0000_hex = "090100002c0055006900410070007000000004071400c9bdb87cebf8344faaea3ee4af6516a10406140021aa2c4614760345836e8ab6f46623317fff0400";
-        let boot0000 = hex::decode(boot0000_hex).context("Failed to decode boot0000 hex string")?;
-        write_boot_option_analysis(output, "Boot0000", &boot0000)?;
+    if let Some(options) = boot_options {
+        let boot_variables = tdx_measure::build_boot_variables(&options)?;
+        let boot_entries = write_boot_order_analysis(output, &boot_variables.boot_order)?;
+        for (boot_entry, data) in boot_entries.iter().zip(boot_variables.entries) {
+            let name = format!("Boot{:04X}", boot_entry);
+            match data {
+                Some(data) => write_boot_option_analysis(output, &name, &data)?,
+                None => writeln!(output, "{}: no such boot option", name).unwrap(),
+            }
+        }
     } else {
+        // Parse and display BootOrder
+        let boot_order_path = paths.boot_order.as_deref().unwrap_or("");
+        let data = fs::read(boot_order_path)
+            .with_context(|| format!("Failed to read BootOrder from {}", boot_order_path))?;
+        let boot_entries = write_boot_order_analysis(output, &data)?;
+
+        // Parse and display Boot#### variables
         for boot_entry in boot_entries {
             let name = format!("Boot{:04X}", boot_entry);
             let path = format!("{}/{}.bin", paths.path_boot_xxxx.as_deref().unwrap_or(""), name);
@@ -640,18 +412,11 @@ fn write_boot_variables(output: &mut Vec<u8>, paths: &PathStorage, direct_boot:
 }
 
 /// Write BootOrder analysis with pretty printing
-fn write_boot_order_analysis(output: &mut Vec<u8>, boot_order_path: &str, direct_boot: bool) -> Result<Vec<u16>> {
+fn write_boot_order_analysis(output: &mut Vec<u8>, data: &[u8]) -> Result<Vec<u16>> {
     writeln!(output, "--- BootOrder Analysis ---").unwrap();
 
-    let data = if direct_boot {
-        vec![0, 0]
-    } else {
-        fs::read(boot_order_path)
-            .with_context(|| format!("Failed to read BootOrder from {}", boot_order_path))?
-    };
-
     // Parse BootOrder first
-    let boot_order = match parse_boot_order(&data) {
+    let boot_order = match parse_boot_order(data) {
         Ok(boot_order) => {
             writeln!(output, "BootOrder contains {} entries:", boot_order.len()).unwrap();
             for (i, boot_num) in boot_order.iter().enumerate() {
@@ -670,11 +435,7 @@ fn write_boot_order_analysis(output: &mut Vec<u8>, boot_order_path: &str, direct
     writeln!(output).unwrap();
 
     // Also include hex dump
-    if direct_boot {
-        write_raw_hex_dump(output, &data, "BootOrder (hex dump)")?;
-    } else {
-        write_hex_dump(output, boot_order_path, "BootOrder (hex dump)")?;
-    }
+    write_raw_hex_dump(output, data, "BootOrder (hex dump)")?;
 
     Ok(boot_order)
 }
@@ -690,17 +451,13 @@ fn write_boot_option_analysis(output: &mut Vec<u8>, name: &str, data: &[u8]) ->
     }
 
     // Pretty print Boot#### variable
-    match parse_boot_option(&data) {
+    match EfiLoadOption::parse(data) {
         Ok(boot_option) => {
             writeln!(output, "EFI_LOAD_OPTION structure:").unwrap();
-            writeln!(output, "  Attributes: {}", format_load_option_attributes(boot_option.attributes)).unwrap();
-            writeln!(output, "  FilePathListLength: {} bytes", boot_option.file_path_list_length).unwrap();
+            writeln!(output, "  Attributes: {} (0x{:08x})", boot_option.attributes_string(), boot_option.attributes).unwrap();
             writeln!(output, "  Description: \"{}\"", boot_option.description).unwrap();
-            writeln!(output, "  FilePathList: {} bytes", boot_option.file_path_list.len()).unwrap();
-
-            if !boot_option.file_path_list.is_empty() {
-                let device_path_str = format_device_path_list(&boot_option.file_path_list);
-                writeln!(output, "    Device Path: {}", device_path_str).unwrap();
+            for path in &boot_option.file_path_list {
+                writeln!(output, "  Device Path: {}", path).unwrap();
             }
 
             if !boot_option.optional_data.is_empty() {
@@ -710,8 +467,8 @@ fn write_boot_option_analysis(output: &mut Vec<u8>, name: &str, data: &[u8]) ->
             }
 
             // Boot status summary
-            let active = boot_option.attributes & LOAD_OPTION_ACTIVE != 0;
-            let hidden = boot_option.attributes & LOAD_OPTION_HIDDEN != 0;
+            let active = boot_option.attributes & EfiLoadOption::ACTIVE != 0;
+            let hidden = boot_option.attributes & EfiLoadOption::HIDDEN != 0;
             writeln!(output, "  Status: {} {}",
                     if active { "ACTIVE" } else { "INACTIVE" },
                     if hidden { "(HIDDEN)" } else { "(VISIBLE)" }).unwrap();
@@ -42,7 +42,7 @@ log_error() {
 # Display usage information
 usage() {
     cat <<EOF
-Usage: ${SCRIPT_NAME} [OPTIONS]
+Usage: ${SCRIPT_NAME} [OPTIONS] [-- <QEMU PLATFORM OPTIONS>]
 
 Generate ACPI tables using QEMU in a Docker container for TDX measurements.
 
@@ -52,8 +52,12 @@ OPTIONS:
                 Supported distributions: ubuntu:25.04
     -h          Display this help message
 
+QEMU PLATFORM OPTIONS replace the -smp and -m options derived from the
+metadata, e.g. to add NUMA nodes or memory hotplug slots.
+
 EXAMPLES:
     ./${SCRIPT_NAME} -j metadata.json -d ubuntu:25.04
+    ./${SCRIPT_NAME} -j metadata.json -d ubuntu:25.04 -- -smp 256,sockets=2 -m 8G
 EOF
 }
 
@@ -119,6 +123,8 @@ process_args() {
                 ;;
         esac
     done
+    shift $((OPTIND - 1))
+    PLATFORM_ARGS=("$@")
 
     validate_args
 }
@@ -153,12 +159,16 @@ parse_metadata() {
 
     # Read settings from metadata JSON file
     local has_errors=false
-    extract_and_validate CPUS             '.boot_config.cpus'          || has_errors=true
-    extract_and_validate MEMORY           '.boot_config.memory'        || has_errors=true
+    if [[ ${#PLATFORM_ARGS[@]} -eq 0 ]]; then
+        extract_and_validate CPUS         '.boot_config.cpus'          || has_errors=true
+        extract_and_validate MEMORY       '.boot_config.memory'        || has_errors=true
+        PLATFORM_ARGS=("-smp" "$CPUS" "-m" "$MEMORY")
+    fi
     extract_and_validate BIOS             '.boot_config.bios'          || has_errors=true
     extract_and_validate ACPI_TABLES_PATH '.boot_config.acpi_tables'   || has_errors=true
     extract_and_validate KERNEL           '.direct.kernel'             || has_errors=true
-    extract_and_validate INITRD           '.direct.initrd'             || has_errors=true
+    # The initrd is optional and, when several are given, the first one is enough to boot
+    INITRD=$(jq -r '.direct.initrd // empty | if type == "array" then (.[0] // empty) else . end' "$METADATA_JSON_PATH")
     extract_and_validate CMDLINE          '.direct.cmdline'            || has_errors=true
 
     if [[ "$has_errors" == "true" ]]; then
@@ -170,7 +180,7 @@ parse_metadata() {
     [[ "$BIOS" != /* ]] && BIOS="$metadata_dir/$BIOS"
     [[ "$ACPI_TABLES_PATH" != /* ]] && ACPI_TABLES_PATH="$metadata_dir/$ACPI_TABLES_PATH"
     [[ "$KERNEL" != /* ]] && KERNEL="$metadata_dir/$KERNEL"
-    [[ "$INITRD" != /* ]] && INITRD="$metadata_dir/$INITRD"
+    [[ -n "$INITRD" && "$INITRD" != /* ]] && INITRD="$metadata_dir/$INITRD"
 
     # Check if target directory for ACPI tables exists
     if [[ ! -d "$(dirname "$ACPI_TABLES_PATH")" ]]; then
@@ -181,11 +191,11 @@ parse_metadata() {
     ACPI_TABLES_PATH="$(realpath "$ACPI_TABLES_PATH")"
     BIOS="$(realpath "$BIOS")"
     KERNEL="$(realpath "$KERNEL")"
-    INITRD="$(realpath "$INITRD")"
+    [[ -n "$INITRD" ]] && INITRD="$(realpath "$INITRD")"
 
     # Validate that provided BIOS, kernel, and initrd files exist
     local file_path
-    for file_path in "$BIOS" "$KERNEL" "$INITRD"; do
+    for file_path in "$BIOS" "$KERNEL" ${INITRD:+"$INITRD"}; do
         if [[ ! -f "$file_path" ]]; then
             log_error "File not found: $file_path"
             exit 1
@@ -193,7 +203,7 @@ parse_metadata() {
     done
 
     log_success "Metadata parsed successfully"
-    log_info "Configuration: CPUs=$CPUS, Memory=$MEMORY, BIOS=$BIOS, ACPI Tables Target Path=$ACPI_TABLES_PATH, Kernel=$KERNEL, Initrd=$INITRD, Cmdline=$CMDLINE"
+    log_info "Configuration: Platform=${PLATFORM_ARGS[*]}, BIOS=$BIOS, ACPI Tables Target Path=$ACPI_TABLES_PATH, Kernel=$KERNEL, Initrd=$INITRD, Cmdline=$CMDLINE"
 }
 
 # Build Docker image
@@ -230,11 +240,10 @@ generate_acpi_tables() {
     local qemu_args=(
         "-accel" "kvm"
         "-cpu" "host"
-        "-smp" "$CPUS"
-        "-m" "$MEMORY"
+        "${PLATFORM_ARGS[@]}"
         "-bios" "/usr/share/ovmf/OVMF.fd"
         "-kernel" "/vmlinuz"
-        "-initrd" "/initrd.img"
+        ${INITRD:+"-initrd" "/initrd.img"}
         "-nographic"
         "-nodefaults"
         "-serial" "stdio"
@@ -248,7 +257,7 @@ generate_acpi_tables() {
         --name "$CONTAINER_NAME" \
         --device /dev/kvm:/dev/kvm \
         -v "$BIOS:/usr/share/ovmf/OVMF.fd" \
-        -v "$INITRD:/initrd.img" \
+        ${INITRD:+-v "$INITRD:/initrd.img"} \
         -v "$KERNEL:/vmlinuz" \
         -v "$(dirname "$ACPI_TABLES_PATH"):/output" \
         "$IMAGE_NAME" \
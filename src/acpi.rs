@@ -6,13 +6,23 @@
  */
 //! This module provides functionality to load ACPI tables for QEMU from files.
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 
 use crate::util::read_file_data;
 use crate::Machine;
 
 const LDR_LENGTH: usize = 4096;
 const FIXED_STRING_LEN: usize = 56;
+const ACPI_TABLE_HEADER_LEN: usize = 36;
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+
+/// Pointer fields of the FADT QEMU patches: FIRMWARE_CTRL, DSDT and X_DSDT
+const FADT_POINTERS: [(usize, usize, &[u8]); 3] = [(36, 4, b"FACS"), (40, 4, b"DSDT"), (140, 8, b"DSDT")];
+
+/// Tables QEMU links to other fw_cfg files, e.g. the TPM event log or the
+/// hardware error status blocks, which cannot be derived from the tables
+const EXTERNAL_POINTER_TABLES: &[&[u8]] = &[b"TCPA", b"TPM2", b"HEST", b"BERT"];
 
 pub struct Tables {
     pub tables: Vec<u8>,
@@ -23,136 +33,18 @@ pub struct Tables {
 impl Machine<'_> {
     pub fn build_tables(&self) -> Result<Tables> {
         let tables  = read_file_data(self.acpi_tables)?;
+        let table_list = parse_tables(&tables)?;
 
         let rsdp: Vec<u8> = if !self.rsdp.is_empty() {
             read_file_data(self.rsdp)?
         } else {
-            let (rsdt_offset, _rsdt_csum, _rsdt_len) = find_acpi_table(&tables , "RSDT")?;
-
-            // Generate RSDP
-            let mut rsdp = Vec::with_capacity(20);
-            rsdp.extend_from_slice(b"RSD PTR "); // Signature
-            rsdp.push(0x00); // Checksum placeholder
-            rsdp.extend_from_slice(b"BOCHS "); // OEM ID
-            rsdp.push(0x00); // Revision
-            rsdp.extend_from_slice(&rsdt_offset.to_le_bytes()); // RSDT Address
-            rsdp
+            build_rsdp(&table_list)?
         };
 
         let loader: Vec<u8> = if !self.table_loader.is_empty() {
             read_file_data(self.table_loader)?
         } else {
-            let (dsdt_offset, dsdt_csum, dsdt_len) = find_acpi_table(&tables , "DSDT")?;
-            let (facp_offset, facp_csum, facp_len) = find_acpi_table(&tables , "FACP")?;
-            let (apic_offset, apic_csum, apic_len) = find_acpi_table(&tables , "APIC")?;
-            let (mcfg_offset, mcfg_csum, mcfg_len) = find_acpi_table(&tables , "MCFG")?;
-            let (waet_offset, waet_csum, waet_len) = find_acpi_table(&tables , "WAET")?;
-            let (rsdt_offset, rsdt_csum, rsdt_len) = find_acpi_table(&tables , "RSDT")?;
-            let mut loader: TableLoader = TableLoader::new();
-            loader.append(LoaderCmd::Allocate {
-                file: "etc/acpi/rsdp",
-                alignment: 16,
-                zone: 2,
-            });
-            loader.append(LoaderCmd::Allocate {
-                file: "etc/acpi/tables",
-                alignment: 64,
-                zone: 1,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/tables",
-                result_offset: dsdt_csum,
-                start: dsdt_offset,
-                length: dsdt_len,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: facp_offset + 36,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: facp_offset + 40,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: facp_offset + 140,
-                pointer_size: 8,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/tables",
-                result_offset: facp_csum,
-                start: facp_offset,
-                length: facp_len,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/tables",
-                result_offset: apic_csum,
-                start: apic_offset,
-                length: apic_len,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/tables",
-                result_offset: mcfg_csum,
-                start: mcfg_offset,
-                length: mcfg_len,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/tables",
-                result_offset: waet_csum,
-                start: waet_offset,
-                length: waet_len,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: rsdt_offset + 36,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: rsdt_offset + 40,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: rsdt_offset + 44,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/tables",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: rsdt_offset + 48,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/tables",
-                result_offset: rsdt_csum,
-                start: rsdt_offset,
-                length: rsdt_len,
-            });
-            loader.append(LoaderCmd::AddPtr {
-                pointer_file: "etc/acpi/rsdp",
-                pointee_file: "etc/acpi/tables",
-                pointer_offset: 16,
-                pointer_size: 4,
-            });
-            loader.append(LoaderCmd::AddChecksum {
-                file: "etc/acpi/rsdp",
-                result_offset: 8,
-                start: 0,
-                length: 20,
-            });
-            if loader.buffer.len() < LDR_LENGTH {
-                loader.buffer.resize(LDR_LENGTH, 0);
-            }
-            loader.buffer
+            build_table_loader(&table_list, &rsdp)?
         };
 
         Ok(Tables {
@@ -163,6 +55,194 @@ impl Machine<'_> {
     }
 }
 
+/// An ACPI table of `etc/acpi/tables`, with its offset in the file.
+struct AcpiTable<'a> {
+    offset: u32,
+    data: &'a [u8],
+}
+
+impl AcpiTable<'_> {
+    fn signature(&self) -> &[u8] {
+        &self.data[..4]
+    }
+
+    /// Reads a little-endian field of `size` bytes, `None` beyond the table.
+    fn field(&self, offset: usize, size: usize) -> Option<u64> {
+        let bytes = self.data.get(offset..offset + size)?;
+        Some(bytes.iter().rev().fold(0, |value, &b| (value << 8) | b as u64))
+    }
+
+    fn name(&self) -> String {
+        String::from_utf8_lossy(self.signature()).into_owned()
+    }
+}
+
+/// Walks the tables of `etc/acpi/tables`, up to the zero padding QEMU appends.
+fn parse_tables(tables: &[u8]) -> Result<Vec<AcpiTable<'_>>> {
+    let mut result = Vec::new();
+    let mut offset = 0;
+    while offset < tables.len() {
+        if tables[offset..].iter().all(|&b| b == 0) {
+            break;
+        }
+        if offset + ACPI_TABLE_HEADER_LEN > tables.len() {
+            bail!("Truncated ACPI table header at offset {offset}");
+        }
+        let tbl_len = u32::from_le_bytes(tables[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if tbl_len < 8 || offset + tbl_len > tables.len() {
+            bail!("Invalid ACPI table length {tbl_len} at offset {offset}");
+        }
+        result.push(AcpiTable {
+            offset: offset as u32,
+            data: &tables[offset..offset + tbl_len],
+        });
+        offset += tbl_len;
+    }
+    Ok(result)
+}
+
+fn find_table<'t, 'a>(tables: &'t [AcpiTable<'a>], signature: &[u8]) -> Option<&'t AcpiTable<'a>> {
+    tables.iter().find(|t| t.signature() == signature)
+}
+
+/// Builds the RSDP as QEMU does: revision 2 pointing to the XSDT when there is
+/// one, revision 0 pointing to the RSDT otherwise.
+fn build_rsdp(tables: &[AcpiTable]) -> Result<Vec<u8>> {
+    let rsdt = find_table(tables, b"RSDT");
+    let xsdt = find_table(tables, b"XSDT");
+    let root = xsdt.or(rsdt).ok_or_else(|| anyhow!("Table not found: RSDT or XSDT"))?;
+    if root.data.len() < ACPI_TABLE_HEADER_LEN {
+        bail!("Truncated {} table", root.name());
+    }
+
+    let mut rsdp = Vec::with_capacity(RSDP_V2_LEN);
+    rsdp.extend_from_slice(b"RSD PTR "); // Signature
+    rsdp.push(0x00); // Checksum placeholder
+    rsdp.extend_from_slice(&root.data[10..16]); // OEM ID, the one of the tables
+    rsdp.push(if xsdt.is_some() { 2 } else { 0 }); // Revision
+    rsdp.extend_from_slice(&rsdt.map_or(0, |t| t.offset).to_le_bytes()); // RSDT Address
+    if let Some(xsdt) = xsdt {
+        rsdp.extend_from_slice(&(RSDP_V2_LEN as u32).to_le_bytes()); // Length
+        rsdp.extend_from_slice(&(xsdt.offset as u64).to_le_bytes()); // XSDT Address
+        rsdp.push(0x00); // Extended checksum placeholder
+        rsdp.extend_from_slice(&[0u8; 3]); // Reserved
+    }
+    Ok(rsdp)
+}
+
+/// Builds the loader commands QEMU's `bios_linker_loader` emits for the
+/// tables: in table order, the pointers of the FADT, RSDT and XSDT followed by
+/// the checksum of each table but the FACS, then the pointers and checksums of
+/// the RSDP.
+fn build_table_loader(tables: &[AcpiTable], rsdp: &[u8]) -> Result<Vec<u8>> {
+    let mut loader: TableLoader = TableLoader::new();
+    loader.append(LoaderCmd::Allocate {
+        file: "etc/acpi/rsdp",
+        alignment: 16,
+        zone: 2,
+    });
+    loader.append(LoaderCmd::Allocate {
+        file: "etc/acpi/tables",
+        alignment: 64,
+        zone: 1,
+    });
+
+    let add_table_ptr = |loader: &mut TableLoader, pointer_offset: u32, pointer_size: u8| {
+        loader.append(LoaderCmd::AddPtr {
+            pointer_file: "etc/acpi/tables",
+            pointee_file: "etc/acpi/tables",
+            pointer_offset,
+            pointer_size,
+        });
+    };
+
+    for table in tables {
+        if EXTERNAL_POINTER_TABLES.contains(&table.signature()) {
+            bail!("{} table points into other fw_cfg files, a table_loader file is required", table.name());
+        }
+        match table.signature() {
+            // Firmware controlled, without checksum
+            b"FACS" => continue,
+            b"FACP" => {
+                for (field, size, pointee) in FADT_POINTERS {
+                    let Some(pointee) = find_table(tables, pointee) else {
+                        continue;
+                    };
+                    // QEMU leaves the fields of absent tables zero
+                    if table.field(field, size) == Some(pointee.offset as u64) {
+                        add_table_ptr(&mut loader, table.offset + field as u32, size as u8);
+                    }
+                }
+            }
+            b"RSDT" | b"XSDT" => {
+                let size = if table.signature() == b"RSDT" { 4 } else { 8 };
+                for field in (ACPI_TABLE_HEADER_LEN..table.data.len()).step_by(size) {
+                    let value = table.field(field, size).ok_or_else(|| anyhow!("Truncated {} entry", table.name()))?;
+                    if !tables.iter().any(|t| t.offset as u64 == value) {
+                        bail!("{} entry at offset {field} does not point to a table", table.name());
+                    }
+                    add_table_ptr(&mut loader, table.offset + field as u32, size as u8);
+                }
+            }
+            _ => {}
+        }
+        loader.append(LoaderCmd::AddChecksum {
+            file: "etc/acpi/tables",
+            result_offset: table.offset + 9,
+            start: table.offset,
+            length: table.data.len() as u32,
+        });
+    }
+
+    // RSDP, revision 0 with an RSDT or revision 2 with an XSDT
+    let revision = *rsdp.get(15).ok_or_else(|| anyhow!("Truncated RSDP"))?;
+    let rsdp_field = |offset: usize, size: usize| {
+        rsdp.get(offset..offset + size)
+            .map(|bytes| bytes.iter().rev().fold(0u64, |value, &b| (value << 8) | b as u64))
+            .ok_or_else(|| anyhow!("Truncated RSDP"))
+    };
+    let rsdt = find_table(tables, b"RSDT");
+    if rsdt.is_some_and(|t| rsdp_field(16, 4).ok() == Some(t.offset as u64)) {
+        loader.append(LoaderCmd::AddPtr {
+            pointer_file: "etc/acpi/rsdp",
+            pointee_file: "etc/acpi/tables",
+            pointer_offset: 16,
+            pointer_size: 4,
+        });
+    } else if revision < 2 {
+        bail!("RSDP does not point to the RSDT");
+    }
+    loader.append(LoaderCmd::AddChecksum {
+        file: "etc/acpi/rsdp",
+        result_offset: 8,
+        start: 0,
+        length: RSDP_V1_LEN as u32,
+    });
+    if revision >= 2 {
+        let xsdt = find_table(tables, b"XSDT").ok_or_else(|| anyhow!("Table not found: XSDT"))?;
+        if rsdp_field(24, 8)? != xsdt.offset as u64 {
+            bail!("RSDP does not point to the XSDT");
+        }
+        loader.append(LoaderCmd::AddPtr {
+            pointer_file: "etc/acpi/rsdp",
+            pointee_file: "etc/acpi/tables",
+            pointer_offset: 24,
+            pointer_size: 8,
+        });
+        loader.append(LoaderCmd::AddChecksum {
+            file: "etc/acpi/rsdp",
+            result_offset: 32,
+            start: 0,
+            length: RSDP_V2_LEN as u32,
+        });
+    }
+
+    // QEMU pads the commands to a multiple of the page size
+    let len = loader.buffer.len().next_multiple_of(LDR_LENGTH);
+    loader.buffer.resize(len, 0);
+    Ok(loader.buffer)
+}
+
 /// An enum to represent the different QEMU loader commands in a type-safe way.
 #[derive(Debug)]
 enum LoaderCmd<'a> {
@@ -248,38 +328,20 @@ impl TableLoader {
     }
 }
 
-/// Searches for an ACPI table with the given signature and returns its offset,
-/// checksum offset, and length.
-fn find_acpi_table(tables: &[u8], signature: &str) -> Result<(u32, u32, u32)> {
-    if signature.len() != 4 {
-        bail!("Signature must be 4 characters long, but got '{signature}'");
-    }
-
-    let sig_bytes = signature.as_bytes();
-
+/// Splits concatenated ACPI tables using the length of each table header.
+pub(crate) fn split_acpi_tables(tables: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut result = Vec::new();
     let mut offset = 0;
     while offset < tables.len() {
-        // Ensure there's enough space for a table header
         if offset + 8 > tables.len() {
-            bail!("Table not found: {signature}");
-        }
-
-        let tbl_sig = &tables[offset..offset + 4];
-        let tbl_len_bytes: [u8; 4] = tables[offset + 4..offset + 8].try_into().unwrap();
-        let tbl_len = u32::from_le_bytes(tbl_len_bytes) as usize;
-
-        if tbl_sig == sig_bytes {
-            // Found the table
-            return Ok((offset as u32, (offset + 9) as u32, tbl_len as u32));
+            bail!("Truncated ACPI table header at offset {offset}");
         }
-
-        if tbl_len == 0 {
-            // Invalid table length, stop searching
-            bail!("Found table with zero length at offset {offset}");
+        let tbl_len = u32::from_le_bytes(tables[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if tbl_len < 8 || offset + tbl_len > tables.len() {
+            bail!("Invalid ACPI table length {tbl_len} at offset {offset}");
         }
-        // Move to the next table
+        result.push(&tables[offset..offset + tbl_len]);
         offset += tbl_len;
     }
-
-    bail!("Table not found: {signature}");
+    Ok(result)
 }
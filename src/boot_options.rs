@@ -0,0 +1,340 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Synthesis of the Boot#### and BootOrder variables OVMF creates while
+//! enumerating boot devices, so that RTMR[0] can be computed without dumping
+//! them from a running TD.
+
+use anyhow::{bail, Context, Result};
+
+use crate::device_path::{parse_mac, DevicePath, DevicePathNode, PartitionSignature};
+use crate::num::read_le;
+use crate::tdvf::encode_guid;
+use crate::util::utf16_encode;
+use crate::{BootOption, BootOptions, DiskBus, PciNode};
+
+/// Volume holding the DXE drivers and applications of OVMF
+const OVMF_DXE_FV_GUID: &str = "7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1";
+/// File of the UiApp boot manager menu
+const UI_APP_FILE_GUID: &str = "462caa21-7614-4503-836e-8ab6f4662331";
+/// File of the UEFI shell
+const SHELL_FILE_GUID: &str = "7c04a583-9e3e-4f1c-ad65-e05268d0b4d1";
+/// Optional data tagging the options the boot manager creates for devices
+const AUTO_CREATED_BOOT_OPTION_GUID: &str = "8108ac4e-9f11-4d59-850e-e21a522c59b2";
+
+/// BootOrder and the Boot#### variables it lists
+#[derive(Debug, Clone)]
+pub struct BootVariables {
+    pub boot_order: Vec<u8>,
+    /// Data of each entry of BootOrder, in order, `None` if the option does not exist
+    pub entries: Vec<Option<Vec<u8>>>,
+}
+
+/// An EFI_LOAD_OPTION, the data of a Boot#### variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EfiLoadOption {
+    pub attributes: u32,
+    pub description: String,
+    /// Device paths of the option; the first one is the device to boot
+    pub file_path_list: Vec<DevicePath>,
+    pub optional_data: Vec<u8>,
+}
+
+impl EfiLoadOption {
+    pub const ACTIVE: u32 = 0x00000001;
+    pub const FORCE_RECONNECT: u32 = 0x00000002;
+    pub const HIDDEN: u32 = 0x00000008;
+    pub const CATEGORY: u32 = 0x00001f00;
+    pub const CATEGORY_APP: u32 = 0x00000100;
+
+    /// Parses the data of a Boot#### variable.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let attributes = read_le::<u32>(data, 0, "load option attributes")?;
+        let file_path_list_length = read_le::<u16>(data, 4, "load option file path list length")? as usize;
+
+        // Description is a NUL-terminated UTF-16 string
+        let mut chars = Vec::new();
+        let mut offset = 6;
+        loop {
+            let c = read_le::<u16>(data, offset, "load option description")?;
+            offset += 2;
+            if c == 0 {
+                break;
+            }
+            chars.push(c);
+        }
+        let description = String::from_utf16_lossy(&chars);
+
+        let end = offset + file_path_list_length;
+        let mut list = data.get(offset..end).with_context(|| {
+            format!("File path list extends beyond data (need {} bytes, have {})", end, data.len())
+        })?;
+        let mut file_path_list = Vec::new();
+        while !list.is_empty() {
+            let (path, size) = DevicePath::parse(list)?;
+            file_path_list.push(path);
+            list = &list[size..];
+        }
+
+        Ok(EfiLoadOption {
+            attributes,
+            description,
+            file_path_list,
+            optional_data: data[end..].to_vec(),
+        })
+    }
+
+    /// Formats the attributes, e.g. `ACTIVE | HIDDEN | CATEGORY_1`.
+    pub fn attributes_string(&self) -> String {
+        let mut parts = Vec::new();
+        if self.attributes & Self::ACTIVE != 0 {
+            parts.push("ACTIVE".to_string());
+        }
+        if self.attributes & Self::FORCE_RECONNECT != 0 {
+            parts.push("FORCE_RECONNECT".to_string());
+        }
+        if self.attributes & Self::HIDDEN != 0 {
+            parts.push("HIDDEN".to_string());
+        }
+        let category = (self.attributes & Self::CATEGORY) >> 8;
+        if category != 0 {
+            parts.push(format!("CATEGORY_{}", category));
+        }
+        if parts.is_empty() {
+            "NONE".to_string()
+        } else {
+            parts.join(" | ")
+        }
+    }
+
+    /// Serializes the option into the data of a Boot#### variable.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut file_path_list = Vec::new();
+        for path in &self.file_path_list {
+            file_path_list.extend(path.to_bytes()?);
+        }
+        let file_path_list_length = u16::try_from(file_path_list.len()).context("File path list too long")?;
+
+        let mut data = self.attributes.to_le_bytes().to_vec();
+        data.extend_from_slice(&file_path_list_length.to_le_bytes());
+        data.extend(utf16_encode(&self.description));
+        data.extend_from_slice(&[0, 0]);
+        data.extend(file_path_list);
+        data.extend_from_slice(&self.optional_data);
+        Ok(data)
+    }
+}
+
+/// Device path of a PCI device on the root bridge, through bridges if any.
+fn pci_path(pci: &[PciNode]) -> Result<Vec<DevicePathNode>> {
+    if pci.is_empty() {
+        bail!("PCI address of boot device required");
+    }
+    let mut path = vec![DevicePathNode::pci_root(0)];
+    for node in pci {
+        path.push(DevicePathNode::Pci { device: node.device, function: node.function });
+    }
+    Ok(path)
+}
+
+/// Returns the load option of a boot option, and whether the boot manager
+/// created it while enumerating devices.
+fn load_option(option: &BootOption) -> Result<(EfiLoadOption, bool)> {
+    let auto_created = encode_guid(AUTO_CREATED_BOOT_OPTION_GUID)?;
+    let (attributes, description, nodes, optional_data, auto) = match option {
+        BootOption::UiApp => (
+            EfiLoadOption::CATEGORY_APP | EfiLoadOption::ACTIVE | EfiLoadOption::HIDDEN,
+            "UiApp".to_string(),
+            vec![DevicePathNode::Fv(OVMF_DXE_FV_GUID.to_string()), DevicePathNode::FvFile(UI_APP_FILE_GUID.to_string())],
+            Vec::new(),
+            false,
+        ),
+        BootOption::Shell => (
+            EfiLoadOption::ACTIVE,
+            "EFI Internal Shell".to_string(),
+            vec![DevicePathNode::Fv(OVMF_DXE_FV_GUID.to_string()), DevicePathNode::FvFile(SHELL_FILE_GUID.to_string())],
+            Vec::new(),
+            false,
+        ),
+        BootOption::Disk { pci, bus, target, lun, description } => {
+            let mut nodes = pci_path(pci)?;
+            let default_description = match bus {
+                DiskBus::VirtioBlk => "UEFI Misc Device",
+                DiskBus::VirtioScsi => {
+                    nodes.push(DevicePathNode::Scsi { target: *target, lun: *lun });
+                    "UEFI QEMU QEMU HARDDISK"
+                }
+            };
+            let description = description.clone().unwrap_or_else(|| default_description.to_string());
+            (EfiLoadOption::ACTIVE, description, nodes, auto_created, true)
+        }
+        BootOption::Pxe { pci, mac, ipv6, description } => {
+            let address = parse_mac(mac)?;
+            let mut nodes = pci_path(pci)?;
+            nodes.push(DevicePathNode::ethernet(&address));
+            nodes.push(if *ipv6 { DevicePathNode::ipv6_any() } else { DevicePathNode::ipv4_any() });
+            let version = if *ipv6 { 6 } else { 4 };
+            let description = description
+                .clone()
+                .unwrap_or_else(|| format!("UEFI PXEv{} (MAC:{})", version, hex::encode(address)));
+            (EfiLoadOption::ACTIVE, description, nodes, auto_created, true)
+        }
+        BootOption::File { description, partition, partition_guid, start_lba, size_lba, path, optional_data } => {
+            let nodes = vec![
+                DevicePathNode::HardDrive {
+                    partition: *partition,
+                    start_lba: *start_lba,
+                    size_lba: *size_lba,
+                    signature: PartitionSignature::Gpt(partition_guid.clone()),
+                    mbr_type: 0x02,
+                },
+                DevicePathNode::FilePath(path.clone()),
+            ];
+            let optional_data = match optional_data {
+                Some(data) => hex::decode(data).context("Invalid optional data")?,
+                None => Vec::new(),
+            };
+            (EfiLoadOption::ACTIVE, description.clone(), nodes, optional_data, false)
+        }
+    };
+    let option = EfiLoadOption {
+        attributes,
+        description,
+        file_path_list: vec![DevicePath::new(nodes)],
+        optional_data,
+    };
+    Ok((option, auto))
+}
+
+/// Builds the BootOrder data and the Boot#### variables it lists, in order, as
+/// the boot manager of OVMF creates them. Options are numbered in the order
+/// they are given; entries of BootOrder without an option are `None`.
+pub fn build_boot_variables(options: &BootOptions) -> Result<BootVariables> {
+    let mut variables = Vec::new();
+    let mut descriptions: Vec<String> = Vec::new();
+    for option in &options.options {
+        let (mut option, auto_created) = load_option(option)?;
+        // The boot manager appends " 2", " 3", ... to the descriptions it creates twice
+        let count = descriptions.iter().filter(|d| **d == option.description).count();
+        descriptions.push(option.description.clone());
+        if auto_created && count > 0 {
+            option.description = format!("{} {}", option.description, count + 1);
+        }
+        variables.push(option.to_bytes()?);
+    }
+
+    let order: Vec<u16> = match &options.order {
+        Some(order) => order.clone(),
+        None => (0..variables.len() as u16).collect(),
+    };
+    let boot_order = order.iter().flat_map(|n| n.to_le_bytes()).collect();
+    let entries = order.iter().map(|n| variables.get(*n as usize).cloned()).collect();
+    Ok(BootVariables { boot_order, entries })
+}
+
+impl BootOptions {
+    /// The only option of direct boot, the UiApp boot manager menu.
+    pub fn direct_boot() -> Self {
+        BootOptions { options: vec![BootOption::UiApp], order: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Boot0000 OVMF creates for direct boot, as dumped from a TD
+    const DIRECT_BOOT_BOOT0000: &str = "090100002c0055006900410070007000000004071400c9bdb87cebf8344faaea3ee4af6516a10406140021aa2c4614760345836e8ab6f46623317fff0400";
+
+    fn disk(device: u8) -> BootOption {
+        BootOption::Disk {
+            pci: vec![PciNode { device, function: 0 }],
+            bus: DiskBus::VirtioBlk,
+            target: 0,
+            lun: 0,
+            description: None,
+        }
+    }
+
+    fn description(data: &Option<Vec<u8>>) -> String {
+        EfiLoadOption::parse(data.as_ref().unwrap()).unwrap().description
+    }
+
+    #[test]
+    fn direct_boot_variables() {
+        let variables = build_boot_variables(&BootOptions::direct_boot()).unwrap();
+        assert_eq!(variables.boot_order, [0, 0]);
+        assert_eq!(variables.entries, [Some(hex::decode(DIRECT_BOOT_BOOT0000).unwrap())]);
+    }
+
+    #[test]
+    fn load_option_round_trip() {
+        let data = hex::decode(DIRECT_BOOT_BOOT0000).unwrap();
+        let option = EfiLoadOption::parse(&data).unwrap();
+        assert_eq!(option.description, "UiApp");
+        assert_eq!(option.attributes_string(), "ACTIVE | HIDDEN | CATEGORY_1");
+        assert_eq!(option.file_path_list.len(), 1);
+        assert!(option.optional_data.is_empty());
+        assert_eq!(option.to_bytes().unwrap(), data);
+
+        let options = BootOptions {
+            options: vec![
+                disk(3),
+                BootOption::Pxe {
+                    pci: vec![PciNode { device: 2, function: 0 }],
+                    mac: "52:54:00:12:34:56".to_string(),
+                    ipv6: true,
+                    description: None,
+                },
+                BootOption::File {
+                    description: "ubuntu".to_string(),
+                    partition: 15,
+                    partition_guid: "3c5a6dc4-5c8e-4b4b-9a83-9ab1e8b4f33e".to_string(),
+                    start_lba: 2048,
+                    size_lba: 217088,
+                    path: "\\EFI\\ubuntu\\shimx64.efi".to_string(),
+                    optional_data: Some("0102".to_string()),
+                },
+            ],
+            order: None,
+        };
+        for data in build_boot_variables(&options).unwrap().entries {
+            let data = data.unwrap();
+            let option = EfiLoadOption::parse(&data).unwrap();
+            assert_eq!(option.to_bytes().unwrap(), data);
+        }
+
+        let file = EfiLoadOption::parse(&load_option(&options.options[2]).unwrap().0.to_bytes().unwrap()).unwrap();
+        assert_eq!(file.optional_data, [1, 2]);
+        let pxe = load_option(&options.options[1]).unwrap().0;
+        assert_eq!(pxe.description, "UEFI PXEv6 (MAC:525400123456)");
+        assert_eq!(pxe.optional_data, encode_guid(AUTO_CREATED_BOOT_OPTION_GUID).unwrap());
+    }
+
+    #[test]
+    fn duplicate_descriptions() {
+        let file = |description: &str| BootOption::File {
+            description: description.to_string(),
+            partition: 1,
+            partition_guid: "3c5a6dc4-5c8e-4b4b-9a83-9ab1e8b4f33e".to_string(),
+            start_lba: 2048,
+            size_lba: 2048,
+            path: "\\EFI\\BOOT\\BOOTX64.EFI".to_string(),
+            optional_data: None,
+        };
+        let options = BootOptions {
+            options: vec![disk(3), disk(4), file("ubuntu"), disk(5), file("ubuntu")],
+            order: Some(vec![3, 1, 0, 4, 2, 7]),
+        };
+        let variables = build_boot_variables(&options).unwrap();
+        assert_eq!(variables.boot_order, [3, 0, 1, 0, 0, 0, 4, 0, 2, 0, 7, 0]);
+        let descriptions: Vec<_> = variables.entries[..5].iter().map(description).collect();
+        // Only the options the boot manager creates get a suffix
+        assert_eq!(
+            descriptions,
+            ["UEFI Misc Device 3", "UEFI Misc Device 2", "UEFI Misc Device", "ubuntu", "ubuntu"]
+        );
+        assert_eq!(variables.entries[5], None);
+    }
+}
@@ -0,0 +1,503 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Parser for the configuration firmware volume (CFV) of OVMF: the firmware
+//! volume header, the variable store header and the variables baked into it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use fs_err as fs;
+use std::io::Write;
+use std::path::Path;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+use serde_human_bytes as hex_bytes;
+
+use crate::num::read_le;
+use crate::tdvf::{encode_guid, Tdvf};
+use crate::{FirmwareCustomization, ImageConfig};
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const FV_SIGNATURE_OFFSET: usize = 40;
+const FV_HEADER_LENGTH_OFFSET: usize = 48;
+const FV_CHECKSUM_OFFSET: usize = 50;
+
+const AUTHENTICATED_VARIABLE_GUID: &str = "AAF32C78-947B-439A-A180-2E144EC37792";
+const VARIABLE_GUID: &str = "DDCF3616-3275-4164-98B6-FE85707FFE7D";
+const VARIABLE_STORE_HEADER_SIZE: usize = 28;
+const VARIABLE_STORE_FORMATTED: u8 = 0x5a;
+const VARIABLE_STORE_HEALTHY: u8 = 0xfe;
+
+const VARIABLE_START_ID: u16 = 0x55aa;
+const VARIABLE_HEADER_SIZE: usize = 32;
+const AUTHENTICATED_VARIABLE_HEADER_SIZE: usize = 60;
+const VAR_ADDED: u8 = 0x3f;
+const VAR_IN_DELETED_TRANSITION: u8 = 0xfe;
+
+pub(crate) const EFI_GLOBAL_VARIABLE_GUID: &str = "8BE4DF61-93CA-11D2-AA0D-00E098032B8C";
+pub(crate) const EFI_IMAGE_SECURITY_DATABASE_GUID: &str = "D719B2CB-3D3A-4596-A3BC-DAD00E67656F";
+/// OVMF variable that turns Secure Boot off when set to 0
+const SECURE_BOOT_ENABLE_GUID: &str = "F0A30BC7-AF08-4556-99C4-001009C93A44";
+
+const ATTRIBUTE_NV_BS_RT: u32 = 0x07;
+const ATTRIBUTE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS: u32 = 0x20;
+
+/// Variables OVMF measures as Secure Boot configuration in RTMR0.
+pub(crate) const SECURE_BOOT_VARIABLES: &[(&str, &str)] = &[
+    (EFI_GLOBAL_VARIABLE_GUID, "SecureBoot"),
+    (EFI_GLOBAL_VARIABLE_GUID, "PK"),
+    (EFI_GLOBAL_VARIABLE_GUID, "KEK"),
+    (EFI_IMAGE_SECURITY_DATABASE_GUID, "db"),
+    (EFI_IMAGE_SECURITY_DATABASE_GUID, "dbx"),
+];
+
+const ATTRIBUTE_NAMES: &[(u32, &str)] = &[
+    (0x01, "NV"),
+    (0x02, "BS"),
+    (0x04, "RT"),
+    (0x08, "HR"),
+    (0x10, "AW"),
+    (0x20, "AT"),
+    (0x40, "AP"),
+];
+
+/// Formats a GUID in its binary representation as a string.
+pub(crate) fn format_guid(guid: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{}-{}",
+        u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]),
+        u16::from_le_bytes([guid[4], guid[5]]),
+        u16::from_le_bytes([guid[6], guid[7]]),
+        hex::encode_upper(&guid[8..10]),
+        hex::encode_upper(&guid[10..16]),
+    )
+}
+
+/// A variable of the firmware variable store.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareVariable {
+    pub name: String,
+    pub vendor_guid: String,
+    pub attributes: u32,
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>,
+    /// Offset of the variable header in the CFV, 0 for a new variable
+    pub offset: usize,
+    /// MonotonicCount, TimeStamp and PubKeyIndex of an authenticated variable header
+    #[serde(skip)]
+    auth_fields: Vec<u8>,
+}
+
+impl FirmwareVariable {
+    /// Creates a variable to add to a variable store.
+    pub fn new(vendor_guid: &str, name: &str, attributes: u32, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_string(),
+            vendor_guid: vendor_guid.to_uppercase(),
+            attributes,
+            data,
+            offset: 0,
+            auth_fields: vec![],
+        }
+    }
+
+    /// Formats the attributes, e.g. `NV+BS+RT`.
+    pub fn attributes_string(&self) -> String {
+        let names: Vec<&str> = ATTRIBUTE_NAMES
+            .iter()
+            .filter(|(bit, _)| self.attributes & bit != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        names.join("+")
+    }
+
+    /// Whether the variable is part of the Secure Boot configuration OVMF measures in RTMR0.
+    pub fn is_secure_boot_variable(&self) -> bool {
+        SECURE_BOOT_VARIABLES
+            .iter()
+            .any(|(guid, name)| guid.eq_ignore_ascii_case(&self.vendor_guid) && *name == self.name)
+    }
+}
+
+/// The variable store of a CFV.
+#[derive(Debug, Clone, Serialize)]
+pub struct VariableStore {
+    /// Whether variables carry the authenticated variable header
+    pub authenticated: bool,
+    /// Offset of the variable store header in the CFV
+    pub offset: usize,
+    /// Size of the variable store, headers included
+    pub size: usize,
+    pub variables: Vec<FirmwareVariable>,
+}
+
+impl VariableStore {
+    /// Parses the firmware volume of a CFV and its variable store.
+    pub fn parse(cfv: &[u8]) -> Result<Self> {
+        if cfv.get(FV_SIGNATURE_OFFSET..FV_SIGNATURE_OFFSET + 4) != Some(FV_SIGNATURE) {
+            bail!("CFV has no firmware volume header");
+        }
+        let fv_length = read_le::<u64>(cfv, 32, "FV length")? as usize;
+        if fv_length > cfv.len() {
+            bail!("Firmware volume extends beyond the CFV");
+        }
+        let offset = read_le::<u16>(cfv, FV_HEADER_LENGTH_OFFSET, "FV header length")? as usize;
+
+        let header = cfv
+            .get(offset..offset + VARIABLE_STORE_HEADER_SIZE)
+            .ok_or_else(|| anyhow!("Truncated variable store header"))?;
+        let authenticated = match format_guid(&header[0..16]).as_str() {
+            AUTHENTICATED_VARIABLE_GUID => true,
+            VARIABLE_GUID => false,
+            guid => bail!("Unknown variable store format {guid}"),
+        };
+        let size = read_le::<u32>(header, 16, "variable store size")? as usize;
+        if header[20] != VARIABLE_STORE_FORMATTED {
+            bail!("Variable store is not formatted");
+        }
+        if offset + size > fv_length {
+            bail!("Variable store extends beyond the firmware volume");
+        }
+
+        let store = &cfv[..offset + size];
+        let header_size = if authenticated { AUTHENTICATED_VARIABLE_HEADER_SIZE } else { VARIABLE_HEADER_SIZE };
+        let mut variables = Vec::new();
+        let mut pos = offset + VARIABLE_STORE_HEADER_SIZE;
+        while pos + header_size <= store.len() {
+            if read_le::<u16>(store, pos, "variable start id")? != VARIABLE_START_ID {
+                break;
+            }
+            let state = store[pos + 2];
+            let attributes = read_le::<u32>(store, pos + 4, "variable attributes")?;
+            // NameSize, DataSize and VendorGuid end the header in both formats
+            let name_size = read_le::<u32>(store, pos + header_size - 24, "variable name size")? as usize;
+            let data_size = read_le::<u32>(store, pos + header_size - 20, "variable data size")? as usize;
+            let vendor_guid = format_guid(&store[pos + header_size - 16..pos + header_size]);
+
+            let name_start = pos + header_size;
+            let data_start = name_start + name_size;
+            let end = data_start + data_size;
+            if end > store.len() {
+                bail!("Variable at offset {pos:#x} extends beyond the variable store");
+            }
+
+            if state == VAR_ADDED || state == VAR_ADDED & VAR_IN_DELETED_TRANSITION {
+                let name: Vec<u16> = store[name_start..data_start]
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect();
+                variables.push(FirmwareVariable {
+                    name: String::from_utf16_lossy(&name),
+                    vendor_guid,
+                    attributes,
+                    data: store[data_start..end].to_vec(),
+                    offset: pos,
+                    auth_fields: store[pos + 8..pos + header_size - 24].to_vec(),
+                });
+            }
+            pos = end.next_multiple_of(4);
+        }
+
+        Ok(Self { authenticated, offset, size, variables })
+    }
+
+    /// Returns a variable by vendor GUID and name.
+    pub fn get(&self, vendor_guid: &str, name: &str) -> Option<&FirmwareVariable> {
+        self.variables
+            .iter()
+            .find(|v| v.vendor_guid.eq_ignore_ascii_case(vendor_guid) && v.name == name)
+    }
+
+    /// Adds a variable or replaces the one with the same vendor GUID and name.
+    pub fn set(&mut self, variable: FirmwareVariable) {
+        match self.variables.iter_mut().find(|v| {
+            v.vendor_guid.eq_ignore_ascii_case(&variable.vendor_guid) && v.name == variable.name
+        }) {
+            Some(existing) => *existing = variable,
+            None => self.variables.push(variable),
+        }
+    }
+
+    /// Removes a variable, returning whether it was present.
+    pub fn remove(&mut self, vendor_guid: &str, name: &str) -> bool {
+        let len = self.variables.len();
+        self.variables
+            .retain(|v| !(v.vendor_guid.eq_ignore_ascii_case(vendor_guid) && v.name == name));
+        self.variables.len() != len
+    }
+
+    /// Writes the variable store over the one of `cfv`: the store is rebuilt
+    /// without deleted variables, the rest of the store is erased (0xFF), and
+    /// the store and firmware volume headers are recomputed.
+    pub fn write(&self, cfv: &[u8]) -> Result<Vec<u8>> {
+        let header_size = if self.authenticated { AUTHENTICATED_VARIABLE_HEADER_SIZE } else { VARIABLE_HEADER_SIZE };
+        let mut store = Vec::with_capacity(self.size);
+
+        let store_guid = if self.authenticated { AUTHENTICATED_VARIABLE_GUID } else { VARIABLE_GUID };
+        store.extend_from_slice(&encode_guid(store_guid)?);
+        store.extend_from_slice(&(self.size as u32).to_le_bytes());
+        store.extend_from_slice(&[VARIABLE_STORE_FORMATTED, VARIABLE_STORE_HEALTHY, 0, 0, 0, 0, 0, 0]);
+
+        for variable in &self.variables {
+            let mut name = crate::util::utf16_encode(&variable.name);
+            name.extend_from_slice(&[0, 0]);
+
+            store.extend_from_slice(&VARIABLE_START_ID.to_le_bytes());
+            store.extend_from_slice(&[VAR_ADDED, 0]);
+            store.extend_from_slice(&variable.attributes.to_le_bytes());
+            if self.authenticated {
+                let mut auth_fields = variable.auth_fields.clone();
+                auth_fields.resize(header_size - VARIABLE_HEADER_SIZE, 0);
+                store.extend_from_slice(&auth_fields);
+            }
+            store.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            store.extend_from_slice(&(variable.data.len() as u32).to_le_bytes());
+            store.extend_from_slice(&encode_guid(&variable.vendor_guid)?);
+            store.extend_from_slice(&name);
+            store.extend_from_slice(&variable.data);
+            store.resize(store.len().next_multiple_of(4), 0xff);
+        }
+        if store.len() > self.size {
+            bail!("Variables need {} bytes, the variable store has {}", store.len(), self.size);
+        }
+        store.resize(self.size, 0xff);
+
+        let mut cfv = cfv.to_vec();
+        cfv.get_mut(self.offset..self.offset + self.size)
+            .ok_or_else(|| anyhow!("Variable store extends beyond the CFV"))?
+            .copy_from_slice(&store);
+        update_fv_checksum(&mut cfv, self.offset)?;
+        Ok(cfv)
+    }
+
+    /// Variables that make OVMF measure a non-empty Secure Boot configuration.
+    pub fn secure_boot_variables(&self) -> Vec<&FirmwareVariable> {
+        self.variables.iter().filter(|v| v.is_secure_boot_variable()).collect()
+    }
+}
+
+/// Sets the checksum of the firmware volume header so that its 16-bit words sum to 0.
+fn update_fv_checksum(cfv: &mut [u8], header_length: usize) -> Result<()> {
+    let header = cfv
+        .get_mut(..header_length)
+        .ok_or_else(|| anyhow!("Truncated firmware volume header"))?;
+    header[FV_CHECKSUM_OFFSET..FV_CHECKSUM_OFFSET + 2].fill(0);
+    let sum = header
+        .chunks_exact(2)
+        .fold(0u16, |sum, w| sum.wrapping_add(u16::from_le_bytes([w[0], w[1]])));
+    header[FV_CHECKSUM_OFFSET..FV_CHECKSUM_OFFSET + 2].copy_from_slice(&sum.wrapping_neg().to_le_bytes());
+    Ok(())
+}
+
+/// Whether the variable store turns Secure Boot on: a PK is enrolled and
+/// `SecureBootEnable` does not turn it off.
+pub(crate) fn secure_boot_enabled(store: &VariableStore) -> bool {
+    store.get(EFI_GLOBAL_VARIABLE_GUID, "PK").is_some()
+        && store
+            .get(SECURE_BOOT_ENABLE_GUID, "SecureBootEnable")
+            .is_none_or(|v| v.data.first() == Some(&1))
+}
+
+/// Data of the Secure Boot events OVMF logs in RTMR0 for this variable store,
+/// in the order of `SECURE_BOOT_VARIABLES`. OVMF sets `SecureBoot` once a PK
+/// is enrolled, to 1 unless `SecureBootEnable` turns Secure Boot off.
+pub(crate) fn secure_boot_event_data(store: Option<&VariableStore>) -> Vec<Option<Vec<u8>>> {
+    SECURE_BOOT_VARIABLES
+        .iter()
+        .map(|(guid, name)| {
+            let store = store?;
+            if *name == "SecureBoot" {
+                store.get(EFI_GLOBAL_VARIABLE_GUID, "PK")?;
+                return Some(vec![secure_boot_enabled(store) as u8]);
+            }
+            store.get(guid, name).map(|v| v.data.clone())
+        })
+        .collect()
+}
+
+/// Returns the firmware with its CFV replaced by one holding the given variable store.
+pub fn customize_firmware(fw: &[u8], store: &VariableStore) -> Result<Vec<u8>> {
+    let tdvf = Tdvf::parse(fw)?;
+    let cfv = tdvf.cfv()?;
+    let offset = cfv.as_ptr() as usize - fw.as_ptr() as usize;
+    let new_cfv = store.write(cfv)?;
+
+    let mut fw = fw.to_vec();
+    fw[offset..offset + new_cfv.len()].copy_from_slice(&new_cfv);
+    Ok(fw)
+}
+
+/// Default vendor GUID of the variables set in a customization.
+fn default_vendor_guid(name: &str) -> &'static str {
+    match name {
+        "db" | "dbx" | "dbt" | "dbr" => EFI_IMAGE_SECURITY_DATABASE_GUID,
+        _ => EFI_GLOBAL_VARIABLE_GUID,
+    }
+}
+
+impl ImageConfig {
+    /// Builds the firmware with the customized CFV variables, if a
+    /// customization is given, and measures that firmware instead of `bios`.
+    /// It is written to `output` only with `write_output`, to a temporary
+    /// file otherwise, which is returned and must be kept until measured.
+    /// `base_dir` is the directory the metadata paths are relative to.
+    pub fn customize_firmware(&mut self, base_dir: &Path, write_output: bool) -> Result<Option<NamedTempFile>> {
+        let Some(boot_config) = self.boot_config.as_mut() else {
+            return Ok(None);
+        };
+        let Some(FirmwareCustomization { output, variables }) = boot_config.firmware_variables.take() else {
+            return Ok(None);
+        };
+
+        let fw = fs::read(base_dir.join(&boot_config.bios))?;
+        let mut store = read_firmware_variables(&fw).context("Failed to read firmware variables")?;
+        for spec in variables {
+            let vendor_guid = spec.vendor_guid.as_deref().unwrap_or_else(|| default_vendor_guid(&spec.name));
+            let data = match (&spec.data, &spec.file) {
+                (Some(data), None) => hex::decode(data).with_context(|| format!("Invalid data of {}", spec.name))?,
+                (None, Some(file)) => fs::read(base_dir.join(file))?,
+                (None, None) => {
+                    if !store.remove(vendor_guid, &spec.name) {
+                        bail!("Variable {} to remove is not in the CFV", spec.name);
+                    }
+                    continue;
+                }
+                (Some(_), Some(_)) => bail!("Variable {} has both data and a file", spec.name),
+            };
+            let attributes = spec.attributes.unwrap_or_else(|| {
+                if SECURE_BOOT_VARIABLES.iter().any(|(_, name)| *name == spec.name) {
+                    ATTRIBUTE_NV_BS_RT | ATTRIBUTE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS
+                } else {
+                    ATTRIBUTE_NV_BS_RT
+                }
+            });
+            store.set(FirmwareVariable::new(vendor_guid, &spec.name, attributes, data));
+        }
+
+        let fw = customize_firmware(&fw, &store)?;
+        if write_output {
+            fs::write(base_dir.join(&output), fw)?;
+            boot_config.bios = output;
+            return Ok(None);
+        }
+        let mut file = NamedTempFile::with_prefix("tdx-measure-firmware-")?;
+        file.write_all(&fw)?;
+        boot_config.bios = file.path().display().to_string();
+        Ok(Some(file))
+    }
+}
+
+/// Reads the variable store from the CFV of a TDVF image.
+pub fn read_firmware_variables(fw: &[u8]) -> Result<VariableStore> {
+    let tdvf = Tdvf::parse(fw)?;
+    VariableStore::parse(tdvf.cfv()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FV_HEADER_SIZE: usize = 72;
+    const STORE_SIZE: usize = 0x200;
+
+    /// A CFV with an empty authenticated variable store after the firmware volume header.
+    fn empty_cfv() -> Vec<u8> {
+        let mut cfv = vec![0; FV_HEADER_SIZE];
+        cfv[32..40].copy_from_slice(&((FV_HEADER_SIZE + STORE_SIZE) as u64).to_le_bytes());
+        cfv[FV_SIGNATURE_OFFSET..FV_SIGNATURE_OFFSET + 4].copy_from_slice(FV_SIGNATURE);
+        cfv[44..48].copy_from_slice(&0x0004_feffu32.to_le_bytes());
+        cfv[FV_HEADER_LENGTH_OFFSET..FV_HEADER_LENGTH_OFFSET + 2]
+            .copy_from_slice(&(FV_HEADER_SIZE as u16).to_le_bytes());
+        cfv[55] = 2;
+        cfv[56..60].copy_from_slice(&1u32.to_le_bytes());
+        cfv[60..64].copy_from_slice(&((FV_HEADER_SIZE + STORE_SIZE) as u32).to_le_bytes());
+
+        let mut store = encode_guid(AUTHENTICATED_VARIABLE_GUID).unwrap();
+        store.extend_from_slice(&(STORE_SIZE as u32).to_le_bytes());
+        store.extend_from_slice(&[VARIABLE_STORE_FORMATTED, VARIABLE_STORE_HEALTHY, 0, 0, 0, 0, 0, 0]);
+        store.resize(STORE_SIZE, 0xff);
+        cfv.extend(store);
+        cfv
+    }
+
+    fn header_sum(cfv: &[u8]) -> u16 {
+        cfv[..FV_HEADER_SIZE]
+            .chunks_exact(2)
+            .fold(0u16, |sum, w| sum.wrapping_add(u16::from_le_bytes([w[0], w[1]])))
+    }
+
+    #[test]
+    fn parse_write_round_trip() {
+        let cfv = empty_cfv();
+        let mut store = VariableStore::parse(&cfv).unwrap();
+        assert!(store.authenticated);
+        assert_eq!((store.offset, store.size), (FV_HEADER_SIZE, STORE_SIZE));
+        assert!(store.variables.is_empty());
+
+        let attributes = ATTRIBUTE_NV_BS_RT | ATTRIBUTE_TIME_BASED_AUTHENTICATED_WRITE_ACCESS;
+        store.set(FirmwareVariable::new(EFI_GLOBAL_VARIABLE_GUID, "PK", attributes, vec![1, 2, 3]));
+        store.set(FirmwareVariable::new(EFI_IMAGE_SECURITY_DATABASE_GUID, "db", attributes, vec![4; 5]));
+        let written = store.write(&cfv).unwrap();
+        assert_eq!(written.len(), cfv.len());
+        assert_eq!(header_sum(&written), 0);
+
+        let parsed = VariableStore::parse(&written).unwrap();
+        let variables: Vec<_> = parsed
+            .variables
+            .iter()
+            .map(|v| (v.vendor_guid.as_str(), v.name.as_str(), v.attributes, v.data.as_slice()))
+            .collect();
+        assert_eq!(
+            variables,
+            [
+                (EFI_GLOBAL_VARIABLE_GUID, "PK", attributes, &[1, 2, 3][..]),
+                (EFI_IMAGE_SECURITY_DATABASE_GUID, "db", attributes, &[4; 5][..]),
+            ]
+        );
+        // Variables follow the store header, each 4-byte aligned
+        assert_eq!(parsed.variables[0].offset, FV_HEADER_SIZE + VARIABLE_STORE_HEADER_SIZE);
+        assert_eq!(parsed.variables[1].offset, parsed.variables[0].offset + (60 + 6 + 3usize).next_multiple_of(4));
+        assert!(written[FV_HEADER_SIZE + 0x100..].iter().all(|b| *b == 0xff));
+
+        // Writing the parsed store again keeps the bytes, authenticated fields included
+        assert_eq!(parsed.write(&written).unwrap(), written);
+    }
+
+    #[test]
+    fn removed_variables_are_erased() {
+        let cfv = empty_cfv();
+        let mut store = VariableStore::parse(&cfv).unwrap();
+        store.set(FirmwareVariable::new(EFI_GLOBAL_VARIABLE_GUID, "PK", ATTRIBUTE_NV_BS_RT, vec![1]));
+        let written = store.write(&cfv).unwrap();
+
+        let mut store = VariableStore::parse(&written).unwrap();
+        assert!(store.remove(EFI_GLOBAL_VARIABLE_GUID, "PK"));
+        assert!(!store.remove(EFI_GLOBAL_VARIABLE_GUID, "PK"));
+        let erased = store.write(&written).unwrap();
+        let mut expected = cfv.clone();
+        update_fv_checksum(&mut expected, FV_HEADER_SIZE).unwrap();
+        assert_eq!(erased, expected);
+        assert_eq!(header_sum(&erased), 0);
+    }
+
+    #[test]
+    fn store_overflow() {
+        let cfv = empty_cfv();
+        let mut store = VariableStore::parse(&cfv).unwrap();
+        store.set(FirmwareVariable::new(EFI_GLOBAL_VARIABLE_GUID, "PK", ATTRIBUTE_NV_BS_RT, vec![0; STORE_SIZE]));
+        assert!(store.write(&cfv).is_err());
+    }
+
+    #[test]
+    fn secure_boot_data() {
+        let cfv = empty_cfv();
+        let mut store = VariableStore::parse(&cfv).unwrap();
+        assert_eq!(secure_boot_event_data(Some(&store))[0], None);
+        store.set(FirmwareVariable::new(EFI_GLOBAL_VARIABLE_GUID, "PK", ATTRIBUTE_NV_BS_RT, vec![1]));
+        assert_eq!(secure_boot_event_data(Some(&store))[0], Some(vec![1]));
+        store.set(FirmwareVariable::new(SECURE_BOOT_ENABLE_GUID, "SecureBootEnable", ATTRIBUTE_NV_BS_RT, vec![0]));
+        assert_eq!(secure_boot_event_data(Some(&store))[0], Some(vec![0]));
+        assert!(secure_boot_event_data(None).iter().all(Option::is_none));
+    }
+}
@@ -0,0 +1,168 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Derivation of the TD ATTRIBUTES and XFAM fields from the `tdx-guest`
+//! object options and the `-cpu` model, the way QEMU sets them up.
+
+use anyhow::{bail, Result};
+use log::warn;
+use std::collections::BTreeSet;
+
+use crate::TdxGuestConfig;
+
+const ATTRIBUTE_DEBUG: u64 = 1 << 0;
+const ATTRIBUTE_SEPT_VE_DISABLE: u64 = 1 << 28;
+const ATTRIBUTE_PKS: u64 = 1 << 30;
+const ATTRIBUTE_PERFMON: u64 = 1 << 63;
+
+const XFAM_X87: u64 = 1 << 0;
+const XFAM_SSE: u64 = 1 << 1;
+const XFAM_AVX: u64 = 1 << 2;
+const XFAM_AVX512: u64 = 0b111 << 5;
+const XFAM_PT: u64 = 1 << 8;
+const XFAM_PKRU: u64 = 1 << 9;
+const XFAM_CET: u64 = 0b11 << 11;
+const XFAM_ULI: u64 = 1 << 14;
+const XFAM_ARCH_LBR: u64 = 1 << 15;
+const XFAM_AMX: u64 = 0b11 << 17;
+
+/// XFAM bits enabled by each CPU feature, x87 and SSE are always enabled.
+const XFAM_FEATURES: &[(&str, u64)] = &[
+    ("avx", XFAM_AVX),
+    ("avx512f", XFAM_AVX512),
+    ("intel-pt", XFAM_PT),
+    ("pku", XFAM_PKRU),
+    ("shstk", XFAM_CET),
+    ("ibt", XFAM_CET),
+    ("uintr", XFAM_ULI),
+    ("arch-lbr", XFAM_ARCH_LBR),
+    ("amx-tile", XFAM_AMX),
+];
+
+/// Features of the named QEMU CPU models that matter for XFAM and ATTRIBUTES.
+fn model_features(model: &str) -> Result<&'static [&'static str]> {
+    Ok(match model {
+        // Depends on the host, features must be listed explicitly
+        "host" | "max" => &[],
+        "qemu64" | "kvm64" => &[],
+        "Skylake-Server" | "Cascadelake-Server" | "Cooperlake" | "Icelake-Server" => {
+            &["avx", "avx512f", "pku"]
+        }
+        "SapphireRapids" | "GraniteRapids" => &["avx", "avx512f", "pku", "amx-tile"],
+        "SierraForest" => &["avx"],
+        _ => bail!("Unknown CPU model {model}"),
+    })
+}
+
+/// A `-cpu` option: a model followed by `+feature`, `-feature` or `feature=on|off`.
+struct CpuConfig {
+    features: BTreeSet<String>,
+    pmu: bool,
+    /// Whether the features of the model depend on the host
+    host_dependent: bool,
+    /// Whether features are listed after the model
+    listed_features: bool,
+}
+
+impl CpuConfig {
+    fn parse(cpu: &str) -> Result<Self> {
+        let mut parts = cpu.split(',');
+        let model = parts.next().unwrap_or_default().trim();
+        let mut features: BTreeSet<String> =
+            model_features(model)?.iter().map(|f| f.to_string()).collect();
+        // QEMU enables the PMU by default for the max model, which host derives
+        // from, and then sets PERFMON in the TD ATTRIBUTES
+        let host_dependent = matches!(model, "host" | "max");
+        let mut pmu = host_dependent;
+        let mut listed_features = false;
+
+        for part in parts.map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, enabled) = if let Some(name) = part.strip_prefix('+') {
+                (name, true)
+            } else if let Some(name) = part.strip_prefix('-') {
+                (name, false)
+            } else if let Some((name, value)) = part.split_once('=') {
+                match value {
+                    "on" | "true" | "yes" => (name, true),
+                    "off" | "false" | "no" => (name, false),
+                    // Other properties (e.g. model-id) do not change the TD identity
+                    _ => continue,
+                }
+            } else {
+                (part, true)
+            };
+
+            let name = name.replace('_', "-");
+            if name == "pmu" {
+                pmu = enabled;
+                continue;
+            }
+            listed_features = true;
+            if enabled {
+                features.insert(name);
+            } else {
+                features.remove(&name);
+            }
+        }
+        Ok(Self { features, pmu, host_dependent, listed_features })
+    }
+}
+
+/// Returns the expected TD ATTRIBUTES and XFAM for the given options.
+pub(crate) fn td_attributes_and_xfam(tdx_guest: Option<&TdxGuestConfig>, cpu: &str) -> Result<(u64, u64)> {
+    let cpu = CpuConfig::parse(cpu)?;
+
+    let mut attributes = match tdx_guest.and_then(|t| t.attributes.as_deref()) {
+        Some(value) => parse_u64(value)?,
+        None => ATTRIBUTE_SEPT_VE_DISABLE,
+    };
+    if let Some(tdx_guest) = tdx_guest {
+        if let Some(sept_ve_disable) = tdx_guest.sept_ve_disable {
+            attributes = set_bit(attributes, ATTRIBUTE_SEPT_VE_DISABLE, sept_ve_disable);
+        }
+        if let Some(debug) = tdx_guest.debug {
+            attributes = set_bit(attributes, ATTRIBUTE_DEBUG, debug);
+        }
+    }
+    attributes = set_bit(attributes, ATTRIBUTE_PKS, cpu.features.contains("pks"));
+    attributes = set_bit(attributes, ATTRIBUTE_PERFMON, cpu.pmu);
+
+    if attributes & ATTRIBUTE_DEBUG != 0 {
+        warn!("TD ATTRIBUTES has DEBUG set, the TD is not confidential");
+    }
+    if attributes & ATTRIBUTE_SEPT_VE_DISABLE == 0 {
+        warn!("TD ATTRIBUTES has SEPT_VE_DISABLE off, the guest must handle #VE on private memory");
+    }
+
+    let xfam = match tdx_guest.and_then(|t| t.xfam.as_deref()) {
+        Some(value) => parse_u64(value)?,
+        // The features of host and max would otherwise silently come out as x87 and SSE only
+        None if cpu.host_dependent && !cpu.listed_features => {
+            bail!("CPU model depends on the host, list its features (e.g. host,+avx,+avx512f,+pku) or give xfam")
+        }
+        None => XFAM_FEATURES
+            .iter()
+            .filter(|(name, _)| cpu.features.contains(*name))
+            .fold(XFAM_X87 | XFAM_SSE, |xfam, (_, bits)| xfam | bits),
+    };
+
+    Ok((attributes, xfam))
+}
+
+fn set_bit(value: u64, bit: u64, enabled: bool) -> u64 {
+    if enabled {
+        value | bit
+    } else {
+        value & !bit
+    }
+}
+
+fn parse_u64(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let parsed = match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| anyhow::anyhow!("Invalid value {value}: {e}"))
+}
@@ -0,0 +1,213 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Offline application of Debian packages: the data member of each .deb is
+//! unpacked natively (ar, tar, xz/zstd/gzip) into an overlay of the guest
+//! filesystem, so that an image can be measured as it will be after upgrade.
+
+use anyhow::{anyhow, bail, Context, Result};
+use fs_err as fs;
+use log::debug;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+
+use crate::UpgradeConfig;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_SIZE: usize = 60;
+
+/// Files replacing or adding to those of the guest filesystem, by guest path.
+pub(crate) type Overlay = BTreeMap<String, Vec<u8>>;
+
+/// Guest files installed by maintainer scripts rather than unpacked by dpkg,
+/// with the package files they are copied from, in order of preference.
+const DEFAULT_INSTALLS: &[(&str, &[&str])] = &[
+    (
+        "/boot/efi/EFI/ubuntu/shimx64.efi",
+        &["/usr/lib/shim/shimx64.efi.signed.latest", "/usr/lib/shim/shimx64.efi.signed"],
+    ),
+    (
+        "/boot/efi/EFI/ubuntu/grubx64.efi",
+        &["/usr/lib/grub/x86_64-efi-signed/grubx64.efi.signed"],
+    ),
+];
+
+/// Returns the members of an ar archive by name.
+fn ar_members(data: &[u8]) -> Result<Vec<(String, &[u8])>> {
+    let mut rest = data
+        .strip_prefix(AR_MAGIC)
+        .ok_or_else(|| anyhow!("Not an ar archive"))?;
+
+    let mut members = Vec::new();
+    while rest.len() >= AR_HEADER_SIZE {
+        let (header, body) = rest.split_at(AR_HEADER_SIZE);
+        if &header[58..60] != b"`\n" {
+            bail!("Invalid ar member header");
+        }
+        let name = String::from_utf8_lossy(&header[0..16]).trim_end().trim_end_matches('/').to_string();
+        let size: usize = String::from_utf8_lossy(&header[48..58])
+            .trim()
+            .parse()
+            .context("Invalid ar member size")?;
+        if size > body.len() {
+            bail!("Truncated ar member {name}");
+        }
+        members.push((name, &body[..size]));
+        // Members are aligned to 2 bytes
+        rest = &body[(size + size % 2).min(body.len())..];
+    }
+    Ok(members)
+}
+
+/// Decompresses the data member of a .deb according to its extension.
+fn decompress(name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match name.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("tar") => out.extend_from_slice(data),
+        Some("xz") => lzma_rs::xz_decompress(&mut &data[..], &mut out)
+            .map_err(|e| anyhow!("Failed to decompress {name}: {e:?}"))?,
+        Some("zst") => {
+            ruzstd::decoding::StreamingDecoder::new(data)
+                .map_err(|e| anyhow!("Failed to decompress {name}: {e}"))?
+                .read_to_end(&mut out)?;
+        }
+        Some("gz") => {
+            flate2::read::GzDecoder::new(data).read_to_end(&mut out)?;
+        }
+        _ => bail!("Unsupported package member {name}"),
+    }
+    Ok(out)
+}
+
+/// Contents of the data archives of a set of packages.
+#[derive(Debug, Default)]
+struct Unpacked {
+    files: BTreeMap<String, Vec<u8>>,
+    symlinks: BTreeMap<String, String>,
+}
+
+impl Unpacked {
+    /// Unpacks the data member of a .deb, later packages replace earlier files.
+    fn unpack(&mut self, deb: &Path) -> Result<()> {
+        let data = fs::read(deb)?;
+        let members = ar_members(&data)?;
+        let (name, member) = members
+            .iter()
+            .find(|(name, _)| name.starts_with("data.tar"))
+            .ok_or_else(|| anyhow!("Package has no data member"))?;
+        let tar_data = decompress(name, member)?;
+
+        let mut archive = tar::Archive::new(&tar_data[..]);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = guest_path(&entry.path()?.to_string_lossy());
+            let entry_type = entry.header().entry_type();
+            if entry_type.is_file() {
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                self.symlinks.remove(&path);
+                self.files.insert(path, content);
+            } else if entry_type.is_symlink() || entry_type.is_hard_link() {
+                let target = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("Link {path} has no target"))?
+                    .to_string_lossy()
+                    .into_owned();
+                let target = if entry_type.is_hard_link() {
+                    guest_path(&target)
+                } else {
+                    resolve_link(&path, &target)
+                };
+                self.files.remove(&path);
+                self.symlinks.insert(path, target);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the content of a file, following symlinks within the packages.
+    fn get(&self, path: &str) -> Option<&Vec<u8>> {
+        let mut path = path;
+        for _ in 0..16 {
+            if let Some(data) = self.files.get(path) {
+                return Some(data);
+            }
+            path = self.symlinks.get(path)?;
+        }
+        None
+    }
+}
+
+/// Turns a path of a data archive (e.g. `./boot/vmlinuz`) into a guest path.
+fn guest_path(path: &str) -> String {
+    format!("/{}", path.trim_start_matches('.').trim_start_matches('/'))
+}
+
+/// Resolves a symlink target relative to the directory of the link.
+fn resolve_link(link: &str, target: &str) -> String {
+    let mut parts: Vec<&str> = if target.starts_with('/') {
+        Vec::new()
+    } else {
+        link.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    if !target.starts_with('/') {
+        parts.pop();
+    }
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+/// Builds the overlay of an upgrade: the files of the packages under `/boot`,
+/// the files maintainer scripts install from the packages, and the given files.
+pub(crate) fn build_overlay(upgrade: &UpgradeConfig) -> Result<Overlay> {
+    let mut unpacked = Unpacked::default();
+    for package in &upgrade.packages {
+        unpacked
+            .unpack(Path::new(package))
+            .with_context(|| format!("Failed to unpack {package}"))?;
+    }
+
+    let mut overlay: Overlay = unpacked
+        .files
+        .iter()
+        .filter(|(path, _)| path.starts_with("/boot/"))
+        .map(|(path, data)| (path.clone(), data.clone()))
+        .collect();
+
+    match &upgrade.install {
+        Some(install) => {
+            for (guest, source) in install {
+                let data = unpacked
+                    .get(source)
+                    .ok_or_else(|| anyhow!("{source} is not part of the packages"))?;
+                overlay.insert(guest.clone(), data.clone());
+            }
+        }
+        None => {
+            for (guest, sources) in DEFAULT_INSTALLS {
+                if let Some(data) = sources.iter().find_map(|s| unpacked.get(s)) {
+                    overlay.insert(guest.to_string(), data.clone());
+                }
+            }
+        }
+    }
+
+    for (guest, host) in &upgrade.files {
+        overlay.insert(guest.clone(), fs::read(host)?);
+    }
+
+    for path in overlay.keys() {
+        debug!("Upgrade overlay: {path}");
+    }
+    Ok(overlay)
+}
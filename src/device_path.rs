@@ -0,0 +1,848 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! UEFI device paths, as found in Boot#### variables: parsing, serialization
+//! and rendering in the text format of the UEFI specification.
+
+use anyhow::{bail, Context, Result};
+use std::fmt;
+
+use crate::cfv::format_guid;
+use crate::num::read_le;
+use crate::tdvf::encode_guid;
+use crate::util::utf16_encode;
+
+const HARDWARE_DEVICE_PATH: u8 = 0x01;
+const ACPI_DEVICE_PATH: u8 = 0x02;
+const MESSAGING_DEVICE_PATH: u8 = 0x03;
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const END_DEVICE_PATH: u8 = 0x7f;
+
+const HW_PCI_DP: u8 = 0x01;
+const HW_MEMMAP_DP: u8 = 0x03;
+const HW_VENDOR_DP: u8 = 0x04;
+const ACPI_DP: u8 = 0x01;
+const MSG_SCSI_DP: u8 = 0x02;
+const MSG_MAC_ADDR_DP: u8 = 0x0b;
+const MSG_IPV4_DP: u8 = 0x0c;
+const MSG_IPV6_DP: u8 = 0x0d;
+const MSG_VENDOR_DP: u8 = 0x0a;
+const MSG_SATA_DP: u8 = 0x12;
+const MSG_NVME_NAMESPACE_DP: u8 = 0x17;
+const MSG_URI_DP: u8 = 0x18;
+const MEDIA_HARDDRIVE_DP: u8 = 0x01;
+const MEDIA_VENDOR_DP: u8 = 0x03;
+const MEDIA_FILEPATH_DP: u8 = 0x04;
+const MEDIA_PIWG_FW_FILE_DP: u8 = 0x06;
+const MEDIA_PIWG_FW_VOL_DP: u8 = 0x07;
+const END_INSTANCE_DEVICE_PATH_SUBTYPE: u8 = 0x01;
+const END_ENTIRE_DEVICE_PATH_SUBTYPE: u8 = 0xff;
+
+/// EISA ID of a PCI root bridge, `PNP0A03`
+const PNP0A03: u32 = 0x0a0341d0;
+/// EISA ID of a PCI Express root bridge, `PNP0A08`
+const PNP0A08: u32 = 0x0a0841d0;
+/// Vendor part of the EISA IDs of `PNP` devices
+const PNP_EISA_VENDOR: u32 = 0x41d0;
+
+/// Network interface type of Ethernet in MAC nodes
+pub const IF_TYPE_ETHERNET: u8 = 0x01;
+
+/// Partition signature of a hard drive node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartitionSignature {
+    None,
+    /// MBR disk signature
+    Mbr(u32),
+    /// GPT unique partition GUID
+    Gpt(String),
+}
+
+/// A node of a device path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DevicePathNode {
+    /// `Pci(device,function)`
+    Pci { device: u8, function: u8 },
+    /// `MemoryMapped(type,start,end)`
+    MemoryMapped { memory_type: u32, start: u64, end: u64 },
+    /// `VenHw`, `VenMsg` or `VenMedia`, after the type of the node
+    Vendor { node_type: u8, guid: String, data: Vec<u8> },
+    /// `Acpi(hid,uid)`, `PciRoot(uid)` or `PcieRoot(uid)`
+    Acpi { hid: u32, uid: u32 },
+    /// `Scsi(target,lun)`
+    Scsi { target: u16, lun: u16 },
+    /// `Sata(hba_port,port_multiplier_port,lun)`
+    Sata { hba_port: u16, port_multiplier_port: u16, lun: u16 },
+    /// `NVMe(namespace_id,eui64)`
+    Nvme { namespace_id: u32, eui64: [u8; 8] },
+    /// `MAC(address,if_type)`, with the address padded to 32 bytes
+    Mac { address: [u8; 32], if_type: u8 },
+    /// `IPv4(remote,protocol,DHCP|Static,local,gateway,subnet)`, the gateway
+    /// and subnet mask being absent from nodes older than UEFI 2.0
+    Ipv4 {
+        local: [u8; 4],
+        remote: [u8; 4],
+        local_port: u16,
+        remote_port: u16,
+        protocol: u16,
+        static_address: bool,
+        gateway: Option<[u8; 4]>,
+        subnet_mask: Option<[u8; 4]>,
+    },
+    /// `IPv6(remote,protocol,origin,local,gateway,prefix)`, the prefix length
+    /// and gateway being absent from nodes older than UEFI 2.3
+    Ipv6 {
+        local: [u8; 16],
+        remote: [u8; 16],
+        local_port: u16,
+        remote_port: u16,
+        protocol: u16,
+        address_origin: u8,
+        prefix_length: Option<u8>,
+        gateway: Option<[u8; 16]>,
+    },
+    /// `Uri(uri)`
+    Uri(String),
+    /// `HD(partition,signature,start,size)`, `mbr_type` being 0x01 for an MBR
+    /// and 0x02 for a GPT partition table
+    HardDrive { partition: u32, start_lba: u64, size_lba: u64, signature: PartitionSignature, mbr_type: u8 },
+    /// A file path, e.g. `\EFI\BOOT\BOOTX64.EFI`
+    FilePath(String),
+    /// `FvFile(guid)`
+    FvFile(String),
+    /// `Fv(guid)`
+    Fv(String),
+    /// End of an instance of a multi-instance device path, rendered as `,`
+    EndInstance,
+    /// Any other node, rendered as `Path(type,subtype,data)`
+    Unknown { node_type: u8, sub_type: u8, data: Vec<u8> },
+}
+
+/// Reads a fixed-size array from a node.
+fn read_array<const N: usize>(data: &[u8], offset: usize) -> Result<[u8; N]> {
+    data.get(offset..offset + N)
+        .and_then(|b| b.try_into().ok())
+        .context("Truncated device path node")
+}
+
+fn read_guid(data: &[u8], offset: usize) -> Result<String> {
+    Ok(format_guid(&read_array::<16>(data, offset)?))
+}
+
+fn read_utf16(data: &[u8]) -> String {
+    let chars: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+fn format_protocol(protocol: u16) -> String {
+    match protocol {
+        6 => "TCP".to_string(),
+        17 => "UDP".to_string(),
+        other => format!("{other:#x}"),
+    }
+}
+
+fn format_ipv4(address: &[u8; 4]) -> String {
+    std::net::Ipv4Addr::from(*address).to_string()
+}
+
+fn format_ipv6(address: &[u8; 16]) -> String {
+    address
+        .chunks(2)
+        .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+impl DevicePathNode {
+    /// `PciRoot(uid)`
+    pub fn pci_root(uid: u32) -> Self {
+        DevicePathNode::Acpi { hid: PNP0A03, uid }
+    }
+
+    /// `MAC(address,0x1)` of an Ethernet interface
+    pub fn ethernet(address: &[u8; 6]) -> Self {
+        let mut padded = [0u8; 32];
+        padded[..6].copy_from_slice(address);
+        DevicePathNode::Mac { address: padded, if_type: IF_TYPE_ETHERNET }
+    }
+
+    /// `IPv4(0.0.0.0)` with every field zero, as the PXE driver appends it
+    pub fn ipv4_any() -> Self {
+        DevicePathNode::Ipv4 {
+            local: [0; 4],
+            remote: [0; 4],
+            local_port: 0,
+            remote_port: 0,
+            protocol: 0,
+            static_address: false,
+            gateway: Some([0; 4]),
+            subnet_mask: Some([0; 4]),
+        }
+    }
+
+    /// `IPv6(::)` with every field zero, as the PXE driver appends it
+    pub fn ipv6_any() -> Self {
+        DevicePathNode::Ipv6 {
+            local: [0; 16],
+            remote: [0; 16],
+            local_port: 0,
+            remote_port: 0,
+            protocol: 0,
+            address_origin: 0,
+            prefix_length: Some(0),
+            gateway: Some([0; 16]),
+        }
+    }
+
+    /// Decodes a node from its type, subtype and data, header excluded. A node
+    /// that would not serialize back to the same data, e.g. with non-zero
+    /// reserved bytes, is kept as an unknown node.
+    fn decode(node_type: u8, sub_type: u8, data: &[u8]) -> Result<Self> {
+        let node = Self::decode_fields(node_type, sub_type, data)?;
+        if node.encode().is_ok_and(|(_, _, encoded)| encoded == data) {
+            Ok(node)
+        } else {
+            Ok(DevicePathNode::Unknown { node_type, sub_type, data: data.to_vec() })
+        }
+    }
+
+    /// Decodes the fields of a node from its type, subtype and data.
+    fn decode_fields(node_type: u8, sub_type: u8, data: &[u8]) -> Result<Self> {
+        let le16 = |offset| read_le::<u16>(data, offset, "device path node");
+        let le32 = |offset| read_le::<u32>(data, offset, "device path node");
+        let le64 = |offset| read_le::<u64>(data, offset, "device path node");
+        Ok(match (node_type, sub_type) {
+            (HARDWARE_DEVICE_PATH, HW_PCI_DP) => {
+                let [function, device] = read_array(data, 0)?;
+                DevicePathNode::Pci { device, function }
+            }
+            (HARDWARE_DEVICE_PATH, HW_MEMMAP_DP) => {
+                DevicePathNode::MemoryMapped { memory_type: le32(0)?, start: le64(4)?, end: le64(12)? }
+            }
+            (HARDWARE_DEVICE_PATH, HW_VENDOR_DP)
+            | (MESSAGING_DEVICE_PATH, MSG_VENDOR_DP)
+            | (MEDIA_DEVICE_PATH, MEDIA_VENDOR_DP) => DevicePathNode::Vendor {
+                node_type,
+                guid: read_guid(data, 0)?,
+                data: data[16..].to_vec(),
+            },
+            (ACPI_DEVICE_PATH, ACPI_DP) => DevicePathNode::Acpi { hid: le32(0)?, uid: le32(4)? },
+            (MESSAGING_DEVICE_PATH, MSG_SCSI_DP) => DevicePathNode::Scsi { target: le16(0)?, lun: le16(2)? },
+            (MESSAGING_DEVICE_PATH, MSG_SATA_DP) => DevicePathNode::Sata {
+                hba_port: le16(0)?,
+                port_multiplier_port: le16(2)?,
+                lun: le16(4)?,
+            },
+            (MESSAGING_DEVICE_PATH, MSG_NVME_NAMESPACE_DP) => {
+                DevicePathNode::Nvme { namespace_id: le32(0)?, eui64: read_array(data, 4)? }
+            }
+            (MESSAGING_DEVICE_PATH, MSG_MAC_ADDR_DP) => {
+                DevicePathNode::Mac { address: read_array(data, 0)?, if_type: read_array::<1>(data, 32)?[0] }
+            }
+            (MESSAGING_DEVICE_PATH, MSG_IPV4_DP) => DevicePathNode::Ipv4 {
+                local: read_array(data, 0)?,
+                remote: read_array(data, 4)?,
+                local_port: le16(8)?,
+                remote_port: le16(10)?,
+                protocol: le16(12)?,
+                static_address: read_array::<1>(data, 14)?[0] != 0,
+                // Gateway and subnet mask were added in UEFI 2.0
+                gateway: read_array(data, 15).ok(),
+                subnet_mask: read_array(data, 19).ok(),
+            },
+            (MESSAGING_DEVICE_PATH, MSG_IPV6_DP) => DevicePathNode::Ipv6 {
+                local: read_array(data, 0)?,
+                remote: read_array(data, 16)?,
+                local_port: le16(32)?,
+                remote_port: le16(34)?,
+                protocol: le16(36)?,
+                address_origin: read_array::<1>(data, 38)?[0],
+                prefix_length: read_array::<1>(data, 39).ok().map(|b| b[0]),
+                gateway: read_array(data, 40).ok(),
+            },
+            (MESSAGING_DEVICE_PATH, MSG_URI_DP) => DevicePathNode::Uri(String::from_utf8_lossy(data).into_owned()),
+            (MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP) => {
+                let [mbr_type, signature_type] = read_array(data, 36)?;
+                let signature = match signature_type {
+                    0x01 => PartitionSignature::Mbr(le32(20)?),
+                    0x02 => PartitionSignature::Gpt(read_guid(data, 20)?),
+                    _ => PartitionSignature::None,
+                };
+                DevicePathNode::HardDrive {
+                    partition: le32(0)?,
+                    start_lba: le64(4)?,
+                    size_lba: le64(12)?,
+                    signature,
+                    mbr_type,
+                }
+            }
+            (MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP) => DevicePathNode::FilePath(read_utf16(data)),
+            (MEDIA_DEVICE_PATH, MEDIA_PIWG_FW_FILE_DP) => DevicePathNode::FvFile(read_guid(data, 0)?),
+            (MEDIA_DEVICE_PATH, MEDIA_PIWG_FW_VOL_DP) => DevicePathNode::Fv(read_guid(data, 0)?),
+            (END_DEVICE_PATH, END_INSTANCE_DEVICE_PATH_SUBTYPE) => DevicePathNode::EndInstance,
+            _ => DevicePathNode::Unknown { node_type, sub_type, data: data.to_vec() },
+        })
+    }
+
+    /// Returns the type, subtype and data of the node.
+    fn encode(&self) -> Result<(u8, u8, Vec<u8>)> {
+        let mut data = Vec::new();
+        let (node_type, sub_type) = match self {
+            DevicePathNode::Pci { device, function } => {
+                data.extend_from_slice(&[*function, *device]);
+                (HARDWARE_DEVICE_PATH, HW_PCI_DP)
+            }
+            DevicePathNode::MemoryMapped { memory_type, start, end } => {
+                data.extend_from_slice(&memory_type.to_le_bytes());
+                data.extend_from_slice(&start.to_le_bytes());
+                data.extend_from_slice(&end.to_le_bytes());
+                (HARDWARE_DEVICE_PATH, HW_MEMMAP_DP)
+            }
+            DevicePathNode::Vendor { node_type, guid, data: vendor_data } => {
+                data.extend(encode_guid(guid)?);
+                data.extend_from_slice(vendor_data);
+                let sub_type = match *node_type {
+                    HARDWARE_DEVICE_PATH => HW_VENDOR_DP,
+                    MESSAGING_DEVICE_PATH => MSG_VENDOR_DP,
+                    MEDIA_DEVICE_PATH => MEDIA_VENDOR_DP,
+                    other => bail!("No vendor node for device path type {other:#x}"),
+                };
+                (*node_type, sub_type)
+            }
+            DevicePathNode::Acpi { hid, uid } => {
+                data.extend_from_slice(&hid.to_le_bytes());
+                data.extend_from_slice(&uid.to_le_bytes());
+                (ACPI_DEVICE_PATH, ACPI_DP)
+            }
+            DevicePathNode::Scsi { target, lun } => {
+                data.extend_from_slice(&target.to_le_bytes());
+                data.extend_from_slice(&lun.to_le_bytes());
+                (MESSAGING_DEVICE_PATH, MSG_SCSI_DP)
+            }
+            DevicePathNode::Sata { hba_port, port_multiplier_port, lun } => {
+                data.extend_from_slice(&hba_port.to_le_bytes());
+                data.extend_from_slice(&port_multiplier_port.to_le_bytes());
+                data.extend_from_slice(&lun.to_le_bytes());
+                (MESSAGING_DEVICE_PATH, MSG_SATA_DP)
+            }
+            DevicePathNode::Nvme { namespace_id, eui64 } => {
+                data.extend_from_slice(&namespace_id.to_le_bytes());
+                data.extend_from_slice(eui64);
+                (MESSAGING_DEVICE_PATH, MSG_NVME_NAMESPACE_DP)
+            }
+            DevicePathNode::Mac { address, if_type } => {
+                data.extend_from_slice(address);
+                data.push(*if_type);
+                (MESSAGING_DEVICE_PATH, MSG_MAC_ADDR_DP)
+            }
+            DevicePathNode::Ipv4 {
+                local,
+                remote,
+                local_port,
+                remote_port,
+                protocol,
+                static_address,
+                gateway,
+                subnet_mask,
+            } => {
+                data.extend_from_slice(local);
+                data.extend_from_slice(remote);
+                data.extend_from_slice(&local_port.to_le_bytes());
+                data.extend_from_slice(&remote_port.to_le_bytes());
+                data.extend_from_slice(&protocol.to_le_bytes());
+                data.push(*static_address as u8);
+                if let Some(gateway) = gateway {
+                    data.extend_from_slice(gateway);
+                }
+                if let Some(subnet_mask) = subnet_mask {
+                    data.extend_from_slice(subnet_mask);
+                }
+                (MESSAGING_DEVICE_PATH, MSG_IPV4_DP)
+            }
+            DevicePathNode::Ipv6 {
+                local,
+                remote,
+                local_port,
+                remote_port,
+                protocol,
+                address_origin,
+                prefix_length,
+                gateway,
+            } => {
+                data.extend_from_slice(local);
+                data.extend_from_slice(remote);
+                data.extend_from_slice(&local_port.to_le_bytes());
+                data.extend_from_slice(&remote_port.to_le_bytes());
+                data.extend_from_slice(&protocol.to_le_bytes());
+                data.push(*address_origin);
+                data.extend(prefix_length);
+                if let Some(gateway) = gateway {
+                    data.extend_from_slice(gateway);
+                }
+                (MESSAGING_DEVICE_PATH, MSG_IPV6_DP)
+            }
+            DevicePathNode::Uri(uri) => {
+                data.extend_from_slice(uri.as_bytes());
+                (MESSAGING_DEVICE_PATH, MSG_URI_DP)
+            }
+            DevicePathNode::HardDrive { partition, start_lba, size_lba, signature, mbr_type } => {
+                data.extend_from_slice(&partition.to_le_bytes());
+                data.extend_from_slice(&start_lba.to_le_bytes());
+                data.extend_from_slice(&size_lba.to_le_bytes());
+                let signature_type = match signature {
+                    PartitionSignature::None => {
+                        data.extend_from_slice(&[0; 16]);
+                        0x00
+                    }
+                    PartitionSignature::Mbr(id) => {
+                        data.extend_from_slice(&id.to_le_bytes());
+                        data.extend_from_slice(&[0; 12]);
+                        0x01
+                    }
+                    PartitionSignature::Gpt(guid) => {
+                        data.extend(encode_guid(guid).with_context(|| format!("Invalid partition GUID {guid}"))?);
+                        0x02
+                    }
+                };
+                data.extend_from_slice(&[*mbr_type, signature_type]);
+                (MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP)
+            }
+            DevicePathNode::FilePath(path) => {
+                data.extend(utf16_encode(path));
+                data.extend_from_slice(&[0, 0]);
+                (MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP)
+            }
+            DevicePathNode::FvFile(guid) => {
+                data.extend(encode_guid(guid)?);
+                (MEDIA_DEVICE_PATH, MEDIA_PIWG_FW_FILE_DP)
+            }
+            DevicePathNode::Fv(guid) => {
+                data.extend(encode_guid(guid)?);
+                (MEDIA_DEVICE_PATH, MEDIA_PIWG_FW_VOL_DP)
+            }
+            DevicePathNode::EndInstance => (END_DEVICE_PATH, END_INSTANCE_DEVICE_PATH_SUBTYPE),
+            DevicePathNode::Unknown { node_type, sub_type, data: node_data } => {
+                data.extend_from_slice(node_data);
+                (*node_type, *sub_type)
+            }
+        };
+        Ok((node_type, sub_type, data))
+    }
+
+    /// Serializes the node, header included.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let (node_type, sub_type, data) = self.encode()?;
+        let length = u16::try_from(data.len() + 4).context("Device path node too long")?;
+        let mut node = vec![node_type, sub_type];
+        node.extend_from_slice(&length.to_le_bytes());
+        node.extend(data);
+        Ok(node)
+    }
+}
+
+impl fmt::Display for DevicePathNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DevicePathNode::Pci { device, function } => write!(f, "Pci({device:#x},{function:#x})"),
+            DevicePathNode::MemoryMapped { memory_type, start, end } => {
+                write!(f, "MemoryMapped({memory_type:#x},{start:#x},{end:#x})")
+            }
+            DevicePathNode::Vendor { node_type, guid, data } => {
+                let name = match *node_type {
+                    HARDWARE_DEVICE_PATH => "VenHw",
+                    MESSAGING_DEVICE_PATH => "VenMsg",
+                    _ => "VenMedia",
+                };
+                let guid = guid.to_lowercase();
+                if data.is_empty() {
+                    write!(f, "{name}({guid})")
+                } else {
+                    write!(f, "{name}({guid},{})", hex::encode(data))
+                }
+            }
+            DevicePathNode::Acpi { hid: PNP0A03, uid } => write!(f, "PciRoot({uid:#x})"),
+            DevicePathNode::Acpi { hid: PNP0A08, uid } => write!(f, "PcieRoot({uid:#x})"),
+            DevicePathNode::Acpi { hid, uid } if hid & 0xffff == PNP_EISA_VENDOR => match hid >> 16 {
+                0x0604 => write!(f, "Floppy({uid:#x})"),
+                0x0301 => write!(f, "Keyboard({uid:#x})"),
+                0x0501 => write!(f, "Serial({uid:#x})"),
+                0x0401 => write!(f, "ParallelPort({uid:#x})"),
+                id => write!(f, "Acpi(PNP{id:04x},{uid:#x})"),
+            },
+            DevicePathNode::Acpi { hid, uid } => write!(f, "Acpi({hid:#010x},{uid:#x})"),
+            DevicePathNode::Scsi { target, lun } => write!(f, "Scsi({target:#x},{lun:#x})"),
+            DevicePathNode::Sata { hba_port, port_multiplier_port, lun } => {
+                write!(f, "Sata({hba_port:#x},{port_multiplier_port:#x},{lun:#x})")
+            }
+            DevicePathNode::Nvme { namespace_id, eui64 } => {
+                let eui: Vec<String> = eui64.iter().rev().map(|b| format!("{b:02x}")).collect();
+                write!(f, "NVMe({namespace_id:#x},{})", eui.join("-"))
+            }
+            DevicePathNode::Mac { address, if_type } => {
+                let size = if *if_type <= IF_TYPE_ETHERNET { 6 } else { 32 };
+                write!(f, "MAC({},{if_type:#x})", hex::encode(&address[..size]))
+            }
+            DevicePathNode::Ipv4 { local, remote, protocol, static_address, gateway, subnet_mask, .. } => {
+                write!(
+                    f,
+                    "IPv4({},{},{},{}",
+                    format_ipv4(remote),
+                    format_protocol(*protocol),
+                    if *static_address { "Static" } else { "DHCP" },
+                    format_ipv4(local),
+                )?;
+                let gateway = gateway.unwrap_or_default();
+                let subnet_mask = subnet_mask.unwrap_or_default();
+                if gateway != [0; 4] || subnet_mask != [0; 4] {
+                    write!(f, ",{},{}", format_ipv4(&gateway), format_ipv4(&subnet_mask))?;
+                }
+                write!(f, ")")
+            }
+            DevicePathNode::Ipv6 { local, remote, protocol, address_origin, prefix_length, gateway, .. } => {
+                let origin = match address_origin {
+                    0 => "Static",
+                    1 => "StatelessAutoConfigure",
+                    _ => "StatefulAutoConfigure",
+                };
+                write!(
+                    f,
+                    "IPv6({},{},{},{}",
+                    format_ipv6(remote),
+                    format_protocol(*protocol),
+                    origin,
+                    format_ipv6(local),
+                )?;
+                let gateway = gateway.unwrap_or_default();
+                let prefix_length = prefix_length.unwrap_or_default();
+                if gateway != [0; 16] || prefix_length != 0 {
+                    write!(f, ",{},{}", format_ipv6(&gateway), prefix_length)?;
+                }
+                write!(f, ")")
+            }
+            DevicePathNode::Uri(uri) => write!(f, "Uri({uri})"),
+            DevicePathNode::HardDrive { partition, start_lba, size_lba, signature, .. } => match signature {
+                PartitionSignature::None => write!(f, "HD({partition},0,0,{start_lba:#x},{size_lba:#x})"),
+                PartitionSignature::Mbr(id) => write!(f, "HD({partition},MBR,{id:#010x},{start_lba:#x},{size_lba:#x})"),
+                PartitionSignature::Gpt(guid) => {
+                    write!(f, "HD({partition},GPT,{},{start_lba:#x},{size_lba:#x})", guid.to_lowercase())
+                }
+            },
+            DevicePathNode::FilePath(path) => write!(f, "{path}"),
+            DevicePathNode::FvFile(guid) => write!(f, "FvFile({})", guid.to_lowercase()),
+            DevicePathNode::Fv(guid) => write!(f, "Fv({})", guid.to_lowercase()),
+            DevicePathNode::EndInstance => write!(f, ","),
+            DevicePathNode::Unknown { node_type, sub_type, data } => {
+                write!(f, "Path({node_type},{sub_type},{})", hex::encode(data))
+            }
+        }
+    }
+}
+
+/// A device path, the nodes before its end node.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DevicePath {
+    pub nodes: Vec<DevicePathNode>,
+}
+
+impl DevicePath {
+    pub fn new(nodes: Vec<DevicePathNode>) -> Self {
+        DevicePath { nodes }
+    }
+
+    /// Parses a device path up to its end node, returning it with the number of bytes read.
+    pub fn parse(data: &[u8]) -> Result<(DevicePath, usize)> {
+        let mut nodes = Vec::new();
+        let mut offset = 0;
+        loop {
+            let header = data
+                .get(offset..offset + 4)
+                .with_context(|| format!("Device path truncated at {offset:#x}, without end node"))?;
+            let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+            if length < 4 || offset + length > data.len() {
+                bail!("Invalid length {length} of device path node at {offset:#x}");
+            }
+            let node_data = &data[offset + 4..offset + length];
+            offset += length;
+            if header[0] == END_DEVICE_PATH && header[1] == END_ENTIRE_DEVICE_PATH_SUBTYPE {
+                break;
+            }
+            nodes.push(DevicePathNode::decode(header[0], header[1], node_data)?);
+        }
+        Ok((DevicePath { nodes }, offset))
+    }
+
+    /// Serializes the device path, end node included.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        for node in &self.nodes {
+            data.extend(node.to_bytes()?);
+        }
+        data.extend_from_slice(&[END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH_SUBTYPE, 4, 0]);
+        Ok(data)
+    }
+}
+
+impl fmt::Display for DevicePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        for node in &self.nodes {
+            if *node == DevicePathNode::EndInstance {
+                write!(f, ",")?;
+                separator = "";
+                continue;
+            }
+            write!(f, "{separator}{node}")?;
+            separator = "/";
+        }
+        Ok(())
+    }
+}
+
+/// Parses a MAC address such as `52:54:00:12:34:56`.
+pub(crate) fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let bytes = mac
+        .split([':', '-'])
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .with_context(|| format!("Invalid MAC address {mac}"))?;
+    match bytes.try_into() {
+        Ok(address) => Ok(address),
+        Err(_) => bail!("Invalid MAC address {mac}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUID: &str = "8BE4DF61-93CA-11D2-AA0D-00E098032B8C";
+
+    /// A node with its header
+    fn node(node_type: u8, sub_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut node = vec![node_type, sub_type];
+        node.extend_from_slice(&(data.len() as u16 + 4).to_le_bytes());
+        node.extend_from_slice(data);
+        node
+    }
+
+    /// Parses a device path made of `nodes`, checks it serializes back to the
+    /// same bytes and returns it as text.
+    fn round_trip(nodes: &[Vec<u8>]) -> (DevicePath, String) {
+        let mut data = nodes.concat();
+        data.extend_from_slice(&[END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH_SUBTYPE, 4, 0]);
+        let (path, length) = DevicePath::parse(&data).unwrap();
+        assert_eq!(length, data.len());
+        assert_eq!(path.to_bytes().unwrap(), data);
+        let text = path.to_string();
+        (path, text)
+    }
+
+    fn text(nodes: &[Vec<u8>]) -> String {
+        round_trip(nodes).1
+    }
+
+    fn ipv4_data(gateway_and_mask: bool) -> Vec<u8> {
+        let mut data = vec![192, 168, 0, 2, 192, 168, 0, 1, 0x44, 0, 0x43, 0, 17, 0, 1];
+        if gateway_and_mask {
+            data.extend_from_slice(&[192, 168, 0, 254, 255, 255, 255, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn hardware_nodes() {
+        assert_eq!(text(&[node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0x2, 0x1f])]), "Pci(0x1f,0x2)");
+
+        let mut data = 11u32.to_le_bytes().to_vec();
+        data.extend_from_slice(&0xffc0_0000u64.to_le_bytes());
+        data.extend_from_slice(&0xffff_ffffu64.to_le_bytes());
+        assert_eq!(text(&[node(HARDWARE_DEVICE_PATH, HW_MEMMAP_DP, &data)]), "MemoryMapped(0xb,0xffc00000,0xffffffff)");
+    }
+
+    #[test]
+    fn vendor_nodes() {
+        let guid = encode_guid(GUID).unwrap();
+        let mut data = guid.clone();
+        data.extend_from_slice(&[0xab, 0x01]);
+        assert_eq!(
+            text(&[node(HARDWARE_DEVICE_PATH, HW_VENDOR_DP, &data)]),
+            "VenHw(8be4df61-93ca-11d2-aa0d-00e098032b8c,ab01)"
+        );
+        assert_eq!(
+            text(&[node(MESSAGING_DEVICE_PATH, MSG_VENDOR_DP, &guid)]),
+            "VenMsg(8be4df61-93ca-11d2-aa0d-00e098032b8c)"
+        );
+        assert_eq!(
+            text(&[node(MEDIA_DEVICE_PATH, MEDIA_VENDOR_DP, &guid)]),
+            "VenMedia(8be4df61-93ca-11d2-aa0d-00e098032b8c)"
+        );
+    }
+
+    #[test]
+    fn acpi_nodes() {
+        let acpi = |hid: u32, uid: u32| {
+            let mut data = hid.to_le_bytes().to_vec();
+            data.extend_from_slice(&uid.to_le_bytes());
+            text(&[node(ACPI_DEVICE_PATH, ACPI_DP, &data)])
+        };
+        assert_eq!(acpi(PNP0A03, 0), "PciRoot(0x0)");
+        assert_eq!(acpi(PNP0A08, 1), "PcieRoot(0x1)");
+        assert_eq!(acpi(0x050141d0, 0), "Serial(0x0)");
+        assert_eq!(acpi(0x0b0041d0, 2), "Acpi(PNP0b00,0x2)");
+        assert_eq!(acpi(0x12345678, 0), "Acpi(0x12345678,0x0)");
+    }
+
+    #[test]
+    fn storage_nodes() {
+        assert_eq!(text(&[node(MESSAGING_DEVICE_PATH, MSG_SCSI_DP, &[1, 0, 2, 0])]), "Scsi(0x1,0x2)");
+        assert_eq!(
+            text(&[node(MESSAGING_DEVICE_PATH, MSG_SATA_DP, &[0, 0, 0xff, 0xff, 0, 0])]),
+            "Sata(0x0,0xffff,0x0)"
+        );
+        assert_eq!(
+            text(&[node(MESSAGING_DEVICE_PATH, MSG_NVME_NAMESPACE_DP, &[1, 0, 0, 0, 0xab, 2, 3, 4, 5, 6, 7, 8])]),
+            "NVMe(0x1,08-07-06-05-04-03-02-ab)"
+        );
+    }
+
+    #[test]
+    fn hard_drive_nodes() {
+        let hd = |signature: &[u8], mbr_type: u8, signature_type: u8| {
+            let mut data = 1u32.to_le_bytes().to_vec();
+            data.extend_from_slice(&0x800u64.to_le_bytes());
+            data.extend_from_slice(&0x32000u64.to_le_bytes());
+            let mut padded = [0u8; 16];
+            padded[..signature.len()].copy_from_slice(signature);
+            data.extend_from_slice(&padded);
+            data.extend_from_slice(&[mbr_type, signature_type]);
+            node(MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP, &data)
+        };
+        assert_eq!(
+            text(&[hd(&encode_guid(GUID).unwrap(), 0x02, 0x02)]),
+            "HD(1,GPT,8be4df61-93ca-11d2-aa0d-00e098032b8c,0x800,0x32000)"
+        );
+        assert_eq!(text(&[hd(&[0x78, 0x56, 0x34, 0x12], 0x01, 0x01)]), "HD(1,MBR,0x12345678,0x800,0x32000)");
+
+        // A partition without signature keeps its partition table type
+        let (path, text) = round_trip(&[hd(&[], 0x02, 0x00)]);
+        assert_eq!(text, "HD(1,0,0,0x800,0x32000)");
+        assert!(matches!(path.nodes[0], DevicePathNode::HardDrive { mbr_type: 0x02, .. }));
+    }
+
+    #[test]
+    fn media_nodes() {
+        let mut path = utf16_encode("\\EFI\\BOOT\\BOOTX64.EFI");
+        path.extend_from_slice(&[0, 0]);
+        assert_eq!(text(&[node(MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP, &path)]), "\\EFI\\BOOT\\BOOTX64.EFI");
+
+        let guid = encode_guid(GUID).unwrap();
+        assert_eq!(
+            text(&[node(MEDIA_DEVICE_PATH, MEDIA_PIWG_FW_VOL_DP, &guid), node(MEDIA_DEVICE_PATH, MEDIA_PIWG_FW_FILE_DP, &guid)]),
+            "Fv(8be4df61-93ca-11d2-aa0d-00e098032b8c)/FvFile(8be4df61-93ca-11d2-aa0d-00e098032b8c)"
+        );
+    }
+
+    #[test]
+    fn network_nodes() {
+        let mut mac = [0u8; 33];
+        mac[..6].copy_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        mac[32] = IF_TYPE_ETHERNET;
+        assert_eq!(text(&[node(MESSAGING_DEVICE_PATH, MSG_MAC_ADDR_DP, &mac)]), "MAC(525400123456,0x1)");
+
+        assert_eq!(
+            text(&[node(MESSAGING_DEVICE_PATH, MSG_IPV4_DP, &ipv4_data(true))]),
+            "IPv4(192.168.0.1,UDP,Static,192.168.0.2,192.168.0.254,255.255.255.0)"
+        );
+        assert_eq!(
+            text(&[node(MESSAGING_DEVICE_PATH, MSG_URI_DP, b"http://192.168.0.1/boot.efi")]),
+            "Uri(http://192.168.0.1/boot.efi)"
+        );
+    }
+
+    #[test]
+    fn legacy_ip_nodes() {
+        // 19-byte IPv4 node, without gateway and subnet mask
+        let (path, text) = round_trip(&[node(MESSAGING_DEVICE_PATH, MSG_IPV4_DP, &ipv4_data(false))]);
+        assert_eq!(text, "IPv4(192.168.0.1,UDP,Static,192.168.0.2)");
+        assert!(matches!(path.nodes[0], DevicePathNode::Ipv4 { gateway: None, subnet_mask: None, .. }));
+
+        // 43-byte IPv6 node, without prefix length and gateway
+        let mut data = vec![0u8; 39];
+        data[15] = 2;
+        data[31] = 1;
+        data[36] = 6;
+        data[38] = 1;
+        let (path, text) = round_trip(&[node(MESSAGING_DEVICE_PATH, MSG_IPV6_DP, &data)]);
+        assert_eq!(
+            text,
+            "IPv6(0000:0000:0000:0000:0000:0000:0000:0001,TCP,StatelessAutoConfigure,0000:0000:0000:0000:0000:0000:0000:0002)"
+        );
+        assert!(matches!(path.nodes[0], DevicePathNode::Ipv6 { prefix_length: None, gateway: None, .. }));
+
+        // 60-byte IPv6 node
+        data.push(64);
+        data.extend_from_slice(&[0xfe, 0x80]);
+        data.extend_from_slice(&[0; 13]);
+        data.push(1);
+        let (_, text) = round_trip(&[node(MESSAGING_DEVICE_PATH, MSG_IPV6_DP, &data)]);
+        assert_eq!(
+            text,
+            "IPv6(0000:0000:0000:0000:0000:0000:0000:0001,TCP,StatelessAutoConfigure,0000:0000:0000:0000:0000:0000:0000:0002,fe80:0000:0000:0000:0000:0000:0000:0001,64)"
+        );
+    }
+
+    #[test]
+    fn pxe_nodes() {
+        let path = DevicePath::new(vec![
+            DevicePathNode::pci_root(0),
+            DevicePathNode::Pci { device: 3, function: 0 },
+            DevicePathNode::ethernet(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]),
+            DevicePathNode::ipv4_any(),
+        ]);
+        let data = path.to_bytes().unwrap();
+        assert_eq!(data.len(), 12 + 6 + 37 + 27 + 4);
+        assert_eq!(DevicePath::parse(&data).unwrap().0, path);
+        assert_eq!(
+            path.to_string(),
+            "PciRoot(0x0)/Pci(0x3,0x0)/MAC(525400123456,0x1)/IPv4(0.0.0.0,0x0,DHCP,0.0.0.0)"
+        );
+        assert_eq!(DevicePathNode::ipv6_any().to_bytes().unwrap().len(), 60);
+    }
+
+    #[test]
+    fn multi_instance_and_unknown_nodes() {
+        assert_eq!(
+            text(&[
+                node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 1]),
+                node(END_DEVICE_PATH, END_INSTANCE_DEVICE_PATH_SUBTYPE, &[]),
+                node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 2]),
+            ]),
+            "Pci(0x1,0x0),Pci(0x2,0x0)"
+        );
+
+        assert_eq!(text(&[node(0x05, 0x01, &[0xab, 0xcd])]), "Path(5,1,abcd)");
+    }
+
+    #[test]
+    fn non_canonical_nodes_are_kept() {
+        // A PCI node with a trailing byte and an MBR partition with a non-zero
+        // reserved signature byte only survive as unknown nodes
+        let (path, text) = round_trip(&[node(HARDWARE_DEVICE_PATH, HW_PCI_DP, &[0, 1, 0xff])]);
+        assert!(matches!(path.nodes[0], DevicePathNode::Unknown { .. }));
+        assert_eq!(text, "Path(1,1,0001ff)");
+
+        let mut data = vec![0u8; 38];
+        data[20] = 1;
+        data[30] = 0xff;
+        data[36] = 0x01;
+        data[37] = 0x01;
+        let (path, _) = round_trip(&[node(MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP, &data)]);
+        assert!(matches!(path.nodes[0], DevicePathNode::Unknown { .. }));
+    }
+}
@@ -0,0 +1,441 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Inspection of a firmware image: the TDVF metadata sections, the firmware
+//! volumes and their FFS files, including those of LZMA compressed volumes,
+//! and the version of the EDK2 build.
+
+use anyhow::{anyhow, bail, Result};
+use log::debug;
+use object::Object;
+use serde::Serialize;
+use serde_human_bytes as hex_bytes;
+
+use crate::cfv::format_guid;
+use crate::measure_sha384;
+use crate::num::read_le;
+use crate::tdvf::{Tdvf, ATTRIBUTE_MR_EXTEND, ATTRIBUTE_PAGE_AUG};
+
+const FV_SIGNATURE: &[u8; 4] = b"_FVH";
+const FV_SIGNATURE_OFFSET: usize = 40;
+const FV_SCAN_ALIGNMENT: usize = 0x1000;
+
+/// File systems laid out as FFS files; others, like the NV variable store, are not walked
+const FFS_FILE_SYSTEMS: &[&str] = &[
+    "8C8CE578-8A3D-4F1C-9935-896185C32DD3",
+    "5473C07A-3DCB-4DCA-BD6F-1E9689E7349A",
+];
+
+const FFS_HEADER_SIZE: usize = 24;
+const FFS_LARGE_HEADER_SIZE: usize = 32;
+const FFS_ATTRIB_LARGE_FILE: u8 = 0x01;
+const FFS_TYPE_PAD: u8 = 0xf0;
+
+const SECTION_COMPRESSION: u8 = 0x01;
+const SECTION_GUID_DEFINED: u8 = 0x02;
+const SECTION_VERSION: u8 = 0x14;
+const SECTION_USER_INTERFACE: u8 = 0x15;
+const SECTION_FIRMWARE_VOLUME_IMAGE: u8 = 0x17;
+const SECTION_PE32: u8 = 0x10;
+
+const LZMA_CUSTOM_DECOMPRESS_GUID: &str = "EE4E5898-3914-4259-9D6E-DC7BD79403CF";
+
+/// Vendor string of the default SMBIOS Type 0 table compiled into OvmfPkg
+/// SmbiosPlatformDxe, which identifies its image.
+const OVMF_SMBIOS_VENDOR: &[u8] = b"EFI Development Kit II / OVMF\0";
+
+const UNKNOWN: &str = "unknown";
+
+/// A section declared by the TDVF metadata.
+#[derive(Debug, Clone, Serialize)]
+pub struct TdvfSectionInfo {
+    pub section_type: String,
+    pub data_offset: u32,
+    pub raw_data_size: u32,
+    pub memory_address: u64,
+    pub memory_data_size: u64,
+    pub attributes: u32,
+}
+
+impl TdvfSectionInfo {
+    /// Formats the attributes, e.g. `MR_EXTEND`.
+    pub fn attributes_string(&self) -> String {
+        let mut names = Vec::new();
+        if self.attributes & ATTRIBUTE_MR_EXTEND != 0 {
+            names.push("MR_EXTEND");
+        }
+        if self.attributes & ATTRIBUTE_PAGE_AUG != 0 {
+            names.push("PAGE_AUG");
+        }
+        names.join("|")
+    }
+}
+
+/// A file of a firmware volume.
+#[derive(Debug, Clone, Serialize)]
+pub struct FfsFile {
+    /// Offset in the firmware image, or in the decompressed data for files of nested volumes
+    pub offset: usize,
+    pub size: usize,
+    pub guid: String,
+    pub file_type: String,
+    pub ui_name: Option<String>,
+    pub version: Option<String>,
+    /// SHA384 of the file, header included
+    #[serde(with = "hex_bytes")]
+    pub digest: Vec<u8>,
+    /// Volumes embedded in the file, decompressed if needed
+    pub volumes: Vec<FirmwareVolume>,
+    /// Path of the debug information of a PE32 image, e.g. its build directory
+    pub debug_path: Option<String>,
+}
+
+/// A firmware volume and its files.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareVolume {
+    /// Offset in the firmware image, or in the decompressed data for nested volumes
+    pub offset: usize,
+    pub length: usize,
+    pub file_system: String,
+    pub files: Vec<FfsFile>,
+}
+
+impl FirmwareVolume {
+    /// Iterates over the files of the volume and of its nested volumes.
+    pub fn all_files(&self) -> Box<dyn Iterator<Item = &FfsFile> + '_> {
+        Box::new(self.files.iter().flat_map(|f| {
+            std::iter::once(f).chain(f.volumes.iter().flat_map(FirmwareVolume::all_files))
+        }))
+    }
+}
+
+/// Build identifier of OVMF, from its PcdFirmwareVersionString and
+/// PcdFirmwareReleaseDateString, "unknown" when the build does not set them.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareVersion {
+    pub version: String,
+    pub release_date: String,
+}
+
+impl Default for FirmwareVersion {
+    fn default() -> Self {
+        Self { version: UNKNOWN.to_string(), release_date: UNKNOWN.to_string() }
+    }
+}
+
+/// Everything known about a firmware image.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareReport {
+    pub size: usize,
+    pub firmware_version: FirmwareVersion,
+    /// Debug path of the first PE32 image, which names the build target and toolchain
+    pub build_path: Option<String>,
+    pub sections: Vec<TdvfSectionInfo>,
+    pub volumes: Vec<FirmwareVolume>,
+}
+
+impl FirmwareReport {
+    /// Iterates over all files of all volumes.
+    pub fn all_files(&self) -> impl Iterator<Item = &FfsFile> {
+        self.volumes.iter().flat_map(FirmwareVolume::all_files)
+    }
+}
+
+fn file_type_name(file_type: u8) -> String {
+    match file_type {
+        0x01 => "RAW",
+        0x02 => "FREEFORM",
+        0x03 => "SECURITY_CORE",
+        0x04 => "PEI_CORE",
+        0x05 => "DXE_CORE",
+        0x06 => "PEIM",
+        0x07 => "DRIVER",
+        0x08 => "COMBINED_PEIM_DRIVER",
+        0x09 => "APPLICATION",
+        0x0a => "MM",
+        0x0b => "FIRMWARE_VOLUME_IMAGE",
+        0x0c => "COMBINED_MM_DXE",
+        0x0d => "MM_CORE",
+        0x0e => "MM_STANDALONE",
+        0x0f => "MM_CORE_STANDALONE",
+        FFS_TYPE_PAD => "PAD",
+        other => return format!("{other:#04x}"),
+    }
+    .to_string()
+}
+
+/// Reads a 24-bit little-endian size.
+fn read_u24(data: &[u8], offset: usize) -> Result<usize> {
+    let bytes = data
+        .get(offset..offset + 3)
+        .ok_or_else(|| anyhow!("Truncated size at {offset:#x}"))?;
+    Ok(bytes[0] as usize | (bytes[1] as usize) << 8 | (bytes[2] as usize) << 16)
+}
+
+/// Decodes a NUL-terminated UTF-16 string.
+fn read_utf16(data: &[u8]) -> String {
+    let chars: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+/// Whether a firmware volume header starts at `offset`.
+fn is_volume(data: &[u8], offset: usize) -> bool {
+    data.get(offset + FV_SIGNATURE_OFFSET..offset + FV_SIGNATURE_OFFSET + 4) == Some(FV_SIGNATURE)
+}
+
+/// Walks volumes, files and sections, collecting what is found along the way.
+#[derive(Default)]
+struct Inspector {
+    firmware_version: Option<FirmwareVersion>,
+}
+
+impl Inspector {
+    /// Parses a firmware volume starting at `offset` in `data`.
+    fn parse_volume(&mut self, data: &[u8], offset: usize, base: usize) -> Result<FirmwareVolume> {
+        let length = read_le::<u64>(data, offset + 32, "FV length")? as usize;
+        let fv = data
+            .get(offset..offset + length)
+            .ok_or_else(|| anyhow!("Firmware volume at {offset:#x} extends beyond its container"))?;
+        let file_system = format_guid(&fv[16..32]);
+        let header_length = read_le::<u16>(fv, 48, "FV header length")? as usize;
+        let ext_header_offset = read_le::<u16>(fv, 52, "FV extended header offset")? as usize;
+
+        // Files follow the extended header, if any, aligned to 8 bytes
+        let mut pos = if ext_header_offset != 0 {
+            ext_header_offset + read_le::<u32>(fv, ext_header_offset + 16, "FV extended header size")? as usize
+        } else {
+            header_length
+        };
+
+        let mut files = Vec::new();
+        if !FFS_FILE_SYSTEMS.contains(&file_system.as_str()) {
+            return Ok(FirmwareVolume { offset: base + offset, length, file_system, files });
+        }
+        loop {
+            pos = pos.next_multiple_of(8);
+            if pos + FFS_HEADER_SIZE > fv.len() || fv[pos..pos + FFS_HEADER_SIZE].iter().all(|b| *b == 0xff) {
+                break;
+            }
+            let attributes = fv[pos + 19];
+            let (header_size, size) = if attributes & FFS_ATTRIB_LARGE_FILE != 0 {
+                (FFS_LARGE_HEADER_SIZE, read_le::<u64>(fv, pos + 24, "FFS extended size")? as usize)
+            } else {
+                (FFS_HEADER_SIZE, read_u24(fv, pos + 20)?)
+            };
+            if size < header_size || pos + size > fv.len() {
+                bail!("Invalid FFS file size at {:#x}", base + offset + pos);
+            }
+
+            let file_type = fv[pos + 18];
+            let file = &fv[pos..pos + size];
+            let mut ffs = FfsFile {
+                offset: base + offset + pos,
+                size,
+                guid: format_guid(&file[0..16]),
+                file_type: file_type_name(file_type),
+                ui_name: None,
+                version: None,
+                digest: measure_sha384(file),
+                volumes: Vec::new(),
+                debug_path: None,
+            };
+            if file_type != FFS_TYPE_PAD {
+                self.parse_sections(&file[header_size..], &mut ffs)?;
+            }
+            files.push(ffs);
+            pos += size;
+        }
+
+        Ok(FirmwareVolume { offset: base + offset, length, file_system, files })
+    }
+
+    /// Walks the sections of a file, or of an encapsulation section.
+    fn parse_sections(&mut self, data: &[u8], file: &mut FfsFile) -> Result<()> {
+        let mut pos = 0;
+        while pos + 4 <= data.len() {
+            let mut size = read_u24(data, pos)?;
+            let section_type = data[pos + 3];
+            let mut header_size = 4;
+            if size == 0xffffff {
+                size = read_le::<u32>(data, pos + 4, "section extended size")? as usize;
+                header_size = 8;
+            }
+            if size < header_size || pos + size > data.len() {
+                bail!("Invalid section size in file {}", file.guid);
+            }
+            let section = &data[pos..pos + size];
+            let body = &section[header_size..];
+
+            match section_type {
+                SECTION_USER_INTERFACE => file.ui_name = Some(read_utf16(body)),
+                SECTION_VERSION if body.len() >= 2 => file.version = Some(read_utf16(&body[2..])),
+                SECTION_FIRMWARE_VOLUME_IMAGE if is_volume(body, 0) => {
+                    file.volumes.push(self.parse_volume(body, 0, 0)?);
+                }
+                SECTION_PE32 => {
+                    if self.firmware_version.is_none() && contains(body, OVMF_SMBIOS_VENDOR) {
+                        self.firmware_version = Some(find_firmware_version(body));
+                    }
+                    if let Ok(pe) = object::File::parse(body) {
+                        if let Ok(Some(pdb)) = pe.pdb_info() {
+                            file.debug_path = Some(String::from_utf8_lossy(pdb.path()).into_owned());
+                        }
+                    }
+                }
+                SECTION_GUID_DEFINED if body.len() >= 20 => {
+                    let guid = format_guid(&body[0..16]);
+                    let data_offset = read_le::<u16>(body, 16, "GUID defined section data offset")? as usize;
+                    let payload = section
+                        .get(data_offset..)
+                        .ok_or_else(|| anyhow!("Invalid GUID defined section in file {}", file.guid))?;
+                    if guid == LZMA_CUSTOM_DECOMPRESS_GUID {
+                        let mut decompressed = Vec::new();
+                        lzma_rs::lzma_decompress(&mut &payload[..], &mut decompressed)
+                            .map_err(|e| anyhow!("Failed to decompress LZMA section of {}: {e:?}", file.guid))?;
+                        self.parse_sections(&decompressed, file)?;
+                    } else {
+                        debug!("Skipping GUID defined section {guid} of file {}", file.guid);
+                    }
+                }
+                SECTION_COMPRESSION => debug!("Skipping EFI compressed section of file {}", file.guid),
+                _ => {}
+            }
+            pos = (pos + size).next_multiple_of(4);
+        }
+        Ok(())
+    }
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    data.windows(pattern.len()).any(|w| w == pattern)
+}
+
+/// Finds the firmware version strings in the SmbiosPlatformDxe image, which
+/// builds the Type 0 table from them when they are set. They are fixed at
+/// build time, as NUL-terminated UTF-16 strings one after the other and
+/// aligned to at most 16 bytes, so the release date, formatted `MM/DD/YYYY`,
+/// is looked for with the version string just before it.
+fn find_firmware_version(image: &[u8]) -> FirmwareVersion {
+    let units: Vec<u16> = image.chunks_exact(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+
+    // Runs of printable characters between NULs, as (start, end) in units
+    let mut strings = Vec::new();
+    let mut start = 0;
+    for (i, unit) in units.iter().enumerate() {
+        if !(0x20..0x7f).contains(unit) {
+            if *unit == 0 && i > start && start > 0 && units[start - 1] == 0 {
+                strings.push((start, i));
+            }
+            start = i + 1;
+        }
+    }
+
+    let text = |(start, end): (usize, usize)| String::from_utf16_lossy(&units[start..end]);
+    let is_date = |date: &str| {
+        date.len() == 10
+            && date.char_indices().all(|(i, c)| if i == 2 || i == 5 { c == '/' } else { c.is_ascii_digit() })
+    };
+    for (index, &date) in strings.iter().enumerate() {
+        if !is_date(&text(date)) {
+            continue;
+        }
+        // The date follows the version right away or at the next 16-byte boundary,
+        // any other gap holds an empty version string
+        let version = index.checked_sub(1).map(|i| strings[i]).filter(|&(_, end)| {
+            let next = (end + 1) * 2;
+            date.0 * 2 == next || date.0 * 2 == next.next_multiple_of(16)
+        });
+        return FirmwareVersion {
+            version: version.map_or_else(|| UNKNOWN.to_string(), text),
+            release_date: text(date),
+        };
+    }
+    FirmwareVersion::default()
+}
+
+/// Inspects a firmware image: TDVF metadata sections, firmware volumes,
+/// FFS files and version strings.
+pub fn inspect_firmware(fw: &[u8]) -> Result<FirmwareReport> {
+    let tdvf = Tdvf::parse(fw)?;
+    let sections = tdvf
+        .sections()
+        .iter()
+        .map(|s| {
+            Ok(TdvfSectionInfo {
+                section_type: s.type_name()?.to_string(),
+                data_offset: s.data_offset,
+                raw_data_size: s.raw_data_size,
+                memory_address: s.memory_address,
+                memory_data_size: s.memory_data_size,
+                attributes: s.attributes,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // Volumes are aligned to pages in the image
+    let mut inspector = Inspector::default();
+    let mut volumes = Vec::new();
+    let mut offset = 0;
+    while offset < fw.len() {
+        if is_volume(fw, offset) {
+            let volume = inspector.parse_volume(fw, offset, 0)?;
+            offset += volume.length.max(FV_SCAN_ALIGNMENT).next_multiple_of(FV_SCAN_ALIGNMENT);
+            volumes.push(volume);
+        } else {
+            offset += FV_SCAN_ALIGNMENT;
+        }
+    }
+
+    let build_path = volumes
+        .iter()
+        .flat_map(FirmwareVolume::all_files)
+        .find_map(|f| f.debug_path.clone());
+
+    Ok(FirmwareReport {
+        size: fw.len(),
+        firmware_version: inspector.firmware_version.unwrap_or_default(),
+        build_path,
+        sections,
+        volumes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::utf16_encode;
+
+    /// An image with the SMBIOS defaults and the given UTF-16 strings, each NUL-terminated and aligned to `align`.
+    fn image(align: usize, strings: &[&str]) -> Vec<u8> {
+        let mut image = [b"\x4d\x5a\x90\0".as_slice(), OVMF_SMBIOS_VENDOR, b"0.0.0\x0002/06/2015\0"].concat();
+        for string in strings {
+            image.resize(image.len().next_multiple_of(align), 0);
+            image.extend(utf16_encode(string));
+            image.extend([0, 0]);
+        }
+        image.extend([0xcc; 6]);
+        image
+    }
+
+    #[test]
+    fn firmware_version() {
+        for align in [2, 16] {
+            let version = find_firmware_version(&image(align, &["EDK II", "2024.02-3+tdx1.0", "05/21/2024"]));
+            assert_eq!(version.version, "2024.02-3+tdx1.0");
+            assert_eq!(version.release_date, "05/21/2024");
+
+            // An empty PcdFirmwareVersionString
+            let version = find_firmware_version(&image(align, &["EDK II", "", "05/21/2024"]));
+            assert_eq!((version.version.as_str(), version.release_date.as_str()), ("unknown", "05/21/2024"));
+        }
+
+        // The ASCII defaults are not a version
+        let version = find_firmware_version(&image(2, &["EDK II"]));
+        assert_eq!((version.version.as_str(), version.release_date.as_str()), ("unknown", "unknown"));
+    }
+}
@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Comparison of two firmware images, page by page in the order MRTD measures
+//! them, to explain why their MRTD or RTMR[0] differ.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_human_bytes as hex_bytes;
+use std::collections::BTreeMap;
+
+use crate::fv::{inspect_firmware, FfsFile, FirmwareVolume};
+use crate::measure_sha384;
+use crate::tdvf::{
+    Tdvf, TdvfSection, ATTRIBUTE_MR_EXTEND, ATTRIBUTE_PAGE_AUG, PAGE_SIZE, TDVF_SECTION_TD_CFV,
+};
+use crate::Vmm;
+
+/// Memory size the TD HOBs of both images are compared at
+const REFERENCE_MEMORY_SIZE: u64 = 2 << 30;
+
+/// A range of guest physical addresses, end excluded.
+#[derive(Debug, Clone, Serialize)]
+pub struct GpaRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Differences of a TDVF section between two images.
+#[derive(Debug, Clone, Serialize)]
+pub struct SectionDiff {
+    pub section_type: String,
+    /// GPA of the section in the old and new image, `None` if absent
+    pub old_address: Option<u64>,
+    pub new_address: Option<u64>,
+    /// Whether the GPA, size or attributes of the section changed
+    pub layout_changed: bool,
+    /// Pages whose content differs, merged into ranges
+    pub ranges: Vec<GpaRange>,
+    pub affects_mrtd: bool,
+    pub affects_rtmr0: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileChange {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// An FFS file that differs between two images.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileDiff {
+    pub guid: String,
+    pub ui_name: Option<String>,
+    pub change: FileChange,
+    /// TDVF section holding the file, e.g. `BFV`
+    pub section: Option<String>,
+}
+
+/// Everything that differs between two firmware images.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareDiff {
+    #[serde(with = "hex_bytes")]
+    pub old_mrtd: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub new_mrtd: Vec<u8>,
+    /// SHA384 of the CFV, the event RTMR[0] gets for it
+    #[serde(with = "hex_bytes")]
+    pub old_cfv_digest: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub new_cfv_digest: Vec<u8>,
+    /// SHA384 of the TD HOB QEMU builds for a 2 GiB TD, the first event of RTMR[0]
+    #[serde(with = "hex_bytes")]
+    pub old_td_hob_digest: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    pub new_td_hob_digest: Vec<u8>,
+    pub affects_mrtd: bool,
+    pub affects_rtmr0: bool,
+    pub sections: Vec<SectionDiff>,
+    pub files: Vec<FileDiff>,
+}
+
+/// Returns each section with its number among the sections of the same type,
+/// so that sections of both images can be paired.
+fn keyed_sections<'t>(tdvf: &'t Tdvf) -> Vec<((u32, usize), &'t TdvfSection)> {
+    let mut counts = BTreeMap::new();
+    tdvf.sections()
+        .iter()
+        .map(|s| {
+            let n = counts.entry(s.sec_type).or_insert(0);
+            *n += 1;
+            ((s.sec_type, *n), s)
+        })
+        .collect()
+}
+
+/// Whether the content of the section is measured into MRTD or RTMR[0].
+fn content_measured(s: &TdvfSection) -> (bool, bool) {
+    (s.attributes & ATTRIBUTE_MR_EXTEND != 0, s.sec_type == TDVF_SECTION_TD_CFV)
+}
+
+/// Compares a section of both images; `td_hob_changed` tells whether the
+/// layout of the sections changes the TD HOB.
+fn diff_section(
+    old: &Tdvf,
+    new: &Tdvf,
+    old_section: Option<&TdvfSection>,
+    new_section: Option<&TdvfSection>,
+    td_hob_changed: bool,
+) -> Result<Option<SectionDiff>> {
+    let either = old_section.or(new_section).context("No section to compare")?;
+    let layout_changed = match (old_section, new_section) {
+        (Some(o), Some(n)) => {
+            o.memory_address != n.memory_address
+                || o.memory_data_size != n.memory_data_size
+                || o.attributes != n.attributes
+        }
+        _ => true,
+    };
+
+    // Walk the pages as MRTD does, through the GPAs of the new image
+    let mut ranges: Vec<GpaRange> = Vec::new();
+    let reference = new_section.unwrap_or(either);
+    for page in 0..reference.memory_data_size / PAGE_SIZE {
+        let differs = match (old_section, new_section) {
+            (Some(o), Some(n)) if page < o.memory_data_size / PAGE_SIZE => {
+                old.page_data(o, page) != new.page_data(n, page)
+            }
+            _ => true,
+        };
+        if !differs {
+            continue;
+        }
+        let start = reference.memory_address + page * PAGE_SIZE;
+        match ranges.last_mut() {
+            Some(range) if range.end == start => range.end += PAGE_SIZE,
+            _ => ranges.push(GpaRange { start, end: start + PAGE_SIZE }),
+        }
+    }
+
+    if !layout_changed && ranges.is_empty() {
+        return Ok(None);
+    }
+
+    let (old_mr_extend, old_cfv) = old_section.map(content_measured).unwrap_or_default();
+    let (new_mr_extend, new_cfv) = new_section.map(content_measured).unwrap_or_default();
+    let content_changed = !ranges.is_empty();
+    // Pages that are not augmented are added with their GPA, which MRTD records
+    let page_add = |s: Option<&TdvfSection>| s.is_some_and(|s| s.attributes & ATTRIBUTE_PAGE_AUG == 0);
+    let affects_mrtd = (layout_changed && (page_add(old_section) || page_add(new_section)))
+        || (content_changed && (old_mr_extend || new_mr_extend));
+    // The TD HOB accepts the memory of the sections, which a layout change moves
+    let affects_rtmr0 = (content_changed && (old_cfv || new_cfv)) || (layout_changed && td_hob_changed);
+
+    Ok(Some(SectionDiff {
+        section_type: either.type_name()?.to_string(),
+        old_address: old_section.map(|s| s.memory_address),
+        new_address: new_section.map(|s| s.memory_address),
+        layout_changed,
+        ranges,
+        affects_mrtd,
+        affects_rtmr0,
+    }))
+}
+
+/// Collects all files of the volumes, with the image offset of the top-level
+/// file holding them, as nested volumes are decompressed.
+fn collect_files<'r>(volumes: &'r [FirmwareVolume], top: Option<usize>, out: &mut Vec<(&'r FfsFile, usize)>) {
+    for volume in volumes {
+        for file in &volume.files {
+            let offset = top.unwrap_or(file.offset);
+            out.push((file, offset));
+            collect_files(&file.volumes, Some(offset), out);
+        }
+    }
+}
+
+/// Indexes files by GUID and UI name, numbering files which share both.
+fn keyed_files(volumes: &[FirmwareVolume]) -> BTreeMap<(String, Option<String>, usize), (&FfsFile, usize)> {
+    let mut files = Vec::new();
+    collect_files(volumes, None, &mut files);
+    let mut keyed = BTreeMap::new();
+    for (file, offset) in files {
+        let mut n = 0;
+        while keyed.contains_key(&(file.guid.clone(), file.ui_name.clone(), n)) {
+            n += 1;
+        }
+        keyed.insert((file.guid.clone(), file.ui_name.clone(), n), (file, offset));
+    }
+    keyed
+}
+
+/// Returns the name of the TDVF section holding the image offset.
+fn section_at(tdvf: &Tdvf, offset: usize) -> Option<String> {
+    tdvf.sections()
+        .iter()
+        .find(|s| (s.data_offset as usize..(s.data_offset + s.raw_data_size) as usize).contains(&offset))
+        .and_then(|s| s.type_name().ok())
+        .map(str::to_string)
+}
+
+/// Compares two firmware images and reports the TDVF sections, GPA ranges and
+/// FFS files that differ, and whether MRTD and RTMR[0] are affected.
+pub fn diff_firmware(old_fw: &[u8], new_fw: &[u8]) -> Result<FirmwareDiff> {
+    let old = Tdvf::parse(old_fw).context("Failed to parse old firmware")?;
+    let new = Tdvf::parse(new_fw).context("Failed to parse new firmware")?;
+
+    // QEMU accepts the TD_HOB, TempMem and PermMem sections in the TD HOB and
+    // Cloud Hypervisor every section, which shows at any memory size
+    let td_hob_digest = |tdvf: &Tdvf, vmm| -> Result<Vec<u8>> {
+        Ok(tdvf.build_td_hob(vmm, REFERENCE_MEMORY_SIZE, &[])?.digest())
+    };
+    let (old_td_hob_digest, new_td_hob_digest) = (td_hob_digest(&old, Vmm::Qemu)?, td_hob_digest(&new, Vmm::Qemu)?);
+    let td_hob_changed = old_td_hob_digest != new_td_hob_digest
+        || td_hob_digest(&old, Vmm::CloudHypervisor)? != td_hob_digest(&new, Vmm::CloudHypervisor)?;
+
+    let old_sections: BTreeMap<_, _> = keyed_sections(&old).into_iter().collect();
+    let new_sections = keyed_sections(&new);
+    let mut sections = Vec::new();
+    for (key, new_section) in &new_sections {
+        let old_section = old_sections.get(key).copied();
+        sections.extend(diff_section(&old, &new, old_section, Some(new_section), td_hob_changed)?);
+    }
+    for (key, old_section) in &old_sections {
+        if !new_sections.iter().any(|(k, _)| k == key) {
+            sections.extend(diff_section(&old, &new, Some(old_section), None, td_hob_changed)?);
+        }
+    }
+
+    let old_report = inspect_firmware(old_fw).context("Failed to inspect old firmware")?;
+    let new_report = inspect_firmware(new_fw).context("Failed to inspect new firmware")?;
+    let old_files = keyed_files(&old_report.volumes);
+    let new_files = keyed_files(&new_report.volumes);
+    let mut files = Vec::new();
+    for (key, (file, offset)) in &new_files {
+        let change = match old_files.get(key) {
+            None => FileChange::Added,
+            Some((old_file, _)) if old_file.digest != file.digest => FileChange::Modified,
+            Some(_) => continue,
+        };
+        files.push(FileDiff {
+            guid: file.guid.clone(),
+            ui_name: file.ui_name.clone(),
+            change,
+            section: section_at(&new, *offset),
+        });
+    }
+    for (key, (file, offset)) in &old_files {
+        if !new_files.contains_key(key) {
+            files.push(FileDiff {
+                guid: file.guid.clone(),
+                ui_name: file.ui_name.clone(),
+                change: FileChange::Removed,
+                section: section_at(&old, *offset),
+            });
+        }
+    }
+
+    // The firmware reaches RTMR[0] through the CFV and its variables, and through
+    // the TD HOB when its memory layout changes
+    let cfv_digest = |tdvf: &Tdvf| tdvf.cfv().map(measure_sha384).unwrap_or_default();
+    let (old_mrtd, new_mrtd) = (old.mrtd()?, new.mrtd()?);
+    let (old_cfv_digest, new_cfv_digest) = (cfv_digest(&old), cfv_digest(&new));
+    Ok(FirmwareDiff {
+        affects_mrtd: old_mrtd != new_mrtd,
+        affects_rtmr0: old_cfv_digest != new_cfv_digest || td_hob_changed,
+        old_mrtd,
+        new_mrtd,
+        old_cfv_digest,
+        new_cfv_digest,
+        old_td_hob_digest,
+        new_td_hob_digest,
+        sections,
+        files,
+    })
+}
@@ -0,0 +1,1324 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! A small GRUB script interpreter that replays grub.cfg for the selected menu
+//! entry and records the events a measuring GRUB build logs through the EFI CC
+//! measurement protocol.
+//!
+//! GRUB measures every executed command line (after variable expansion) as a
+//! `grub_cmd:` string, the kernel command line as a `kernel_cmdline:` string
+//! and the content of every file it opens through the verifier framework.
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::util::{concat_initrds, measure_sha384};
+
+/// Maximum nesting of `source`/`configfile`/function calls before giving up.
+const MAX_DEPTH: usize = 32;
+/// Maximum iterations of a `while`/`until` loop before giving up.
+const MAX_LOOP_ITERATIONS: usize = 1024;
+
+/// An event logged by GRUB, in the order it is logged.
+#[derive(Debug, Clone)]
+pub(crate) enum GrubEvent {
+    /// `grub_cmd:` event, the digest is taken over the expanded command string.
+    Command(String),
+    /// `kernel_cmdline:` event logged by the `linux` command.
+    KernelCmdline(String),
+    /// A file read through the verifier framework.
+    File { path: String, digest: Vec<u8> },
+}
+
+impl GrubEvent {
+    /// Returns the digest extended into the RTMR for this event.
+    pub(crate) fn digest(&self) -> Vec<u8> {
+        match self {
+            GrubEvent::Command(s) | GrubEvent::KernelCmdline(s) => measure_sha384(s.as_bytes()),
+            GrubEvent::File { digest, .. } => digest.clone(),
+        }
+    }
+
+    /// Returns the event description as GRUB logs it.
+    pub(crate) fn description(&self) -> String {
+        match self {
+            GrubEvent::Command(s) => format!("grub_cmd: {s}"),
+            GrubEvent::KernelCmdline(s) => format!("kernel_cmdline: {s}"),
+            GrubEvent::File { path, .. } => path.clone(),
+        }
+    }
+}
+
+/// Access to the files GRUB can see while executing its configuration.
+pub(crate) trait GrubFs {
+    /// Reads a file, `path` is a GRUB path with the device already resolved.
+    fn read(&self, device: &str, path: &str) -> Result<Vec<u8>>;
+    /// Returns whether a regular file exists.
+    fn is_file(&self, device: &str, path: &str) -> Result<bool>;
+    /// Returns whether a directory exists.
+    fn is_dir(&self, device: &str, path: &str) -> Result<bool>;
+}
+
+/// Outcome of interpreting grub.cfg up to the point where GRUB boots the kernel.
+#[derive(Debug, Default)]
+pub(crate) struct GrubBoot {
+    pub events: Vec<GrubEvent>,
+    /// Kernel path given to the `linux` command.
+    pub kernel: Option<String>,
+    /// Kernel image loaded by the `linux` command.
+    pub kernel_data: Vec<u8>,
+    /// Kernel command line built by the `linux` command, without `BOOT_IMAGE=`.
+    pub cmdline: Option<String>,
+    /// Initrd images in the order given to the `initrd` command.
+    pub initrds: Vec<Vec<u8>>,
+}
+
+impl GrubBoot {
+    /// Returns the initrd contents as the kernel sees them.
+    pub(crate) fn initrd_data(&self) -> Vec<u8> {
+        concat_initrds(&self.initrds)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Lit(String),
+    Var(String),
+    QuotedVar(String),
+}
+
+#[derive(Debug, Clone, Default)]
+struct Word {
+    parts: Vec<Part>,
+    quoted: bool,
+}
+
+impl Word {
+    /// Returns the word as a keyword if it is a single unquoted literal.
+    fn keyword(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [Part::Lit(s)] if !self.quoted => Some(s.as_str()),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Word(Word),
+    Separator,
+    LBrace,
+    RBrace,
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Cmd(Vec<Word>),
+    If {
+        branches: Vec<(Vec<Stmt>, Vec<Stmt>)>,
+        otherwise: Vec<Stmt>,
+    },
+    For {
+        var: String,
+        items: Vec<Word>,
+        body: Vec<Stmt>,
+    },
+    While {
+        cond: Vec<Stmt>,
+        body: Vec<Stmt>,
+        until: bool,
+    },
+    Function {
+        name: String,
+        body: Vec<Stmt>,
+    },
+    Menu {
+        args: Vec<Word>,
+        body: Vec<Stmt>,
+        submenu: bool,
+    },
+}
+
+fn is_var_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits a GRUB script into tokens.
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut word = Word::default();
+    let mut lit = String::new();
+    let mut in_word = false;
+    let mut i = 0;
+
+    fn flush_lit(word: &mut Word, lit: &mut String) {
+        if !lit.is_empty() {
+            word.parts.push(Part::Lit(std::mem::take(lit)));
+        }
+    }
+
+    fn read_var(chars: &[char], i: &mut usize) -> Result<String> {
+        // `*i` points right after the `$`
+        if chars.get(*i) == Some(&'{') {
+            let start = *i + 1;
+            let end = chars[start..]
+                .iter()
+                .position(|&c| c == '}')
+                .context("Unterminated variable reference in GRUB script")?;
+            *i = start + end + 1;
+            return Ok(chars[start..start + end].iter().collect());
+        }
+        match chars.get(*i) {
+            Some(&c) if c == '?' || c == '#' || c == '@' || c == '*' => {
+                *i += 1;
+                Ok(c.to_string())
+            }
+            _ => {
+                let start = *i;
+                while *i < chars.len() && is_var_char(chars[*i]) {
+                    *i += 1;
+                }
+                Ok(chars[start..*i].iter().collect())
+            }
+        }
+    }
+
+    macro_rules! end_word {
+        () => {
+            if in_word {
+                flush_lit(&mut word, &mut lit);
+                tokens.push(Token::Word(std::mem::take(&mut word)));
+                in_word = false;
+            }
+        };
+    }
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\r' => {
+                end_word!();
+                i += 1;
+            }
+            '\n' | ';' => {
+                end_word!();
+                tokens.push(Token::Separator);
+                i += 1;
+            }
+            '#' if !in_word => {
+                while i < chars.len() && chars[i] != '\n' {
+                    i += 1;
+                }
+            }
+            '{' | '}' if !in_word => {
+                tokens.push(if c == '{' { Token::LBrace } else { Token::RBrace });
+                i += 1;
+            }
+            '\\' => {
+                in_word = true;
+                match chars.get(i + 1) {
+                    Some('\n') => {}
+                    Some(&next) => lit.push(next),
+                    None => {}
+                }
+                i += 2;
+            }
+            '\'' => {
+                in_word = true;
+                word.quoted = true;
+                let start = i + 1;
+                let end = chars[start..]
+                    .iter()
+                    .position(|&c| c == '\'')
+                    .context("Unterminated single quote in GRUB script")?;
+                lit.extend(&chars[start..start + end]);
+                i = start + end + 1;
+            }
+            '"' => {
+                in_word = true;
+                word.quoted = true;
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => bail!("Unterminated double quote in GRUB script"),
+                        Some('"') => {
+                            i += 1;
+                            break;
+                        }
+                        Some('\\') => {
+                            match chars.get(i + 1) {
+                                Some(&n) if n == '"' || n == '\\' || n == '$' => lit.push(n),
+                                Some('\n') => {}
+                                Some(&n) => {
+                                    lit.push('\\');
+                                    lit.push(n);
+                                }
+                                None => {}
+                            }
+                            i += 2;
+                        }
+                        Some('$') => {
+                            i += 1;
+                            let name = read_var(&chars, &mut i)?;
+                            flush_lit(&mut word, &mut lit);
+                            word.parts.push(Part::QuotedVar(name));
+                        }
+                        Some(&n) => {
+                            lit.push(n);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            '$' => {
+                in_word = true;
+                i += 1;
+                let name = read_var(&chars, &mut i)?;
+                if name.is_empty() {
+                    lit.push('$');
+                } else {
+                    flush_lit(&mut word, &mut lit);
+                    word.parts.push(Part::Var(name));
+                }
+            }
+            _ => {
+                in_word = true;
+                lit.push(c);
+                i += 1;
+            }
+        }
+    }
+    if in_word {
+        flush_lit(&mut word, &mut lit);
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn skip_separators(&mut self) {
+        while let Some(Token::Separator) = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek_keyword(&self) -> Option<&str> {
+        match self.peek() {
+            Some(Token::Word(w)) => w.keyword(),
+            _ => None,
+        }
+    }
+
+    fn expect_keyword(&mut self, kw: &str) -> Result<()> {
+        self.skip_separators();
+        if self.peek_keyword() != Some(kw) {
+            bail!("Expected '{kw}' in GRUB script");
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Parses statements until one of the terminating keywords (or `}`) is found.
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<Vec<Stmt>> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_separators();
+            match self.peek() {
+                None | Some(Token::RBrace) => break,
+                Some(Token::Word(w)) if w.keyword().is_some_and(|k| terminators.contains(&k)) => {
+                    break
+                }
+                _ => list.push(self.parse_stmt()?),
+            }
+        }
+        Ok(list)
+    }
+
+    fn parse_block(&mut self) -> Result<Vec<Stmt>> {
+        self.skip_separators();
+        if !matches!(self.peek(), Some(Token::LBrace)) {
+            bail!("Expected '{{' in GRUB script");
+        }
+        self.pos += 1;
+        let body = self.parse_list(&[])?;
+        if !matches!(self.peek(), Some(Token::RBrace)) {
+            bail!("Expected '}}' in GRUB script");
+        }
+        self.pos += 1;
+        Ok(body)
+    }
+
+    fn parse_words(&mut self) -> Vec<Word> {
+        let mut words = Vec::new();
+        while let Some(Token::Word(w)) = self.peek() {
+            words.push(w.clone());
+            self.pos += 1;
+        }
+        words
+    }
+
+    fn parse_stmt(&mut self) -> Result<Stmt> {
+        match self.peek_keyword() {
+            Some("if") => {
+                self.pos += 1;
+                let mut branches = Vec::new();
+                let mut otherwise = Vec::new();
+                loop {
+                    let cond = self.parse_list(&["then"])?;
+                    self.expect_keyword("then")?;
+                    let body = self.parse_list(&["elif", "else", "fi"])?;
+                    branches.push((cond, body));
+                    match self.peek_keyword() {
+                        Some("elif") => self.pos += 1,
+                        Some("else") => {
+                            self.pos += 1;
+                            otherwise = self.parse_list(&["fi"])?;
+                            self.expect_keyword("fi")?;
+                            break;
+                        }
+                        _ => {
+                            self.expect_keyword("fi")?;
+                            break;
+                        }
+                    }
+                }
+                Ok(Stmt::If { branches, otherwise })
+            }
+            Some("for") => {
+                self.pos += 1;
+                let var = match self.peek() {
+                    Some(Token::Word(w)) => w.keyword().context("Invalid for loop variable")?.to_string(),
+                    _ => bail!("Missing for loop variable in GRUB script"),
+                };
+                self.pos += 1;
+                self.expect_keyword("in")?;
+                let items = self.parse_words();
+                self.expect_keyword("do")?;
+                let body = self.parse_list(&["done"])?;
+                self.expect_keyword("done")?;
+                Ok(Stmt::For { var, items, body })
+            }
+            Some(kw @ ("while" | "until")) => {
+                let until = kw == "until";
+                self.pos += 1;
+                let cond = self.parse_list(&["do"])?;
+                self.expect_keyword("do")?;
+                let body = self.parse_list(&["done"])?;
+                self.expect_keyword("done")?;
+                Ok(Stmt::While { cond, body, until })
+            }
+            Some("function") => {
+                self.pos += 1;
+                let name = match self.peek() {
+                    Some(Token::Word(w)) => w.keyword().context("Invalid function name")?.to_string(),
+                    _ => bail!("Missing function name in GRUB script"),
+                };
+                self.pos += 1;
+                let body = self.parse_block()?;
+                Ok(Stmt::Function { name, body })
+            }
+            Some(kw @ ("menuentry" | "submenu")) => {
+                let submenu = kw == "submenu";
+                self.pos += 1;
+                let args = self.parse_words();
+                let body = self.parse_block()?;
+                Ok(Stmt::Menu { args, body, submenu })
+            }
+            _ => {
+                let words = self.parse_words();
+                if words.is_empty() {
+                    bail!("Unexpected token in GRUB script");
+                }
+                Ok(Stmt::Cmd(words))
+            }
+        }
+    }
+}
+
+fn parse_script(src: &str) -> Result<Vec<Stmt>> {
+    let mut parser = Parser {
+        tokens: tokenize(src)?,
+        pos: 0,
+    };
+    let script = parser.parse_list(&[])?;
+    if parser.pos != parser.tokens.len() {
+        bail!("Unbalanced braces in GRUB script");
+    }
+    Ok(script)
+}
+
+#[derive(Debug, Clone)]
+struct MenuEntry {
+    title: String,
+    id: Option<String>,
+    args: Vec<String>,
+    body: Vec<Stmt>,
+    submenu: bool,
+}
+
+/// Control flow signals propagated out of statement execution.
+enum Flow {
+    Normal(bool),
+    Break,
+    Continue,
+    Return(bool),
+    Booted,
+}
+
+/// Splits a GRUB path such as `($root)/boot/grub` into device and path.
+fn split_device<'a>(path: &'a str, root: &'a str) -> (&'a str, &'a str) {
+    if let Some(rest) = path.strip_prefix('(') {
+        if let Some(end) = rest.find(')') {
+            return (&rest[..end], &rest[end + 1..]);
+        }
+    }
+    (root, path)
+}
+
+pub(crate) struct Interpreter<'a> {
+    fs: &'a dyn GrubFs,
+    vars: HashMap<String, String>,
+    exported: HashSet<String>,
+    functions: HashMap<String, Vec<Stmt>>,
+    menu: Vec<MenuEntry>,
+    positional: Vec<String>,
+    search_hints: &'a BTreeMap<String, String>,
+    depth: usize,
+    boot: GrubBoot,
+}
+
+impl<'a> Interpreter<'a> {
+    pub(crate) fn new(
+        fs: &'a dyn GrubFs,
+        env: &BTreeMap<String, String>,
+        search_hints: &'a BTreeMap<String, String>,
+    ) -> Self {
+        let mut vars: HashMap<String, String> = [
+            ("grub_platform", "efi"),
+            ("grub_cpu", "x86_64"),
+            ("root", "hd0,gpt1"),
+            ("feature_200_final", "y"),
+            ("feature_all_video_module", "y"),
+            ("feature_chainloader_bpb", "y"),
+            ("feature_default_font_path", "y"),
+            ("feature_menuentry_id", "y"),
+            ("feature_menuentry_options", "y"),
+            ("feature_nativedisk_cmd", "y"),
+            ("feature_ntldr", "y"),
+            ("feature_platform_search_hint", "y"),
+            ("feature_timeout_style", "y"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        vars.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if !vars.contains_key("prefix") {
+            let prefix = format!("({})/boot/grub", vars["root"]);
+            vars.insert("prefix".to_string(), prefix);
+        }
+        let exported = ["root", "prefix", "cmdpath", "grub_platform", "grub_cpu"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        Self {
+            fs,
+            vars,
+            exported,
+            functions: HashMap::new(),
+            menu: Vec::new(),
+            positional: Vec::new(),
+            search_hints,
+            depth: 0,
+            boot: GrubBoot::default(),
+        }
+    }
+
+    /// Runs the configuration file at `config` and boots `entry` (or `$default`).
+    pub(crate) fn run(mut self, config: &str, entry: Option<&str>) -> Result<GrubBoot> {
+        let flow = self.exec_config_file(config, entry)?;
+        if !matches!(flow, Flow::Booted) {
+            bail!("GRUB configuration {config} did not boot a kernel");
+        }
+        Ok(self.boot)
+    }
+
+    fn var(&self, name: &str) -> String {
+        match name {
+            "#" => self.positional.len().to_string(),
+            "@" | "*" => self.positional.join(" "),
+            _ => match name.parse::<usize>() {
+                Ok(0) => String::new(),
+                Ok(n) => self.positional.get(n - 1).cloned().unwrap_or_default(),
+                Err(_) => self.vars.get(name).cloned().unwrap_or_default(),
+            },
+        }
+    }
+
+    fn expand(&self, words: &[Word]) -> Vec<String> {
+        let mut args = Vec::new();
+        for word in words {
+            // Unquoted variables are split on whitespace like the GRUB lexer does
+            let mut fields: Vec<String> = vec![String::new()];
+            let mut has_content = word.quoted;
+            for part in &word.parts {
+                match part {
+                    Part::Lit(s) => {
+                        fields.last_mut().unwrap().push_str(s);
+                        has_content = true;
+                    }
+                    Part::QuotedVar(name) => fields.last_mut().unwrap().push_str(&self.var(name)),
+                    Part::Var(name) => {
+                        let value = self.var(name);
+                        let mut pieces = value.split_whitespace();
+                        if let Some(first) = pieces.next() {
+                            fields.last_mut().unwrap().push_str(first);
+                            has_content = true;
+                        }
+                        for piece in pieces {
+                            fields.push(piece.to_string());
+                        }
+                    }
+                }
+            }
+            if fields.len() == 1 && fields[0].is_empty() && !has_content {
+                continue;
+            }
+            args.extend(fields);
+        }
+        args
+    }
+
+    fn resolve<'p>(&'p self, path: &'p str) -> (&'p str, &'p str) {
+        let root = self.vars.get("root").map(String::as_str).unwrap_or("");
+        split_device(path, root)
+    }
+
+    /// Reads a file through the verifier framework, logging its measurement.
+    fn read_measured(&mut self, path: &str) -> Result<Vec<u8>> {
+        let (device, file) = self.resolve(path);
+        let data = self
+            .fs
+            .read(device, file)
+            .with_context(|| format!("GRUB failed to open {path}"))?;
+        self.boot.events.push(GrubEvent::File {
+            path: path.to_string(),
+            digest: measure_sha384(&data),
+        });
+        Ok(data)
+    }
+
+    fn file_exists(&self, path: &str) -> Result<bool> {
+        let (device, file) = self.resolve(path);
+        self.fs.is_file(device, file)
+    }
+
+    fn dir_exists(&self, path: &str) -> Result<bool> {
+        let (device, file) = self.resolve(path);
+        self.fs.is_dir(device, file)
+    }
+
+    fn exec_config_file(&mut self, path: &str, entry: Option<&str>) -> Result<Flow> {
+        let data = self.read_measured(path)?;
+        let script = parse_script(&String::from_utf8_lossy(&data))
+            .with_context(|| format!("Failed to parse {path}"))?;
+        self.menu.clear();
+        if let Flow::Booted = self.exec_list(&script)? {
+            return Ok(Flow::Booted);
+        }
+        self.boot_menu(entry)
+    }
+
+    /// Selects a menu entry the way GRUB's normal mode does and executes it.
+    fn boot_menu(&mut self, entry: Option<&str>) -> Result<Flow> {
+        if self.menu.is_empty() {
+            return Ok(Flow::Normal(true));
+        }
+        let default = self.var("default");
+        let spec = entry.map(str::to_string).unwrap_or(default);
+        let (first, rest) = match spec.split_once('>') {
+            Some((first, rest)) => (first.to_string(), Some(rest.to_string())),
+            None => (spec.clone(), None),
+        };
+        let index = if first.is_empty() {
+            0
+        } else if let Ok(index) = first.parse::<usize>() {
+            index
+        } else {
+            self.menu
+                .iter()
+                .position(|e| e.title == first || e.id.as_deref() == Some(first.as_str()))
+                .with_context(|| format!("GRUB menu entry '{first}' not found"))?
+        };
+        let selected = self
+            .menu
+            .get(index)
+            .cloned()
+            .with_context(|| format!("GRUB menu entry {index} not found"))?;
+        debug!("GRUB boots menu entry '{}'", selected.title);
+
+        // The entry source code is prefixed with a `setparams` command holding its arguments
+        let mut setparams = vec!["setparams".to_string()];
+        setparams.extend(selected.args.iter().cloned());
+        self.exec_command(&setparams)?;
+
+        if selected.submenu {
+            self.menu.clear();
+            if let Flow::Booted = self.exec_list(&selected.body)? {
+                return Ok(Flow::Booted);
+            }
+            return self.boot_menu(rest.as_deref().or(Some("0")));
+        }
+
+        if let Flow::Booted = self.exec_list(&selected.body)? {
+            return Ok(Flow::Booted);
+        }
+        if self.boot.kernel.is_none() {
+            bail!("GRUB menu entry '{}' did not load a kernel", selected.title);
+        }
+        Ok(Flow::Booted)
+    }
+
+    fn exec_list(&mut self, list: &[Stmt]) -> Result<Flow> {
+        let mut status = true;
+        for stmt in list {
+            match self.exec_stmt(stmt)? {
+                Flow::Normal(s) => status = s,
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal(status))
+    }
+
+    fn exec_stmt(&mut self, stmt: &Stmt) -> Result<Flow> {
+        match stmt {
+            Stmt::Cmd(words) => {
+                let argv = self.expand(words);
+                if argv.is_empty() {
+                    return Ok(Flow::Normal(true));
+                }
+                self.exec_command(&argv)
+            }
+            Stmt::If { branches, otherwise } => {
+                for (cond, body) in branches {
+                    match self.exec_list(cond)? {
+                        Flow::Normal(true) => return self.exec_list(body),
+                        Flow::Normal(false) => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                self.exec_list(otherwise)
+            }
+            Stmt::For { var, items, body } => {
+                let mut status = true;
+                for item in self.expand(items) {
+                    self.vars.insert(var.clone(), item);
+                    match self.exec_list(body)? {
+                        Flow::Normal(s) => status = s,
+                        Flow::Break => break,
+                        Flow::Continue => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                Ok(Flow::Normal(status))
+            }
+            Stmt::While { cond, body, until } => {
+                let mut status = true;
+                for _ in 0..MAX_LOOP_ITERATIONS {
+                    match self.exec_list(cond)? {
+                        Flow::Normal(s) if s != *until => {}
+                        Flow::Normal(_) => return Ok(Flow::Normal(status)),
+                        flow => return Ok(flow),
+                    }
+                    match self.exec_list(body)? {
+                        Flow::Normal(s) => status = s,
+                        Flow::Break => return Ok(Flow::Normal(status)),
+                        Flow::Continue => {}
+                        flow => return Ok(flow),
+                    }
+                }
+                bail!("GRUB loop did not terminate after {MAX_LOOP_ITERATIONS} iterations")
+            }
+            Stmt::Function { name, body } => {
+                self.functions.insert(name.clone(), body.clone());
+                Ok(Flow::Normal(true))
+            }
+            Stmt::Menu { args, body, submenu } => {
+                self.add_menu_entry(self.expand(args), body, *submenu)?;
+                Ok(Flow::Normal(true))
+            }
+        }
+    }
+
+    fn add_menu_entry(&mut self, argv: Vec<String>, body: &[Stmt], submenu: bool) -> Result<()> {
+        let mut id = None;
+        let mut args = Vec::new();
+        let mut iter = argv.into_iter();
+        while let Some(arg) = iter.next() {
+            if let Some(value) = arg.strip_prefix("--id=") {
+                id = Some(value.to_string());
+            } else if let "--class" | "--users" | "--hotkey" | "--id" = arg.as_str() {
+                let value = iter.next();
+                if arg == "--id" {
+                    id = value;
+                }
+            } else if arg.starts_with("--") {
+                // Flags without values such as --unrestricted
+            } else {
+                args.push(arg);
+            }
+        }
+        let title = args.first().cloned().context("Menu entry without a title")?;
+        self.menu.push(MenuEntry {
+            title,
+            id,
+            args,
+            body: body.to_vec(),
+            submenu,
+        });
+        Ok(())
+    }
+
+    /// Executes a simple command, logging it first as GRUB does.
+    fn exec_command(&mut self, argv: &[String]) -> Result<Flow> {
+        self.boot.events.push(GrubEvent::Command(argv.join(" ")));
+        let args = &argv[1..];
+
+        if let Some(body) = self.functions.get(&argv[0]).cloned() {
+            self.depth += 1;
+            if self.depth > MAX_DEPTH {
+                bail!("GRUB script recursion too deep");
+            }
+            let saved = std::mem::replace(&mut self.positional, args.to_vec());
+            let flow = self.exec_list(&body);
+            self.positional = saved;
+            self.depth -= 1;
+            return Ok(match flow? {
+                Flow::Return(s) => Flow::Normal(s),
+                Flow::Break | Flow::Continue => Flow::Normal(true),
+                flow => flow,
+            });
+        }
+
+        let status = match argv[0].as_str() {
+            "set" => {
+                for arg in args {
+                    match arg.split_once('=') {
+                        Some((name, value)) => {
+                            self.vars.insert(name.to_string(), value.to_string());
+                        }
+                        None => {
+                            self.vars.entry(arg.clone()).or_default();
+                        }
+                    }
+                }
+                true
+            }
+            "unset" => {
+                for arg in args {
+                    self.vars.remove(arg);
+                }
+                true
+            }
+            "export" => {
+                self.exported.extend(args.iter().cloned());
+                true
+            }
+            "setparams" => {
+                self.positional = args.to_vec();
+                true
+            }
+            "true" => true,
+            "false" => false,
+            "[" | "test" => {
+                let mut args = args.to_vec();
+                if argv[0] == "[" {
+                    if args.last().map(String::as_str) != Some("]") {
+                        bail!("Missing ']' in GRUB test command");
+                    }
+                    args.pop();
+                }
+                self.eval_test(&args)?
+            }
+            "break" => return Ok(Flow::Break),
+            "continue" => return Ok(Flow::Continue),
+            "return" => {
+                let status = args.first().is_none_or(|s| s == "0");
+                return Ok(Flow::Return(status));
+            }
+            "search" | "search.fs_uuid" | "search.fs_label" | "search.file" => self.exec_search(&argv[0], args),
+            "load_env" => {
+                self.exec_load_env(args)?;
+                true
+            }
+            "source" | "." => {
+                let path = args.first().context("source requires a file")?;
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    bail!("GRUB script recursion too deep");
+                }
+                let data = self.read_measured(path)?;
+                let script = parse_script(&String::from_utf8_lossy(&data))
+                    .with_context(|| format!("Failed to parse {path}"))?;
+                let flow = self.exec_list(&script)?;
+                self.depth -= 1;
+                match flow {
+                    Flow::Normal(s) | Flow::Return(s) => s,
+                    flow => return Ok(flow),
+                }
+            }
+            "configfile" => {
+                let path = args.first().context("configfile requires a file")?.clone();
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    bail!("GRUB script recursion too deep");
+                }
+                // configfile starts a new environment context with only exported variables
+                let saved_vars = self.vars.clone();
+                let saved_menu = std::mem::take(&mut self.menu);
+                let exported = self.exported.clone();
+                self.vars.retain(|k, _| exported.contains(k));
+                let flow = self.exec_config_file(&path, None)?;
+                self.depth -= 1;
+                if let Flow::Booted = flow {
+                    return Ok(Flow::Booted);
+                }
+                self.vars = saved_vars;
+                self.menu = saved_menu;
+                true
+            }
+            "loadfont" => {
+                for font in args {
+                    let path = if font.contains('/') {
+                        font.clone()
+                    } else {
+                        format!("{}/fonts/{font}.pf2", self.var("prefix"))
+                    };
+                    if self.file_exists(&path)? {
+                        self.read_measured(&path)?;
+                    }
+                }
+                true
+            }
+            "linux" | "linuxefi" | "linux16" => {
+                let kernel = args.first().context("linux requires a kernel")?.clone();
+                self.boot.kernel_data = self.read_measured(&kernel)?;
+                let cmdline = args.join(" ");
+                self.boot.events.push(GrubEvent::KernelCmdline(cmdline.clone()));
+                self.boot.kernel = Some(kernel);
+                self.boot.cmdline = Some(cmdline);
+                self.boot.initrds.clear();
+                true
+            }
+            "initrd" | "initrdefi" | "initrd16" => {
+                if self.boot.kernel.is_none() {
+                    bail!("GRUB initrd command used before linux");
+                }
+                let mut initrds = Vec::new();
+                for path in args.iter().filter(|a| !a.starts_with("--")) {
+                    initrds.push(self.read_measured(path)?);
+                }
+                self.boot.initrds = initrds;
+                true
+            }
+            "boot" => return Ok(Flow::Booted),
+            "chainloader" | "reboot" | "halt" | "fwsetup" | "exit" | "normal_exit" => {
+                bail!("GRUB command '{}' is not supported for measurement", argv[0])
+            }
+            // Commands opening files, which would be measured
+            "acpi" | "background_image" | "cat" | "cmp" | "devicetree" | "hashsum" | "hexdump" | "loopback"
+            | "md5sum" | "module" | "module2" | "multiboot" | "multiboot2" | "sha1sum" | "sha256sum"
+            | "sha512sum" | "trust" | "verify_detached" => {
+                bail!("GRUB command '{}' is not supported for measurement", argv[0])
+            }
+            name if argv.len() == 1 && name.contains('=') => {
+                // Plain `name=value` assignment
+                let (name, value) = name.split_once('=').unwrap();
+                self.vars.insert(name.to_string(), value.to_string());
+                true
+            }
+            _ => {
+                // Commands without influence on the measured state (insmod, echo, save_env, ...)
+                debug!("GRUB command without modeled effect: {}", argv.join(" "));
+                true
+            }
+        };
+        Ok(Flow::Normal(status))
+    }
+
+    fn exec_search(&mut self, command: &str, args: &[String]) -> bool {
+        let mut var = "root".to_string();
+        let mut hint = None;
+        let mut keys = Vec::new();
+        for arg in args {
+            if let Some(v) = arg.strip_prefix("--set=") {
+                var = v.to_string();
+            } else if let Some(v) = arg.strip_prefix("--hint-efi=").or_else(|| arg.strip_prefix("--hint=")) {
+                hint.get_or_insert_with(|| v.to_string());
+            } else if !arg.starts_with('-') {
+                keys.push(arg.clone());
+            }
+        }
+        // search.fs_uuid UUID VAR
+        if command != "search" && keys.len() > 1 {
+            var = keys.pop().unwrap();
+        }
+        let found = keys
+            .first()
+            .and_then(|key| self.search_hints.get(key).cloned())
+            .or(hint);
+        match found {
+            Some(device) => {
+                self.vars.insert(var, device);
+                true
+            }
+            None => {
+                debug!("GRUB search for {keys:?} left ${var} unchanged");
+                false
+            }
+        }
+    }
+
+    fn exec_load_env(&mut self, args: &[String]) -> Result<()> {
+        let mut file = format!("{}/grubenv", self.var("prefix"));
+        let mut names = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "-f" | "--file" => file = iter.next().cloned().unwrap_or(file),
+                "-s" | "--skip-sig" => {}
+                _ => names.push(arg.clone()),
+            }
+        }
+        // The environment block is opened with GRUB_FILE_TYPE_SKIP_SIGNATURE and not measured
+        let (device, path) = self.resolve(&file);
+        if !self.fs.is_file(device, path)? {
+            return Ok(());
+        }
+        let data = self.fs.read(device, path)?;
+        for line in String::from_utf8_lossy(&data).lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((name, value)) = line.split_once('=') {
+                if names.is_empty() || names.iter().any(|n| n == name) {
+                    self.vars.insert(name.to_string(), value.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn eval_test(&mut self, args: &[String]) -> Result<bool> {
+        // Handle -o with the lowest precedence, then -a
+        if let Some(pos) = args.iter().position(|a| a == "-o") {
+            let left = self.eval_test(&args[..pos])?;
+            let right = self.eval_test(&args[pos + 1..])?;
+            return Ok(left || right);
+        }
+        if let Some(pos) = args.iter().position(|a| a == "-a") {
+            let left = self.eval_test(&args[..pos])?;
+            let right = self.eval_test(&args[pos + 1..])?;
+            return Ok(left && right);
+        }
+        if let Some(first) = args.first() {
+            if first == "!" {
+                return Ok(!self.eval_test(&args[1..])?);
+            }
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        Ok(match args.as_slice() {
+            [] => false,
+            [s] => !s.is_empty(),
+            ["-z", s] => s.is_empty(),
+            ["-n", s] => !s.is_empty(),
+            // -e, -f and -d only look up the directory entry, without opening the file
+            ["-e", path] => self.file_exists(path)? || self.dir_exists(path)?,
+            ["-f", path] => self.file_exists(path)?,
+            ["-d", path] => self.dir_exists(path)?,
+            ["-s", path] => {
+                // Opening the file to get its size runs it through the verifiers, so it is measured
+                self.file_exists(path)? && !self.read_measured(path)?.is_empty()
+            }
+            [a, "=" | "==", b] => a == b,
+            [a, "!=", b] => a != b,
+            [a, op @ ("-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge"), b] => {
+                let a: i64 = a.parse().unwrap_or(0);
+                let b: i64 = b.parse().unwrap_or(0);
+                match *op {
+                    "-eq" => a == b,
+                    "-ne" => a != b,
+                    "-lt" => a < b,
+                    "-le" => a <= b,
+                    "-gt" => a > b,
+                    _ => a >= b,
+                }
+            }
+            _ => bail!("Unsupported GRUB test expression: {}", args.join(" ")),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Files and directories of a single GRUB device
+    #[derive(Default)]
+    struct MemFs {
+        files: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl MemFs {
+        fn new(files: &[(&str, &str)]) -> Self {
+            Self {
+                files: files
+                    .iter()
+                    .map(|(path, data)| (path.to_string(), data.as_bytes().to_vec()))
+                    .collect(),
+            }
+        }
+    }
+
+    impl GrubFs for MemFs {
+        fn read(&self, _device: &str, path: &str) -> Result<Vec<u8>> {
+            self.files.get(path).cloned().context("No such file")
+        }
+
+        fn is_file(&self, _device: &str, path: &str) -> Result<bool> {
+            Ok(self.files.contains_key(path))
+        }
+
+        fn is_dir(&self, _device: &str, path: &str) -> Result<bool> {
+            let prefix = format!("{}/", path.trim_end_matches('/'));
+            Ok(self.files.keys().any(|p| p.starts_with(&prefix)))
+        }
+    }
+
+    fn run(fs: &MemFs, entry: Option<&str>) -> Result<GrubBoot> {
+        let env = BTreeMap::new();
+        let search = BTreeMap::new();
+        Interpreter::new(fs, &env, &search).run("/boot/grub/grub.cfg", entry)
+    }
+
+    fn descriptions(boot: &GrubBoot) -> Vec<String> {
+        boot.events.iter().map(GrubEvent::description).collect()
+    }
+
+    fn literal(token: &Token) -> String {
+        match token {
+            Token::Word(word) => word
+                .parts
+                .iter()
+                .map(|part| match part {
+                    Part::Lit(s) => s.clone(),
+                    Part::Var(name) => format!("${name}"),
+                    Part::QuotedVar(name) => format!("\"${name}\""),
+                })
+                .collect(),
+            Token::Separator => ";".to_string(),
+            Token::LBrace => "{".to_string(),
+            Token::RBrace => "}".to_string(),
+        }
+    }
+
+    #[test]
+    fn tokenize_quotes_variables_and_comments() {
+        let tokens = tokenize("set a='x y' # comment\necho \"$a-${b}\" c\\ d $1}").unwrap();
+        let tokens: Vec<String> = tokens.iter().map(literal).collect();
+        assert_eq!(
+            tokens,
+            ["set", "a=x y", ";", "echo", "\"$a\"-\"$b\"", "c d", "$1}"]
+        );
+    }
+
+    #[test]
+    fn tokenize_rejects_unterminated_quotes() {
+        assert!(tokenize("echo 'a").is_err());
+        assert!(tokenize("echo \"a").is_err());
+        assert!(tokenize("echo ${a").is_err());
+    }
+
+    #[test]
+    fn parse_compound_statements() {
+        let script = parse_script(
+            "if true; then a; elif false; then b; else c; fi\n\
+             for i in 1 2; do d; done\n\
+             while false; do e; done\n\
+             function f { g; }\n\
+             menuentry 'x' --id y { h }\n",
+        )
+        .unwrap();
+        assert_eq!(script.len(), 5);
+        assert!(matches!(&script[0], Stmt::If { branches, otherwise } if branches.len() == 2 && otherwise.len() == 1));
+        assert!(matches!(&script[1], Stmt::For { var, items, .. } if var == "i" && items.len() == 2));
+        assert!(matches!(&script[2], Stmt::While { until: false, .. }));
+        assert!(matches!(&script[3], Stmt::Function { name, .. } if name == "f"));
+        assert!(matches!(&script[4], Stmt::Menu { args, submenu: false, .. } if args.len() == 3));
+    }
+
+    #[test]
+    fn parse_rejects_unbalanced_scripts() {
+        assert!(parse_script("if true; then a;").is_err());
+        assert!(parse_script("menuentry x { a").is_err());
+        assert!(parse_script("a }").is_err());
+    }
+
+    #[test]
+    fn boot_default_menu_entry() {
+        let fs = MemFs::new(&[
+            (
+                "/boot/grub/grub.cfg",
+                "set default=1\n\
+                 menuentry 'first' { linux /vmlinuz-1 }\n\
+                 menuentry 'second' --id second {\n\
+                 \tset root='hd0,gpt2'\n\
+                 \tlinux /vmlinuz-2 root=/dev/sda2 ro\n\
+                 \tinitrd /initrd-a /initrd-b\n\
+                 }\n",
+            ),
+            ("/vmlinuz-2", "kernel"),
+            ("/initrd-a", "a"),
+            ("/initrd-b", "b"),
+        ]);
+        let boot = run(&fs, None).unwrap();
+        assert_eq!(
+            descriptions(&boot),
+            [
+                "/boot/grub/grub.cfg",
+                "grub_cmd: set default=1",
+                "grub_cmd: setparams second",
+                "grub_cmd: set root=hd0,gpt2",
+                "grub_cmd: linux /vmlinuz-2 root=/dev/sda2 ro",
+                "/vmlinuz-2",
+                "kernel_cmdline: /vmlinuz-2 root=/dev/sda2 ro",
+                "grub_cmd: initrd /initrd-a /initrd-b",
+                "/initrd-a",
+                "/initrd-b",
+            ]
+        );
+        assert_eq!(boot.kernel.as_deref(), Some("/vmlinuz-2"));
+        assert_eq!(boot.initrds, [b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(
+            boot.events[5].digest(),
+            measure_sha384(b"kernel"),
+        );
+    }
+
+    #[test]
+    fn boot_submenu_entry_by_id() {
+        let fs = MemFs::new(&[
+            (
+                "/boot/grub/grub.cfg",
+                "menuentry 'main' { linux /a }\n\
+                 submenu 'advanced' --id adv {\n\
+                 \tmenuentry 'old' --id old { linux /b }\n\
+                 }\n",
+            ),
+            ("/b", "b"),
+        ]);
+        let boot = run(&fs, Some("adv>old")).unwrap();
+        assert_eq!(boot.kernel.as_deref(), Some("/b"));
+    }
+
+    #[test]
+    fn functions_loops_and_positional_parameters() {
+        let fs = MemFs::new(&[
+            (
+                "/boot/grub/grub.cfg",
+                "function load { linux $1 $2; }\n\
+                 for f in x y; do set last=$f; done\n\
+                 set n=0\n\
+                 until [ $n = 2 ]; do if [ $n = 0 ]; then n=1; else n=2; fi; done\n\
+                 menuentry 'e' { load /k \"$last\" }\n",
+            ),
+            ("/k", "k"),
+        ]);
+        let boot = run(&fs, None).unwrap();
+        assert_eq!(boot.cmdline.as_deref(), Some("/k y"));
+    }
+
+    #[test]
+    fn file_tests_only_measure_size_tests() {
+        let fs = MemFs::new(&[
+            (
+                "/boot/grub/grub.cfg",
+                "if [ -e /boot/grub/grubenv ]; then a=1; fi\n\
+                 if [ -f /boot/grub/grubenv ]; then b=1; fi\n\
+                 if [ -d /boot/grub ]; then c=1; fi\n\
+                 if [ -d /missing -o -f /missing ]; then d=1; fi\n\
+                 if [ -s /boot/grub/grubenv ]; then e=1; fi\n\
+                 menuentry 'e' { linux /k $a$b$c$d$e }\n",
+            ),
+            ("/boot/grub/grubenv", "# GRUB Environment Block\n"),
+            ("/k", "k"),
+        ]);
+        let boot = run(&fs, None).unwrap();
+        assert_eq!(boot.cmdline.as_deref(), Some("/k 1111"));
+        let files: Vec<&str> = boot
+            .events
+            .iter()
+            .filter_map(|e| match e {
+                GrubEvent::File { path, .. } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(files, ["/boot/grub/grub.cfg", "/boot/grub/grubenv", "/k"]);
+    }
+
+    #[test]
+    fn configfile_keeps_only_exported_variables() {
+        let fs = MemFs::new(&[
+            (
+                "/boot/grub/grub.cfg",
+                "set a=1\nset b=2\nexport a\nconfigfile /boot/grub/other.cfg\n",
+            ),
+            ("/boot/grub/other.cfg", "menuentry 'e' { linux /k a=$a b=$b }\n"),
+            ("/k", "k"),
+        ]);
+        let boot = run(&fs, None).unwrap();
+        assert_eq!(boot.cmdline.as_deref(), Some("/k a=1 b="));
+    }
+
+    #[test]
+    fn load_env_is_not_measured() {
+        let fs = MemFs::new(&[
+            ("/boot/grub/grub.cfg", "load_env\nmenuentry 'e' { linux /k $saved }\n"),
+            ("/boot/grub/grubenv", "# GRUB Environment Block\nsaved=x\n"),
+            ("/k", "k"),
+        ]);
+        let boot = run(&fs, None).unwrap();
+        assert_eq!(boot.cmdline.as_deref(), Some("/k x"));
+        assert!(!descriptions(&boot).iter().any(|d| d == "/boot/grub/grubenv"));
+    }
+
+    #[test]
+    fn unsupported_constructs_fail() {
+        for script in [
+            "while true; do true; done\n",
+            "chainloader /efi/x.efi\n",
+            "multiboot /xen\n",
+            "[ a -nt b ]\n",
+        ] {
+            let fs = MemFs::new(&[("/boot/grub/grub.cfg", script)]);
+            assert!(run(&fs, None).is_err(), "{script}");
+        }
+    }
+
+    #[test]
+    fn config_without_kernel_fails() {
+        let fs = MemFs::new(&[("/boot/grub/grub.cfg", "menuentry 'e' { true }\n")]);
+        assert!(run(&fs, None).is_err());
+    }
+}
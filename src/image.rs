@@ -4,44 +4,212 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 use crate::{measure_log, measure_sha384, util::{debug_print_log, authenticode_sha384_hash}};
+use crate::grub::{GrubBoot, GrubEvent, GrubFs, Interpreter};
+use crate::systemd_boot::{self, BootEntry};
+use crate::uki::{self, Uki};
+use crate::util::{concat_initrds, measure_cmdline};
+use crate::deb::Overlay;
+use crate::secure_boot::{BootImage, VerifiedImages};
+use crate::{GrubConfig, SystemdBootConfig};
 use anyhow::{bail, Context, Result};
+use std::cell::OnceCell;
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
-use std::process::Command;
-use log::debug;
+use std::process::{Command, Output};
+use log::{debug, warn};
 use crate::util::utf16_encode;
 
-/// Helper function to download a file using guestfish
-fn guestfish_download(qcow2_path: &str, source_path: &str) -> Result<Vec<u8>> {
+/// A guestfish process listening for commands on a qcow2 image, so that the
+/// appliance is launched once for all the files read from the image
+struct Guestfish {
+    pid: String,
+}
 
-    // Create a temporary directory for the extracted files
-    let temp_dir = std::env::temp_dir().join("tdx_bootloader_extract");
-    std::fs::create_dir_all(&temp_dir)?;
+impl Guestfish {
+    fn launch(qcow2_path: &str) -> Result<Self> {
+        let output = Command::new("guestfish")
+            .args(["--listen", "--ro", "-a", qcow2_path, "-i"])
+            .output()
+            .context(format!("Failed to launch guestfish on {}", qcow2_path))?;
 
-    // Create a temporary file path
-    let dest_path = temp_dir.join("extracted_file");
+        if !output.status.success() {
+            bail!("Failed to launch guestfish on {}: {}", qcow2_path, String::from_utf8_lossy(&output.stderr));
+        }
 
-    // Download the file using guestfish
-    let output = Command::new("guestfish")
-        .args(&[
-            "--ro", "-a", qcow2_path, "-i",
-            "download", source_path, dest_path.to_str().unwrap()
-        ])
-        .output()
-        .context(format!("Failed to extract {}", source_path))?;
+        // guestfish prints `GUESTFISH_PID=<pid>; export GUESTFISH_PID`
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let pid = stdout
+            .split(';')
+            .find_map(|s| s.trim().strip_prefix("GUESTFISH_PID="))
+            .context("guestfish did not report its PID")?;
+        Ok(Self { pid: pid.to_string() })
+    }
 
-    if !output.status.success() {
-        bail!("Failed to extract {}: {}", source_path, String::from_utf8_lossy(&output.stderr));
+    fn command(&self, args: &[&str]) -> Result<Output> {
+        Command::new("guestfish")
+            .arg(format!("--remote={}", self.pid))
+            .args(args)
+            .output()
+            .context(format!("Failed to run guestfish {}", args.join(" ")))
     }
 
-    // Read the extracted file
-    let data = std::fs::read(&dest_path)
-        .context(format!("Failed to read extracted {}", dest_path.to_str().unwrap()))?;
+    /// Downloads a file
+    fn download(&self, source_path: &str) -> Result<Vec<u8>> {
+        // Create a temporary directory for the extracted files
+        let temp_dir = std::env::temp_dir().join(format!("tdx_bootloader_extract.{}", self.pid));
+        std::fs::create_dir_all(&temp_dir)?;
 
-    // Cleanup
-    let _ = std::fs::remove_dir_all(&temp_dir);
+        // Create a temporary file path
+        let dest_path = temp_dir.join("extracted_file");
+
+        let output = self
+            .command(&["download", source_path, dest_path.to_str().unwrap()])
+            .context(format!("Failed to extract {}", source_path))?;
+
+        if !output.status.success() {
+            bail!("Failed to extract {}: {}", source_path, String::from_utf8_lossy(&output.stderr));
+        }
+
+        // Read the extracted file
+        let data = std::fs::read(&dest_path)
+            .context(format!("Failed to read extracted {}", dest_path.to_str().unwrap()))?;
+
+        // Cleanup
+        let _ = std::fs::remove_dir_all(&temp_dir);
+
+        Ok(data)
+    }
 
-    Ok(data)
+    /// Runs a test command such as `is-file`, which prints `true` or `false`
+    fn test(&self, command: &str, path: &str) -> Result<bool> {
+        let output = self.command(&[command, path])?;
+
+        if !output.status.success() {
+            bail!("Failed to check {}: {}", path, String::from_utf8_lossy(&output.stderr));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim() == "true")
+    }
+
+    /// Lists a directory, a missing directory is empty
+    fn ls(&self, dir: &str) -> Result<Vec<String>> {
+        let output = self.command(&["ls", dir])?;
+
+        if !output.status.success() {
+            debug!("Failed to list {}: {}", dir, String::from_utf8_lossy(&output.stderr));
+            return Ok(Vec::new());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+impl Drop for Guestfish {
+    fn drop(&mut self) {
+        if let Err(e) = self.command(&["exit"]) {
+            warn!("Failed to stop guestfish: {}", e);
+        }
+    }
+}
+
+/// A qcow2 image, with the files of an upgrade overlaid on its guest filesystem
+pub(crate) struct GuestImage<'a> {
+    pub qcow2_path: &'a str,
+    overlay: &'a Overlay,
+    guestfish: OnceCell<Guestfish>,
+}
+
+impl<'a> GuestImage<'a> {
+    pub(crate) fn new(qcow2_path: &'a str, overlay: &'a Overlay) -> Self {
+        Self {
+            qcow2_path,
+            overlay,
+            guestfish: OnceCell::new(),
+        }
+    }
+
+    /// Returns the guestfish session, launched on first use
+    fn guestfish(&self) -> Result<&Guestfish> {
+        if let Some(guestfish) = self.guestfish.get() {
+            return Ok(guestfish);
+        }
+        let guestfish = Guestfish::launch(self.qcow2_path)?;
+        Ok(self.guestfish.get_or_init(|| guestfish))
+    }
+
+    fn download(&self, path: &str) -> Result<Vec<u8>> {
+        match self.overlay.get(path) {
+            Some(data) => Ok(data.clone()),
+            None => self.guestfish()?.download(path),
+        }
+    }
+
+    fn is_file(&self, path: &str) -> Result<bool> {
+        Ok(self.overlay.contains_key(path) || self.guestfish()?.test("is-file", path)?)
+    }
+
+    fn is_dir(&self, path: &str) -> Result<bool> {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        Ok(self.overlay.keys().any(|p| p.starts_with(&prefix)) || self.guestfish()?.test("is-dir", path)?)
+    }
+
+    fn ls(&self, dir: &str) -> Result<Vec<String>> {
+        let mut names = self.guestfish()?.ls(dir)?;
+        let prefix = format!("{}/", dir.trim_end_matches('/'));
+        for path in self.overlay.keys() {
+            if let Some(name) = path.strip_prefix(&prefix).filter(|n| !n.contains('/')) {
+                if !names.iter().any(|n| n == name) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Gives GRUB access to the guest filesystem of a qcow2 image
+struct GuestfishFs<'a> {
+    image: &'a GuestImage<'a>,
+    devices: &'a BTreeMap<String, String>,
+}
+
+impl GuestfishFs<'_> {
+    /// Maps a GRUB device and path to a path in the guest filesystem
+    fn guest_path(&self, device: &str, path: &str) -> String {
+        match self.devices.get(device) {
+            Some(mount_point) => format!("{}{}", mount_point.trim_end_matches('/'), path),
+            None => path.to_string(),
+        }
+    }
+}
+
+impl GrubFs for GuestfishFs<'_> {
+    fn read(&self, device: &str, path: &str) -> Result<Vec<u8>> {
+        self.image.download(&self.guest_path(device, path))
+    }
+
+    fn is_file(&self, device: &str, path: &str) -> Result<bool> {
+        self.image.is_file(&self.guest_path(device, path))
+    }
+
+    fn is_dir(&self, device: &str, path: &str) -> Result<bool> {
+        self.image.is_dir(&self.guest_path(device, path))
+    }
+}
+
+/// Replays grub.cfg from the image to obtain the events GRUB logs in RTMR2
+fn replay_grub(image: &GuestImage, grub: &GrubConfig) -> Result<GrubBoot> {
+    let fs = GuestfishFs {
+        image,
+        devices: &grub.devices,
+    };
+    Interpreter::new(&fs, &grub.env, &grub.search)
+        .run(&grub.config, grub.entry.as_deref())
+        .context("Failed to replay GRUB configuration")
 }
 
 /// Extracts GPT event data in the format used by EV_EFI_GPT_EVENT
@@ -179,12 +347,12 @@ fn extract_kernel_version_from_cmdline(cmdline: &str) -> Result<String> {
 }
 
 /// Main function to measure RTMR1 from a qcow2 disk image
-pub fn measure_rtmr1_from_qcow2(qcow2_path: &str) -> Result<Vec<u8>> {
+pub(crate) fn measure_rtmr1_from_qcow2(image: &GuestImage) -> Result<Vec<u8>> {
 
     // Extract bootloader files
-    let gpt_data = extract_gpt_event_data(qcow2_path)?;
-    let shim_data = guestfish_download(qcow2_path, "/boot/efi/EFI/ubuntu/shimx64.efi")?;
-    let grub_data = guestfish_download(qcow2_path, "/boot/efi/EFI/ubuntu/grubx64.efi")?;
+    let gpt_data = extract_gpt_event_data(image.qcow2_path)?;
+    let shim_data = image.download("/boot/efi/EFI/ubuntu/shimx64.efi")?;
+    let grub_data = image.download("/boot/efi/EFI/ubuntu/grubx64.efi")?;
 
     // Compute hashes of the bootloader components
     let gpt_hash = measure_sha384(&gpt_data);
@@ -206,30 +374,183 @@ pub fn measure_rtmr1_from_qcow2(qcow2_path: &str) -> Result<Vec<u8>> {
     Ok(measure_log(&rtmr1_log))
 }
 
-/// Measures RTMR2 using actual MOK variable data extracted from shim
-pub fn measure_rtmr2_from_qcow2(qcow2_path: &str, cmdline: &str, ref_mok_list: &str, ref_mok_list_trusted: &str, ref_mok_list_x: &str) -> Result<Vec<u8>> {
+/// Returns shim and the images it verifies when Secure Boot is on: GRUB and
+/// the kernel GRUB boots.
+pub(crate) fn shim_verified_images(image: &GuestImage, cmdline: &str, grub: Option<&GrubConfig>) -> Result<VerifiedImages> {
+    let shim_data = image.download("/boot/efi/EFI/ubuntu/shimx64.efi")?;
+    let grub_data = image.download("/boot/efi/EFI/ubuntu/grubx64.efi")?;
+    let kernel_data = match grub {
+        Some(grub) => replay_grub(image, grub)?.kernel_data,
+        None => image.download(&format!("/boot/vmlinuz-{}", extract_kernel_version_from_cmdline(cmdline)?))?,
+    };
+    Ok(VerifiedImages {
+        firmware: vec![BootImage::new("shim", shim_data.clone())],
+        shim: Some((shim_data, vec![BootImage::new("GRUB", grub_data), BootImage::new("kernel", kernel_data)])),
+    })
+}
+
+/// Returns the images the firmware verifies when Secure Boot is on:
+/// systemd-boot and the image of the entry it starts.
+pub(crate) fn systemd_boot_verified_images(image: &GuestImage, config: &SystemdBootConfig) -> Result<VerifiedImages> {
+    let loader_data = image.download(&format!("{}{}", config.esp.trim_end_matches('/'), config.loader))?;
+    let (_, image_data) = select_systemd_boot_entry(image, config)?;
+    Ok(VerifiedImages {
+        firmware: vec![BootImage::new("systemd-boot", loader_data), BootImage::new("boot entry image", image_data)],
+        shim: None,
+    })
+}
+
+/// Returns the events shim logs for the MOK variables it mirrors, from reference variable data.
+pub(crate) fn measure_mok_variables(ref_mok_list: &str, ref_mok_list_trusted: &str, ref_mok_list_x: &str) -> Result<Vec<Vec<u8>>> {
 
     // Extract reference MOK variables
     let ref_mok_list_data = read_file_data(ref_mok_list)?;
     let ref_mok_list_trusted_data = read_file_data(ref_mok_list_trusted)?;
     let ref_mok_list_x_data = read_file_data(ref_mok_list_x)?;
 
-    // Extract kernel version from command line and construct initrd path
-    let kernel_version = extract_kernel_version_from_cmdline(cmdline)?;
-    let initrd_path = format!("/boot/initrd.img-{}", kernel_version);
-
-    // Extract initrd
-    let initrd_data = guestfish_download(qcow2_path, &initrd_path)?;
-
-    // Compute RTMR2 log
-    let rtmr2_log = vec![
+    Ok(vec![
         measure_sha384(&ref_mok_list_data),
         measure_sha384(&ref_mok_list_x_data),
         measure_sha384(&ref_mok_list_trusted_data),
-        measure_sha384(&utf16_encode(cmdline)),
-        measure_sha384(&initrd_data),
-    ];
+    ])
+}
+
+/// Measures RTMR2 using actual MOK variable data extracted from shim.
+/// When a GRUB configuration is given, the events of a measuring GRUB build are included.
+pub(crate) fn measure_rtmr2_from_qcow2(image: &GuestImage, cmdline: &str, ref_mok_list: &str, ref_mok_list_trusted: &str, ref_mok_list_x: &str, grub: Option<&GrubConfig>) -> Result<Vec<u8>> {
+
+    // Compute RTMR2 log
+    let mut rtmr2_log = measure_mok_variables(ref_mok_list, ref_mok_list_trusted, ref_mok_list_x)?;
+
+    let initrd_data = if let Some(grub) = grub {
+        // GRUB commands, loaded files and the kernel command line, in execution order
+        let boot = replay_grub(image, grub)?;
+        for event in &boot.events {
+            debug!("GRUB event: {}", event.description());
+        }
+        rtmr2_log.extend(boot.events.iter().map(GrubEvent::digest));
+
+        // The kernel measures the command line GRUB built, which must be the configured one
+        if let Some(ref grub_cmdline) = boot.cmdline {
+            let grub_cmdline = format!("BOOT_IMAGE={}", grub_cmdline);
+            if grub_cmdline != cmdline {
+                bail!("Command line built by GRUB does not match the configured one: {}", grub_cmdline);
+            }
+        }
+        boot.initrd_data()
+    } else {
+        // Extract kernel version from command line and construct initrd path
+        let kernel_version = extract_kernel_version_from_cmdline(cmdline)?;
+        let initrd_path = format!("/boot/initrd.img-{}", kernel_version);
+
+        // Extract initrd
+        image.download(&initrd_path)?
+    };
+
+    rtmr2_log.push(measure_sha384(&utf16_encode(cmdline)));
+    rtmr2_log.push(measure_sha384(&initrd_data));
 
     debug_print_log("RTMR2", &rtmr2_log);
     Ok(measure_log(&rtmr2_log))
 }
+
+/// Discovers the systemd-boot entries of the ESP and selects the one that boots.
+/// Returns the entry together with the image it starts.
+fn select_systemd_boot_entry(image: &GuestImage, config: &SystemdBootConfig) -> Result<(BootEntry, Vec<u8>)> {
+    let esp = config.esp.trim_end_matches('/');
+    let mut entries = Vec::new();
+    let mut images = BTreeMap::new();
+
+    // Type #1 entries
+    let entries_dir = format!("{}/loader/entries", esp);
+    for name in image.ls(&entries_dir)? {
+        if name.ends_with(".conf") {
+            let text = image.download(&format!("{}/{}", entries_dir, name))?;
+            entries.push(systemd_boot::parse_type1_entry(&name, &String::from_utf8_lossy(&text))?);
+        }
+    }
+
+    // Type #2 entries: UKIs in EFI/Linux
+    let linux_dir = format!("{}/EFI/Linux", esp);
+    for name in image.ls(&linux_dir)? {
+        if !name.to_ascii_lowercase().ends_with(".efi") {
+            continue;
+        }
+        let data = image.download(&format!("{}/{}", linux_dir, name))?;
+        if let Some(uki) = Uki::parse(&data)? {
+            let entry = systemd_boot::type2_entry(&name, &format!("/EFI/Linux/{}", name), &uki);
+            images.insert(entry.image.clone(), data);
+            entries.push(entry);
+        }
+    }
+
+    let loader_conf = format!("{}/loader/loader.conf", esp);
+    let default = if image.is_file(&loader_conf)? {
+        systemd_boot::parse_loader_conf(&String::from_utf8_lossy(&image.download(&loader_conf)?))
+    } else {
+        None
+    };
+
+    systemd_boot::sort_entries(&mut entries);
+    let entry = systemd_boot::select_entry(&entries, default.as_deref(), config.entry.as_deref())?.clone();
+    debug!("systemd-boot boots entry {}", entry.id);
+
+    let image = match images.remove(&entry.image) {
+        Some(data) => data,
+        None => image.download(&format!("{}{}", esp, entry.image))?,
+    };
+    Ok((entry, image))
+}
+
+/// Measures RTMR1 and RTMR2 of an image booting a type #1 entry or a type #2 UKI
+/// through systemd-boot.
+pub(crate) fn measure_systemd_boot_from_qcow2(image: &GuestImage, config: &SystemdBootConfig) -> Result<(Vec<u8>, Vec<u8>)> {
+    let esp = config.esp.trim_end_matches('/');
+
+    let gpt_data = extract_gpt_event_data(image.qcow2_path)?;
+    let loader_data = image.download(&format!("{}{}", esp, config.loader))?;
+    let (entry, image_data) = select_systemd_boot_entry(image, config)?;
+    let uki = Uki::parse(&image_data)?;
+
+    let mut initrds = Vec::new();
+    for initrd in &entry.initrds {
+        initrds.push(image.download(&format!("{}{}", esp, initrd))?);
+    }
+
+    // Firmware measures systemd-boot and the image systemd-boot loads
+    let mut rtmr1_log = vec![
+        measure_sha384(b"Calling EFI Application from Boot Option"),
+        measure_sha384(&[0x00, 0x00, 0x00, 0x00]), // Separator
+        measure_sha384(&gpt_data),
+        authenticode_sha384_hash(&loader_data).context("Failed to compute systemd-boot hash")?,
+        authenticode_sha384_hash(&image_data).context("Failed to compute boot entry image hash")?,
+    ];
+    if let Some(ref uki) = uki {
+        // systemd-stub starts the embedded kernel with LoadImage
+        rtmr1_log.push(authenticode_sha384_hash(uki.linux()).context("Failed to compute UKI kernel hash")?);
+    }
+    rtmr1_log.push(measure_sha384(b"Exit Boot Services Invocation"));
+    rtmr1_log.push(measure_sha384(b"Exit Boot Services Returned with Success"));
+
+    // systemd-boot measures the options of the entry it starts
+    let mut rtmr2_log = Vec::new();
+    if let Some(ref options) = entry.options {
+        rtmr2_log.push(measure_cmdline(options));
+    }
+    match uki {
+        Some(ref uki) => rtmr2_log.extend(uki::stub_rtmr2_events(uki, entry.options.as_deref(), &initrds)?),
+        None => {
+            // A plain EFI kernel measures its load options and the initrds systemd-boot passes
+            if let Some(ref options) = entry.options {
+                rtmr2_log.push(measure_cmdline(options));
+            }
+            if !initrds.is_empty() {
+                rtmr2_log.push(measure_sha384(&concat_initrds(&initrds)));
+            }
+        }
+    }
+
+    debug_print_log("RTMR1", &rtmr1_log);
+    debug_print_log("RTMR2", &rtmr2_log);
+    Ok((measure_log(&rtmr1_log), measure_log(&rtmr2_log)))
+}
@@ -0,0 +1,276 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Reproducible initrd: a directory tree packed into a newc cpio archive with
+//! normalized metadata, so that the same tree always gives the same bytes.
+
+use anyhow::{bail, Context, Result};
+use flate2::{Compression, GzBuilder};
+use fs_err as fs;
+use std::io::Write;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+use crate::{ImageConfig, InitrdCompression, InitrdTree};
+
+const NEWC_MAGIC: &str = "070701";
+const NEWC_TRAILER: &str = "TRAILER!!!";
+
+/// Metadata of a newc entry, the owner is always root.
+struct NewcHeader {
+    ino: u32,
+    mode: u32,
+    nlink: u32,
+    mtime: u32,
+    rdev: (u32, u32),
+}
+
+/// Writes one newc entry: header, NUL-terminated name and data, each padded to 4 bytes.
+fn write_entry(archive: &mut Vec<u8>, name: &str, header: NewcHeader, data: &[u8]) {
+    let fields = [
+        header.ino,
+        header.mode,
+        0, // uid
+        0, // gid
+        header.nlink,
+        header.mtime,
+        data.len() as u32,
+        0, // devmajor
+        0, // devminor
+        header.rdev.0,
+        header.rdev.1,
+        name.len() as u32 + 1,
+        0, // check
+    ];
+    archive.extend_from_slice(NEWC_MAGIC.as_bytes());
+    for field in fields {
+        archive.extend_from_slice(format!("{field:08X}").as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+/// Lists the entries below `dir`, relative to `root`.
+fn collect_entries(root: &Path, dir: &Path, entries: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let child = entry?.path();
+        entries.push(child.strip_prefix(root)?.to_path_buf());
+        if fs::symlink_metadata(&child)?.is_dir() {
+            collect_entries(root, &child, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the file type bits of a mode with normalized permissions: 0755 for
+/// directories and executables, 0777 for symlinks and 0644 otherwise.
+fn normalize_mode(mode: u32) -> u32 {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFDIR: u32 = 0o040000;
+    const S_IFLNK: u32 = 0o120000;
+    let permissions = match mode & S_IFMT {
+        S_IFDIR => 0o755,
+        S_IFLNK => 0o777,
+        _ if mode & 0o111 != 0 => 0o755,
+        _ => 0o644,
+    };
+    (mode & S_IFMT) | permissions
+}
+
+/// Packs a directory into a newc cpio archive.
+///
+/// Entries are sorted by their full path bytewise (so parents come before
+/// their children), owned by root, stamped with `mtime` and numbered in order.
+/// Permissions are normalized with [`normalize_mode`], setuid, setgid and
+/// sticky bits being dropped; symlink targets and device numbers are kept.
+/// Hard links are stored as separate files.
+pub fn build_cpio(dir: &Path, mtime: u32) -> Result<Vec<u8>> {
+    let mut entries = Vec::new();
+    collect_entries(dir, dir, &mut entries).with_context(|| format!("Failed to list {}", dir.display()))?;
+    entries.sort_by(|a, b| a.as_os_str().as_encoded_bytes().cmp(b.as_os_str().as_encoded_bytes()));
+
+    let mut archive = Vec::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let path = dir.join(entry);
+        let metadata = fs::symlink_metadata(&path)?;
+        let file_type = metadata.file_type();
+        let Some(name) = entry.to_str() else {
+            bail!("Non UTF-8 path in initrd tree: {}", entry.display());
+        };
+
+        let (nlink, rdev, data) = if file_type.is_dir() {
+            (2, (0, 0), vec![])
+        } else if file_type.is_file() {
+            (1, (0, 0), fs::read(&path)?)
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            (1, (0, 0), target.as_os_str().as_encoded_bytes().to_vec())
+        } else if file_type.is_char_device() || file_type.is_block_device() || file_type.is_fifo() {
+            let rdev = metadata.rdev();
+            // Linux encoding of device numbers
+            let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+            let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+            (1, (major as u32, minor as u32), vec![])
+        } else {
+            bail!("Unsupported file type in initrd tree: {}", path.display());
+        };
+
+        let header = NewcHeader { ino: index as u32 + 1, mode: normalize_mode(metadata.mode()), nlink, mtime, rdev };
+        write_entry(&mut archive, name, header, &data);
+    }
+    let trailer = NewcHeader { ino: 0, mode: 0, nlink: 1, mtime: 0, rdev: (0, 0) };
+    write_entry(&mut archive, NEWC_TRAILER, trailer, &[]);
+    Ok(archive)
+}
+
+/// Compresses an archive with fixed settings and no timestamp or file name.
+pub fn compress_initrd(archive: &[u8], compression: InitrdCompression) -> Result<Vec<u8>> {
+    match compression {
+        InitrdCompression::None => Ok(archive.to_vec()),
+        InitrdCompression::Gzip => {
+            let mut encoder = GzBuilder::new().mtime(0).write(Vec::new(), Compression::best());
+            encoder.write_all(archive)?;
+            Ok(encoder.finish()?)
+        }
+    }
+}
+
+impl ImageConfig {
+    /// Builds the initrd of the direct boot configuration from its directory
+    /// tree, if one is given, and appends it to the measured initrds.
+    /// The initrd is written to its `output` path if `write_output` is set,
+    /// and to a temporary file otherwise, which is returned and must be kept
+    /// until measured. `base_dir` is the directory the metadata paths are relative to.
+    pub fn build_initrd(&mut self, base_dir: &Path, write_output: bool) -> Result<Option<NamedTempFile>> {
+        let Some(direct) = self.direct.as_mut() else {
+            return Ok(None);
+        };
+        let Some(InitrdTree { dir, output, compression, mtime }) = &direct.initrd_tree else {
+            return Ok(None);
+        };
+
+        let archive = build_cpio(&base_dir.join(dir), *mtime)?;
+        let initrd = compress_initrd(&archive, *compression).context("Failed to compress initrd")?;
+        if write_output {
+            fs::write(base_dir.join(output), initrd)?;
+            direct.initrd.push(output.clone());
+            return Ok(None);
+        }
+        let mut file = NamedTempFile::with_prefix("tdx-measure-initrd-")?;
+        file.write_all(&initrd)?;
+        direct.initrd.push(file.path().display().to_string());
+        Ok(Some(file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    /// Splits a newc archive into (name, fields, data) entries.
+    fn parse_newc(archive: &[u8]) -> Vec<(String, Vec<u32>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos < archive.len() {
+            assert_eq!(&archive[pos..pos + 6], NEWC_MAGIC.as_bytes());
+            let fields: Vec<u32> = archive[pos + 6..pos + 110]
+                .chunks_exact(8)
+                .map(|f| u32::from_str_radix(std::str::from_utf8(f).unwrap(), 16).unwrap())
+                .collect();
+            let name_start = pos + 110;
+            let name_end = name_start + fields[11] as usize;
+            assert_eq!(archive[name_end - 1], 0);
+            let name = String::from_utf8(archive[name_start..name_end - 1].to_vec()).unwrap();
+            let data_start = name_end.next_multiple_of(4);
+            let data_end = data_start + fields[6] as usize;
+            entries.push((name, fields, archive[data_start..data_end].to_vec()));
+            pos = data_end.next_multiple_of(4);
+        }
+        entries
+    }
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir(root.join("bin")).unwrap();
+        fs::write(root.join("bin/init"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("bin/init"), std::fs::Permissions::from_mode(0o4700)).unwrap();
+        fs::write(root.join("bin.conf"), "conf").unwrap();
+        fs::set_permissions(root.join("bin.conf"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        symlink("bin/init", root.join("init")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn newc_archive() {
+        let dir = tree();
+        let archive = build_cpio(dir.path(), 1700000000).unwrap();
+        assert_eq!(archive.len() % 4, 0);
+
+        let entries = parse_newc(&archive);
+        let names: Vec<&str> = entries.iter().map(|(name, _, _)| name.as_str()).collect();
+        // Bytewise order puts "bin.conf" before the "bin/" children
+        assert_eq!(names, ["bin", "bin.conf", "bin/init", "init", NEWC_TRAILER]);
+
+        // ino, mode, uid, gid, nlink, mtime
+        let headers: Vec<&[u32]> = entries.iter().map(|(_, fields, _)| &fields[..6]).collect();
+        assert_eq!(
+            headers,
+            [
+                &[1, 0o40755, 0, 0, 2, 1700000000][..],
+                &[2, 0o100644, 0, 0, 1, 1700000000],
+                &[3, 0o100755, 0, 0, 1, 1700000000],
+                &[4, 0o120777, 0, 0, 1, 1700000000],
+                &[0, 0, 0, 0, 1, 0],
+            ]
+        );
+        assert_eq!(entries[1].2, b"conf");
+        assert_eq!(entries[2].2, b"#!/bin/sh\n");
+        assert_eq!(entries[3].2, b"bin/init");
+        assert!(entries[4].2.is_empty());
+
+        // The first entry is written byte for byte as the kernel expects it
+        let first = [
+            "070701",
+            "00000001000041ED000000000000000000000002",
+            "6553F100000000000000000000000000",
+            "00000000000000000000000400000000",
+            "bin\0\0\0",
+        ]
+        .concat();
+        assert_eq!(&archive[..first.len()], first.as_bytes());
+
+        assert_eq!(build_cpio(dir.path(), 1700000000).unwrap(), archive);
+    }
+
+    #[test]
+    fn normalized_modes() {
+        assert_eq!(normalize_mode(0o41777), 0o40755);
+        assert_eq!(normalize_mode(0o104750), 0o100755);
+        assert_eq!(normalize_mode(0o100600), 0o100644);
+        assert_eq!(normalize_mode(0o120755), 0o120777);
+        assert_eq!(normalize_mode(0o20620), 0o20644);
+    }
+
+    #[test]
+    fn reproducible_gzip() {
+        let archive = build_cpio(tree().path(), 0).unwrap();
+        let compressed = compress_initrd(&archive, InitrdCompression::Gzip).unwrap();
+        // Deflate, no name nor timestamp, maximum compression
+        assert_eq!(compressed[..9], [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 2]);
+        assert_eq!(compress_initrd(&archive, InitrdCompression::Gzip).unwrap(), compressed);
+
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice()).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, archive);
+        assert_eq!(compress_initrd(&archive, InitrdCompression::None).unwrap(), archive);
+    }
+}
@@ -0,0 +1,337 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Kata Containers profile: turns the hypervisor section of a Kata runtime
+//! configuration (e.g. `configuration-qemu-tdx.toml`) into a direct boot
+//! configuration with the platform settings the runtime starts the TD with.
+
+use anyhow::{anyhow, bail, Context, Result};
+use fs_err as fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::num::read_le;
+use crate::{BootConfig, DirectBoot, ImageConfig, KataConfig, VerityConfig, Vmm};
+
+/// Kernel parameters kata-runtime always passes to a QEMU guest on x86_64.
+const KATA_QEMU_KERNEL_PARAMS: &[&str] = &[
+    "tsc=reliable",
+    "no_timer_check",
+    "rcupdate.rcu_expedited=1",
+    "i8042.direct=1",
+    "i8042.dumbkbd=1",
+    "i8042.nopnp=1",
+    "i8042.noaux=1",
+    "noreplace-smp",
+    "reboot=k",
+    "cryptomgr.notests",
+    "net.ifnames=0",
+    "pci=lastbus=0",
+];
+
+/// Kernel parameters kata-runtime always passes to a Cloud Hypervisor guest.
+const KATA_CLH_KERNEL_PARAMS: &[&str] = &["panic=1", "no_timer_check", "noreplace-smp"];
+
+/// Kernel parameters for a rootfs image attached as the first virtio-blk disk.
+const KATA_IMAGE_ROOTFS_PARAMS: &str = "root=/dev/vda1 rootflags=data=ordered,errors=remount-ro ro";
+
+/// Kernel parameters kata-runtime builds from `kernel_verity_params` for a
+/// rootfs image whose first partition is checked against the hash tree in the
+/// second one. The placeholders are filled in from the computed hash tree.
+const KATA_VERITY_ROOTFS_PARAMS: &str = "root=/dev/dm-0 rootflags=data=ordered,errors=remount-ro ro \
+    dm-mod.create=\"dm-verity,,,ro,0 {data_sectors} verity 1 /dev/vda1 /dev/vda2 \
+    {data_block_size} {hash_block_size} {data_blocks} 0 {hash} {root_hash} {salt}\"";
+
+/// ACPI tables of the TD, when the metadata gives none
+const KATA_DEFAULT_ACPI_TABLES: &str = "acpi_tables.bin";
+
+const SECTOR_SIZE: u64 = 512;
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_PARTITION_TYPE_GPT: u8 = 0xEE;
+
+/// The settings of a Kata hypervisor section that determine the measurements.
+#[derive(Debug, Clone)]
+pub(crate) struct KataHypervisor {
+    pub kernel: String,
+    pub initrd: Option<String>,
+    pub image: Option<String>,
+    pub firmware: String,
+    pub kernel_params: String,
+    pub rootfs_type: String,
+    /// dm-verity settings of the rootfs image, from `kernel_verity_params`
+    pub verity_params: Option<String>,
+    pub vcpus: u32,
+    pub memory_mib: u64,
+    pub debug: bool,
+    pub vmm: Vmm,
+}
+
+impl KataHypervisor {
+    /// Reads the section `[hypervisor.<name>]` of a Kata configuration file.
+    pub(crate) fn load(path: &Path, name: &str) -> Result<Self> {
+        let text = fs::read_to_string(path).context("Failed to read Kata configuration")?;
+        let config: toml::Table = text.parse().context("Failed to parse Kata configuration")?;
+        let section = config
+            .get("hypervisor")
+            .and_then(|h| h.get(name))
+            .and_then(|s| s.as_table())
+            .ok_or_else(|| anyhow!("Kata configuration has no [hypervisor.{name}] section"))?;
+
+        let string = |key: &str| section.get(key).and_then(|v| v.as_str()).map(str::to_string);
+        let integer = |key: &str| section.get(key).and_then(|v| v.as_integer());
+
+        let kernel = string("kernel").ok_or_else(|| anyhow!("Kata configuration has no kernel"))?;
+        let firmware = string("firmware").ok_or_else(|| anyhow!("Kata configuration has no firmware"))?;
+        let initrd = string("initrd").filter(|s| !s.is_empty());
+        let image = string("image").filter(|s| !s.is_empty());
+        if initrd.is_some() && image.is_some() {
+            bail!("Kata configuration cannot have both an image and an initrd");
+        }
+
+        // Defaults of kata-runtime when the keys are absent
+        let vcpus = integer("default_vcpus").filter(|v| *v > 0).unwrap_or(1);
+        let memory_mib = integer("default_memory").filter(|v| *v > 0).unwrap_or(2048);
+
+        Ok(Self {
+            kernel,
+            initrd,
+            image,
+            firmware,
+            kernel_params: string("kernel_params").unwrap_or_default(),
+            rootfs_type: string("rootfs_type").unwrap_or_else(|| "ext4".to_string()),
+            verity_params: string("kernel_verity_params").filter(|s| !s.trim().is_empty()),
+            vcpus: u32::try_from(vcpus).context("Kata default_vcpus out of range")?,
+            memory_mib: memory_mib as u64,
+            debug: section.get("enable_debug").and_then(|v| v.as_bool()).unwrap_or(false),
+            vmm: if name == "clh" { Vmm::CloudHypervisor } else { Vmm::Qemu },
+        })
+    }
+
+    /// Builds the kernel command line the way kata-runtime assembles it for the VMM.
+    pub(crate) fn cmdline(&self) -> String {
+        let mut params = Vec::new();
+        if self.image.is_some() {
+            let rootfs_params = match self.verity_params {
+                Some(_) => KATA_VERITY_ROOTFS_PARAMS,
+                None => KATA_IMAGE_ROOTFS_PARAMS,
+            };
+            params.push(rootfs_params.to_string());
+            params.push(format!("rootfstype={}", self.rootfs_type));
+        }
+        match self.vmm {
+            Vmm::Qemu => {
+                params.extend(KATA_QEMU_KERNEL_PARAMS.iter().map(|p| p.to_string()));
+                params.push("console=hvc0".to_string());
+                params.push("console=hvc1".to_string());
+                params.push(if self.debug { "debug" } else { "quiet" }.to_string());
+                params.push("panic=1".to_string());
+            }
+            Vmm::CloudHypervisor => {
+                params.extend(KATA_CLH_KERNEL_PARAMS.iter().map(|p| p.to_string()));
+                params.push("console=hvc0".to_string());
+                params.push(if self.debug { "debug" } else { "quiet" }.to_string());
+            }
+        }
+        params.push(format!("nr_cpus={}", self.vcpus));
+        params.push("scsi_mod.scan=none".to_string());
+        if !self.kernel_params.trim().is_empty() {
+            params.push(self.kernel_params.trim().to_string());
+        }
+        params.join(" ")
+    }
+}
+
+/// Returns the offset of the first partition of a disk image from its MBR or
+/// GPT, or 0 for an image without partition table.
+fn first_partition_offset(image: &Path) -> Result<u64> {
+    let mut header = vec![0u8; 2 * SECTOR_SIZE as usize];
+    let read = fs::File::open(image)?.read(&mut header)?;
+    header.truncate(read);
+    if header.get(510..512) != Some([0x55, 0xAA].as_slice()) {
+        return Ok(0);
+    }
+    let entry = MBR_PARTITION_TABLE_OFFSET;
+    if header[entry + 4] != MBR_PARTITION_TYPE_GPT {
+        let start_lba: u32 = read_le(&header, entry + 8, "MBR partition start")?;
+        return Ok(start_lba as u64 * SECTOR_SIZE);
+    }
+
+    // Protective MBR: the first entry of the GPT partition array holds the start
+    if header.get(512..520) != Some(b"EFI PART".as_slice()) {
+        bail!("Invalid GPT header in {}", image.display());
+    }
+    let entries_lba: u64 = read_le(&header, 512 + 72, "GPT partition entry LBA")?;
+    let mut file = fs::File::open(image)?;
+    file.seek(SeekFrom::Start(entries_lba * SECTOR_SIZE))?;
+    let mut entry = [0u8; 128];
+    file.read_exact(&mut entry).context("Failed to read GPT partition entry")?;
+    let start_lba: u64 = read_le(&entry, 32, "GPT partition start")?;
+    Ok(start_lba * SECTOR_SIZE)
+}
+
+/// Parses Kata's `kernel_verity_params`, e.g. `root_hash=<hex>,salt=<hex>,
+/// data_blocks=<n>,data_block_size=4096,hash_block_size=4096`, into the root
+/// hash they state and the parameters to compute it from the rootfs partition.
+fn parse_verity_params(params: &str, image: &str, base_dir: &Path) -> Result<(String, VerityConfig)> {
+    let mut root_hash = None;
+    let mut verity = VerityConfig {
+        image: image.to_string(),
+        salt: "-".to_string(),
+        hash: "sha256".to_string(),
+        data_block_size: 4096,
+        hash_block_size: 4096,
+        data_blocks: None,
+        offset: first_partition_offset(&base_dir.join(image))?,
+        format: 1,
+    };
+    for param in params.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (key, value) = param
+            .split_once('=')
+            .with_context(|| format!("Invalid Kata kernel_verity_params entry {param}"))?;
+        let number = || value.parse().with_context(|| format!("Invalid Kata kernel_verity_params {key}"));
+        match key {
+            "root_hash" => root_hash = Some(value.to_lowercase()),
+            "salt" => verity.salt = value.to_string(),
+            "hash" | "hash_algorithm" => verity.hash = value.to_string(),
+            "data_blocks" => verity.data_blocks = Some(number()?),
+            "data_block_size" => verity.data_block_size = number()? as u32,
+            "hash_block_size" => verity.hash_block_size = number()? as u32,
+            _ => bail!("Unknown Kata kernel_verity_params entry {key}"),
+        }
+    }
+    let root_hash = root_hash.context("Kata kernel_verity_params has no root_hash")?;
+    Ok((root_hash, verity))
+}
+
+/// Maps an asset path of the Kata configuration into the assets directory, if one is given.
+fn asset_path(path: &str, assets_dir: Option<&str>) -> String {
+    match assets_dir {
+        Some(dir) => {
+            let file_name = Path::new(path).file_name().map(|f| f.to_string_lossy().into_owned());
+            Path::new(dir).join(file_name.unwrap_or_default()).display().to_string()
+        }
+        None => path.to_string(),
+    }
+}
+
+impl ImageConfig {
+    /// Replaces a Kata profile by the direct boot configuration it stands for.
+    /// `base_dir` is the directory the metadata paths are relative to.
+    pub fn apply_kata_profile(&mut self, base_dir: &Path) -> Result<()> {
+        let Some(kata) = self.kata.take() else {
+            return Ok(());
+        };
+        let KataConfig { configuration, hypervisor, assets_dir, cmdline } = kata;
+
+        let hv = KataHypervisor::load(&base_dir.join(&configuration), &hypervisor)?;
+        let assets_dir = assets_dir.as_deref();
+
+        let boot_config = self.boot_config.get_or_insert_with(BootConfig::default);
+        boot_config.cpus = hv.vcpus;
+        boot_config.memory = format!("{}M", hv.memory_mib);
+        boot_config.bios = asset_path(&hv.firmware, assets_dir);
+        boot_config.vmm = hv.vmm;
+        if boot_config.acpi_tables.is_empty() {
+            boot_config.acpi_tables = KATA_DEFAULT_ACPI_TABLES.to_string();
+        }
+
+        let verity = match (&hv.verity_params, &hv.image) {
+            (Some(params), Some(image)) => Some(parse_verity_params(params, &asset_path(image, assets_dir), base_dir)?),
+            (Some(_), None) => bail!("Kata configuration has kernel_verity_params but no image"),
+            (None, _) => None,
+        };
+        let cmdline = match (cmdline, &verity) {
+            // The root hash computed from the rootfs replaces the one of a pod's command line
+            (Some(cmdline), Some((root_hash, _))) => {
+                if !cmdline.contains(root_hash) {
+                    bail!("The dm-verity root hash of kernel_verity_params is not in kata.cmdline");
+                }
+                cmdline.replace(root_hash, "{root_hash}")
+            }
+            (Some(cmdline), None) => cmdline,
+            (None, _) => hv.cmdline(),
+        };
+
+        self.direct = Some(DirectBoot {
+            kernel: asset_path(&hv.kernel, assets_dir),
+            initrd: hv.initrd.iter().map(|p| asset_path(p, assets_dir)).collect(),
+            cmdline,
+            shim: None,
+            mok_list: None,
+            mok_list_trusted: None,
+            mok_list_x: None,
+            sbat_level: None,
+            initrd_tree: None,
+            verity: verity.map(|(_, verity)| verity),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIGURATION: &str = r#"
+[hypervisor.qemu]
+kernel = "/opt/kata/share/kata-containers/vmlinuz.container"
+image = "/opt/kata/share/kata-containers/kata-containers.img"
+firmware = "/usr/share/ovmf/OVMF.inteltdx.fd"
+kernel_params = "agent.log=debug"
+kernel_verity_params = "root_hash=00112233,salt=0a0b,data_blocks=2,data_block_size=4096,hash_block_size=4096"
+"#;
+
+    fn measured_cmdline(cmdline: Option<&str>) -> Result<String> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("configuration.toml"), CONFIGURATION)?;
+        fs::write(dir.path().join("kata-containers.img"), vec![0x5a; 2 * 4096])?;
+        let mut config = ImageConfig {
+            boot_config: None,
+            direct: None,
+            indirect: None,
+            kata: Some(KataConfig {
+                configuration: "configuration.toml".to_string(),
+                hypervisor: "qemu".to_string(),
+                assets_dir: Some(".".to_string()),
+                cmdline: cmdline.map(str::to_string),
+            }),
+            rtmr3: None,
+        };
+        config.apply_kata_profile(dir.path())?;
+        config.apply_verity(dir.path())?;
+        Ok(config.direct.unwrap().cmdline)
+    }
+
+    #[test]
+    fn verity_cmdline() {
+        let cmdline = measured_cmdline(None).unwrap();
+        let (root_params, rest) = cmdline.split_once(" rootfstype=ext4 ").unwrap();
+        assert_eq!(
+            root_params,
+            format!(
+                "root=/dev/dm-0 rootflags=data=ordered,errors=remount-ro ro dm-mod.create=\"dm-verity,,,ro,0 16 \
+                 verity 1 /dev/vda1 /dev/vda2 4096 4096 2 0 sha256 {} 0a0b\"",
+                expected_root_hash()
+            )
+        );
+        assert!(rest.ends_with("nr_cpus=1 scsi_mod.scan=none agent.log=debug"));
+    }
+
+    /// Root hash of two data blocks of 0x5a, a single hash block holding their salted hashes
+    fn expected_root_hash() -> String {
+        use sha2::{Digest, Sha256};
+        let salt = [0x0a, 0x0b];
+        let block_hash = Sha256::new().chain_update(salt).chain_update([0x5a; 4096]).finalize();
+        let mut hash_block = [block_hash.as_slice(), block_hash.as_slice()].concat();
+        hash_block.resize(4096, 0);
+        hex::encode(Sha256::new().chain_update(salt).chain_update(hash_block).finalize())
+    }
+
+    #[test]
+    fn pod_cmdline() {
+        let cmdline = measured_cmdline(Some("root=/dev/dm-0 verity 00112233 0a0b")).unwrap();
+        assert_eq!(cmdline, format!("root=/dev/dm-0 verity {} 0a0b", expected_root_hash()));
+        assert!(measured_cmdline(Some("root=/dev/vda1")).is_err());
+    }
+}
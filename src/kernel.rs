@@ -4,6 +4,8 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 use crate::{measure_log, measure_sha384, util::debug_print_log, util::authenticode_sha384_hash};
+use crate::uki::{self, Uki};
+use crate::Vmm;
 use anyhow::{bail, Context, Result};
 use fs_err as fs;
 
@@ -21,7 +23,12 @@ fn patch_kernel(
 
     let mut kd = kernel_data.to_vec();
 
-    let protocol = u16::from_le_bytes(kd[0x206..0x208].try_into().unwrap());
+    // Without the HdrS magic, e.g. for a UKI, QEMU takes the image for a protocol 0 kernel
+    let protocol = if &kd[0x202..0x206] == b"HdrS" {
+        u16::from_le_bytes(kd[0x206..0x208].try_into().unwrap())
+    } else {
+        0
+    };
 
     let (real_addr, cmdline_addr) = if protocol < 0x200 || (kd[0x211] & 0x01) == 0 {
         (0x90000_u32, 0x9a000_u32)
@@ -92,54 +99,114 @@ fn patch_kernel(
     Ok(kd)
 }
 
+/// Reads the initrds given to QEMU and concatenates them into the single
+/// ramdisk QEMU hands to the firmware, as `-initrd a,b` does.
+fn read_initrds(initrd_paths: &[String]) -> Result<Vec<u8>> {
+    let mut initrd_data = Vec::new();
+    for path in initrd_paths {
+        let data = fs::read(path).with_context(|| format!("Failed to read initrd file {path}"))?;
+        initrd_data.extend_from_slice(&data);
+    }
+    Ok(initrd_data)
+}
+
 /// Measures a QEMU-patched TDX kernel image from file paths (for direct boot).
+///
+/// A Unified Kernel Image has no Linux setup header, so QEMU patches it as a
+/// protocol 0 kernel, and the embedded kernel started by systemd-stub is
+/// measured too. QEMU refuses an initrd for such a kernel.
+/// With a shim, OVMF starts shim instead, and shim measures the kernel it loads.
+/// Cloud Hypervisor loads the kernel as it is, without QEMU's header patching.
 pub(crate) fn measure_rtmr1_direct(
     kernel_path: &str,
-    initrd_path: &str,
+    initrd_paths: &[String],
+    shim_path: Option<&str>,
+    vmm: Vmm,
     mem_size: u64,
     acpi_data_size: u32,
 ) -> Result<Vec<u8>> {
 
-    // Read kernel and initrd files
+    // Read kernel and initrd files, without initrd QEMU leaves the ramdisk fields untouched
     let kernel_data = fs::read(kernel_path).context("Failed to read kernel file")?;
-    let initrd_data = fs::read(initrd_path).context("Failed to read initrd file")?;
+    let initrd_data = read_initrds(initrd_paths)?;
     let initrd_size = initrd_data.len() as u32;
 
-    // Patch kernel to mimic QEMU's behavior
-    let kd = patch_kernel(&kernel_data, initrd_size, mem_size, acpi_data_size)
-        .context("Failed to patch kernel")?;
-    let kernel_hash = authenticode_sha384_hash(&kd).context("Failed to compute kernel hash")?;
+    let uki = Uki::parse(&kernel_data)?;
+    if uki.is_some() && initrd_size > 0 {
+        bail!("An initrd cannot be passed with a UKI, add it to the UKI instead");
+    }
+    let kernel_hash = if vmm == Vmm::CloudHypervisor {
+        authenticode_sha384_hash(&kernel_data).context("Failed to compute kernel hash")?
+    } else {
+        // Patch kernel to mimic QEMU's behavior
+        let kd = patch_kernel(&kernel_data, initrd_size, mem_size, acpi_data_size)
+            .context("Failed to patch kernel")?;
+        authenticode_sha384_hash(&kd).context("Failed to compute kernel hash")?
+    };
 
     // Compute RTMR1 log
-    let rtmr1_log = vec![
-        kernel_hash,
-        measure_sha384(b"Calling EFI Application from Boot Option"),
-        measure_sha384(&[0x00, 0x00, 0x00, 0x00]), // Separator
-        measure_sha384(b"Exit Boot Services Invocation"),
-        measure_sha384(b"Exit Boot Services Returned with Success"),
-    ];
+    let mut rtmr1_log = Vec::new();
+    if let Some(shim_path) = shim_path {
+        let shim_data = fs::read(shim_path).context("Failed to read shim file")?;
+        rtmr1_log.push(authenticode_sha384_hash(&shim_data).context("Failed to compute shim hash")?);
+        rtmr1_log.push(measure_sha384(b"Calling EFI Application from Boot Option"));
+        rtmr1_log.push(measure_sha384(&[0x00, 0x00, 0x00, 0x00])); // Separator
+        rtmr1_log.push(kernel_hash);
+    } else {
+        rtmr1_log.push(kernel_hash);
+        rtmr1_log.push(measure_sha384(b"Calling EFI Application from Boot Option"));
+        rtmr1_log.push(measure_sha384(&[0x00, 0x00, 0x00, 0x00])); // Separator
+    }
+    if let Some(uki) = uki {
+        rtmr1_log.push(authenticode_sha384_hash(uki.linux()).context("Failed to compute UKI kernel hash")?);
+    }
+    rtmr1_log.push(measure_sha384(b"Exit Boot Services Invocation"));
+    rtmr1_log.push(measure_sha384(b"Exit Boot Services Returned with Success"));
 
     debug_print_log("RTMR1", &rtmr1_log);
     Ok(measure_log(&rtmr1_log))
 }
 
 /// Measures RTMR2 for direct boot from file paths.
+///
+/// OVMF only installs the initrd and adds it to the command line when QEMU
+/// provides a non-empty ramdisk, and the kernel only measures what it gets.
+/// `shim_events` are the events shim logs before it starts the kernel.
 pub(crate) fn measure_rtmr2_direct(
-    initrd_path: &str,
+    kernel_path: &str,
+    initrd_paths: &[String],
     kernel_cmdline: &str,
+    vmm: Vmm,
+    shim_events: Vec<Vec<u8>>,
 ) -> Result<Vec<u8>> {
 
-    // Reads our initrd file
-    let initrd_data = fs::read(initrd_path).context("Failed to read initrd file")?;
+    // Reads our kernel and initrd files
+    let kernel_data = fs::read(kernel_path).context("Failed to read kernel file")?;
+    let initrd_data = read_initrds(initrd_paths)?;
 
-    // OVFM adds initrd to the command line
-    let cmdline = kernel_cmdline.to_string() + " initrd=initrd";
+    // OVFM adds initrd to the command line when it loads the kernel from QEMU
+    let cmdline = if initrd_data.is_empty() || vmm != Vmm::Qemu {
+        kernel_cmdline.to_string()
+    } else {
+        kernel_cmdline.to_string() + " initrd=initrd"
+    };
 
-    // Compute RTMR2 log
-    let rtmr2_log = vec![
-        crate::util::measure_cmdline(&cmdline),
-        measure_sha384(&initrd_data),
-    ];
+    // A UKI is started with the QEMU command line as load options, which
+    // replace its embedded .cmdline
+    let mut rtmr2_log = shim_events;
+    if let Some(uki) = Uki::parse(&kernel_data)? {
+        if !initrd_data.is_empty() {
+            bail!("An initrd cannot be passed with a UKI, add it to the UKI instead");
+        }
+        rtmr2_log.extend(uki::stub_rtmr2_events(&uki, Some(&cmdline), &[])?);
+    } else {
+        if !cmdline.is_empty() {
+            rtmr2_log.push(crate::util::measure_cmdline(&cmdline));
+        }
+        if !initrd_data.is_empty() {
+            rtmr2_log.push(measure_sha384(&initrd_data));
+        }
+    }
 
     debug_print_log("RTMR2", &rtmr2_log);
     Ok(measure_log(&rtmr2_log))
@@ -7,18 +7,49 @@
 use serde::{Deserialize, Serialize};
 use serde_human_bytes as hex_bytes;
 use anyhow::{anyhow, Result};
+use sha2::Digest;
+use std::collections::BTreeMap;
 
 pub use machine::Machine;
+pub use fv::{inspect_firmware, FfsFile, FirmwareReport, FirmwareVersion, FirmwareVolume, TdvfSectionInfo};
+pub use fwdiff::{diff_firmware, FileChange, FileDiff, FirmwareDiff, GpaRange, SectionDiff};
+pub use cfv::{customize_firmware, read_firmware_variables, FirmwareVariable, VariableStore};
+pub use initrd::{build_cpio, compress_initrd};
+pub use boot_options::{build_boot_variables, BootVariables, EfiLoadOption};
+pub use device_path::{DevicePath, DevicePathNode, PartitionSignature};
+pub use memory_search::{qemu_memory_option, MemoryMatch, MemorySearch};
+pub use td_hob::{
+    compare_hob_lists, parse_hob_list, resource_attributes_string, resource_type_name, AcceptedRange, Hob, HobDifference,
+    TdHob,
+};
 
 use util::{measure_log, measure_sha384};
 
 mod acpi;
+mod boot_options;
+mod cfv;
+mod cpu;
+mod deb;
+mod device_path;
+mod fv;
+mod fwdiff;
+mod grub;
 mod kernel;
 mod image;
+mod initrd;
+mod kata;
 mod machine;
+mod memory_search;
 mod num;
+mod platform;
+mod rtmr3;
+mod secure_boot;
+mod systemd_boot;
+mod td_hob;
 mod tdvf;
+mod uki;
 mod util;
+mod verity;
 
 /// Contains all the measurement values for TDX.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,38 +66,530 @@ pub struct TdxMeasurements {
     #[serde(with = "hex_bytes")]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub rtmr2: Vec<u8>,
+    #[serde(with = "hex_bytes", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rtmr3: Vec<u8>,
+    #[serde(with = "hex_bytes", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mrconfigid: Vec<u8>,
+    #[serde(with = "hex_bytes", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mrowner: Vec<u8>,
+    #[serde(with = "hex_bytes", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mrownerconfig: Vec<u8>,
+    /// TD ATTRIBUTES as reported in the TD report (little-endian)
+    #[serde(with = "hex_bytes", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub td_attributes: Vec<u8>,
+    /// XFAM as reported in the TD report (little-endian)
+    #[serde(with = "hex_bytes", default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub xfam: Vec<u8>,
+}
+
+/// Virtual machine monitor that launches the TD
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Vmm {
+    #[default]
+    Qemu,
+    CloudHypervisor,
 }
 
 /// Common boot configuration (platform-specific)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BootConfig {
-    pub cpus: u8,
+    /// Filled from the Kata configuration when a Kata profile is used
+    #[serde(default)]
+    pub cpus: u32,
+    /// Total memory, the sum of the memory of the NUMA nodes when left out
+    #[serde(default)]
     pub memory: String,
+    #[serde(default)]
     pub bios: String,
+    #[serde(default)]
     pub acpi_tables: String,
     pub rsdp: Option<String>,
     pub table_loader: Option<String>,
     pub boot_order: Option<String>,
     pub path_boot_xxxx: Option<String>,
+    #[serde(default)]
+    pub vmm: Vmm,
+    /// Values of the `mrconfigid`, `mrowner` and `mrownerconfig` options of the `tdx-guest` object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mrconfigid: Option<TdRegisterValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mrowner: Option<TdRegisterValue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mrownerconfig: Option<TdRegisterValue>,
+    /// Other options of the `tdx-guest` object
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tdx_guest: Option<TdxGuestConfig>,
+    /// QEMU `-cpu` option, e.g. `host,-pks,pmu=off`, used for TD ATTRIBUTES and XFAM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<String>,
+    /// Variables written into the CFV of `bios`, producing the measured firmware
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware_variables: Option<FirmwareCustomization>,
+    /// Boot#### and BootOrder variables synthesized instead of read from `boot_order` and `path_boot_xxxx`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_options: Option<BootOptions>,
+    /// Topology of the QEMU `-smp` option
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smp: Option<SmpTopology>,
+    /// NUMA nodes of the QEMU `-numa` option, numbered in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub numa: Vec<NumaNode>,
+    /// Memory hotplug slots of the QEMU `-m` option
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_hotplug: Option<MemoryHotplug>,
+}
+
+/// CPU topology of the QEMU `-smp` option, `cpus` being the vCPUs present at boot
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SmpTopology {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sockets: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dies: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cores: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u32>,
+    /// vCPUs that can be hotplugged, `cpus` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maxcpus: Option<u32>,
+}
+
+/// A NUMA node, backed by its own memory backend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NumaNode {
+    pub memory: String,
+    /// vCPU indexes of the node, e.g. `0-127` or `0-3,8-11`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<String>,
+    /// Distance to each node in order, written to the SLIT
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distances: Option<Vec<u8>>,
+}
+
+/// Memory hotplug configuration of the QEMU `-m` option
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryHotplug {
+    pub slots: u32,
+    /// Maximum memory including hotpluggable memory, e.g. `64G`
+    pub maxmem: String,
+}
+
+/// Boot options created by the boot manager of OVMF, or registered by the guest
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootOptions {
+    /// Options, numbered Boot0000, Boot0001, ... in order
+    pub options: Vec<BootOption>,
+    /// BootOrder as option numbers, all options in order by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub order: Option<Vec<u16>>,
+}
+
+/// A Boot#### variable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum BootOption {
+    /// The UiApp boot manager menu of the firmware
+    UiApp,
+    /// The UEFI shell of the firmware
+    Shell,
+    /// A disk found by the boot manager
+    Disk {
+        pci: Vec<PciNode>,
+        #[serde(default)]
+        bus: DiskBus,
+        #[serde(default)]
+        target: u16,
+        #[serde(default)]
+        lun: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+    },
+    /// A NIC found by the boot manager, booting through PXE
+    Pxe {
+        pci: Vec<PciNode>,
+        mac: String,
+        #[serde(default)]
+        ipv6: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        description: Option<String>,
+    },
+    /// A file on a GPT partition, as registered by shim's fallback or efibootmgr
+    File {
+        description: String,
+        partition: u32,
+        partition_guid: String,
+        start_lba: u64,
+        size_lba: u64,
+        /// Path on the partition, e.g. `\EFI\ubuntu\shimx64.efi`
+        path: String,
+        /// Optional data of the option as hex, e.g. arguments passed to the loader
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        optional_data: Option<String>,
+    },
+}
+
+/// Device and function of a PCI device, or of a bridge leading to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PciNode {
+    pub device: u8,
+    #[serde(default)]
+    pub function: u8,
+}
+
+/// Controller of a disk
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskBus {
+    #[default]
+    VirtioBlk,
+    VirtioScsi,
+}
+
+/// Customization of the variables of the firmware CFV
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FirmwareCustomization {
+    /// Path the customized firmware is written to
+    pub output: String,
+    pub variables: Vec<VariableSpec>,
+}
+
+/// A variable to set in the CFV, or to remove when it has no data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VariableSpec {
+    pub name: String,
+    /// EFI global variable GUID by default, image security database GUID for `db`, `dbx`, `dbt` and `dbr`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vendor_guid: Option<String>,
+    /// NV+BS+RT by default, with time-based authenticated write access for Secure Boot variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<u32>,
+    /// Variable data as hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    /// File holding the variable data, e.g. an EFI_SIGNATURE_LIST
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+/// Options of the QEMU `tdx-guest` object that determine TD ATTRIBUTES
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TdxGuestConfig {
+    /// Raw `attributes` value, SEPT_VE_DISABLE only by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributes: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sept_ve_disable: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug: Option<bool>,
+    /// Expected XFAM, instead of deriving it from the CPU features
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xfam: Option<String>,
+}
+
+/// Size of MRCONFIGID, MROWNER and MROWNERCONFIG
+pub const TD_REGISTER_SIZE: usize = 48;
+
+/// Value of a 48-byte TD register set by the VMM at launch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TdRegisterValue {
+    /// Raw value as a hex string of exactly 48 bytes
+    Raw(String),
+    /// Digest of a file, e.g. a launch policy blob
+    File {
+        file: String,
+        #[serde(default)]
+        scheme: TdRegisterScheme,
+    },
+}
+
+/// How a file is turned into a TD register value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TdRegisterScheme {
+    /// SHA384 digest of the file
+    #[default]
+    Sha384,
+    /// SHA256 digest of the file, zero-padded to 48 bytes
+    Sha256,
+    /// File content taken as is, zero-padded to 48 bytes
+    Raw,
+}
+
+impl TdRegisterValue {
+    /// Resolves the register value, with file paths relative to `base_dir`.
+    pub fn resolve(&self, base_dir: &std::path::Path) -> Result<Vec<u8>> {
+        let mut value = match self {
+            TdRegisterValue::Raw(text) => {
+                let value = hex::decode(text.trim()).map_err(|e| anyhow!("Invalid TD register value: {e}"))?;
+                if value.len() != TD_REGISTER_SIZE {
+                    return Err(anyhow!("TD register value is {} bytes, {} are required", value.len(), TD_REGISTER_SIZE));
+                }
+                value
+            }
+            TdRegisterValue::File { file, scheme } => {
+                let data = util::read_file_data(&base_dir.join(file).display().to_string())?;
+                match scheme {
+                    TdRegisterScheme::Sha384 => measure_sha384(&data),
+                    TdRegisterScheme::Sha256 => sha2::Sha256::digest(&data).to_vec(),
+                    TdRegisterScheme::Raw => data,
+                }
+            }
+        };
+        if value.len() > TD_REGISTER_SIZE {
+            return Err(anyhow!("TD register value is {} bytes, at most {} are allowed", value.len(), TD_REGISTER_SIZE));
+        }
+        value.resize(TD_REGISTER_SIZE, 0);
+        Ok(value)
+    }
 }
 
 /// Direct boot specific information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DirectBoot {
     pub kernel: String,
-    pub initrd: String,
+    /// Initrds concatenated by QEMU, given as a single path or a list
+    #[serde(default, deserialize_with = "deserialize_initrds", skip_serializing_if = "Vec::is_empty")]
+    pub initrd: Vec<String>,
     pub cmdline: String,
+    /// Shim passed to QEMU with `-shim`, which then verifies and starts the kernel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shim: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mok_list: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mok_list_trusted: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mok_list_x: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sbat_level: Option<String>,
+    /// Directory tree packed into a reproducible initrd, measured after `initrd`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initrd_tree: Option<InitrdTree>,
+    /// dm-verity rootfs whose root hash is substituted into `cmdline`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verity: Option<VerityConfig>,
+}
+
+/// Directory tree to build an initrd from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InitrdTree {
+    /// Directory packed into the initrd
+    pub dir: String,
+    /// Path the initrd is written to
+    pub output: String,
+    #[serde(default)]
+    pub compression: InitrdCompression,
+    /// Modification time of all entries, e.g. `SOURCE_DATE_EPOCH`
+    #[serde(default)]
+    pub mtime: u32,
+}
+
+/// Compression of a built initrd
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitrdCompression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// dm-verity parameters, with the defaults of `veritysetup format`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerityConfig {
+    /// Rootfs image the hash tree is computed over
+    pub image: String,
+    /// Salt as hex, or `-` for none
+    pub salt: String,
+    #[serde(default = "default_verity_hash")]
+    pub hash: String,
+    #[serde(default = "default_verity_block_size")]
+    pub data_block_size: u32,
+    #[serde(default = "default_verity_block_size")]
+    pub hash_block_size: u32,
+    /// Number of data blocks, the whole image by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_blocks: Option<u64>,
+    /// Offset in bytes of the data in the image, e.g. of the rootfs partition
+    #[serde(default)]
+    pub offset: u64,
+    /// Hash format: 1 (default) or 0 for Chrome OS
+    #[serde(default = "default_verity_format")]
+    pub format: u32,
+}
+
+fn default_verity_hash() -> String {
+    "sha256".to_string()
+}
+
+fn default_verity_block_size() -> u32 {
+    4096
+}
+
+fn default_verity_format() -> u32 {
+    1
+}
+
+/// Accepts `initrd` as a single path or as a list of paths.
+fn deserialize_initrds<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Initrds {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Initrds::deserialize(deserializer)? {
+        Initrds::One(path) => vec![path],
+        Initrds::Many(paths) => paths,
+    })
 }
 
 /// Indirect boot specific information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndirectBoot {
     pub qcow2: String,
+    #[serde(default)]
     pub cmdline: String,
+    #[serde(default)]
     pub mok_list: String,
+    #[serde(default)]
     pub mok_list_trusted: String,
+    #[serde(default)]
     pub mok_list_x: String,
+    #[serde(default)]
     pub sbat_level: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grub: Option<GrubConfig>,
+    /// Boot through systemd-boot instead of shim and GRUB
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub systemd_boot: Option<SystemdBootConfig>,
+    /// Packages and files applied to the image before measuring it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<UpgradeConfig>,
+}
+
+/// Upgrade of an image, measured without booting or modifying it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpgradeConfig {
+    /// .deb packages whose data is unpacked over the image, in order
+    #[serde(default)]
+    pub packages: Vec<String>,
+    /// Guest paths installed from package paths by maintainer scripts,
+    /// the shim and GRUB binaries of the ESP by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub install: Option<BTreeMap<String, String>>,
+    /// Guest paths replaced by host files, e.g. a regenerated initrd or grub.cfg
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+/// systemd-boot configuration of an image booting type #1 entries or type #2 UKIs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemdBootConfig {
+    /// Mount point of the ESP in the guest filesystem
+    #[serde(default = "default_esp")]
+    pub esp: String,
+    /// Path of the systemd-boot binary started by the firmware, relative to the ESP
+    #[serde(default = "default_systemd_boot_loader")]
+    pub loader: String,
+    /// Entry id to boot, overriding the loader.conf default (like `LoaderEntryDefault`)
+    #[serde(default)]
+    pub entry: Option<String>,
+}
+
+fn default_esp() -> String {
+    "/boot/efi".to_string()
+}
+
+fn default_systemd_boot_loader() -> String {
+    "/EFI/systemd/systemd-bootx64.efi".to_string()
+}
+
+/// GRUB configuration replayed to reproduce the events logged by a measuring GRUB build
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrubConfig {
+    /// Path of grub.cfg as GRUB opens it, e.g. `($root)/boot/grub/grub.cfg`
+    #[serde(default = "default_grub_config")]
+    pub config: String,
+    /// Menu entry to boot (index, title or id, `>` separates submenus); defaults to `$default`
+    #[serde(default)]
+    pub entry: Option<String>,
+    /// Initial GRUB environment (e.g. `root`, `prefix`, `cmdpath`)
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Mount point in the guest filesystem of each GRUB device, e.g. `"hd0,gpt15": "/boot/efi"`
+    #[serde(default)]
+    pub devices: BTreeMap<String, String>,
+    /// GRUB device returned by `search` for a UUID, label or file
+    #[serde(default)]
+    pub search: BTreeMap<String, String>,
+}
+
+fn default_grub_config() -> String {
+    "/boot/grub/grub.cfg".to_string()
+}
+
+/// Kata Containers profile, measured as the direct boot kata-runtime performs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KataConfig {
+    /// Kata runtime configuration, e.g. `configuration-qemu-tdx.toml`
+    pub configuration: String,
+    /// Hypervisor section of the configuration to use
+    #[serde(default = "default_kata_hypervisor")]
+    pub hypervisor: String,
+    /// Directory holding the kernel, initrd and firmware referenced by the configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assets_dir: Option<String>,
+    /// Kernel command line, when the runtime adds parameters the profile does not know
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+}
+
+fn default_kata_hypervisor() -> String {
+    "qemu".to_string()
+}
+
+/// An event the workload extends into RTMR3
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rtmr3Event {
+    /// Event name, e.g. `compose-hash`
+    pub name: String,
+    /// Event payload as hex, or the digest itself with the `digest` scheme
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    /// Event payload as text, instead of `payload`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub scheme: Rtmr3Scheme,
+    /// Event type hashed with the `dstack` scheme, 0x08000001 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_type: Option<u32>,
+}
+
+/// How an RTMR3 event payload becomes the digest extended into RTMR3
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rtmr3Scheme {
+    /// SHA384 of the payload
+    #[default]
+    Sha384,
+    /// SHA384 of the event type, name and payload, as dstack extends its events
+    Dstack,
+    /// The payload is the 48-byte digest itself
+    Digest,
 }
 
 /// Complete image configuration
@@ -78,11 +601,32 @@ pub struct ImageConfig {
     pub direct: Option<DirectBoot>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub indirect: Option<IndirectBoot>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kata: Option<KataConfig>,
+    /// Events extended into RTMR3 by the workload, in order
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtmr3: Option<Vec<Rtmr3Event>>,
 }
 
 impl ImageConfig {
     /// Validate that exactly one boot mode is specified
     pub fn validate(&self) -> Result<(), String> {
+        if self.kata.is_some() && (self.direct.is_some() || self.indirect.is_some()) {
+            return Err("Cannot combine a Kata profile with direct or indirect boot".to_string());
+        }
+        if let Some(direct) = self.direct.as_ref().filter(|d| d.shim.is_some()) {
+            let fields = [
+                ("mok_list", &direct.mok_list),
+                ("mok_list_trusted", &direct.mok_list_trusted),
+                ("mok_list_x", &direct.mok_list_x),
+                ("sbat_level", &direct.sbat_level),
+            ];
+            for (name, value) in fields {
+                if value.is_none() {
+                    return Err(format!("{name} is required for direct boot through shim"));
+                }
+            }
+        }
         match (&self.direct, &self.indirect) {
             (Some(_), None) => Ok(()),
             (None, Some(_)) => Ok(()),
@@ -116,7 +660,7 @@ impl ImageConfig {
     }
 
     /// Get CPU count from boot config
-    pub fn cpu_count(&self) -> Result<u8> {
+    pub fn cpu_count(&self) -> Result<u32> {
         let boot_config = self.boot_config.as_ref()
             .ok_or_else(|| anyhow!("Boot config is required"))?;
 
@@ -128,7 +672,7 @@ impl ImageConfig {
         let boot_config = self.boot_config.as_ref()
             .ok_or_else(|| anyhow!("Boot config is required"))?;
 
-        parse_memory_size(&boot_config.memory)
+        boot_config.memory_size()
     }
 }
 
@@ -4,34 +4,162 @@
  * Copyright (c) 2025 Intel Corporation
  * SPDX-License-Identifier: Apache-2.0
  */
+use crate::deb::{self, Overlay};
+use crate::image::GuestImage;
+use crate::memory_search::{self, MemorySearch};
+use crate::secure_boot::{BootImage, VerifiedImages};
+use crate::td_hob::TdHob;
 use crate::tdvf::Tdvf;
-use crate::{kernel, image, TdxMeasurements};
+use crate::{cpu, image, kernel, measure_log, rtmr3, BootOptions, GrubConfig, Rtmr3Event, SystemdBootConfig, TdxGuestConfig, TdxMeasurements, UpgradeConfig, Vmm, TD_REGISTER_SIZE};
 use anyhow::{Context, Result};
 use fs_err as fs;
 use log::debug;
 
 #[derive(Debug, bon::Builder)]
 pub struct Machine<'a> {
-    pub cpu_count: u8,
+    pub cpu_count: u32,
     pub memory_size: u64,
     pub qcow2: Option<&'a str>,
     pub firmware: &'a str,
     pub kernel: Option<&'a str>,
-    pub initrd: Option<&'a str>,
+    #[builder(default)]
+    pub initrds: &'a [String],
     pub kernel_cmdline: &'a str,
     pub acpi_tables: &'a str,
     pub rsdp: &'a str,
     pub table_loader: &'a str,
     pub boot_order: &'a str,
     pub path_boot_xxxx: &'a str,
+    pub boot_options: Option<&'a BootOptions>,
     pub mok_list: Option<&'a str>,
     pub mok_list_trusted: Option<&'a str>,
     pub mok_list_x: Option<&'a str>,
     pub sbat_level: Option<&'a str>,
+    pub shim: Option<&'a str>,
+    pub grub: Option<&'a GrubConfig>,
+    pub systemd_boot: Option<&'a SystemdBootConfig>,
     pub direct_boot: bool,
+    #[builder(default)]
+    pub vmm: Vmm,
+    pub mrconfigid: Option<&'a [u8]>,
+    pub mrowner: Option<&'a [u8]>,
+    pub mrownerconfig: Option<&'a [u8]>,
+    pub tdx_guest: Option<&'a TdxGuestConfig>,
+    pub cpu: Option<&'a str>,
+    pub rtmr3_events: Option<&'a [Rtmr3Event]>,
+    pub upgrade: Option<&'a UpgradeConfig>,
 }
 
 impl Machine<'_> {
+    /// Whether shim is part of the boot chain, creating the MOK and SBAT variables
+    pub(crate) fn boots_through_shim(&self) -> bool {
+        if self.direct_boot {
+            self.shim.is_some()
+        } else {
+            self.systemd_boot.is_none()
+        }
+    }
+
+    /// Builds the overlay of the upgrade and opens the image for indirect boot.
+    fn with_guest_image<T>(&self, f: impl FnOnce(&GuestImage) -> Result<T>) -> Result<T> {
+        let qcow2_path = self.qcow2.ok_or_else(|| anyhow::anyhow!("Qcow2 path required for indirect boot"))?;
+
+        // Files of an upgrade take precedence over those of the image
+        let overlay = match self.upgrade {
+            Some(upgrade) => deb::build_overlay(upgrade).context("Failed to apply upgrade")?,
+            None => Overlay::new(),
+        };
+        f(&GuestImage::new(qcow2_path, &overlay))
+    }
+
+    /// Returns the images verified against the Secure Boot databases, in load order.
+    /// A kernel without EFI stub is started by OVMF's legacy loader, without verification.
+    pub(crate) fn verified_images(&self) -> Result<VerifiedImages> {
+        if !self.direct_boot {
+            return self.with_guest_image(|image| match self.systemd_boot {
+                Some(systemd_boot) => image::systemd_boot_verified_images(image, systemd_boot),
+                None => image::shim_verified_images(image, self.kernel_cmdline, self.grub),
+            });
+        }
+
+        let kernel_path = self.kernel.ok_or_else(|| anyhow::anyhow!("Kernel path required for direct boot"))?;
+        let kernel = BootImage::new("kernel", fs::read(kernel_path).context("Failed to read kernel file")?);
+        Ok(match self.shim {
+            Some(shim_path) => {
+                let shim_data = fs::read(shim_path).context("Failed to read shim file")?;
+                VerifiedImages {
+                    firmware: vec![BootImage::new("shim", shim_data.clone())],
+                    shim: Some((shim_data, vec![kernel])),
+                }
+            }
+            None if object::read::pe::PeFile64::parse(kernel.data.as_slice()).is_ok() => {
+                VerifiedImages { firmware: vec![kernel], shim: None }
+            }
+            None => VerifiedImages::default(),
+        })
+    }
+
+    /// Measures RTMR1 and RTMR2 for direct boot, optionally through shim
+    fn measure_direct(&self, memory_size: u64) -> Result<(Vec<u8>, Vec<u8>)> {
+        let kernel_path = self.kernel.ok_or_else(|| anyhow::anyhow!("Kernel path required for direct boot"))?;
+
+        let shim_events = if self.shim.is_some() {
+            let mok_list_path = self.mok_list.ok_or_else(|| anyhow::anyhow!("MOK list path required for direct boot through shim"))?;
+            let mok_list_trusted_path = self.mok_list_trusted.ok_or_else(|| anyhow::anyhow!("MOK list trusted path required for direct boot through shim"))?;
+            let mok_list_x_path = self.mok_list_x.ok_or_else(|| anyhow::anyhow!("MOK list X path required for direct boot through shim"))?;
+            image::measure_mok_variables(mok_list_path, mok_list_trusted_path, mok_list_x_path)?
+        } else {
+            vec![]
+        };
+
+        let rtmr1 = kernel::measure_rtmr1_direct(kernel_path, self.initrds, self.shim, self.vmm, memory_size, 0x28000)?;
+        let rtmr2 = kernel::measure_rtmr2_direct(kernel_path, self.initrds, self.kernel_cmdline, self.vmm, shim_events)?;
+        Ok((rtmr1, rtmr2))
+    }
+
+    /// Measures RTMR1 and RTMR2 for indirect boot, through shim and GRUB or through systemd-boot
+    fn measure_indirect(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.with_guest_image(|image| {
+            if let Some(systemd_boot) = self.systemd_boot {
+                return image::measure_systemd_boot_from_qcow2(image, systemd_boot);
+            }
+
+            let mok_list_path = self.mok_list.ok_or_else(|| anyhow::anyhow!("MOK list path required for indirect boot"))?;
+            let mok_list_trusted_path = self.mok_list_trusted.ok_or_else(|| anyhow::anyhow!("MOK list trusted path required for indirect boot"))?;
+            let mok_list_x_path = self.mok_list_x.ok_or_else(|| anyhow::anyhow!("MOK list X path required for indirect boot"))?;
+
+            let rtmr1 = image::measure_rtmr1_from_qcow2(image)?;
+            let rtmr2 = image::measure_rtmr2_from_qcow2(image, self.kernel_cmdline, mok_list_path, mok_list_trusted_path, mok_list_x_path, self.grub)?;
+            Ok((rtmr1, rtmr2))
+        })
+    }
+
+    /// Returns a TD register as set at launch, all zeros unless the VMM provides it.
+    fn td_register(value: Option<&[u8]>) -> Vec<u8> {
+        let mut register = value.unwrap_or_default().to_vec();
+        register.resize(TD_REGISTER_SIZE, 0);
+        register
+    }
+
+    /// Returns the expected TD ATTRIBUTES and XFAM, empty without a CPU configuration.
+    fn td_attributes_and_xfam(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self.cpu {
+            Some(cpu) => {
+                let (attributes, xfam) = cpu::td_attributes_and_xfam(self.tdx_guest, cpu)?;
+                Ok((attributes.to_le_bytes().to_vec(), xfam.to_le_bytes().to_vec()))
+            }
+            None => Ok((vec![], vec![])),
+        }
+    }
+
+    /// Measures RTMR3 from the workload events, empty when none are configured.
+    fn measure_rtmr3(&self) -> Result<Vec<u8>> {
+        match self.rtmr3_events {
+            Some(events) => rtmr3::measure_rtmr3(events).context("Failed to compute RTMR3"),
+            None => Ok(vec![]),
+        }
+    }
+
     pub fn measure(&self) -> Result<TdxMeasurements> {
         debug!("measuring machine: {self:#?}");
 
@@ -40,26 +168,21 @@ impl Machine<'_> {
         let tdvf = Tdvf::parse(&fw_data).context("Failed to parse TDVF metadata")?;
         let mrtd = tdvf.mrtd().context("Failed to compute MR TD")?;
         let rtmr0 = tdvf.rtmr0(self).context("Failed to compute RTMR0")?;
+        let (td_attributes, xfam) = self.td_attributes_and_xfam().context("Failed to compute TD ATTRIBUTES and XFAM")?;
 
         let rtmr1;
         let rtmr2;
 
-        // Direct boot
-        if self.direct_boot {
-            let kernel_path = self.kernel.ok_or_else(|| anyhow::anyhow!("Kernel path required for direct boot"))?;
-            let initrd_path = self.initrd.ok_or_else(|| anyhow::anyhow!("Initrd path required for direct boot"))?;
+        // td-shim starts its payload itself and logs nothing in RTMR2
+        if tdvf.is_td_shim() {
+            rtmr1 = tdvf.td_shim_rtmr1(self).context("Failed to compute RTMR1")?;
+            rtmr2 = measure_log(&[]);
 
-            rtmr1 = kernel::measure_rtmr1_direct(kernel_path, initrd_path, self.memory_size, 0x28000)?;
-            rtmr2 = kernel::measure_rtmr2_direct(initrd_path, self.kernel_cmdline)?;
+        } else if self.direct_boot { // Direct boot
+            (rtmr1, rtmr2) = self.measure_direct(self.memory_size)?;
 
         } else { // Indirect boot
-            let qcow2_path = self.qcow2.ok_or_else(|| anyhow::anyhow!("Qcow2 path required for indirect boot"))?;
-            let mok_list_path = self.mok_list.ok_or_else(|| anyhow::anyhow!("MOK list path required for indirect boot"))?;
-            let mok_list_trusted_path = self.mok_list_trusted.ok_or_else(|| anyhow::anyhow!("MOK list trusted path required for indirect boot"))?;
-            let mok_list_x_path = self.mok_list_x.ok_or_else(|| anyhow::anyhow!("MOK list X path required for indirect boot"))?;
-
-            rtmr1 = image::measure_rtmr1_from_qcow2(qcow2_path)?;
-            rtmr2 = image::measure_rtmr2_from_qcow2(qcow2_path, self.kernel_cmdline, mok_list_path, mok_list_trusted_path, mok_list_x_path)?;
+            (rtmr1, rtmr2) = self.measure_indirect()?;
         }
 
         Ok(TdxMeasurements {
@@ -67,6 +190,12 @@ impl Machine<'_> {
             rtmr0,
             rtmr1,
             rtmr2,
+            rtmr3: self.measure_rtmr3()?,
+            mrconfigid: Self::td_register(self.mrconfigid),
+            mrowner: Self::td_register(self.mrowner),
+            mrownerconfig: Self::td_register(self.mrownerconfig),
+            td_attributes,
+            xfam,
         })
     }
 
@@ -75,36 +204,60 @@ impl Machine<'_> {
         let tdvf = Tdvf::parse(&fw_data).context("Failed to parse TDVF metadata")?;
         let mrtd = tdvf.mrtd().context("Failed to compute MR TD")?;
         let rtmr0 = tdvf.rtmr0(self).context("Failed to compute RTMR0")?;
+        let (td_attributes, xfam) = self.td_attributes_and_xfam().context("Failed to compute TD ATTRIBUTES and XFAM")?;
 
         Ok(TdxMeasurements {
             mrtd,
             rtmr0,
             rtmr1: vec![],
             rtmr2: vec![],
+            rtmr3: vec![],
+            mrconfigid: Self::td_register(self.mrconfigid),
+            mrowner: Self::td_register(self.mrowner),
+            mrownerconfig: Self::td_register(self.mrownerconfig),
+            td_attributes,
+            xfam,
         })
     }
 
+    /// Builds the TD HOB the VMM hands to the firmware, the first event of RTMR[0].
+    pub fn td_hob(&self) -> Result<TdHob> {
+        let fw_data = fs::read(self.firmware)?;
+        let tdvf = Tdvf::parse(&fw_data).context("Failed to parse TDVF metadata")?;
+        tdvf.td_hob(self).context("Failed to build TD HOB")
+    }
+
+    /// Searches the memory sizes, in steps of `step` up to `max`, whose TD HOB
+    /// reproduces an observed RTMR[0], with the ACPI tables of the memory size
+    /// of the machine.
+    pub fn search_memory_size(&self, rtmr0: &[u8], step: u64, max: u64) -> Result<MemorySearch> {
+        let fw_data = fs::read(self.firmware)?;
+        let tdvf = Tdvf::parse(&fw_data).context("Failed to parse TDVF metadata")?;
+        memory_search::search_memory_size(&tdvf, self, rtmr0, step, max)
+    }
+
     pub fn measure_runtime(&self) -> Result<TdxMeasurements> {
         let rtmr1;
         let rtmr2;
 
-        // Direct boot
-        if self.direct_boot {
-            let kernel_path = self.kernel.ok_or_else(|| anyhow::anyhow!("Kernel path required for direct boot"))?;
-            let initrd_path = self.initrd.ok_or_else(|| anyhow::anyhow!("Initrd path required for direct boot"))?;
+        // The firmware is optional here, but a td-shim changes the whole boot chain
+        let fw_data = if self.firmware.is_empty() { vec![] } else { fs::read(self.firmware)? };
+        let tdvf = if fw_data.is_empty() {
+            None
+        } else {
+            Some(Tdvf::parse(&fw_data).context("Failed to parse TDVF metadata")?)
+        };
+
+        if let Some(tdvf) = tdvf.filter(|t| t.is_td_shim()) {
+            rtmr1 = tdvf.td_shim_rtmr1(self).context("Failed to compute RTMR1")?;
+            rtmr2 = measure_log(&[]);
 
+        } else if self.direct_boot { // Direct boot
             // WARN : Carefull, when measuring the runtime only, we only compute the measurement for memory size > 0xb0000000
-            rtmr1 = kernel::measure_rtmr1_direct(kernel_path, initrd_path, 0xb0000000, 0x28000)?;
-            rtmr2 = kernel::measure_rtmr2_direct(initrd_path, self.kernel_cmdline)?;
+            (rtmr1, rtmr2) = self.measure_direct(0xb0000000)?;
 
         } else { // Indirect boot
-            let qcow2_path = self.qcow2.ok_or_else(|| anyhow::anyhow!("Qcow2 path required for indirect boot"))?;
-            let mok_list_path = self.mok_list.ok_or_else(|| anyhow::anyhow!("MOK list path required for indirect boot"))?;
-            let mok_list_trusted_path = self.mok_list_trusted.ok_or_else(|| anyhow::anyhow!("MOK list trusted path required for indirect boot"))?;
-            let mok_list_x_path = self.mok_list_x.ok_or_else(|| anyhow::anyhow!("MOK list X path required for indirect boot"))?;
-
-            rtmr1 = image::measure_rtmr1_from_qcow2(qcow2_path)?;
-            rtmr2 = image::measure_rtmr2_from_qcow2(qcow2_path, self.kernel_cmdline, mok_list_path, mok_list_trusted_path, mok_list_x_path)?;
+            (rtmr1, rtmr2) = self.measure_indirect()?;
         }
 
         Ok(TdxMeasurements {
@@ -112,6 +265,12 @@ impl Machine<'_> {
             rtmr0: vec![],
             rtmr1,
             rtmr2,
+            rtmr3: self.measure_rtmr3()?,
+            mrconfigid: vec![],
+            mrowner: vec![],
+            mrownerconfig: vec![],
+            td_attributes: vec![],
+            xfam: vec![],
         })
     }
 }
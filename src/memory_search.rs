@@ -0,0 +1,171 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Search of the memory sizes that reproduce an observed RTMR[0] through the
+//! TD HOB. The ACPI tables also depend on the memory size, e.g. the 64-bit PCI
+//! hole of the QEMU DSDT `_CRS` and the SRAT with NUMA nodes or memory hotplug,
+//! but are held at those given for the memory size of the machine.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_human_bytes as hex_bytes;
+use std::collections::{BTreeSet, HashSet};
+
+use crate::tdvf::{Tdvf, CLOUD_HYPERVISOR_MEM_32BIT_RESERVED_START, QEMU_LOWMEM_SPLIT_SIZE};
+use crate::util::{measure_log, read_file_data};
+use crate::{Machine, Vmm};
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+/// Most memory sizes a search tries, e.g. 1T in steps of 1M
+const MAX_CANDIDATES: u64 = 1 << 20;
+
+/// A memory size whose TD HOB reproduces the observed RTMR[0].
+#[derive(Debug, Clone, Serialize)]
+pub struct MemoryMatch {
+    pub memory_size: u64,
+    /// Value of the QEMU `-m` option, e.g. `4G`
+    pub qemu_option: String,
+    #[serde(with = "hex_bytes")]
+    pub td_hob_digest: Vec<u8>,
+}
+
+/// Outcome of a memory size search.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemorySearch {
+    /// Number of memory sizes tried
+    pub candidates: usize,
+    /// Whether each size tried gives a different TD HOB, so that a match is unambiguous
+    pub distinct_td_hobs: bool,
+    /// Memory size the ACPI tables are generated for, a match at another size
+    /// only holds if they do not depend on it
+    pub acpi_memory_size: u64,
+    pub matches: Vec<MemoryMatch>,
+}
+
+/// Formats a memory size as the QEMU `-m` option, with the largest exact suffix.
+pub fn qemu_memory_option(memory_size: u64) -> String {
+    if memory_size.is_multiple_of(GIB) {
+        format!("{}G", memory_size / GIB)
+    } else if memory_size.is_multiple_of(MIB) {
+        format!("{}M", memory_size / MIB)
+    } else {
+        format!("{}K", memory_size / KIB)
+    }
+}
+
+/// Returns the multiples of `step` up to `max`, along with the sizes on both
+/// sides of the boundary where the VMM changes how it lays out RAM.
+fn candidate_sizes(vmm: Vmm, step: u64, max: u64) -> Result<BTreeSet<u64>> {
+    if max / step > MAX_CANDIDATES {
+        bail!(
+            "Searching {} memory sizes exceeds the limit of {MAX_CANDIDATES}, use a larger memory step or a smaller maximum",
+            max / step
+        );
+    }
+    let mut sizes: BTreeSet<u64> = (1..=max / step).map(|n| n * step).collect();
+    let boundary = match vmm {
+        Vmm::Qemu => QEMU_LOWMEM_SPLIT_SIZE,
+        Vmm::CloudHypervisor => CLOUD_HYPERVISOR_MEM_32BIT_RESERVED_START,
+    };
+    for size in [boundary - MIB, boundary, boundary + MIB] {
+        if size <= max {
+            sizes.insert(size);
+        }
+    }
+    Ok(sizes)
+}
+
+/// Tries every candidate memory size up to `max` and returns those whose
+/// RTMR[0] matches, every other input being taken from the machine.
+pub(crate) fn search_memory_size(
+    tdvf: &Tdvf,
+    machine: &Machine,
+    rtmr0: &[u8],
+    step: u64,
+    max: u64,
+) -> Result<MemorySearch> {
+    if step == 0 || !step.is_multiple_of(4 * KIB) {
+        bail!("Memory step must be a non-zero multiple of 4K");
+    }
+    if max < step {
+        bail!("Maximum memory size must be at least the memory step");
+    }
+
+    // Only the TD HOB, the first event, is rebuilt for each memory size
+    let mut rtmr0_log = tdvf.rtmr0_log(machine)?;
+    let acpi_tables = match machine.vmm {
+        Vmm::Qemu => Vec::new(),
+        Vmm::CloudHypervisor => read_file_data(machine.acpi_tables)?,
+    };
+
+    let sizes = candidate_sizes(machine.vmm, step, max)?;
+    let mut digests = HashSet::new();
+    let mut matches = Vec::new();
+    for &memory_size in &sizes {
+        let td_hob_digest = tdvf.build_td_hob(machine.vmm, memory_size, &acpi_tables)?.digest();
+        rtmr0_log[0] = td_hob_digest.clone();
+        if measure_log(&rtmr0_log) == rtmr0 {
+            matches.push(MemoryMatch {
+                memory_size,
+                qemu_option: qemu_memory_option(memory_size),
+                td_hob_digest: td_hob_digest.clone(),
+            });
+        }
+        digests.insert(td_hob_digest);
+    }
+
+    Ok(MemorySearch {
+        candidates: sizes.len(),
+        distinct_td_hobs: digests.len() == sizes.len(),
+        acpi_memory_size: machine.memory_size,
+        matches,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qemu_candidates() {
+        let sizes: Vec<u64> = candidate_sizes(Vmm::Qemu, GIB, 4 * GIB).unwrap().into_iter().collect();
+        assert_eq!(
+            sizes,
+            [GIB, 2 * GIB, QEMU_LOWMEM_SPLIT_SIZE - MIB, QEMU_LOWMEM_SPLIT_SIZE, QEMU_LOWMEM_SPLIT_SIZE + MIB, 3 * GIB, 4 * GIB]
+        );
+
+        // Sizes around the split that are multiples of the step are tried once
+        let sizes = candidate_sizes(Vmm::Qemu, MIB, 3 * GIB).unwrap();
+        assert_eq!(sizes.len(), 3072);
+        assert!(sizes.contains(&(QEMU_LOWMEM_SPLIT_SIZE + MIB)));
+
+        // The boundary is not searched beyond the maximum
+        let sizes: Vec<u64> = candidate_sizes(Vmm::Qemu, GIB, 2 * GIB).unwrap().into_iter().collect();
+        assert_eq!(sizes, [GIB, 2 * GIB]);
+        let sizes = candidate_sizes(Vmm::Qemu, GIB, QEMU_LOWMEM_SPLIT_SIZE).unwrap();
+        assert!(sizes.contains(&QEMU_LOWMEM_SPLIT_SIZE) && !sizes.contains(&(QEMU_LOWMEM_SPLIT_SIZE + MIB)));
+    }
+
+    #[test]
+    fn cloud_hypervisor_candidates() {
+        let sizes: Vec<u64> = candidate_sizes(Vmm::CloudHypervisor, 2 * GIB, 4 * GIB).unwrap().into_iter().collect();
+        assert_eq!(sizes, [2 * GIB, 3 * GIB - MIB, 3 * GIB, 3 * GIB + MIB, 4 * GIB]);
+    }
+
+    #[test]
+    fn candidate_limit() {
+        assert_eq!(candidate_sizes(Vmm::Qemu, MIB, 1024 * GIB).unwrap().len(), 1 << 20);
+        assert!(candidate_sizes(Vmm::Qemu, 4 * KIB, 1024 * GIB).is_err());
+    }
+
+    #[test]
+    fn memory_options() {
+        assert_eq!(qemu_memory_option(4 * GIB), "4G");
+        assert_eq!(qemu_memory_option(3000 * MIB), "3000M");
+        assert_eq!(qemu_memory_option(GIB + 4 * KIB), "1048580K");
+    }
+}
@@ -27,6 +27,13 @@ impl Num for u32 {
     }
 }
 
+impl Num for u64 {
+    fn read_le(data: &[u8]) -> Option<Self> {
+        let bytes = data.get(0..8)?.try_into().ok()?;
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+
 pub(crate) fn read_le<T: Num>(data: &[u8], index: usize, name: &str) -> Result<T> {
     let data = &data
         .get(index..)
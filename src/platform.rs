@@ -0,0 +1,107 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Platform model of the boot configuration: memory size, CPU topology, NUMA
+//! nodes and memory hotplug, as given to QEMU.
+
+use anyhow::{bail, Context, Result};
+
+use crate::memory_search::qemu_memory_option;
+use crate::{parse_memory_size, BootConfig};
+
+/// Distance of a NUMA node to itself, which QEMU requires
+const NUMA_LOCAL_DISTANCE: u8 = 10;
+
+impl BootConfig {
+    /// Returns the memory size, the sum of the memory of the NUMA nodes when
+    /// `memory` is left out, which must otherwise match it.
+    pub fn memory_size(&self) -> Result<u64> {
+        let mut numa_memory = 0;
+        for (i, node) in self.numa.iter().enumerate() {
+            numa_memory += parse_memory_size(&node.memory).with_context(|| format!("Invalid memory of NUMA node {i}"))?;
+        }
+        if self.memory.is_empty() {
+            if self.numa.is_empty() {
+                bail!("Memory size required, set `memory` or the memory of the NUMA nodes");
+            }
+            return Ok(numa_memory);
+        }
+
+        let memory = parse_memory_size(&self.memory)?;
+        if !self.numa.is_empty() && numa_memory != memory {
+            bail!("Memory of the NUMA nodes ({numa_memory:#x}) does not add up to the memory size ({memory:#x})");
+        }
+        Ok(memory)
+    }
+
+    /// Returns the QEMU `-smp`, `-m`, `-object` and `-numa` options of the
+    /// platform, which shape the ACPI tables QEMU generates (MADT, SRAT, SLIT).
+    pub fn qemu_platform_args(&self) -> Result<Vec<String>> {
+        if self.cpus == 0 {
+            bail!("Number of vCPUs required");
+        }
+        let mut smp = self.cpus.to_string();
+        if let Some(topology) = &self.smp {
+            if topology.maxcpus.is_some_and(|maxcpus| maxcpus < self.cpus) {
+                bail!("maxcpus is lower than the number of vCPUs");
+            }
+            let fields = [
+                ("sockets", topology.sockets),
+                ("dies", topology.dies),
+                ("cores", topology.cores),
+                ("threads", topology.threads),
+                ("maxcpus", topology.maxcpus),
+            ];
+            for (name, value) in fields {
+                if let Some(value) = value {
+                    smp.push_str(&format!(",{name}={value}"));
+                }
+            }
+        }
+
+        let memory_size = self.memory_size()?;
+        let mut memory = qemu_memory_option(memory_size);
+        if let Some(hotplug) = &self.memory_hotplug {
+            let maxmem = parse_memory_size(&hotplug.maxmem).context("Invalid maxmem")?;
+            if maxmem < memory_size {
+                bail!("maxmem is lower than the memory size");
+            }
+            memory.push_str(&format!(",slots={},maxmem={}", hotplug.slots, qemu_memory_option(maxmem)));
+        }
+
+        let mut args = vec!["-smp".to_string(), smp, "-m".to_string(), memory];
+        for (i, node) in self.numa.iter().enumerate() {
+            let size = parse_memory_size(&node.memory)?;
+            args.push("-object".to_string());
+            args.push(format!("memory-backend-ram,id=ram-node{i},size={}", qemu_memory_option(size)));
+
+            let mut numa = format!("node,nodeid={i},memdev=ram-node{i}");
+            for range in node.cpus.iter().flat_map(|cpus| cpus.split(',')) {
+                numa.push_str(&format!(",cpus={}", range.trim()));
+            }
+            args.push("-numa".to_string());
+            args.push(numa);
+        }
+
+        for (i, node) in self.numa.iter().enumerate() {
+            let Some(distances) = &node.distances else {
+                continue;
+            };
+            if distances.len() != self.numa.len() {
+                bail!("NUMA node {i} must list a distance to each of the {} nodes", self.numa.len());
+            }
+            for (j, &distance) in distances.iter().enumerate() {
+                if i == j {
+                    if distance != NUMA_LOCAL_DISTANCE {
+                        bail!("Distance of NUMA node {i} to itself must be {NUMA_LOCAL_DISTANCE}");
+                    }
+                    continue;
+                }
+                args.push("-numa".to_string());
+                args.push(format!("dist,src={i},dst={j},val={distance}"));
+            }
+        }
+        Ok(args)
+    }
+}
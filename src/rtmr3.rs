@@ -0,0 +1,61 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! RTMR3 computed from the events the workload extends at runtime.
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::util::debug_print_log;
+use crate::{measure_log, measure_sha384, Rtmr3Event, Rtmr3Scheme};
+
+/// Event type dstack uses for the events it extends into RTMR3.
+pub(crate) const DSTACK_EVENT_TYPE: u32 = 0x08000001;
+
+impl Rtmr3Event {
+    /// Returns the payload of the event, given as hex or as text.
+    fn payload(&self) -> Result<Vec<u8>> {
+        match (&self.payload, &self.text) {
+            (Some(_), Some(_)) => bail!("RTMR3 event {} has both a payload and a text", self.name),
+            (Some(payload), None) => hex::decode(payload)
+                .map_err(|e| anyhow!("Invalid payload of RTMR3 event {}: {e}", self.name)),
+            (None, Some(text)) => Ok(text.as_bytes().to_vec()),
+            (None, None) => bail!("RTMR3 event {} has no payload", self.name),
+        }
+    }
+
+    /// Computes the digest extended into RTMR3 for this event.
+    pub(crate) fn digest(&self) -> Result<Vec<u8>> {
+        match self.scheme {
+            Rtmr3Scheme::Digest => {
+                let digest = self.payload()?;
+                if digest.len() != 48 {
+                    bail!("Digest of RTMR3 event {} must be 48 bytes, got {}", self.name, digest.len());
+                }
+                Ok(digest)
+            }
+            Rtmr3Scheme::Sha384 => Ok(measure_sha384(&self.payload()?)),
+            Rtmr3Scheme::Dstack => {
+                // SHA384(event type || ":" || name || ":" || payload)
+                let event_type = self.event_type.unwrap_or(DSTACK_EVENT_TYPE);
+                let mut data = event_type.to_le_bytes().to_vec();
+                data.push(b':');
+                data.extend_from_slice(self.name.as_bytes());
+                data.push(b':');
+                data.extend_from_slice(&self.payload()?);
+                Ok(measure_sha384(&data))
+            }
+        }
+    }
+}
+
+/// Measures RTMR3 from the ordered list of events extended by the workload.
+pub(crate) fn measure_rtmr3(events: &[Rtmr3Event]) -> Result<Vec<u8>> {
+    let rtmr3_log = events
+        .iter()
+        .map(Rtmr3Event::digest)
+        .collect::<Result<Vec<_>>>()?;
+
+    debug_print_log("RTMR3", &rtmr3_log);
+    Ok(measure_log(&rtmr3_log))
+}
@@ -0,0 +1,410 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Secure Boot verification of the boot images, as OVMF and shim do it, and
+//! the EV_EFI_VARIABLE_AUTHORITY events they log in RTMR0 for the database
+//! entry that allows each image. A certificate entry allows an image when it
+//! is one of the certificates of its Authenticode signature or the issuer of
+//! one of them; the signatures themselves are not checked.
+
+use anyhow::{anyhow, bail, Context, Result};
+use object::read::pe::PeFile64;
+use object::{pe, Object, ObjectSection};
+
+use crate::cfv::{format_guid, EFI_IMAGE_SECURITY_DATABASE_GUID};
+use crate::num::read_le;
+use crate::tdvf::measure_tdx_efi_variable;
+use crate::util::authenticode_sha256_hash;
+
+const EFI_CERT_SHA256_GUID: &str = "C1C41626-504C-4092-ACA9-41F936934328";
+const EFI_CERT_X509_GUID: &str = "A5C059A1-94E4-4AA7-87B5-AB155C2BF072";
+pub(crate) const SHIM_LOCK_GUID: &str = "605DAB50-E046-4300-ABB6-3DD810DD8B23";
+
+const SIGNATURE_LIST_HEADER_SIZE: usize = 28;
+const WIN_CERTIFICATE_HEADER_SIZE: usize = 8;
+const WIN_CERT_TYPE_PKCS_SIGNED_DATA: u16 = 0x0002;
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_CONTEXT_0: u8 = 0xa0;
+
+/// A boot image verified before it starts.
+#[derive(Debug)]
+pub(crate) struct BootImage {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl BootImage {
+    pub(crate) fn new(name: &str, data: Vec<u8>) -> Self {
+        BootImage { name: name.to_string(), data }
+    }
+}
+
+/// Images verified during boot, in load order.
+#[derive(Debug, Default)]
+pub(crate) struct VerifiedImages {
+    /// Images OVMF starts with LoadImage
+    pub firmware: Vec<BootImage>,
+    /// shim and the images it verifies, when booting through shim
+    pub shim: Option<(Vec<u8>, Vec<BootImage>)>,
+}
+
+/// An entry of a signature database.
+struct Signature<'a> {
+    signature_type: String,
+    /// EFI_SIGNATURE_DATA, the owner GUID followed by the hash or certificate
+    data: &'a [u8],
+}
+
+impl Signature<'_> {
+    fn value(&self) -> &[u8] {
+        &self.data[16..]
+    }
+}
+
+/// Parses the EFI_SIGNATURE_LISTs of a signature database such as db or MokList.
+fn parse_signature_lists(data: &[u8]) -> Result<Vec<Signature<'_>>> {
+    let mut signatures = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let signature_type = format_guid(data.get(offset..offset + 16).context("Truncated signature list")?);
+        let list_size = read_le::<u32>(data, offset + 16, "signature list size")? as usize;
+        let header_size = read_le::<u32>(data, offset + 20, "signature header size")? as usize;
+        let signature_size = read_le::<u32>(data, offset + 24, "signature size")? as usize;
+        let list = data
+            .get(offset..offset + list_size)
+            .filter(|_| list_size >= SIGNATURE_LIST_HEADER_SIZE)
+            .with_context(|| format!("Invalid size {list_size} of signature list at {offset:#x}"))?;
+        let entries = list
+            .get(SIGNATURE_LIST_HEADER_SIZE + header_size..)
+            .context("Signature list header beyond the list")?;
+        if signature_size < 16 || entries.len() % signature_size != 0 {
+            bail!("Invalid signature size {signature_size} in signature list at {offset:#x}");
+        }
+        for data in entries.chunks_exact(signature_size) {
+            signatures.push(Signature { signature_type: signature_type.clone(), data });
+        }
+        offset += list_size;
+    }
+    Ok(signatures)
+}
+
+/// A DER element, with its whole encoding and its contents.
+struct Der<'a> {
+    tag: u8,
+    encoding: &'a [u8],
+    contents: &'a [u8],
+}
+
+/// Reads the DER element at the start of `data`, returning it with the bytes that follow.
+fn read_der(data: &[u8]) -> Result<(Der<'_>, &[u8])> {
+    let tag = *data.first().context("Truncated DER element")?;
+    let first = *data.get(1).context("Truncated DER element")?;
+    let (length, header_size) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 {
+            bail!("Unsupported DER length encoding {first:#x}");
+        }
+        let bytes = data.get(2..2 + count).context("Truncated DER length")?;
+        (bytes.iter().fold(0, |length, b| (length << 8) | *b as usize), 2 + count)
+    };
+    let encoding = data.get(..header_size + length).context("Truncated DER element")?;
+    let der = Der { tag, encoding, contents: &encoding[header_size..] };
+    Ok((der, &data[header_size + length..]))
+}
+
+/// Returns the elements of a constructed DER element.
+fn der_children<'a>(element: &Der<'a>) -> Result<Vec<Der<'a>>> {
+    let mut children = Vec::new();
+    let mut rest = element.contents;
+    while !rest.is_empty() {
+        let (child, next) = read_der(rest)?;
+        children.push(child);
+        rest = next;
+    }
+    Ok(children)
+}
+
+/// Returns the certificates of a PKCS#7 ContentInfo holding SignedData.
+fn pkcs7_certificates(data: &[u8]) -> Result<Vec<&[u8]>> {
+    let (content_info, _) = read_der(data)?;
+    let content = der_children(&content_info)?
+        .into_iter()
+        .find(|e| e.tag == DER_CONTEXT_0)
+        .context("PKCS#7 signature without content")?;
+    let (signed_data, _) = read_der(content.contents)?;
+    let Some(certificates) = der_children(&signed_data)?.into_iter().find(|e| e.tag == DER_CONTEXT_0) else {
+        return Ok(Vec::new());
+    };
+    Ok(der_children(&certificates)?
+        .into_iter()
+        .filter(|c| c.tag == DER_SEQUENCE)
+        .map(|c| c.encoding)
+        .collect())
+}
+
+/// Returns the encoded issuer and subject names of an X.509 certificate.
+fn issuer_and_subject(certificate: &[u8]) -> Result<(&[u8], &[u8])> {
+    let (certificate, _) = read_der(certificate)?;
+    let (tbs_certificate, _) = read_der(certificate.contents)?;
+    let mut fields = der_children(&tbs_certificate)?;
+    if fields.first().is_some_and(|f| f.tag == DER_CONTEXT_0) {
+        fields.remove(0); // version
+    }
+    // serialNumber, signature, issuer, validity, subject
+    match fields.as_slice() {
+        [_, _, issuer, _, subject, ..] => Ok((issuer.encoding, subject.encoding)),
+        _ => bail!("Invalid X.509 certificate"),
+    }
+}
+
+/// Returns the certificates of the Authenticode signatures of a PE image,
+/// none for an unsigned image.
+fn signature_certificates(image: &[u8]) -> Result<Vec<&[u8]>> {
+    let pe = PeFile64::parse(image).map_err(|e| anyhow!("Invalid PE image: {e}"))?;
+    let Some(directory) = pe.data_directory(pe::IMAGE_DIRECTORY_ENTRY_SECURITY) else {
+        return Ok(Vec::new());
+    };
+    // The certificate table is addressed by file offset
+    let (offset, size) = directory.address_range();
+    let table = image
+        .get(offset as usize..(offset + size) as usize)
+        .context("Certificate table beyond the image")?;
+
+    let mut certificates = Vec::new();
+    let mut position = 0;
+    while position + WIN_CERTIFICATE_HEADER_SIZE <= table.len() {
+        let length = read_le::<u32>(table, position, "WIN_CERTIFICATE length")? as usize;
+        let certificate_type = read_le::<u16>(table, position + 6, "WIN_CERTIFICATE type")?;
+        let certificate = table
+            .get(position + WIN_CERTIFICATE_HEADER_SIZE..position + length)
+            .filter(|_| length > WIN_CERTIFICATE_HEADER_SIZE)
+            .context("Invalid WIN_CERTIFICATE length")?;
+        if certificate_type == WIN_CERT_TYPE_PKCS_SIGNED_DATA {
+            certificates.extend(pkcs7_certificates(certificate).context("Invalid Authenticode signature")?);
+        }
+        position += length.next_multiple_of(8);
+    }
+    Ok(certificates)
+}
+
+/// Returns whether a trusted certificate allows an image signed with
+/// `certificates`: it is one of them or the issuer of one of them.
+fn certificate_allows(trusted: &[u8], certificates: &[&[u8]]) -> Result<bool> {
+    let (_, subject) = issuer_and_subject(trusted)?;
+    for certificate in certificates {
+        if *certificate == trusted || issuer_and_subject(certificate)?.0 == subject {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Finds the entry of a signature database that allows an image, checking
+/// the certificates first and then the SHA256 Authenticode hash.
+fn find_allowing<'a>(database: &[Signature<'a>], certificates: &[&[u8]], hash: &[u8]) -> Result<Option<&'a [u8]>> {
+    for signature in database.iter().filter(|s| s.signature_type == EFI_CERT_X509_GUID) {
+        if certificate_allows(signature.value(), certificates)? {
+            return Ok(Some(signature.data));
+        }
+    }
+    Ok(database
+        .iter()
+        .find(|s| s.signature_type == EFI_CERT_SHA256_GUID && s.value() == hash)
+        .map(|s| s.data))
+}
+
+/// Returns the vendor certificate built into shim, from its `.vendor_cert` section.
+fn shim_vendor_cert(shim: &[u8]) -> Result<Option<&[u8]>> {
+    let file = object::File::parse(shim).map_err(|e| anyhow!("Invalid shim image: {e}"))?;
+    let Some(section) = file.section_by_name(".vendor_cert") else {
+        return Ok(None);
+    };
+    let table = section.data().map_err(|e| anyhow!("Invalid shim .vendor_cert section: {e}"))?;
+    let size = read_le::<u32>(table, 0, "vendor certificate size")? as usize;
+    let offset = read_le::<u32>(table, 8, "vendor certificate offset")? as usize;
+    if size == 0 {
+        return Ok(None);
+    }
+    let certificate = table
+        .get(offset..offset + size)
+        .context("Vendor certificate beyond the .vendor_cert section")?;
+    if certificate.first() != Some(&DER_SEQUENCE) {
+        bail!("shim with a vendor database instead of a vendor certificate is not supported");
+    }
+    Ok(Some(certificate))
+}
+
+/// Appends an authority event, which firmware and shim each log only once.
+fn push_once(events: &mut Vec<Vec<u8>>, vendor_guid: &str, name: &str, data: &[u8]) -> Result<()> {
+    let event = measure_tdx_efi_variable(vendor_guid, name, Some(data))?;
+    if !events.contains(&event) {
+        events.push(event);
+    }
+    Ok(())
+}
+
+/// Returns the authority events OVMF logs for the images it verifies against db.
+pub(crate) fn firmware_authority_events(db: &[u8], images: &[BootImage]) -> Result<Vec<Vec<u8>>> {
+    let db = parse_signature_lists(db).context("Invalid db")?;
+    let mut events = Vec::new();
+    for image in images {
+        let certificates = signature_certificates(&image.data).with_context(|| format!("Invalid {}", image.name))?;
+        let hash = authenticode_sha256_hash(&image.data)?;
+        let data = find_allowing(&db, &certificates, &hash)?
+            .with_context(|| format!("{} is not allowed by db, OVMF would refuse to start it", image.name))?;
+        push_once(&mut events, EFI_IMAGE_SECURITY_DATABASE_GUID, "db", data)?;
+    }
+    Ok(events)
+}
+
+/// Returns the authority events shim logs for the images it verifies,
+/// against db, then MokList, then its vendor certificate.
+pub(crate) fn shim_authority_events(db: &[u8], mok_list: &[u8], shim: &[u8], images: &[BootImage]) -> Result<Vec<Vec<u8>>> {
+    let db = parse_signature_lists(db).context("Invalid db")?;
+    let mok_list = parse_signature_lists(mok_list).context("Invalid MokList")?;
+    let vendor_cert = shim_vendor_cert(shim)?;
+    let mut events = Vec::new();
+    for image in images {
+        let certificates = signature_certificates(&image.data).with_context(|| format!("Invalid {}", image.name))?;
+        let hash = authenticode_sha256_hash(&image.data)?;
+        if let Some(data) = find_allowing(&db, &certificates, &hash)? {
+            push_once(&mut events, EFI_IMAGE_SECURITY_DATABASE_GUID, "db", data)?;
+        } else if let Some(data) = find_allowing(&mok_list, &certificates, &hash)? {
+            push_once(&mut events, SHIM_LOCK_GUID, "MokListRT", data)?;
+        } else {
+            match vendor_cert {
+                Some(cert) if certificate_allows(cert, &certificates)? => push_once(&mut events, SHIM_LOCK_GUID, "Shim", cert)?,
+                _ => bail!("{} is not allowed by db, MokList nor the vendor certificate of shim", image.name),
+            }
+        }
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tdvf::encode_guid;
+
+    const OWNER: &str = "77FA9ABD-0359-4D32-BD60-28F4E78F784B";
+
+    fn der(tag: u8, contents: &[&[u8]]) -> Vec<u8> {
+        let contents = contents.concat();
+        let mut element = vec![tag];
+        match contents.len() {
+            length @ 0..=0x7f => element.push(length as u8),
+            length @ 0x80..=0xff => element.extend_from_slice(&[0x81, length as u8]),
+            length => element.extend_from_slice(&[0x82, (length >> 8) as u8, length as u8]),
+        }
+        element.extend(contents);
+        element
+    }
+
+    fn name(common_name: &str) -> Vec<u8> {
+        let attribute = der(0x30, &[&der(0x06, &[&[0x55, 0x04, 0x03]]), &der(0x0c, &[common_name.as_bytes()])]);
+        der(0x30, &[&der(0x31, &[&attribute])])
+    }
+
+    fn certificate(issuer: &str, subject: &str) -> Vec<u8> {
+        let tbs = der(
+            0x30,
+            &[
+                &der(0xa0, &[&der(0x02, &[&[2]])]),
+                &der(0x02, &[&[1]]),
+                &der(0x30, &[]),
+                &name(issuer),
+                &der(0x30, &[]),
+                &name(subject),
+                &der(0x30, &[]),
+            ],
+        );
+        der(0x30, &[&tbs, &der(0x30, &[]), &der(0x03, &[&[0]])])
+    }
+
+    fn pkcs7(certificates: &[&[u8]]) -> Vec<u8> {
+        let signed_data = der(
+            0x30,
+            &[
+                &der(0x02, &[&[1]]),
+                &der(0x31, &[]),
+                &der(0x30, &[]),
+                &der(0xa0, certificates),
+                &der(0x31, &[]),
+            ],
+        );
+        der(0x30, &[&der(0x06, &[&[0x2a, 0x86, 0x48]]), &der(0xa0, &[&signed_data])])
+    }
+
+    fn signature_list(signature_type: &str, values: &[&[u8]]) -> Vec<u8> {
+        let signature_size = 16 + values[0].len();
+        let mut list = encode_guid(signature_type).unwrap();
+        list.extend_from_slice(&((SIGNATURE_LIST_HEADER_SIZE + values.len() * signature_size) as u32).to_le_bytes());
+        list.extend_from_slice(&0u32.to_le_bytes());
+        list.extend_from_slice(&(signature_size as u32).to_le_bytes());
+        for value in values {
+            list.extend(encode_guid(OWNER).unwrap());
+            list.extend_from_slice(value);
+        }
+        list
+    }
+
+    #[test]
+    fn signature_lists() {
+        let ca = certificate("CA", "CA");
+        let mut db = signature_list(EFI_CERT_X509_GUID, &[&ca]);
+        db.extend(signature_list(EFI_CERT_SHA256_GUID, &[&[1; 32], &[2; 32]]));
+
+        let signatures = parse_signature_lists(&db).unwrap();
+        assert_eq!(signatures.len(), 3);
+        assert_eq!(signatures[0].signature_type, EFI_CERT_X509_GUID);
+        assert_eq!(signatures[0].value(), ca);
+        assert_eq!(&signatures[0].data[..16], encode_guid(OWNER).unwrap());
+        assert_eq!(signatures[2].value(), [2; 32]);
+
+        assert!(parse_signature_lists(&db[..db.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn signature_certificates_and_names() {
+        let ca = certificate("CA", "CA");
+        let signer = certificate("CA", "Signer");
+        let signature = pkcs7(&[&signer]);
+        assert_eq!(pkcs7_certificates(&signature).unwrap(), vec![signer.as_slice()]);
+        assert_eq!(issuer_and_subject(&signer).unwrap(), (name("CA").as_slice(), name("Signer").as_slice()));
+
+        // Trusted as the issuer of the signer, or as the signer itself
+        assert!(certificate_allows(&ca, &[&signer]).unwrap());
+        assert!(certificate_allows(&signer, &[&signer]).unwrap());
+        assert!(!certificate_allows(&certificate("Other", "Other"), &[&signer]).unwrap());
+        assert!(!certificate_allows(&ca, &[]).unwrap());
+    }
+
+    #[test]
+    fn certificates_before_hashes() {
+        let ca = certificate("CA", "CA");
+        let signer = certificate("CA", "Signer");
+        let hash = [7; 32];
+        let mut db = signature_list(EFI_CERT_SHA256_GUID, &[&hash]);
+        db.extend(signature_list(EFI_CERT_X509_GUID, &[&ca]));
+        let db = parse_signature_lists(&db).unwrap();
+
+        let allowing = find_allowing(&db, &[&signer], &hash).unwrap().unwrap();
+        assert_eq!(&allowing[16..], ca);
+        let allowing = find_allowing(&db, &[], &hash).unwrap().unwrap();
+        assert_eq!(&allowing[16..], hash);
+        assert!(find_allowing(&db, &[], &[0; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    fn authority_events_logged_once() {
+        let mut events = Vec::new();
+        push_once(&mut events, EFI_IMAGE_SECURITY_DATABASE_GUID, "db", b"entry").unwrap();
+        push_once(&mut events, EFI_IMAGE_SECURITY_DATABASE_GUID, "db", b"entry").unwrap();
+        push_once(&mut events, SHIM_LOCK_GUID, "Shim", b"entry").unwrap();
+        assert_eq!(events.len(), 2);
+    }
+}
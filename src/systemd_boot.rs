@@ -0,0 +1,208 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Boot entry discovery and selection as done by systemd-boot: type #1 entries
+//! from `loader/entries/*.conf`, type #2 UKIs from `EFI/Linux/*.efi`, and the
+//! `default` pattern from `loader/loader.conf`.
+
+use anyhow::{anyhow, bail, Result};
+use std::cmp::Ordering;
+
+use crate::uki::Uki;
+
+/// A boot entry as systemd-boot sees it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BootEntry {
+    /// Entry id, the file name of the entry or UKI (e.g. `ubuntu.conf`)
+    pub id: String,
+    pub title: Option<String>,
+    pub sort_key: Option<String>,
+    pub machine_id: Option<String>,
+    pub version: Option<String>,
+    /// ESP path of the image started for this entry (a UKI or an EFI kernel)
+    pub image: String,
+    /// ESP paths of additional initrds (type #1 `initrd` lines)
+    pub initrds: Vec<String>,
+    /// Options passed explicitly, only type #1 entries have them
+    pub options: Option<String>,
+}
+
+/// Parses the `default` key of loader.conf.
+pub(crate) fn parse_loader_conf(text: &str) -> Option<String> {
+    text.lines()
+        .filter_map(|line| line.trim().split_once(char::is_whitespace))
+        .filter(|(key, _)| *key == "default")
+        .map(|(_, value)| value.trim().to_string())
+        .next_back()
+}
+
+/// Parses a type #1 entry file.
+pub(crate) fn parse_type1_entry(id: &str, text: &str) -> Result<BootEntry> {
+    let mut entry = BootEntry {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    let mut options = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once(char::is_whitespace)
+            .map(|(k, v)| (k, v.trim()))
+            .unwrap_or((line, ""));
+        match key {
+            "title" => entry.title = Some(value.to_string()),
+            "sort-key" => entry.sort_key = Some(value.to_string()),
+            "machine-id" => entry.machine_id = Some(value.to_string()),
+            "version" => entry.version = Some(value.to_string()),
+            "linux" | "efi" | "uki" => entry.image = esp_path(value),
+            "initrd" => entry.initrds.push(esp_path(value)),
+            "options" => options.push(value.to_string()),
+            _ => {}
+        }
+    }
+    if entry.image.is_empty() {
+        bail!("Boot entry {id} has no linux, efi or uki line");
+    }
+    if !options.is_empty() {
+        entry.options = Some(options.join(" "));
+    }
+    Ok(entry)
+}
+
+/// Builds a type #2 entry from a UKI found in `EFI/Linux`.
+pub(crate) fn type2_entry(id: &str, image: &str, uki: &Uki) -> BootEntry {
+    let os_release = uki.os_release();
+    let field = |keys: &[&str]| keys.iter().find_map(|k| os_release.get(*k).cloned());
+    BootEntry {
+        id: id.to_string(),
+        title: field(&["PRETTY_NAME", "NAME"]),
+        sort_key: field(&["IMAGE_ID", "ID"]),
+        machine_id: None,
+        version: field(&["IMAGE_VERSION", "VERSION", "VERSION_ID", "BUILD_ID"]),
+        image: esp_path(image),
+        initrds: Vec::new(),
+        options: None,
+    }
+}
+
+/// Normalizes a path from an entry file into an absolute ESP path.
+fn esp_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{path}")
+    }
+}
+
+/// Version comparison in the spirit of systemd's `strverscmp_improved()`:
+/// digit runs compare numerically, everything else character-wise.
+fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let a_len = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let b_len = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let a_num = std::str::from_utf8(&a[..a_len]).unwrap().trim_start_matches('0');
+                let b_num = std::str::from_utf8(&b[..b_len]).unwrap().trim_start_matches('0');
+                let ord = a_num.len().cmp(&b_num.len()).then_with(|| a_num.cmp(b_num));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[a_len..];
+                b = &b[b_len..];
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(y);
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+fn optional_cmp(a: &Option<String>, b: &Option<String>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => version_cmp(a, b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+/// Sorts entries in systemd-boot menu order: entries with a sort key first,
+/// ordered by sort key and machine id, newest version first, then by id with
+/// the newest first.
+pub(crate) fn sort_entries(entries: &mut [BootEntry]) {
+    entries.sort_by(|a, b| {
+        let keyed = b.sort_key.is_some().cmp(&a.sort_key.is_some());
+        keyed
+            .then_with(|| optional_cmp(&a.sort_key, &b.sort_key))
+            .then_with(|| optional_cmp(&a.machine_id, &b.machine_id))
+            .then_with(|| optional_cmp(&b.version, &a.version))
+            .then_with(|| version_cmp(&b.id, &a.id))
+    });
+}
+
+/// Matches a systemd-boot glob pattern (`*`, `?` and `[...]`) against an id.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &text[1..]),
+        (Some(b'['), Some(&c)) => match pattern.iter().position(|&p| p == b']') {
+            Some(end) => {
+                let set = &pattern[1..end];
+                let matched = set.windows(3).any(|w| w[1] == b'-' && w[0] <= c && c <= w[2])
+                    || set.contains(&c);
+                matched && glob_match(&pattern[end + 1..], &text[1..])
+            }
+            None => false,
+        },
+        (Some(p), Some(c)) => p == c && glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Selects the entry systemd-boot boots without user interaction.
+///
+/// `selected` overrides everything, as `LoaderEntryOneShot`/`LoaderEntryDefault`
+/// would; otherwise the first entry in menu order matching the loader.conf
+/// `default` pattern is booted, and the first entry when nothing matches.
+pub(crate) fn select_entry<'e>(
+    entries: &'e [BootEntry],
+    default: Option<&str>,
+    selected: Option<&str>,
+) -> Result<&'e BootEntry> {
+    if entries.is_empty() {
+        bail!("systemd-boot found no boot entries");
+    }
+    if let Some(id) = selected {
+        return entries
+            .iter()
+            .find(|e| e.id == id || e.id.strip_suffix(".conf") == Some(id))
+            .ok_or_else(|| anyhow!("Boot entry {id} not found"));
+    }
+    match default {
+        Some(pattern) if pattern.starts_with('@') => {
+            bail!("loader.conf default {pattern} depends on EFI variables, select the entry explicitly")
+        }
+        Some(pattern) => Ok(entries
+            .iter()
+            .find(|e| glob_match(pattern.as_bytes(), e.id.as_bytes()))
+            .unwrap_or(&entries[0])),
+        None => Ok(&entries[0]),
+    }
+}
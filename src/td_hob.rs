@@ -0,0 +1,407 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Construction and parsing of the TD HOB list the VMM hands to the firmware,
+//! which RTMR[0] measures first.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_human_bytes as hex_bytes;
+use std::fmt;
+
+use crate::cfv::format_guid;
+use crate::measure_sha384;
+use crate::num::read_le;
+
+const HOB_TYPE_HANDOFF: u16 = 0x0001;
+const HOB_TYPE_RESOURCE_DESCRIPTOR: u16 = 0x0003;
+const HOB_TYPE_GUID_EXTENSION: u16 = 0x0004;
+const HOB_TYPE_END_OF_HOB_LIST: u16 = 0xFFFF;
+
+const PHIT_HOB_LENGTH: u16 = 56;
+const RESOURCE_HOB_LENGTH: u16 = 48;
+/// Offset of EfiEndOfHobList in the PHIT HOB
+const END_OF_HOB_LIST_OFFSET: usize = 48;
+
+pub(crate) const RESOURCE_SYSTEM_MEMORY: u32 = 0x00;
+pub(crate) const RESOURCE_MEMORY_MAPPED_IO: u32 = 0x01;
+pub(crate) const RESOURCE_MEMORY_UNACCEPTED: u32 = 0x07;
+
+const RESOURCE_TYPE_NAMES: &[&str] = &[
+    "SYSTEM_MEMORY",
+    "MEMORY_MAPPED_IO",
+    "IO",
+    "FIRMWARE_DEVICE",
+    "MEMORY_MAPPED_IO_PORT",
+    "MEMORY_RESERVED",
+    "IO_RESERVED",
+    "MEMORY_UNACCEPTED",
+];
+
+const RESOURCE_ATTRIBUTE_NAMES: &[(u32, &str)] = &[
+    (0x00000001, "PRESENT"),
+    (0x00000002, "INITIALIZED"),
+    (0x00000004, "TESTED"),
+    (0x00000400, "UNCACHEABLE"),
+    (0x00000800, "WRITE_COMBINEABLE"),
+    (0x00001000, "WRITE_THROUGH_CACHEABLE"),
+    (0x00002000, "WRITE_BACK_CACHEABLE"),
+];
+
+/// A range of memory the memory acceptor marked accepted for a TDVF section.
+#[derive(Debug, Clone, Serialize)]
+pub struct AcceptedRange {
+    /// TDVF section that required the range, e.g. `TD_HOB`
+    pub section: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The TD HOB list as the VMM builds it for a machine.
+#[derive(Debug, Clone, Serialize)]
+pub struct TdHob {
+    /// GPA of the TD_HOB section the list is placed in
+    pub address: u64,
+    /// Bytes of the list measured into RTMR[0], without the end HOB
+    #[serde(with = "hex_bytes")]
+    pub data: Vec<u8>,
+    /// Memory acceptor decisions, in the order the sections were processed
+    pub accepted: Vec<AcceptedRange>,
+}
+
+impl TdHob {
+    /// Returns the SHA384 digest RTMR[0] is extended with.
+    pub fn digest(&self) -> Vec<u8> {
+        measure_sha384(&self.data)
+    }
+
+    /// Returns the HOBs of the list.
+    pub fn hobs(&self) -> Result<Vec<Hob>> {
+        parse_hob_list(&self.data)
+    }
+}
+
+/// A HOB of the TD HOB list.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Hob {
+    /// The PHIT HOB starting the list
+    HandoffInfoTable {
+        version: u32,
+        boot_mode: u32,
+        memory_top: u64,
+        memory_bottom: u64,
+        free_memory_top: u64,
+        free_memory_bottom: u64,
+        end_of_hob_list: u64,
+    },
+    ResourceDescriptor {
+        owner: String,
+        resource_type: u32,
+        attributes: u32,
+        start: u64,
+        length: u64,
+    },
+    GuidExtension {
+        name: String,
+        #[serde(with = "hex_bytes")]
+        data: Vec<u8>,
+    },
+    Other {
+        hob_type: u16,
+        #[serde(with = "hex_bytes")]
+        data: Vec<u8>,
+    },
+}
+
+/// Returns the name of a resource descriptor type, e.g. `SYSTEM_MEMORY`.
+pub fn resource_type_name(resource_type: u32) -> String {
+    match RESOURCE_TYPE_NAMES.get(resource_type as usize) {
+        Some(name) => name.to_string(),
+        None => format!("{:#x}", resource_type),
+    }
+}
+
+/// Formats resource attributes, e.g. `PRESENT | INITIALIZED | TESTED`.
+pub fn resource_attributes_string(attributes: u32) -> String {
+    let mut parts = Vec::new();
+    let mut rest = attributes;
+    for (bit, name) in RESOURCE_ATTRIBUTE_NAMES {
+        if attributes & bit != 0 {
+            parts.push(name.to_string());
+            rest &= !bit;
+        }
+    }
+    if rest != 0 {
+        parts.push(format!("{:#x}", rest));
+    }
+    if parts.is_empty() {
+        "NONE".to_string()
+    } else {
+        parts.join(" | ")
+    }
+}
+
+impl fmt::Display for Hob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Hob::HandoffInfoTable { version, boot_mode, end_of_hob_list, .. } => write!(
+                f,
+                "PHIT: version {}, boot mode {:#x}, end of HOB list {:#x}",
+                version, boot_mode, end_of_hob_list
+            ),
+            Hob::ResourceDescriptor { resource_type, attributes, start, length, .. } => write!(
+                f,
+                "Resource {:#012x}-{:#012x} {} ({})",
+                start,
+                start + length,
+                resource_type_name(*resource_type),
+                resource_attributes_string(*attributes)
+            ),
+            Hob::GuidExtension { name, data } => write!(f, "GUID extension {} ({} bytes)", name, data.len()),
+            Hob::Other { hob_type, data } => write!(f, "HOB type {:#06x} ({} bytes)", hob_type, data.len()),
+        }
+    }
+}
+
+/// Parses a HOB list up to its end HOB or the end of the data, e.g. the
+/// measured TD HOB or a dump of the TD_HOB section taken from the guest.
+pub fn parse_hob_list(data: &[u8]) -> Result<Vec<Hob>> {
+    let mut hobs = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let hob_type = read_le::<u16>(data, offset, "HOB type")?;
+        if hob_type == HOB_TYPE_END_OF_HOB_LIST {
+            break;
+        }
+        let length = read_le::<u16>(data, offset + 2, "HOB length")? as usize;
+        if length < 8 || offset + length > data.len() {
+            bail!("Invalid length {} of HOB at offset {:#x}", length, offset);
+        }
+        let hob = &data[offset..offset + length];
+        let body = &hob[8..];
+        hobs.push(match hob_type {
+            HOB_TYPE_HANDOFF => Hob::HandoffInfoTable {
+                version: read_le(body, 0, "PHIT version")?,
+                boot_mode: read_le(body, 4, "PHIT boot mode")?,
+                memory_top: read_le(body, 8, "PHIT memory top")?,
+                memory_bottom: read_le(body, 16, "PHIT memory bottom")?,
+                free_memory_top: read_le(body, 24, "PHIT free memory top")?,
+                free_memory_bottom: read_le(body, 32, "PHIT free memory bottom")?,
+                end_of_hob_list: read_le(body, 40, "PHIT end of HOB list")?,
+            },
+            HOB_TYPE_RESOURCE_DESCRIPTOR if body.len() >= 40 => Hob::ResourceDescriptor {
+                owner: format_guid(&body[..16]),
+                resource_type: read_le(body, 16, "resource type")?,
+                attributes: read_le(body, 20, "resource attributes")?,
+                start: read_le(body, 24, "resource start")?,
+                length: read_le(body, 32, "resource length")?,
+            },
+            HOB_TYPE_GUID_EXTENSION if body.len() >= 16 => Hob::GuidExtension {
+                name: format_guid(&body[..16]),
+                data: body[16..].to_vec(),
+            },
+            _ => Hob::Other { hob_type, data: body.to_vec() },
+        });
+        offset += length;
+    }
+    Ok(hobs)
+}
+
+/// A HOB that differs between two lists, `None` where a list has fewer HOBs.
+#[derive(Debug, Clone, Serialize)]
+pub struct HobDifference {
+    pub index: usize,
+    pub expected: Option<Hob>,
+    pub actual: Option<Hob>,
+}
+
+/// Compares two HOB lists entry by entry.
+pub fn compare_hob_lists(expected: &[Hob], actual: &[Hob]) -> Vec<HobDifference> {
+    (0..expected.len().max(actual.len()))
+        .filter_map(|index| {
+            let (e, a) = (expected.get(index), actual.get(index));
+            (e != a).then(|| HobDifference { index, expected: e.cloned(), actual: a.cloned() })
+        })
+        .collect()
+}
+
+/// Appends the PHIT HOB that starts the TD HOB, with a placeholder for EfiEndOfHobList.
+pub(crate) fn push_phit_hob(td_hob: &mut Vec<u8>) {
+    td_hob.extend_from_slice(&HOB_TYPE_HANDOFF.to_le_bytes()); // HobType
+    td_hob.extend_from_slice(&PHIT_HOB_LENGTH.to_le_bytes()); // HobLength
+    td_hob.extend_from_slice(&[0u8; 4]); // Reserved
+    td_hob.extend_from_slice(&9u32.to_le_bytes()); // Version
+    td_hob.extend_from_slice(&[0u8; 4]); // BootMode
+    td_hob.extend_from_slice(&[0u8; 8]); // EfiMemoryTop
+    td_hob.extend_from_slice(&[0u8; 8]); // EfiMemoryBottom
+    td_hob.extend_from_slice(&[0u8; 8]); // EfiFreeMemoryTop
+    td_hob.extend_from_slice(&[0u8; 8]); // EfiFreeMemoryBottom
+    td_hob.extend_from_slice(&[0u8; 8]); // EfiEndOfHobList (placeholder)
+}
+
+/// Appends a resource descriptor HOB.
+pub(crate) fn push_resource_hob(td_hob: &mut Vec<u8>, resource_type: u32, attributes: u32, start: u64, length: u64) {
+    td_hob.extend_from_slice(&HOB_TYPE_RESOURCE_DESCRIPTOR.to_le_bytes()); // HobType
+    td_hob.extend_from_slice(&RESOURCE_HOB_LENGTH.to_le_bytes()); // HobLength
+    td_hob.extend_from_slice(&[0u8; 4]); // Reserved
+    td_hob.extend_from_slice(&[0u8; 16]); // Owner
+    td_hob.extend_from_slice(&resource_type.to_le_bytes());
+    td_hob.extend_from_slice(&attributes.to_le_bytes()); // ResourceAttribute
+    td_hob.extend_from_slice(&start.to_le_bytes());
+    td_hob.extend_from_slice(&length.to_le_bytes());
+}
+
+/// Appends a GUID extension HOB, padding its data to 8 bytes.
+pub(crate) fn push_guid_hob(td_hob: &mut Vec<u8>, guid: &[u8], data: &[u8]) {
+    let padded_len = data.len().next_multiple_of(8);
+    td_hob.extend_from_slice(&HOB_TYPE_GUID_EXTENSION.to_le_bytes()); // HobType
+    td_hob.extend_from_slice(&((24 + padded_len) as u16).to_le_bytes()); // HobLength
+    td_hob.extend_from_slice(&[0u8; 4]); // Reserved
+    td_hob.extend_from_slice(guid); // Name
+    td_hob.extend_from_slice(data);
+    td_hob.resize(td_hob.len() + padded_len - data.len(), 0);
+}
+
+/// Points EfiEndOfHobList at the end HOB the VMM appends after the list.
+pub(crate) fn set_end_of_hob_list(td_hob: &mut [u8], address: u64) {
+    let end_of_hob_list = address + td_hob.len() as u64 + 8;
+    td_hob[END_OF_HOB_LIST_OFFSET..END_OF_HOB_LIST_OFFSET + 8].copy_from_slice(&end_of_hob_list.to_le_bytes());
+}
+
+/// Splits memory into ranges that are accepted or left unaccepted.
+pub(crate) struct MemoryAcceptor {
+    pub ranges: Vec<(bool, u64, u64)>,
+}
+
+impl MemoryAcceptor {
+    pub fn new(start: u64, size: u64) -> Self {
+        Self {
+            ranges: vec![(false, start, start + size)],
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.ranges[0].1
+    }
+
+    pub fn end(&self) -> u64 {
+        self.ranges[self.ranges.len() - 1].2
+    }
+
+    pub fn accept(&mut self, start: u64, end: u64) {
+        if start >= end {
+            return;
+        }
+
+        let mut new_ranges = Vec::new();
+
+        for &(is_accepted, range_start, range_end) in &self.ranges {
+            if is_accepted || range_end <= start || range_start >= end {
+                new_ranges.push((is_accepted, range_start, range_end));
+            } else {
+                if range_start < start {
+                    new_ranges.push((false, range_start, start));
+                }
+                if range_end > end {
+                    new_ranges.push((false, end, range_end));
+                }
+            }
+        }
+        new_ranges.push((true, start, end));
+        new_ranges.sort_by_key(|&(_, start, _)| start);
+        self.ranges = new_ranges;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hob_serialization() {
+        let mut td_hob = Vec::new();
+        push_phit_hob(&mut td_hob);
+        push_resource_hob(&mut td_hob, RESOURCE_MEMORY_UNACCEPTED, 0x07, 0x80_0000, 0x1000);
+        push_guid_hob(&mut td_hob, &[0x11; 16], b"abc");
+        set_end_of_hob_list(&mut td_hob, 0x80_9000);
+
+        let expected = [
+            // PHIT: type, length, reserved, version 9, boot mode, four memory bounds, end of list
+            "01003800000000000900000000000000",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "9090800000000000",
+            // Resource descriptor: type, length, reserved, owner, type, attributes, start, length
+            "0300300000000000",
+            "00000000000000000000000000000000",
+            "0700000007000000",
+            "00008000000000000010000000000000",
+            // GUID extension: type, length, reserved, name, data padded to 8 bytes
+            "0400200000000000",
+            "11111111111111111111111111111111",
+            "6162630000000000",
+        ]
+        .concat();
+        assert_eq!(hex::encode(&td_hob), expected);
+        // EfiEndOfHobList points past the end HOB the VMM appends
+        assert_eq!(0x80_9000 + td_hob.len() as u64 + 8, 0x80_9090);
+
+        let hobs = parse_hob_list(&td_hob).unwrap();
+        assert_eq!(
+            hobs,
+            [
+                Hob::HandoffInfoTable {
+                    version: 9,
+                    boot_mode: 0,
+                    memory_top: 0,
+                    memory_bottom: 0,
+                    free_memory_top: 0,
+                    free_memory_bottom: 0,
+                    end_of_hob_list: 0x80_9090,
+                },
+                Hob::ResourceDescriptor {
+                    owner: "00000000-0000-0000-0000-000000000000".to_string(),
+                    resource_type: RESOURCE_MEMORY_UNACCEPTED,
+                    attributes: 0x07,
+                    start: 0x80_0000,
+                    length: 0x1000,
+                },
+                Hob::GuidExtension {
+                    name: "11111111-1111-1111-1111-111111111111".to_string(),
+                    data: b"abc\0\0\0\0\0".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn hob_list_end() {
+        let mut td_hob = Vec::new();
+        push_resource_hob(&mut td_hob, RESOURCE_SYSTEM_MEMORY, 0x07, 0, 0x1000);
+        td_hob.extend_from_slice(&[0xff, 0xff, 0x08, 0, 0, 0, 0, 0]);
+        td_hob.extend_from_slice(&[0xAA; 16]);
+        assert_eq!(parse_hob_list(&td_hob).unwrap().len(), 1);
+        assert!(parse_hob_list(&td_hob[..40]).is_err());
+    }
+
+    #[test]
+    fn memory_acceptor() {
+        let mut acceptor = MemoryAcceptor::new(0, 0x10000);
+        acceptor.accept(0x2000, 0x3000);
+        acceptor.accept(0x3000, 0x4000);
+        acceptor.accept(0x8000, 0x8000);
+        acceptor.accept(0xF000, 0x10000);
+        assert_eq!(
+            acceptor.ranges,
+            [
+                (false, 0, 0x2000),
+                (true, 0x2000, 0x3000),
+                (true, 0x3000, 0x4000),
+                (false, 0x4000, 0xF000),
+                (true, 0xF000, 0x10000),
+            ]
+        );
+        assert_eq!((acceptor.start(), acceptor.end()), (0, 0x10000));
+    }
+}
@@ -5,40 +5,129 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
 use sha2::{Digest, Sha384};
 
+use crate::boot_options::{self, BootVariables};
+use crate::cfv::{self, VariableStore};
 use crate::num::read_le;
+use crate::secure_boot::{self, VerifiedImages, SHIM_LOCK_GUID};
+use crate::td_hob::{
+    push_guid_hob, push_phit_hob, push_resource_hob, set_end_of_hob_list, AcceptedRange, MemoryAcceptor, TdHob,
+    RESOURCE_MEMORY_MAPPED_IO, RESOURCE_MEMORY_UNACCEPTED, RESOURCE_SYSTEM_MEMORY,
+};
 use crate::util::{debug_print_log, measure_sha384, measure_log, utf16_encode, read_file_data};
-use crate::Machine;
+use crate::{acpi, BootOptions, Machine, Vmm};
 
-const PAGE_SIZE: u64 = 0x1000;
+pub(crate) const PAGE_SIZE: u64 = 0x1000;
 const MR_EXTEND_GRANULARITY: usize = 0x100;
 
-const ATTRIBUTE_MR_EXTEND: u32 = 0x00000001;
-const ATTRIBUTE_PAGE_AUG: u32 = 0x00000002;
+pub(crate) const ATTRIBUTE_MR_EXTEND: u32 = 0x00000001;
+pub(crate) const ATTRIBUTE_PAGE_AUG: u32 = 0x00000002;
+const ATTRIBUTES_KNOWN: u32 = ATTRIBUTE_MR_EXTEND | ATTRIBUTE_PAGE_AUG;
 
-const TDVF_SECTION_TD_CFV: u32 = 0x01;
+/// SbatLevel shim creates with its default policy, measured for indirect boot
+const DEFAULT_SBAT_LEVEL: &[u8] = b"sbat,1,2021030218\n";
+
+/// Metadata versions sharing the descriptor and section layout handled here
+const TDVF_VERSIONS: &[u32] = &[1, 2];
+
+const TDVF_SECTION_BFV: u32 = 0x00;
+pub(crate) const TDVF_SECTION_TD_CFV: u32 = 0x01;
 const TDVF_SECTION_TD_HOB: u32 = 0x02;
 const TDVF_SECTION_TEMP_MEM: u32 = 0x03;
+const TDVF_SECTION_PERM_MEM: u32 = 0x04;
+const TDVF_SECTION_PAYLOAD: u32 = 0x05;
+const TDVF_SECTION_PAYLOAD_PARAM: u32 = 0x06;
+
+/// QEMU splits RAM at 2 GiB around the 32-bit PCI hole from this memory size on
+pub(crate) const QEMU_LOWMEM_SPLIT_SIZE: u64 = 0xB000_0000;
+/// Cloud Hypervisor keeps RAM below its 32-bit reserved area and above 4 GiB
+pub(crate) const CLOUD_HYPERVISOR_MEM_32BIT_RESERVED_START: u64 = 0xC000_0000;
+
+/// td-shim keeps the offset of its metadata 0x20 bytes before the end of the image
+const TD_SHIM_METADATA_PTR_OFFSET: usize = 0x20;
 
 #[derive(Debug)]
-struct TdvfSection {
-    data_offset: u32,
-    raw_data_size: u32,
-    memory_address: u64,
-    memory_data_size: u64,
-    sec_type: u32,
-    attributes: u32,
+pub(crate) struct TdvfSection {
+    pub data_offset: u32,
+    pub raw_data_size: u32,
+    pub memory_address: u64,
+    pub memory_data_size: u64,
+    pub sec_type: u32,
+    pub attributes: u32,
+}
+
+impl TdvfSection {
+    /// Returns the name of the section type, failing for types this tool does not know.
+    pub(crate) fn type_name(&self) -> Result<&'static str> {
+        Ok(match self.sec_type {
+            TDVF_SECTION_BFV => "BFV",
+            TDVF_SECTION_TD_CFV => "CFV",
+            TDVF_SECTION_TD_HOB => "TD_HOB",
+            TDVF_SECTION_TEMP_MEM => "TempMem",
+            TDVF_SECTION_PERM_MEM => "PermMem",
+            TDVF_SECTION_PAYLOAD => "Payload",
+            TDVF_SECTION_PAYLOAD_PARAM => "PayloadParam",
+            other => bail!("Failed to parse TDVF metadata: Unknown section type {other:#x}"),
+        })
+    }
+
+    /// Checks the section against the rules of the TDVF specification for its type.
+    fn validate(&self, fw_len: usize) -> Result<()> {
+        let name = self.type_name()?;
+
+        if !self.memory_address.is_multiple_of(PAGE_SIZE) {
+            bail!("Failed to parse TDVF metadata: {name} section memory address not aligned");
+        }
+        if self.memory_data_size < self.raw_data_size as u64 {
+            bail!("Failed to parse TDVF metadata: {name} section memory data size less than raw");
+        }
+        if !self.memory_data_size.is_multiple_of(PAGE_SIZE) {
+            bail!("Failed to parse TDVF metadata: {name} section memory data size not aligned");
+        }
+        if self.attributes & !ATTRIBUTES_KNOWN != 0 {
+            bail!("Failed to parse TDVF metadata: {name} section has unknown attributes {:#x}", self.attributes);
+        }
+        if self.attributes & ATTRIBUTE_MR_EXTEND != 0 && self.attributes & ATTRIBUTE_PAGE_AUG != 0 {
+            bail!("Failed to parse TDVF metadata: {name} section cannot be both extended and augmented");
+        }
+        if self.data_offset as usize + self.raw_data_size as usize > fw_len {
+            bail!("Failed to parse TDVF metadata: {name} section extends beyond firmware data");
+        }
+
+        match self.sec_type {
+            // Firmware volumes are copied from the image
+            TDVF_SECTION_BFV | TDVF_SECTION_TD_CFV if self.raw_data_size == 0 => {
+                bail!("Failed to parse TDVF metadata: {name} section has no data");
+            }
+            // Memory the VMM provides without content from the image
+            TDVF_SECTION_TD_HOB | TDVF_SECTION_TEMP_MEM | TDVF_SECTION_PERM_MEM
+            | TDVF_SECTION_PAYLOAD_PARAM => {
+                if self.raw_data_size != 0 {
+                    bail!("Failed to parse TDVF metadata: {name} section must not have data");
+                }
+                if self.attributes & ATTRIBUTE_MR_EXTEND != 0 {
+                    bail!("Failed to parse TDVF metadata: {name} section must not be extended");
+                }
+            }
+            // The payload is either embedded or loaded by the VMM
+            _ => {}
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub(crate) struct Tdvf<'a> {
     fw: &'a [u8],
     sections: Vec<TdvfSection>,
+    /// Whether the metadata was found through the td-shim metadata pointer
+    td_shim: bool,
 }
 
 /// Encodes a GUID string into its binary representation.
-fn encode_guid(guid_str: &str) -> Result<Vec<u8>> {
+pub(crate) fn encode_guid(guid_str: &str) -> Result<Vec<u8>> {
     let mut data = Vec::with_capacity(16);
     let atoms: Vec<&str> = guid_str.split('-').collect();
 
@@ -64,7 +153,7 @@ fn encode_guid(guid_str: &str) -> Result<Vec<u8>> {
 }
 
 /// Measures an EFI variable event.
-fn measure_tdx_efi_variable(vendor_guid: &str, var_name: &str, var_data: Option<&[u8]>) -> Result<Vec<u8>> {
+pub(crate) fn measure_tdx_efi_variable(vendor_guid: &str, var_name: &str, var_data: Option<&[u8]>) -> Result<Vec<u8>> {
     let mut data = Vec::new();
     data.extend_from_slice(&encode_guid(vendor_guid)?);
     data.extend_from_slice(&(var_name.len() as u64).to_le_bytes());
@@ -84,13 +173,15 @@ fn measure_tdx_efi_variable(vendor_guid: &str, var_name: &str, var_data: Option<
     Ok(measure_sha384(&data))
 }
 
-/// Loads boot order data and parses boot entry numbers based on boot mode.
-/// For direct boot, returns a simulated boot order with no boot entries.
-/// For indirect boot, reads the boot order file and extracts the list of boot entry numbers.
-fn parse_boot_order(machine: &Machine) -> Result<(Vec<u8>, Vec<u16>)> {
-    if machine.direct_boot {
-        // Direct boot: simulated boot order data
-        Ok((vec![0, 0], Vec::new()))
+/// Loads the BootOrder data and the Boot#### variables it lists, in order.
+/// Synthesizes them from the boot options if given, and for direct boot,
+/// which only has the UiApp option. Otherwise reads them from the dumped files,
+/// with `None` for entries that have no file.
+fn load_boot_variables(machine: &Machine) -> Result<BootVariables> {
+    if let Some(options) = machine.boot_options {
+        boot_options::build_boot_variables(options)
+    } else if machine.direct_boot {
+        boot_options::build_boot_variables(&BootOptions::direct_boot())
     } else if !machine.boot_order.is_empty() {
         // Indirect boot: read and parse the boot order file
         let boot_order_data = read_file_data(machine.boot_order)?;
@@ -100,15 +191,15 @@ fn parse_boot_order(machine: &Machine) -> Result<(Vec<u8>, Vec<u16>)> {
             bail!("BootOrder data length must be even (array of UINT16s)");
         }
 
-        let mut boot_entries = Vec::new();
+        let mut boot_variables = Vec::new();
         for chunk in boot_order_data.chunks(2) {
             let entry_num = u16::from_le_bytes([chunk[0], chunk[1]]);
-            boot_entries.push(entry_num);
+            boot_variables.push(load_boot_variable_if_exists(entry_num, machine)?);
         }
 
-        Ok((boot_order_data, boot_entries))
+        Ok(BootVariables { boot_order: boot_order_data, entries: boot_variables })
     } else {
-        Err(anyhow!("Boot order file is required for indirect boot"))
+        Err(anyhow!("Boot order file or boot options are required for indirect boot"))
     }
 }
 
@@ -126,17 +217,22 @@ fn load_boot_variable_if_exists(boot_entry_num: u16, machine: &Machine) -> Resul
 }
 
 impl<'a> Tdvf<'a> {
-    pub fn parse(fw: &'a [u8]) -> Result<Tdvf<'a>> {
+    /// Locates the TDVF metadata through the OVMF GUIDed table footer.
+    /// Returns `None` if the image has no such footer.
+    fn find_ovmf_metadata(fw: &[u8]) -> Result<Option<usize>> {
         const TDX_METADATA_OFFSET_GUID: &str = "e47a6535-984a-4798-865e-4685a7bf8ec2";
         const TABLE_FOOTER_GUID: &str = "96b582de-1fb2-45f7-baea-a366c55a082d";
         const BYTES_AFTER_TABLE_FOOTER: usize = 32;
 
+        if fw.len() < BYTES_AFTER_TABLE_FOOTER + 18 {
+            bail!("Failed to parse TDVF metadata: Firmware image too small");
+        }
         let offset = fw.len() - BYTES_AFTER_TABLE_FOOTER;
         let encoded_footer_guid = encode_guid(TABLE_FOOTER_GUID)?;
         let guid = &fw[offset - 16..offset];
 
         if guid != encoded_footer_guid {
-            bail!("Failed to parse TDVF metadata: Invalid footer GUID");
+            return Ok(None);
         }
 
         let tables_len =
@@ -169,25 +265,52 @@ impl<'a> Tdvf<'a> {
 
         let tdvf_meta_offset =
             u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap()) as usize;
-        let tdvf_meta_offset = fw.len() - tdvf_meta_offset;
+        if tdvf_meta_offset > fw.len() {
+            bail!("Failed to parse TDVF metadata: Invalid metadata offset");
+        }
+        Ok(Some(fw.len() - tdvf_meta_offset))
+    }
+
+    /// Locates the TDVF metadata through the td-shim metadata pointer, which
+    /// holds the offset of the metadata from the start of the image.
+    fn find_td_shim_metadata(fw: &[u8]) -> Result<usize> {
+        let ptr_offset = fw.len() - TD_SHIM_METADATA_PTR_OFFSET;
+        let tdvf_meta_offset = read_le::<u32>(fw, ptr_offset, "td-shim metadata pointer")? as usize;
+        if fw.get(tdvf_meta_offset..tdvf_meta_offset + 4) != Some(b"TDVF".as_slice()) {
+            bail!("Failed to parse TDVF metadata: Neither an OVMF table footer nor a td-shim metadata pointer");
+        }
+        Ok(tdvf_meta_offset)
+    }
+
+    pub fn parse(fw: &'a [u8]) -> Result<Tdvf<'a>> {
+        let (tdvf_meta_offset, td_shim) = match Self::find_ovmf_metadata(fw)? {
+            Some(offset) => (offset, false),
+            None => (Self::find_td_shim_metadata(fw)?, true),
+        };
+        if tdvf_meta_offset + 16 > fw.len() {
+            bail!("Failed to parse TDVF metadata: Invalid metadata offset");
+        }
         let tdvf_meta_desc = &fw[tdvf_meta_offset..tdvf_meta_offset + 16];
 
         if &tdvf_meta_desc[..4] != b"TDVF" {
             bail!("Failed to parse TDVF metadata: Invalid TDVF descriptor");
         }
         let tdvf_version = u32::from_le_bytes(tdvf_meta_desc[8..12].try_into().unwrap());
-        if tdvf_version != 1 {
-            bail!("Failed to parse TDVF metadata: Unsupported TDVF version");
+        if !TDVF_VERSIONS.contains(&tdvf_version) {
+            bail!("Failed to parse TDVF metadata: Unsupported TDVF version {tdvf_version}");
         }
         let num_sections = u32::from_le_bytes(tdvf_meta_desc[12..16].try_into().unwrap()) as usize;
 
         let mut meta = Tdvf {
             fw,
             sections: Vec::new(),
+            td_shim,
         };
         for i in 0..num_sections {
             let sec_offset = tdvf_meta_offset + 16 + 32 * i;
-            let sec_data = &fw[sec_offset..sec_offset + 32];
+            let sec_data = fw
+                .get(sec_offset..sec_offset + 32)
+                .context("Failed to parse TDVF metadata: Truncated section table")?;
             let s = TdvfSection {
                 data_offset: u32::from_le_bytes(sec_data[0..4].try_into().unwrap()),
                 raw_data_size: u32::from_le_bytes(sec_data[4..8].try_into().unwrap()),
@@ -197,19 +320,11 @@ impl<'a> Tdvf<'a> {
                 attributes: u32::from_le_bytes(sec_data[28..32].try_into().unwrap()),
             };
 
-            if s.memory_address % PAGE_SIZE != 0 {
-                bail!("Failed to parse TDVF metadata: Section memory address not aligned");
-            }
-            if s.memory_data_size < s.raw_data_size as u64 {
-                bail!("Failed to parse TDVF metadata: Section memory data size less than raw");
-            }
-            if s.memory_data_size % PAGE_SIZE != 0 {
-                bail!("Failed to parse TDVF metadata: Section memory data size not aligned");
-            }
-            if s.attributes & ATTRIBUTE_MR_EXTEND != 0
-                && s.raw_data_size as u64 > s.memory_data_size
-            {
-                bail!("Failed to parse TDVF metadata: Section raw data size less than memory");
+            s.validate(fw.len())?;
+            for unique in [TDVF_SECTION_TD_HOB, TDVF_SECTION_TD_CFV, TDVF_SECTION_PAYLOAD] {
+                if s.sec_type == unique && meta.sections.iter().any(|o| o.sec_type == unique) {
+                    bail!("Failed to parse TDVF metadata: Duplicate {} section", s.type_name()?);
+                }
             }
 
             meta.sections.push(s);
@@ -233,18 +348,15 @@ impl<'a> Tdvf<'a> {
 
         let mr_extend = |h: &mut Sha384, s: &TdvfSection, page: u64| {
             if s.attributes & ATTRIBUTE_MR_EXTEND != 0 {
-                for i in 0..(PAGE_SIZE as usize / MR_EXTEND_GRANULARITY) {
+                let data = self.page_data(s, page);
+                for (i, chunk) in data.chunks(MR_EXTEND_GRANULARITY).enumerate() {
                     let mut buf = [0u8; 128];
                     buf[..9].copy_from_slice(b"MR.EXTEND");
                     let gpa =
                         s.memory_address + page * PAGE_SIZE + (i * MR_EXTEND_GRANULARITY) as u64;
                     buf[16..24].copy_from_slice(&gpa.to_le_bytes());
                     h.update(buf);
-
-                    let chunk_offset = s.data_offset as usize
-                        + (page * PAGE_SIZE) as usize
-                        + i * MR_EXTEND_GRANULARITY;
-                    h.update(&self.fw[chunk_offset..chunk_offset + MR_EXTEND_GRANULARITY]);
+                    h.update(chunk);
                 }
             }
         };
@@ -261,120 +373,225 @@ impl<'a> Tdvf<'a> {
     }
 
     pub fn rtmr0(&self, machine: &Machine) -> Result<Vec<u8>> {
+        let rtmr0_log = self.rtmr0_log(machine)?;
+        debug_print_log("RTMR0", &rtmr0_log);
+        Ok(measure_log(&rtmr0_log))
+    }
+
+    /// Returns the digests extended into RTMR0, the TD HOB always being the first.
+    pub(crate) fn rtmr0_log(&self, machine: &Machine) -> Result<Vec<Vec<u8>>> {
+        if self.is_td_shim() {
+            return self.td_shim_rtmr0_log(machine);
+        }
+
         // Calculate measurement of the TD Hand-Off Block (TD-HOB)
-        let td_hob_hash = self.measure_td_hob(machine.memory_size)?;
+        let td_hob_hash = self.measure_td_hob(machine)?;
 
         // Calculate measurement of the Configuration Firmware Volume (CFV)
         let cfv_hash = self.measure_cfv().context("Failed to find CFV section")?;
 
-        // Build ACPI tables
-        let tables = machine.build_tables()?;
-        let acpi_tables_hash = measure_sha384(&tables.tables);
-        let acpi_rsdp_hash = measure_sha384(&tables.rsdp);
-        let acpi_loader_hash = measure_sha384(&tables.loader);
+        // Secure Boot configuration baked into the CFV, none for a stock OVMF
+        let store = match self.cfv().and_then(VariableStore::parse) {
+            Ok(store) => Some(store),
+            Err(e) => {
+                warn!("Failed to parse CFV variable store, assuming no Secure Boot configuration: {e:#}");
+                None
+            }
+        };
+        let secure_boot_data = cfv::secure_boot_event_data(store.as_ref());
+        let db = store
+            .as_ref()
+            .filter(|store| cfv::secure_boot_enabled(store))
+            .map(|store| store.get(cfv::EFI_IMAGE_SECURITY_DATABASE_GUID, "db").map(|v| v.data.clone()).unwrap_or_default());
+        let verified_images = match db {
+            Some(_) => machine.verified_images().context("Failed to read the images verified by Secure Boot")?,
+            None => VerifiedImages::default(),
+        };
 
         // Load boot order data and entries
-        let (boot_order_data, boot_entries) = parse_boot_order(machine)?;
+        let boot_variables = load_boot_variables(machine)?;
 
         // Compute RTMR0 log
         let mut rtmr0_log = vec![
             td_hob_hash,
             cfv_hash,
-            measure_tdx_efi_variable("8BE4DF61-93CA-11D2-AA0D-00E098032B8C", "SecureBoot", None)?,
-            measure_tdx_efi_variable("8BE4DF61-93CA-11D2-AA0D-00E098032B8C", "PK", None)?,
-            measure_tdx_efi_variable("8BE4DF61-93CA-11D2-AA0D-00E098032B8C", "KEK", None)?,
-            measure_tdx_efi_variable("D719B2CB-3D3A-4596-A3BC-DAD00E67656F", "db", None)?,
-            measure_tdx_efi_variable("D719B2CB-3D3A-4596-A3BC-DAD00E67656F", "dbx", None)?,
-            measure_sha384(&[0x00, 0x00, 0x00, 0x00]), // Separator
-            acpi_loader_hash,
-            acpi_rsdp_hash,
-            acpi_tables_hash,
-            measure_sha384(&boot_order_data), // Always measure BootOrder itself
         ];
+        for ((guid, name), data) in cfv::SECURE_BOOT_VARIABLES.iter().zip(&secure_boot_data) {
+            rtmr0_log.push(measure_tdx_efi_variable(guid, name, data.as_deref())?);
+        }
+        rtmr0_log.push(measure_sha384(&[0x00, 0x00, 0x00, 0x00])); // Separator
+
+        // QEMU hands ACPI over through the fw_cfg table loader, which OVMF measures.
+        // Cloud Hypervisor puts the tables in the TD HOB, measured with it.
+        if machine.vmm == Vmm::Qemu {
+            let tables = machine.build_tables()?;
+            rtmr0_log.push(measure_sha384(&tables.loader));
+            rtmr0_log.push(measure_sha384(&tables.rsdp));
+            rtmr0_log.push(measure_sha384(&tables.tables));
+        }
 
-        if machine.direct_boot {
-            // Boot0000 data for direct boot mode
-            let boot0000_hex = "090100002c0055006900410070007000000004071400c9bdb87cebf8344faaea3ee4af6516a10406140021aa2c4614760345836e8ab6f46623317fff0400";
-            let boot0000 = hex::decode(boot0000_hex).context("Failed to decode boot0000 hex string")?;
-            rtmr0_log.push(measure_sha384(&boot0000));
-        } else {
-            for boot_entry_num in boot_entries {
-                if let Some(boot_data) = load_boot_variable_if_exists(boot_entry_num, machine)? {
-                    rtmr0_log.push(measure_sha384(&boot_data));
+        rtmr0_log.push(measure_sha384(&boot_variables.boot_order)); // Always measure BootOrder itself
+
+        for boot_data in boot_variables.entries.iter().flatten() {
+            rtmr0_log.push(measure_sha384(boot_data));
+        }
+
+        // With Secure Boot, OVMF logs the db entry allowing each image it starts
+        if let Some(ref db) = db {
+            rtmr0_log.extend(secure_boot::firmware_authority_events(db, &verified_images.firmware)?);
+        }
+
+        // Add SbatLevel if booting through shim, which creates the variable
+        if machine.boots_through_shim() {
+            let sbat_level = if machine.direct_boot {
+                let sbat_level_path = machine.sbat_level.context("SbatLevel path required for direct boot through shim")?;
+                read_file_data(sbat_level_path)?
+            } else {
+                DEFAULT_SBAT_LEVEL.to_vec()
+            };
+            rtmr0_log.push(measure_tdx_efi_variable(SHIM_LOCK_GUID, "SbatLevel", Some(&sbat_level))?);
+        }
+
+        // shim then logs the entry allowing each image it verifies
+        if let (Some(db), Some((shim, images))) = (&db, &verified_images.shim) {
+            let mok_list_path = machine.mok_list.context("MOK list path required to verify images through shim")?;
+            let mok_list = read_file_data(mok_list_path)?;
+            rtmr0_log.extend(secure_boot::shim_authority_events(db, &mok_list, shim, images)?);
+        }
+
+        Ok(rtmr0_log)
+    }
+
+    /// Builds the TD HOB the VMM hands to the firmware of the TD.
+    pub(crate) fn td_hob(&self, machine: &Machine) -> Result<TdHob> {
+        let tables = match machine.vmm {
+            Vmm::Qemu => Vec::new(),
+            Vmm::CloudHypervisor => read_file_data(machine.acpi_tables)?,
+        };
+        self.build_td_hob(machine.vmm, machine.memory_size, &tables)
+    }
+
+    /// Builds the TD HOB of a VMM for a memory size; only Cloud Hypervisor puts
+    /// the ACPI tables in it.
+    pub(crate) fn build_td_hob(&self, vmm: Vmm, memory_size: u64, acpi_tables: &[u8]) -> Result<TdHob> {
+        match vmm {
+            Vmm::Qemu => self.build_qemu_td_hob(memory_size),
+            Vmm::CloudHypervisor => self.build_cloud_hypervisor_td_hob(memory_size, acpi_tables),
+        }
+    }
+
+    /// Measures the TD HOB the VMM builds for the TD.
+    fn measure_td_hob(&self, machine: &Machine) -> Result<Vec<u8>> {
+        Ok(self.td_hob(machine)?.digest())
+    }
+
+    /// Returns the GPA of the TD_HOB section.
+    fn td_hob_address(&self) -> u64 {
+        self.sections
+            .iter()
+            .find(|s| s.sec_type == TDVF_SECTION_TD_HOB)
+            .map_or(0x809000, |s| s.memory_address)
+    }
+
+    /// Builds the TD HOB as Cloud Hypervisor does: RAM with every TDVF section
+    /// accepted, the 32-bit device hole as MMIO, then one GUID extension HOB
+    /// per ACPI table.
+    fn build_cloud_hypervisor_td_hob(&self, memory_size: u64, acpi_tables: &[u8]) -> Result<TdHob> {
+        const MEM_32BIT_RESERVED_START: u64 = CLOUD_HYPERVISOR_MEM_32BIT_RESERVED_START;
+        const HIGH_RAM_START: u64 = 0x1_0000_0000;
+        const APIC_START: u64 = 0xFEE0_0000;
+        const ACPI_TABLE_HOB_GUID: &str = "6a0c5870-d4ed-44f4-a135-dd238b6f0c8d";
+
+        let low_size = memory_size.min(MEM_32BIT_RESERVED_START);
+        let mut ram = vec![MemoryAcceptor::new(0, low_size)];
+        if memory_size > low_size {
+            ram.push(MemoryAcceptor::new(HIGH_RAM_START, memory_size - low_size));
+        }
+
+        let mut accepted = Vec::new();
+        for s in &self.sections {
+            for region in &mut ram {
+                let start = s.memory_address.max(region.start());
+                let end = (s.memory_address + s.memory_data_size).min(region.end());
+                if start < end {
+                    region.accept(start, end);
+                    accepted.push(AcceptedRange { section: s.type_name()?.to_string(), start, end });
                 }
             }
         }
 
-        // Add SbatLevel if not direct boot
-        if !machine.direct_boot {
-            rtmr0_log.push(measure_tdx_efi_variable("605DAB50-E046-4300-ABB6-3DD810DD8B23", "SbatLevel", Some(b"sbat,1,2021030218\n"))?);
+        let mut td_hob = Vec::new();
+        push_phit_hob(&mut td_hob);
+
+        for region in ram {
+            for (is_accepted, start, end) in region.ranges {
+                let resource_type = if is_accepted { RESOURCE_SYSTEM_MEMORY } else { RESOURCE_MEMORY_UNACCEPTED };
+                push_resource_hob(&mut td_hob, resource_type, 0x07, start, end - start);
+            }
         }
 
-        debug_print_log("RTMR0", &rtmr0_log);
-        Ok(measure_log(&rtmr0_log))
+        // Present, initialized and uncacheable
+        push_resource_hob(&mut td_hob, RESOURCE_MEMORY_MAPPED_IO, 0x403, MEM_32BIT_RESERVED_START, APIC_START - MEM_32BIT_RESERVED_START);
+
+        let guid = encode_guid(ACPI_TABLE_HOB_GUID)?;
+        for table in acpi::split_acpi_tables(acpi_tables)? {
+            push_guid_hob(&mut td_hob, &guid, table);
+        }
+
+        let address = self.td_hob_address();
+        set_end_of_hob_list(&mut td_hob, address);
+
+        Ok(TdHob { address, data: td_hob, accepted })
     }
 
-    fn measure_td_hob(&self, memory_size: u64) -> Result<Vec<u8>> {
+    /// Builds the TD HOB as QEMU does: the TD_HOB, TempMem and PermMem sections
+    /// accepted, the rest of RAM unaccepted, split around the 32-bit PCI hole.
+    /// QEMU derives it from its E820 RAM entries, which NUMA nodes and memory
+    /// hotplug slots do not split, so only the total memory size matters.
+    fn build_qemu_td_hob(&self, memory_size: u64) -> Result<TdHob> {
         let mut memory_acceptor = MemoryAcceptor::new(0, memory_size);
-        let mut td_hob = Vec::new();
+        let mut accepted = Vec::new();
 
-        let mut td_hob_base_addr = 0x809000u64;
         for s in &self.sections {
-            if let TDVF_SECTION_TD_HOB | TDVF_SECTION_TEMP_MEM = s.sec_type {
-                memory_acceptor.accept(s.memory_address, s.memory_address + s.memory_data_size);
-            }
-            if s.sec_type == TDVF_SECTION_TD_HOB {
-                td_hob_base_addr = s.memory_address;
+            if let TDVF_SECTION_TD_HOB | TDVF_SECTION_TEMP_MEM | TDVF_SECTION_PERM_MEM = s.sec_type {
+                let (start, end) = (s.memory_address, s.memory_address + s.memory_data_size);
+                memory_acceptor.accept(start, end);
+                accepted.push(AcceptedRange { section: s.type_name()?.to_string(), start, end });
             }
         }
 
-        td_hob.extend_from_slice(&[0x01, 0x00]); // HobType
-        td_hob.extend_from_slice(&56u16.to_le_bytes()); // HobLength
-        td_hob.extend_from_slice(&[0u8; 4]); // Reserved
-        td_hob.extend_from_slice(&9u32.to_le_bytes()); // Version
-        td_hob.extend_from_slice(&[0u8; 4]); // BootMode
-        td_hob.extend_from_slice(&[0u8; 8]); // EfiMemoryTop
-        td_hob.extend_from_slice(&[0u8; 8]); // EfiMemoryBottom
-        td_hob.extend_from_slice(&[0u8; 8]); // EfiFreeMemoryTop
-        td_hob.extend_from_slice(&[0u8; 8]); // EfiFreeMemoryBottom
-        td_hob.extend_from_slice(&[0u8; 8]); // EfiEndOfHobList (placeholder)
-
-        let mut add_memory_resource_hob = |resource_type: u8, start: u64, length: u64| {
-            td_hob.extend_from_slice(&[0x03, 0x00]); // HobType
-            td_hob.extend_from_slice(&48u16.to_le_bytes()); // HobLength
-            td_hob.extend_from_slice(&[0u8; 4]); // Reserved
-            td_hob.extend_from_slice(&[0u8; 16]); // Owner
-            td_hob.extend_from_slice(&resource_type.to_le_bytes());
-            td_hob.extend_from_slice(&[0u8; 3]); // Padding for resource type
-            td_hob.extend_from_slice(&7u32.to_le_bytes()); // ResourceAttribute
-            td_hob.extend_from_slice(&start.to_le_bytes());
-            td_hob.extend_from_slice(&length.to_le_bytes());
+        let mut td_hob = Vec::new();
+        push_phit_hob(&mut td_hob);
+
+        let mut add_memory_resource_hob = |resource_type: u32, start: u64, length: u64| {
+            push_resource_hob(&mut td_hob, resource_type, 0x07, start, length);
         };
 
         let (_, last_start, last_end) = memory_acceptor.ranges.pop().expect("No ranges");
 
-        for (accepted, start, end) in memory_acceptor.ranges {
-            if accepted {
-                add_memory_resource_hob(0x00, start, end - start);
+        for (is_accepted, start, end) in memory_acceptor.ranges {
+            if is_accepted {
+                add_memory_resource_hob(RESOURCE_SYSTEM_MEMORY, start, end - start);
             } else {
-                add_memory_resource_hob(0x07, start, end - start);
+                add_memory_resource_hob(RESOURCE_MEMORY_UNACCEPTED, start, end - start);
             }
         }
 
-        if memory_size >= 0xB0000000 {
-            add_memory_resource_hob(0x07, last_start, 0x80000000u64 - last_start);
-            add_memory_resource_hob(0x07, 0x100000000, last_end - 0x80000000u64);
+        if memory_size >= QEMU_LOWMEM_SPLIT_SIZE {
+            add_memory_resource_hob(RESOURCE_MEMORY_UNACCEPTED, last_start, 0x80000000u64 - last_start);
+            add_memory_resource_hob(RESOURCE_MEMORY_UNACCEPTED, 0x100000000, last_end - 0x80000000u64);
         } else {
-            add_memory_resource_hob(0x07, last_start, last_end - last_start);
+            add_memory_resource_hob(RESOURCE_MEMORY_UNACCEPTED, last_start, last_end - last_start);
         }
 
-        let end_of_hob_list = td_hob_base_addr + td_hob.len() as u64 + 8;
-        td_hob[48..56].copy_from_slice(&end_of_hob_list.to_le_bytes());
+        let address = self.td_hob_address();
+        set_end_of_hob_list(&mut td_hob, address);
 
-        Ok(measure_sha384(&td_hob))
+        Ok(TdHob { address, data: td_hob, accepted })
     }
 
-    fn measure_cfv(&self) -> Result<Vec<u8>> {
+    /// Returns the raw data of the CFV section.
+    pub(crate) fn cfv(&self) -> Result<&'a [u8]> {
         for section in &self.sections {
             if section.sec_type == TDVF_SECTION_TD_CFV {
                 let start = section.data_offset as usize;
@@ -384,46 +601,195 @@ impl<'a> Tdvf<'a> {
                     return Err(anyhow!("CFV section extends beyond firmware data."));
                 }
 
-                return Ok(measure_sha384(&self.fw[start..end]));
+                return Ok(&self.fw[start..end]);
             }
         }
 
         Err(anyhow!("CFV section does not exist."))
     }
-}
 
-struct MemoryAcceptor {
-    ranges: Vec<(bool, u64, u64)>,
-}
+    fn measure_cfv(&self) -> Result<Vec<u8>> {
+        Ok(measure_sha384(self.cfv()?))
+    }
 
-impl MemoryAcceptor {
-    fn new(start: u64, size: u64) -> Self {
-        Self {
-            ranges: vec![(false, start, start + size)],
+    /// Returns the content of a page of a section as loaded in memory.
+    /// Memory beyond the raw data of the section is zero-filled.
+    pub(crate) fn page_data(&self, s: &TdvfSection, page: u64) -> Vec<u8> {
+        let mut data = vec![0u8; PAGE_SIZE as usize];
+        let start = (page * PAGE_SIZE) as usize;
+        let raw_size = s.raw_data_size as usize;
+        if start < raw_size {
+            let len = (raw_size - start).min(PAGE_SIZE as usize);
+            let offset = s.data_offset as usize + start;
+            data[..len].copy_from_slice(&self.fw[offset..offset + len]);
         }
+        data
+    }
+
+    /// Returns the sections declared by the metadata.
+    pub(crate) fn sections(&self) -> &[TdvfSection] {
+        &self.sections
+    }
+
+    /// Whether the image is a td-shim, whose metadata is located through the
+    /// td-shim metadata pointer rather than the OVMF table footer.
+    pub fn is_td_shim(&self) -> bool {
+        self.td_shim
     }
 
-    fn accept(&mut self, start: u64, end: u64) {
-        if start >= end {
-            return;
+    /// Returns the payload embedded in a td-shim image, if any.
+    pub(crate) fn payload(&self) -> Result<Option<&'a [u8]>> {
+        let Some(section) = self.sections.iter().find(|s| s.sec_type == TDVF_SECTION_PAYLOAD) else {
+            return Ok(None);
+        };
+        if section.raw_data_size == 0 {
+            // The VMM loads the payload into the section
+            return Ok(None);
         }
+        let start = section.data_offset as usize;
+        let end = start + section.raw_data_size as usize;
+        self.fw
+            .get(start..end)
+            .map(Some)
+            .ok_or_else(|| anyhow!("Payload section extends beyond firmware data."))
+    }
 
-        let mut new_ranges = Vec::new();
+    /// Returns the RTMR0 log of td-shim, which only logs the TD HOB and, when
+    /// present, its configuration volume.
+    fn td_shim_rtmr0_log(&self, machine: &Machine) -> Result<Vec<Vec<u8>>> {
+        let mut rtmr0_log = vec![self.measure_td_hob(machine)?];
+        if self.sections.iter().any(|s| s.sec_type == TDVF_SECTION_TD_CFV) {
+            rtmr0_log.push(self.measure_cfv()?);
+        }
+        Ok(rtmr0_log)
+    }
 
-        for &(is_accepted, range_start, range_end) in &self.ranges {
-            if is_accepted || range_end <= start || range_start >= end {
-                new_ranges.push((is_accepted, range_start, range_end));
-            } else {
-                if range_start < start {
-                    new_ranges.push((false, range_start, start));
-                }
-                if range_end > end {
-                    new_ranges.push((false, end, range_end));
-                }
+    /// Measures RTMR1 for td-shim: the payload it starts, either embedded in
+    /// the image or the kernel loaded by the VMM, followed by its parameters.
+    pub(crate) fn td_shim_rtmr1(&self, machine: &Machine) -> Result<Vec<u8>> {
+        let payload = match self.payload()? {
+            Some(payload) => payload.to_vec(),
+            None => {
+                let kernel_path = machine.kernel.ok_or_else(|| anyhow!("Kernel path required for a td-shim without embedded payload"))?;
+                read_file_data(kernel_path)?
             }
+        };
+
+        let mut rtmr1_log = vec![measure_sha384(&payload)];
+        if !machine.kernel_cmdline.is_empty() {
+            rtmr1_log.push(measure_sha384(machine.kernel_cmdline.as_bytes()));
+        }
+
+        debug_print_log("RTMR1", &rtmr1_log);
+        Ok(measure_log(&rtmr1_log))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::td_hob::Hob;
+
+    const GIB: u64 = 1 << 30;
+    const OWNER: &str = "00000000-0000-0000-0000-000000000000";
+
+    fn section(sec_type: u32, memory_address: u64, memory_data_size: u64) -> TdvfSection {
+        TdvfSection { data_offset: 0, raw_data_size: 0, memory_address, memory_data_size, sec_type, attributes: 0 }
+    }
+
+    /// Sections laid out as in OVMF, the firmware volumes below 4 GiB
+    fn ovmf() -> Tdvf<'static> {
+        Tdvf {
+            fw: &[],
+            sections: vec![
+                section(TDVF_SECTION_BFV, 0xFFC8_4000, 0x37C000),
+                section(TDVF_SECTION_TD_CFV, 0xFFC0_0000, 0x84000),
+                section(TDVF_SECTION_TEMP_MEM, 0x80_0000, 0x9000),
+                section(TDVF_SECTION_TD_HOB, 0x80_9000, 0x2000),
+            ],
+            td_shim: false,
+        }
+    }
+
+    fn resource(resource_type: u32, attributes: u32, start: u64, length: u64) -> Hob {
+        Hob::ResourceDescriptor { owner: OWNER.to_string(), resource_type, attributes, start, length }
+    }
+
+    /// Resource HOBs of RAM up to `low_end` with the TempMem and TD_HOB sections accepted
+    fn low_memory(low_end: u64) -> Vec<Hob> {
+        vec![
+            resource(RESOURCE_MEMORY_UNACCEPTED, 0x07, 0, 0x80_0000),
+            resource(RESOURCE_SYSTEM_MEMORY, 0x07, 0x80_0000, 0x9000),
+            resource(RESOURCE_SYSTEM_MEMORY, 0x07, 0x80_9000, 0x2000),
+            resource(RESOURCE_MEMORY_UNACCEPTED, 0x07, 0x80_B000, low_end - 0x80_B000),
+        ]
+    }
+
+    fn end_of_hob_list(hobs: &[Hob]) -> u64 {
+        match hobs[0] {
+            Hob::HandoffInfoTable { version: 9, end_of_hob_list, .. } => end_of_hob_list,
+            _ => panic!("TD HOB does not start with a PHIT HOB"),
         }
-        new_ranges.push((true, start, end));
-        new_ranges.sort_by_key(|&(_, start, _)| start);
-        self.ranges = new_ranges;
+    }
+
+    #[test]
+    fn qemu_td_hob() {
+        let td_hob = ovmf().build_td_hob(Vmm::Qemu, 4 * GIB, &[]).unwrap();
+        assert_eq!(td_hob.address, 0x80_9000);
+        assert_eq!(td_hob.data.len(), 56 + 5 * 48);
+        assert_eq!(
+            hex::encode(td_hob.digest()),
+            "052983374f5dafc21b78ee18a5b59875b04f9b91d05acbfda9882236048a51d475e50ab236c56fdc8c6668e9442cc502"
+        );
+        let hobs = td_hob.hobs().unwrap();
+        assert_eq!(end_of_hob_list(&hobs), 0x80_9130);
+        let mut expected = low_memory(2 * GIB);
+        expected.push(resource(RESOURCE_MEMORY_UNACCEPTED, 0x07, 4 * GIB, 2 * GIB));
+        assert_eq!(hobs[1..], expected);
+
+        let sections: Vec<_> = td_hob.accepted.iter().map(|a| (a.section.as_str(), a.start, a.end)).collect();
+        assert_eq!(sections, [("TempMem", 0x80_0000, 0x80_9000), ("TD_HOB", 0x80_9000, 0x80_B000)]);
+    }
+
+    #[test]
+    fn qemu_lowmem_split() {
+        // Below 2.75 GiB all RAM is low memory, from there on it is split at 2 GiB
+        let below = QEMU_LOWMEM_SPLIT_SIZE - 0x10_0000;
+        let hobs = ovmf().build_td_hob(Vmm::Qemu, below, &[]).unwrap().hobs().unwrap();
+        assert_eq!(hobs[1..], low_memory(below));
+
+        let hobs = ovmf().build_td_hob(Vmm::Qemu, QEMU_LOWMEM_SPLIT_SIZE, &[]).unwrap().hobs().unwrap();
+        let mut expected = low_memory(2 * GIB);
+        expected.push(resource(RESOURCE_MEMORY_UNACCEPTED, 0x07, 4 * GIB, QEMU_LOWMEM_SPLIT_SIZE - 2 * GIB));
+        assert_eq!(hobs[1..], expected);
+    }
+
+    #[test]
+    fn cloud_hypervisor_td_hob() {
+        let mut table = b"TEST".to_vec();
+        table.extend_from_slice(&38u32.to_le_bytes());
+        table.resize(38, 0xAB);
+        let mut tables = table.clone();
+        tables.extend(&table);
+
+        let td_hob = ovmf().build_td_hob(Vmm::CloudHypervisor, 4 * GIB, &tables).unwrap();
+        let hobs = td_hob.hobs().unwrap();
+        assert_eq!(end_of_hob_list(&hobs), 0x80_9000 + td_hob.data.len() as u64 + 8);
+
+        // RAM split at 3 GiB, the 32-bit device hole up to the APIC as MMIO, one HOB per table
+        let mut expected = low_memory(3 * GIB);
+        expected.push(resource(RESOURCE_MEMORY_UNACCEPTED, 0x07, 4 * GIB, GIB));
+        expected.push(resource(RESOURCE_MEMORY_MAPPED_IO, 0x403, 3 * GIB, 0xFEE0_0000 - 3 * GIB));
+        let mut padded = table.clone();
+        padded.resize(40, 0);
+        let guid = Hob::GuidExtension { name: "6A0C5870-D4ED-44F4-A135-DD238B6F0C8D".to_string(), data: padded };
+        expected.extend([guid.clone(), guid]);
+        assert_eq!(hobs[1..], expected);
+
+        // Every section in RAM is accepted, the firmware volumes lie in the device hole
+        let hobs = ovmf().build_td_hob(Vmm::CloudHypervisor, GIB, &[]).unwrap().hobs().unwrap();
+        let mut expected = low_memory(GIB);
+        expected.push(resource(RESOURCE_MEMORY_MAPPED_IO, 0x403, 3 * GIB, 0xFEE0_0000 - 3 * GIB));
+        assert_eq!(hobs[1..], expected);
     }
 }
@@ -0,0 +1,183 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! Parsing of Unified Kernel Images (UKI) and modeling of the events that
+//! systemd-stub and the Linux EFI stub log when a UKI is started.
+
+use anyhow::{anyhow, bail, Result};
+use object::read::pe::PeFile64;
+use object::LittleEndian as LE;
+use std::collections::BTreeMap;
+
+use crate::util::{measure_cmdline, measure_sha384};
+
+/// UKI sections measured by systemd-stub, in the order it measures them.
+/// `.pcrsig` is deliberately absent, it is never measured.
+const MEASURED_SECTIONS: &[&str] = &[
+    ".linux", ".osrel", ".cmdline", ".initrd", ".ucode", ".splash", ".dtb", ".uname", ".sbat",
+    ".pcrpkey",
+];
+
+/// A PE section as it is laid out in memory once the image is loaded.
+#[derive(Debug)]
+struct UkiSection {
+    name: String,
+    data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Uki {
+    sections: Vec<UkiSection>,
+}
+
+impl Uki {
+    /// Parses a PE image and returns it as a UKI if it carries a `.linux` section.
+    pub(crate) fn parse(data: &[u8]) -> Result<Option<Uki>> {
+        let pe = match PeFile64::parse(data) {
+            Ok(pe) => pe,
+            // Not a PE32+ image, e.g. a bzImage without EFI stub
+            Err(_) => return Ok(None),
+        };
+
+        let mut sections = Vec::new();
+        for header in pe.section_table().iter() {
+            let name_len = header.name.iter().position(|&b| b == 0).unwrap_or(header.name.len());
+            let name = String::from_utf8_lossy(&header.name[..name_len]).into_owned();
+
+            // Bytes beyond the raw data are zero-filled by the loader
+            let mut section_data = header
+                .pe_data(data)
+                .map_err(|e| anyhow!("Invalid PE section {name}: {e}"))?
+                .to_vec();
+            section_data.resize(header.virtual_size.get(LE) as usize, 0);
+
+            sections.push(UkiSection {
+                name,
+                data: section_data,
+            });
+        }
+
+        let uki = Uki { sections };
+        Ok(uki.section(".linux").is_some().then_some(uki))
+    }
+
+    /// Returns the in-memory content of a section.
+    pub(crate) fn section(&self, name: &str) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.data.as_slice())
+    }
+
+    /// Returns the embedded kernel image.
+    pub(crate) fn linux(&self) -> &[u8] {
+        self.section(".linux").unwrap_or_default()
+    }
+
+    /// Returns the embedded command line the way systemd-stub passes it on.
+    pub(crate) fn cmdline(&self) -> Option<String> {
+        self.section(".cmdline").map(|data| {
+            let text = String::from_utf8_lossy(data);
+            text.trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+                .to_string()
+        })
+    }
+
+    /// Returns the key/value pairs of the embedded os-release file.
+    pub(crate) fn os_release(&self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        if let Some(data) = self.section(".osrel") {
+            for line in String::from_utf8_lossy(data).lines() {
+                if let Some((key, value)) = line.split_once('=') {
+                    let value = value.trim_matches(|c| c == '"' || c == '\'' || c == '\0');
+                    fields.insert(key.trim().to_string(), value.to_string());
+                }
+            }
+        }
+        fields
+    }
+
+    /// Returns the initrds the stub hands to the kernel: microcode first, then `.initrd`.
+    pub(crate) fn initrds(&self) -> Result<Vec<Vec<u8>>> {
+        // systemd-stub passes these on in cpio archives it generates
+        for name in [".pcrsig", ".pcrpkey"] {
+            if self.section(name).is_some() {
+                bail!("UKI with a {name} section is not supported");
+            }
+        }
+        Ok([".ucode", ".initrd"]
+            .iter()
+            .filter_map(|name| self.section(name))
+            .map(<[u8]>::to_vec)
+            .collect())
+    }
+
+    /// Returns the events systemd-stub logs for the UKI sections: for each
+    /// section, the NUL-terminated section name followed by its content.
+    pub(crate) fn section_events(&self) -> Vec<Vec<u8>> {
+        let mut events = Vec::new();
+        for name in MEASURED_SECTIONS {
+            if let Some(data) = self.section(name) {
+                let mut name_data = name.as_bytes().to_vec();
+                name_data.push(0);
+                events.push(measure_sha384(&name_data));
+                events.push(measure_sha384(data));
+            }
+        }
+        events
+    }
+}
+
+/// Returns the RTMR2 events logged from the moment systemd-stub starts until
+/// the kernel has measured its command line and initrd.
+///
+/// Load options are measured as UTF-16LE including the terminating NUL, both
+/// by systemd-stub and by the kernel, since the stub passes the NUL on.
+/// `load_options` are the options the stub was started with. With Secure Boot
+/// disabled they replace the embedded `.cmdline`, and the stub measures them.
+/// `extra_initrds` are appended to the embedded initrd, as for initrds that
+/// the boot loader provides.
+pub(crate) fn stub_rtmr2_events(
+    uki: &Uki,
+    load_options: Option<&str>,
+    extra_initrds: &[Vec<u8>],
+) -> Result<Vec<Vec<u8>>> {
+    let mut events = uki.section_events();
+
+    let cmdline = match load_options.filter(|o| !o.is_empty()) {
+        Some(options) => {
+            events.push(measure_cmdline(options));
+            Some(options.to_string())
+        }
+        None => uki.cmdline(),
+    };
+
+    let mut initrds = uki.initrds()?;
+    initrds.extend_from_slice(extra_initrds);
+    let initrd = combine_initrds(&initrds);
+
+    // The kernel EFI stub measures its load options and the initrd it loads
+    if let Some(cmdline) = cmdline.filter(|c| !c.is_empty()) {
+        events.push(measure_cmdline(&cmdline));
+    }
+    if !initrd.is_empty() {
+        events.push(measure_sha384(&initrd));
+    }
+    Ok(events)
+}
+
+/// Combines initrds the way systemd-stub does: a single initrd is passed on
+/// as is, several are concatenated with each one padded to 4 bytes.
+fn combine_initrds(initrds: &[Vec<u8>]) -> Vec<u8> {
+    let initrds: Vec<&Vec<u8>> = initrds.iter().filter(|i| !i.is_empty()).collect();
+    if let [initrd] = initrds.as_slice() {
+        return initrd.to_vec();
+    }
+    let mut data = Vec::new();
+    for initrd in initrds {
+        data.extend_from_slice(initrd);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+    data
+}
@@ -4,7 +4,7 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 use log::debug;
-use sha2::{Digest, Sha384};
+use sha2::{Digest, Sha256, Sha384};
 use crate::{num::read_le};
 use anyhow::{bail, Result};
 use object::pe;
@@ -61,8 +61,28 @@ pub(crate) fn measure_cmdline(cmdline: &str) -> Vec<u8> {
     measure_sha384(&utf16_cmdline)
 }
 
-/// Calculates the Authenticode hash of a PE/COFF file
+/// Concatenates initrds the way they are handed to the kernel, each aligned to 4 bytes.
+pub(crate) fn concat_initrds(initrds: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for initrd in initrds {
+        data.resize(data.len().next_multiple_of(4), 0);
+        data.extend_from_slice(initrd);
+    }
+    data
+}
+
+/// Calculates the SHA384 Authenticode hash of a PE/COFF file
 pub(crate) fn authenticode_sha384_hash(data: &[u8]) -> Result<Vec<u8>> {
+    authenticode_hash::<Sha384>(data)
+}
+
+/// Calculates the SHA256 Authenticode hash of a PE/COFF file, as listed in Secure Boot databases
+pub(crate) fn authenticode_sha256_hash(data: &[u8]) -> Result<Vec<u8>> {
+    authenticode_hash::<Sha256>(data)
+}
+
+/// Calculates the Authenticode hash of a PE/COFF file
+fn authenticode_hash<D: Digest>(data: &[u8]) -> Result<Vec<u8>> {
     let lfanew_offset = 0x3c;
     let lfanew: u32 = read_le(data, lfanew_offset, "DOS header")?;
 
@@ -91,7 +111,7 @@ pub(crate) fn authenticode_sha384_hash(data: &[u8]) -> Result<Vec<u8>> {
     let size_of_headers_offset = optional_header_offset + 60;
     let size_of_headers = read_le::<u32>(data, size_of_headers_offset, "size_of_headers")? as usize;
 
-    let mut hasher = Sha384::new();
+    let mut hasher = D::new();
     hasher.update(&data[0..checksum_offset]);
     hasher.update(&data[checksum_end..cert_dir_offset]);
     hasher.update(&data[cert_dir_end..size_of_headers]);
@@ -0,0 +1,291 @@
+/*
+ * Copyright (c) 2025 Intel Corporation
+ * SPDX-License-Identifier: Apache-2.0
+ */
+//! dm-verity hash tree and root hash, computed the way `veritysetup format`
+//! does, and substituted into the kernel command line template.
+
+use anyhow::{anyhow, bail, Context, Result};
+use fs_err as fs;
+use log::warn;
+use sha2::{Digest, Sha256, Sha512};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::{ImageConfig, VerityConfig};
+
+/// Hash algorithms accepted by `veritysetup --hash`.
+#[derive(Debug, Clone, Copy)]
+enum VerityHash {
+    Sha256,
+    Sha512,
+}
+
+impl VerityHash {
+    fn parse(name: &str) -> Result<Self> {
+        match name {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            _ => bail!("Unsupported dm-verity hash algorithm {name}"),
+        }
+    }
+
+    fn digest_size(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+
+    /// Hashes a block with its salt, prepended in format 1 and appended in format 0.
+    fn hash(self, format: u32, salt: &[u8], block: &[u8]) -> Vec<u8> {
+        let (first, second) = if format == 0 { (block, salt) } else { (salt, block) };
+        match self {
+            Self::Sha256 => Sha256::new().chain_update(first).chain_update(second).finalize().to_vec(),
+            Self::Sha512 => Sha512::new().chain_update(first).chain_update(second).finalize().to_vec(),
+        }
+    }
+}
+
+/// Result of the hash tree computation.
+#[derive(Debug, Clone)]
+pub(crate) struct VerityTree {
+    pub root_hash: Vec<u8>,
+    pub data_blocks: u64,
+}
+
+fn check_block_size(name: &str, size: u32) -> Result<usize> {
+    if !size.is_power_of_two() || !(512..=(512 * 1024)).contains(&size) {
+        bail!("Invalid dm-verity {name} {size}");
+    }
+    Ok(size as usize)
+}
+
+/// Computes the dm-verity hash tree of a data image and returns its root hash.
+pub(crate) fn compute_hash_tree(config: &VerityConfig, image: &Path) -> Result<VerityTree> {
+    let hash = VerityHash::parse(&config.hash)?;
+    let data_block_size = check_block_size("data block size", config.data_block_size)?;
+    let hash_block_size = check_block_size("hash block size", config.hash_block_size)?;
+    if config.format > 1 {
+        bail!("Unsupported dm-verity format {}", config.format);
+    }
+    let salt = match config.salt.as_str() {
+        "-" => vec![],
+        salt => hex::decode(salt).map_err(|e| anyhow!("Invalid dm-verity salt: {e}"))?,
+    };
+
+    let image_size = fs::metadata(image)?
+        .len()
+        .checked_sub(config.offset)
+        .with_context(|| format!("dm-verity data offset is beyond the end of {}", image.display()))?;
+    let data_blocks = match config.data_blocks {
+        Some(blocks) => blocks,
+        None => {
+            if image_size % data_block_size as u64 != 0 {
+                bail!("Size of {} is not a multiple of the data block size", image.display());
+            }
+            image_size / data_block_size as u64
+        }
+    };
+    if data_blocks == 0 || data_blocks * data_block_size as u64 > image_size {
+        bail!("Invalid number of dm-verity data blocks {data_blocks}");
+    }
+
+    // Level 0: digests of the data blocks, read without loading the whole image
+    let mut reader = BufReader::new(fs::File::open(image)?);
+    reader.seek(SeekFrom::Start(config.offset))?;
+    let mut block = vec![0u8; data_block_size];
+    let mut digests = Vec::with_capacity(data_blocks as usize);
+    for _ in 0..data_blocks {
+        reader.read_exact(&mut block).context("Failed to read dm-verity data block")?;
+        digests.push(hash.hash(config.format, &salt, &block));
+    }
+
+    // Format 1 pads each digest to a power of two, format 0 packs them
+    let digest_size = hash.digest_size();
+    let digest_size_full = if config.format == 0 { digest_size } else { digest_size.next_power_of_two() };
+    let hashes_per_block = 1 << (hash_block_size / digest_size).ilog2();
+
+    if digests.len() == 1 {
+        return Ok(VerityTree { root_hash: digests.remove(0), data_blocks });
+    }
+
+    loop {
+        let blocks: Vec<Vec<u8>> = digests
+            .chunks(hashes_per_block)
+            .map(|chunk| {
+                let mut block = Vec::with_capacity(hash_block_size);
+                for digest in chunk {
+                    block.extend_from_slice(digest);
+                    block.resize(block.len() + digest_size_full - digest_size, 0);
+                }
+                block.resize(hash_block_size, 0);
+                block
+            })
+            .collect();
+
+        digests = blocks.iter().map(|b| hash.hash(config.format, &salt, b)).collect();
+        if digests.len() == 1 {
+            return Ok(VerityTree { root_hash: digests.remove(0), data_blocks });
+        }
+    }
+}
+
+/// Substitutes the `{root_hash}`, `{salt}`, `{hash}`, `{data_blocks}`, `{data_sectors}`,
+/// `{data_block_size}` and `{hash_block_size}` placeholders of a command line.
+pub(crate) fn substitute_cmdline(cmdline: &str, config: &VerityConfig, tree: &VerityTree) -> String {
+    if !cmdline.contains("{root_hash}") {
+        warn!("Kernel command line does not reference the computed dm-verity {{root_hash}}");
+    }
+    cmdline
+        .replace("{root_hash}", &hex::encode(&tree.root_hash))
+        .replace("{salt}", &config.salt)
+        .replace("{hash}", &config.hash)
+        .replace("{data_blocks}", &tree.data_blocks.to_string())
+        .replace("{data_sectors}", &(tree.data_blocks * config.data_block_size as u64 / 512).to_string())
+        .replace("{data_block_size}", &config.data_block_size.to_string())
+        .replace("{hash_block_size}", &config.hash_block_size.to_string())
+}
+
+impl ImageConfig {
+    /// Computes the dm-verity root hash of the direct boot rootfs, if one is
+    /// configured, and substitutes it into the kernel command line.
+    /// `base_dir` is the directory the metadata paths are relative to.
+    pub fn apply_verity(&mut self, base_dir: &Path) -> Result<()> {
+        let Some(direct) = self.direct.as_mut() else {
+            return Ok(());
+        };
+        let Some(verity) = &direct.verity else {
+            return Ok(());
+        };
+
+        let tree = compute_hash_tree(verity, &base_dir.join(&verity.image))?;
+        log::debug!("dm-verity root hash: {}", hex::encode(&tree.root_hash));
+        direct.cmdline = substitute_cmdline(&direct.cmdline, verity, &tree);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const SALT: &str = "e48da609055204e89ae53b655ca2216dd983cf3cb829f34f63a297d106d53e2d";
+
+    fn config(salt: &str, block_size: u32, format: u32) -> VerityConfig {
+        VerityConfig {
+            image: "rootfs.img".to_string(),
+            salt: salt.to_string(),
+            hash: "sha256".to_string(),
+            data_block_size: block_size,
+            hash_block_size: block_size,
+            data_blocks: None,
+            offset: 0,
+            format,
+        }
+    }
+
+    fn image_file(data: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+        parts.iter().fold(Sha256::new(), |h, p| h.chain_update(p)).finalize().to_vec()
+    }
+
+    /// Data blocks filled with their number
+    fn numbered_blocks(count: u8, block_size: usize) -> Vec<u8> {
+        (0..count).flat_map(|n| vec![n; block_size]).collect()
+    }
+
+    #[test]
+    fn veritysetup_root_hash() {
+        // cryptsetup verity-compat-test: 8 MiB of zeros, 512-byte blocks
+        let image = image_file(&vec![0; 8 << 20]);
+        let tree = compute_hash_tree(&config(SALT, 512, 1), image.path()).unwrap();
+        assert_eq!(tree.data_blocks, 16384);
+        assert_eq!(hex::encode(tree.root_hash), "9de18652fe74edfb9b805aaed72ae2aa48f94333f1ba5c452ac33b1c39325174");
+    }
+
+    #[test]
+    fn salted_hash_blocks() {
+        let salt = hex::decode(SALT).unwrap();
+        let data = numbered_blocks(3, 512);
+        let image = image_file(&data);
+        let digests: Vec<_> = data.chunks(512).map(|b| (sha256(&[&salt, b]), sha256(&[b, &salt]))).collect();
+
+        // Format 1 prepends the salt, format 0 appends it
+        let mut hash_block: Vec<u8> = digests.iter().flat_map(|(prepended, _)| prepended.clone()).collect();
+        hash_block.resize(512, 0);
+        let tree = compute_hash_tree(&config(SALT, 512, 1), image.path()).unwrap();
+        assert_eq!(tree.root_hash, sha256(&[&salt, &hash_block]));
+
+        let mut hash_block: Vec<u8> = digests.iter().flat_map(|(_, appended)| appended.clone()).collect();
+        hash_block.resize(512, 0);
+        let tree = compute_hash_tree(&config(SALT, 512, 0), image.path()).unwrap();
+        assert_eq!(tree.root_hash, sha256(&[&hash_block, &salt]));
+
+        // No salt, and a single block whose digest is the root hash
+        let tree = compute_hash_tree(&config("-", 512, 1), image_file(&data[..512]).path()).unwrap();
+        assert_eq!(tree.root_hash, sha256(&[&data[..512]]));
+    }
+
+    #[test]
+    fn hash_tree_levels() {
+        // 16 SHA256 digests fit in a 512-byte hash block, 17 data blocks need two levels
+        let data = numbered_blocks(17, 512);
+        let image = image_file(&data);
+        let hash_block = |digests: &[Vec<u8>]| {
+            let mut block = digests.concat();
+            block.resize(512, 0);
+            sha256(&[&block])
+        };
+        let digests: Vec<_> = data.chunks(512).map(|b| sha256(&[b])).collect();
+        let level1 = [hash_block(&digests[..16]), hash_block(&digests[16..])];
+        let tree = compute_hash_tree(&config("-", 512, 1), image.path()).unwrap();
+        assert_eq!(tree.root_hash, hash_block(&level1));
+
+        // SHA512 digests are padded to 64 bytes in format 1, 8 per block
+        let mut sha512 = config("-", 512, 1);
+        sha512.hash = "sha512".to_string();
+        sha512.data_blocks = Some(8);
+        let mut block: Vec<u8> = data[..8 * 512].chunks(512).flat_map(|b| Sha512::digest(b).to_vec()).collect();
+        block.resize(512, 0);
+        let tree = compute_hash_tree(&sha512, image.path()).unwrap();
+        assert_eq!(tree.root_hash, Sha512::digest(&block).to_vec());
+        assert_eq!(tree.data_blocks, 8);
+    }
+
+    #[test]
+    fn data_range() {
+        let data = numbered_blocks(4, 512);
+        let image = image_file(&data);
+        let mut offset = config("-", 512, 1);
+        offset.offset = 1024;
+        offset.data_blocks = Some(1);
+        assert_eq!(compute_hash_tree(&offset, image.path()).unwrap().root_hash, sha256(&[&data[1024..1536]]));
+
+        offset.data_blocks = Some(3);
+        assert!(compute_hash_tree(&offset, image.path()).is_err());
+        assert!(compute_hash_tree(&config("-", 4096, 1), image.path()).is_err());
+        assert!(compute_hash_tree(&config("-", 1000, 1), image.path()).is_err());
+    }
+
+    #[test]
+    fn cmdline_placeholders() {
+        let tree = VerityTree { root_hash: vec![0xab; 4], data_blocks: 10 };
+        let cmdline = substitute_cmdline(
+            "dm-mod.create=\"vroot,,,ro,0 {data_sectors} verity 1 /dev/vda /dev/vdb {data_block_size} \
+             {hash_block_size} {data_blocks} 1 {hash} {root_hash} {salt}\"",
+            &config(SALT, 4096, 1),
+            &tree,
+        );
+        assert_eq!(
+            cmdline,
+            format!("dm-mod.create=\"vroot,,,ro,0 80 verity 1 /dev/vda /dev/vdb 4096 4096 10 1 sha256 abababab {SALT}\"")
+        );
+    }
+}